@@ -21,10 +21,10 @@ pub fn bench_canon(c: &mut Criterion) {
     });
 }
 
-fn generate_build_ninja() -> Vec<u8> {
+fn generate_build_ninja(builds: usize) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
     write!(buf, "rule cc\n    command = touch $out",).unwrap();
-    for i in 0..1000 {
+    for i in 0..builds {
         write!(
             buf,
             "build $out/foo/bar{}.o: cc $src/long/file/name{}.cc
@@ -37,7 +37,7 @@ fn generate_build_ninja() -> Vec<u8> {
 }
 
 fn bench_parse_synthetic(c: &mut Criterion) {
-    let mut input = generate_build_ninja();
+    let mut input = generate_build_ninja(1000);
     input.push(0);
     c.bench_function("parse synthetic build.ninja", |b| {
         b.iter(|| {
@@ -54,7 +54,7 @@ fn bench_parse_synthetic(c: &mut Criterion) {
 fn bench_parse_file(c: &mut Criterion, input: &[u8]) {
     c.bench_function("parse benches/build.ninja", |b| {
         b.iter(|| {
-            let mut parser = n2::parse::Parser::new(&input);
+            let mut parser = n2::parse::Parser::new(input);
             loop {
                 if parser.read().unwrap().is_none() {
                     break;
@@ -76,7 +76,8 @@ pub fn bench_parse(c: &mut Criterion) {
 }
 
 fn bench_load_synthetic(c: &mut Criterion) {
-    let mut input = generate_build_ninja();
+    // Large enough that file name bookkeeping shows up, as in a big tree.
+    let mut input = generate_build_ninja(100_000);
     input.push(0);
     c.bench_function("load synthetic build.ninja", |b| {
         b.iter(|| {
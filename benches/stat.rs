@@ -0,0 +1,26 @@
+//! stat()ing a directory of source files one by one, against first listing
+//! the directory as with --stat-by-directory.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use n2::graph::{FileState, Graph, Symlinks};
+
+fn bench_stat_all(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut graph = Graph::default();
+    for i in 0..5000 {
+        let path = dir.path().join(format!("file{}.c", i));
+        std::fs::write(&path, "").unwrap();
+        graph.files.id_from_canonical(path.display().to_string());
+    }
+    for (name, by_directory) in [("stat per file", false), ("stat by directory", true)] {
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let mut file_state = FileState::with_symlinks(&graph, Symlinks::Follow);
+                file_state.stat_all(&graph.files, by_directory).unwrap();
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_stat_all);
+criterion_main!(benches);
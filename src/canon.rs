@@ -58,7 +58,14 @@ pub fn canon_path_fast(path: &mut str) -> usize {
         if src == end {
             return 0;
         }
-        if *src == b'/' || *src == b'\\' {
+        // A Windows drive letter is part of the root, like a leading '/'.
+        // "c:" and "C:" are the same drive, so settle on the latter.
+        if cfg!(windows) && path.len() >= 2 && *src.add(1) == b':' && (*src).is_ascii_alphabetic() {
+            *dst = (*src).to_ascii_uppercase();
+            src = src.add(2);
+            dst = dst.add(2);
+        }
+        if src < end && (*src == b'/' || *src == b'\\') {
             src = src.add(1);
             dst = dst.add(1);
         }
@@ -142,6 +149,118 @@ pub fn canon_path<T: Into<String>>(path: T) -> String {
     path
 }
 
+/// Like canon_path(), but a ".." that follows a symlink is resolved against
+/// the link's target, as the OS does, rather than by dropping the link's own
+/// name: with a/b linking to ../x/y, "a/b/../c" is "x/c" and not "a/c".
+/// Only the components before a ".." are checked, so paths without ".."
+/// never touch the disk.  Links that can't be read, or chains of more than
+/// 40 of them, are collapsed lexically as canon_path() would.
+pub fn canon_path_physical<T: Into<String>>(path: T) -> String {
+    let path = path.into();
+    if !path.contains("..") {
+        return canon_path(path);
+    }
+    let mut links_left = 40;
+    canon_path(resolve_parents(&path, &mut links_left))
+}
+
+fn resolve_parents(path: &str, links_left: &mut usize) -> String {
+    fn is_sep(c: char) -> bool {
+        c == '/' || c == '\\'
+    }
+    fn join(absolute: bool, parts: &[String]) -> String {
+        let joined = parts.join("/");
+        if absolute {
+            format!("/{}", joined)
+        } else if joined.is_empty() {
+            ".".to_owned()
+        } else {
+            joined
+        }
+    }
+
+    let mut absolute = path.starts_with(is_sep);
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.split(is_sep) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                // While the path so far ends in a symlink, swap in its target.
+                while *links_left > 0 && parts.last().is_some_and(|p| p != "..") {
+                    let prefix = join(absolute, &parts);
+                    let target = match std::fs::read_link(&prefix) {
+                        Ok(target) => target.to_string_lossy().into_owned(),
+                        Err(_) => break,
+                    };
+                    *links_left -= 1;
+                    let target = if target.starts_with(is_sep) {
+                        target
+                    } else {
+                        format!("{}/{}", join(absolute, &parts[..parts.len() - 1]), target)
+                    };
+                    let resolved = resolve_parents(&target, links_left);
+                    absolute = resolved.starts_with(is_sep);
+                    parts = resolved
+                        .split(is_sep)
+                        .filter(|c| !c.is_empty() && *c != ".")
+                        .map(str::to_owned)
+                        .collect();
+                }
+                match parts.last() {
+                    Some(p) if p != ".." => {
+                        parts.pop();
+                    }
+                    // "/.." is "/".
+                    _ if absolute => {}
+                    _ => parts.push("..".to_owned()),
+                }
+            }
+            _ => parts.push(component.to_owned()),
+        }
+    }
+    join(absolute, &parts)
+}
+
+/// Lexically express a canonical path relative to a canonical directory, as
+/// in "a/b/c" relative to "a/d" => "../b/c".  Returns the path unchanged if
+/// that can't be done without looking at the disk: if only one of the two is
+/// absolute, or if the directory climbs further up ("..") than the path.
+pub fn relative_to(path: &str, dir: &str) -> String {
+    fn is_sep(c: char) -> bool {
+        c == '/' || (cfg!(windows) && c == '\\')
+    }
+    fn is_absolute(path: &str) -> bool {
+        path.starts_with(is_sep) || (cfg!(windows) && path.as_bytes().get(1) == Some(&b':'))
+    }
+    fn components(path: &str) -> Vec<&str> {
+        path.split(is_sep)
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect()
+    }
+
+    if is_absolute(path) != is_absolute(dir) {
+        return path.to_owned();
+    }
+    let path_parts = components(path);
+    let dir_parts = components(dir);
+    let common = path_parts
+        .iter()
+        .zip(&dir_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    // Absolute paths with nothing in common may be on different drives.
+    if dir_parts[common..].contains(&"..") || (is_absolute(path) && common == 0) {
+        return path.to_owned();
+    }
+    let mut parts = vec![".."; dir_parts.len() - common];
+    parts.extend(&path_parts[common..]);
+    if parts.is_empty() {
+        ".".to_owned()
+    } else {
+        parts.join("/")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,4 +313,54 @@ mod tests {
         assert_canon_path_eq("foo/../../", "../");
         assert_canon_path_eq("foo/../../bar", "../bar");
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn physical() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path().to_str().unwrap();
+        std::fs::create_dir_all(format!("{}/real/sub", dir))?;
+        std::fs::create_dir(format!("{}/a", dir))?;
+        std::os::unix::fs::symlink("../real/sub", format!("{}/a/b", dir))?;
+        std::os::unix::fs::symlink(format!("{}/a/b", dir), format!("{}/abs", dir))?;
+
+        let physical = |path: &str| canon_path_physical(format!("{}/{}", dir, path));
+        assert_eq!(physical("a/b/../c"), format!("{}/real/c", dir));
+        assert_eq!(physical("a/b/./../../c"), format!("{}/c", dir));
+        // A chain of links, the first one absolute.
+        assert_eq!(physical("abs/../c"), format!("{}/real/c", dir));
+        // Plain directories, or paths that don't exist, are collapsed lexically.
+        assert_eq!(physical("a/x/../c"), format!("{}/a/c", dir));
+        assert_eq!(physical("a/b/c"), format!("{}/a/b/c", dir));
+        assert_eq!(canon_path_physical("../x/./y"), "../x/y");
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_letter() {
+        assert_eq!(canon_path("c:\\foo\\..\\bar"), "C:\\bar");
+        assert_eq!(canon_path("C:/foo/./bar"), "C:/foo/bar");
+        assert_eq!(canon_path("c:foo/../bar"), "C:bar");
+        // The drive stays put, but as after a leading '/', a ".." past the
+        // root is kept rather than dropped.
+        assert_eq!(canon_path("c:/foo/../../bar"), "C:/../bar");
+        // Only the drive letter's case changes.
+        assert_eq!(canon_path("d:/Foo/Bar"), "D:/Foo/Bar");
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(relative_to("sub/a.c", "sub"), "a.c");
+        assert_eq!(relative_to("sub/x/a.c", "sub"), "x/a.c");
+        assert_eq!(relative_to("a.c", "sub"), "../a.c");
+        assert_eq!(relative_to("other/a.c", "sub/dir"), "../../other/a.c");
+        assert_eq!(relative_to("../a.c", "sub"), "../../a.c");
+        assert_eq!(relative_to("sub", "sub"), ".");
+        assert_eq!(relative_to("a.c", "."), "a.c");
+        assert_eq!(relative_to("/abs/a.c", "sub"), "/abs/a.c");
+        assert_eq!(relative_to("/abs/a.c", "/abs/sub"), "../a.c");
+        // The name of the directory above ".." can't be known lexically.
+        assert_eq!(relative_to("a.c", "../up"), "a.c");
+    }
 }
@@ -11,9 +11,27 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version 2 added content hashes to build records.  Version 3 added the
+/// version of n2 that created the db to the header.  Version 4 added how long
+/// each build took to build records.  Older databases are still read, and
+/// appended to in their own format until recompacted; newer ones are
+/// discarded.
+const VERSION: u32 = 4;
+
+/// The header of a db that this n2 can't read, e.g. one from a newer n2.
+#[derive(Debug)]
+struct Incompatible(String);
+
+impl std::fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-const VERSION: u32 = 1;
+impl std::error::Error for Incompatible {}
 
 /// Files are identified by integers that are stable across n2 executions.
 #[derive(Debug, Clone, Copy)]
@@ -57,6 +75,10 @@ impl RecordWriter {
         self.write(&n.to_le_bytes()[..3]);
     }
 
+    fn write_u32(&mut self, n: u32) {
+        self.write(&n.to_le_bytes());
+    }
+
     fn write_u64(&mut self, n: u64) {
         self.write(&n.to_le_bytes());
     }
@@ -82,23 +104,34 @@ impl RecordWriter {
 pub struct Writer {
     ids: IdMap,
     w: File,
+    path: PathBuf,
+    /// The format version of the file being appended to.
+    version: u32,
 }
 
 impl Writer {
     fn create(path: &Path) -> std::io::Result<Self> {
         let f = std::fs::File::create(path)?;
-        let mut w = Self::from_opened(IdMap::default(), f);
+        let mut w = Self::from_opened(IdMap::default(), f, path, VERSION);
         w.write_signature()?;
         Ok(w)
     }
 
-    fn from_opened(ids: IdMap, w: File) -> Self {
-        Writer { ids, w }
+    fn from_opened(ids: IdMap, w: File, path: &Path, version: u32) -> Self {
+        Writer {
+            ids,
+            w,
+            path: path.to_owned(),
+            version,
+        }
     }
 
     fn write_signature(&mut self) -> std::io::Result<()> {
-        self.w.write_all("n2db".as_bytes())?;
-        self.w.write_all(&u32::to_le_bytes(VERSION))
+        let mut w = RecordWriter::default();
+        w.write("n2db".as_bytes());
+        w.write(&u32::to_le_bytes(VERSION));
+        w.write_str(env!("CARGO_PKG_VERSION"));
+        w.finish(&mut self.w)
     }
 
     fn write_path(&mut self, name: &str) -> std::io::Result<()> {
@@ -106,7 +139,7 @@ impl Writer {
             panic!("filename too long");
         }
         let mut w = RecordWriter::default();
-        w.write_str(&name);
+        w.write_str(name);
         w.finish(&mut self.w)
     }
 
@@ -128,10 +161,14 @@ impl Writer {
         graph: &Graph,
         id: BuildId,
         hash: BuildHash,
+        content_hash: Option<BuildHash>,
+        time: Option<Duration>,
     ) -> std::io::Result<()> {
         let build = &graph.builds[id];
         let mut w = RecordWriter::default();
-        let outs = build.outs();
+        // Outputs from a dyndep file aren't known when the db is read, so
+        // leave them out or the record wouldn't match the build.
+        let outs = build.manifest_outs();
         let mark = (outs.len() as u16) | 0b1000_0000_0000_0000;
         w.write_u16(mark);
         for &out in outs {
@@ -147,44 +184,98 @@ impl Writer {
         }
 
         w.write_u64(hash.0);
+        if self.version >= 2 {
+            // Zero stands for no content hash.
+            w.write_u64(content_hash.map_or(0, |h| h.0));
+        }
+        if self.version >= 4 {
+            // Milliseconds, with zero standing for unknown.
+            let ms = time.map_or(0, |t| t.as_millis().clamp(1, u32::MAX as u128) as u32);
+            w.write_u32(ms);
+        }
         w.finish(&mut self.w)
     }
+
+    /// Rewrite the database from scratch, keeping only the latest record of
+    /// each build still in the graph, and return a Writer appending to the
+    /// new file.  The db otherwise only grows, as every build appends.
+    pub fn recompact(self, graph: &Graph, hashes: &Hashes) -> std::io::Result<Writer> {
+        let path = self.path;
+        // Close the old file first, as Windows can't rename over an open file.
+        drop(self.w);
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut w = Writer::create(&tmp_path)?;
+        for id in graph.builds.all_ids() {
+            if let Some(hash) = hashes.get(id) {
+                w.write_build(graph, id, hash, hashes.get_content(id), hashes.get_time(id))?;
+            }
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        w.path = path;
+        Ok(w)
+    }
 }
 
 struct Reader<'a> {
     r: BufReader<&'a mut File>,
+    /// Bytes consumed so far.
+    pos: u64,
+    version: u32,
     ids: IdMap,
     graph: &'a mut Graph,
     hashes: &'a mut Hashes,
 }
 
 impl<'a> Reader<'a> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.r.read_exact(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
     fn read_u16(&mut self) -> std::io::Result<u16> {
         let mut buf: [u8; 2] = [0; 2];
-        self.r.read_exact(&mut buf[..])?;
+        self.read_exact(&mut buf[..])?;
         Ok(u16::from_le_bytes(buf))
     }
 
     fn read_u24(&mut self) -> std::io::Result<u32> {
         let mut buf: [u8; 4] = [0; 4];
-        self.r.read_exact(&mut buf[..3])?;
+        self.read_exact(&mut buf[..3])?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        let mut buf: [u8; 4] = [0; 4];
+        self.read_exact(&mut buf)?;
         Ok(u32::from_le_bytes(buf))
     }
 
     fn read_u64(&mut self) -> std::io::Result<u64> {
         let mut buf: [u8; 8] = [0; 8];
-        self.r.read_exact(&mut buf)?;
+        self.read_exact(&mut buf)?;
         Ok(u64::from_le_bytes(buf))
     }
 
-    fn read_id(&mut self) -> std::io::Result<Id> {
-        self.read_u24().map(Id)
+    /// Reads an id, which must refer to a path already read.
+    fn read_id(&mut self) -> std::io::Result<FileId> {
+        let id = Id(self.read_u24()?);
+        match self.ids.fileids.lookup(id) {
+            Some(&fileid) => Ok(fileid),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown file id {}", id.0),
+            )),
+        }
     }
 
     fn read_str(&mut self, len: usize) -> std::io::Result<String> {
         let mut buf = vec![0; len];
-        self.r.read_exact(buf.as_mut_slice())?;
-        Ok(unsafe { String::from_utf8_unchecked(buf) })
+        self.read_exact(buf.as_mut_slice())?;
+        String::from_utf8(buf)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
     }
 
     fn read_path(&mut self, len: usize) -> std::io::Result<()> {
@@ -217,7 +308,7 @@ impl<'a> Reader<'a> {
                 // keep reading to parse through it.
                 continue;
             }
-            match self.graph.file(self.ids.fileids[fileid]).input {
+            match self.graph.file(fileid).input {
                 None => {
                     obsolete = true;
                 }
@@ -240,65 +331,121 @@ impl<'a> Reader<'a> {
         let len = self.read_u16()?;
         let mut deps = Vec::new();
         for _ in 0..len {
-            let id = self.read_id()?;
-            deps.push(self.ids.fileids[id]);
+            deps.push(self.read_id()?);
         }
 
         let hash = BuildHash(self.read_u64()?);
+        let content_hash = if self.version >= 2 {
+            Some(self.read_u64()?).filter(|&h| h != 0).map(BuildHash)
+        } else {
+            None
+        };
+        let time = if self.version >= 4 {
+            Some(self.read_u32()?)
+                .filter(|&ms| ms != 0)
+                .map(|ms| Duration::from_millis(ms as u64))
+        } else {
+            None
+        };
 
         // unique_bid is set here if this record is valid.
         if let Some(id) = unique_bid {
             // Common case: only one associated build.
             self.graph.builds[id].set_discovered_ins(deps);
             self.hashes.set(id, hash);
+            self.hashes.set_content(id, content_hash);
+            self.hashes.set_time(id, time);
         }
         Ok(())
     }
 
     fn read_signature(&mut self) -> anyhow::Result<()> {
         let mut buf: [u8; 4] = [0; 4];
-        self.r.read_exact(&mut buf[..])?;
+        let bad_header = |err: std::io::Error| -> anyhow::Error {
+            match err.kind() {
+                std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData => {
+                    Incompatible(format!("bad header: {}", err)).into()
+                }
+                _ => err.into(),
+            }
+        };
+        self.read_exact(&mut buf[..]).map_err(bad_header)?;
         if buf.as_slice() != "n2db".as_bytes() {
-            bail!("invalid db signature");
+            return Err(Incompatible("invalid signature".to_owned()).into());
         }
-        self.r.read_exact(&mut buf[..])?;
+        self.read_exact(&mut buf[..]).map_err(bad_header)?;
         let version = u32::from_le_bytes(buf);
-        if version != VERSION {
-            bail!("db version mismatch: got {version}, expected {VERSION}; TODO: db upgrades etc");
+        // From version 3 on, the header always ends with the n2 version.
+        let writer = if version >= 3 {
+            let len = self.read_u16().map_err(bad_header)?;
+            let writer = self.read_str(len as usize).map_err(bad_header)?;
+            format!(" (from n2 {})", writer)
+        } else {
+            String::new()
+        };
+        if !(1..=VERSION).contains(&version) {
+            return Err(Incompatible(format!(
+                "version {}{}, expected at most {}",
+                version, writer, VERSION
+            ))
+            .into());
         }
+        self.version = version;
         Ok(())
     }
 
-    fn read_file(&mut self) -> anyhow::Result<()> {
+    fn read_record(&mut self) -> std::io::Result<()> {
+        let mut len = self.read_u16()?;
+        let mask = 0b1000_0000_0000_0000;
+        if len & mask == 0 {
+            self.read_path(len as usize)
+        } else {
+            len &= !mask;
+            self.read_build(len as usize)
+        }
+    }
+
+    /// Reads records up to the end of the file, which is file_len bytes.
+    /// If n2 was killed mid-write the file may end in a partial record, or
+    /// garbage; returns the offset of the first such bad record, if any.
+    fn read_file(&mut self, file_len: u64) -> anyhow::Result<Option<u64>> {
         self.read_signature()?;
-        loop {
-            let mut len = match self.read_u16() {
-                Ok(r) => r,
-                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+        while self.pos < file_len {
+            let start = self.pos;
+            match self.read_record() {
+                Ok(()) => {}
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof
+                        || err.kind() == std::io::ErrorKind::InvalidData =>
+                {
+                    return Ok(Some(start));
+                }
                 Err(err) => bail!(err),
-            };
-            let mask = 0b1000_0000_0000_0000;
-            if len & mask == 0 {
-                self.read_path(len as usize)?;
-            } else {
-                len &= !mask;
-                self.read_build(len as usize)?;
             }
         }
-        Ok(())
+        Ok(None)
     }
 
     /// Reads an on-disk database, loading its state into the provided Graph/Hashes.
-    fn read(f: &mut File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<IdMap> {
+    /// Returns the ids found, the file's format version, and the length to
+    /// truncate the file to if it ends in a bad record.
+    fn read(
+        f: &mut File,
+        graph: &mut Graph,
+        hashes: &mut Hashes,
+    ) -> anyhow::Result<(IdMap, u32, Option<u64>)> {
+        let file_len = f.metadata()?.len();
         let mut r = Reader {
             r: std::io::BufReader::new(f),
+            pos: 0,
+            version: VERSION,
             ids: IdMap::default(),
             graph,
             hashes,
         };
-        r.read_file()?;
+        let truncate = r.read_file(file_len)?;
 
-        Ok(r.ids)
+        Ok((r.ids, r.version, truncate))
     }
 }
 
@@ -310,8 +457,25 @@ pub fn open(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Resu
         .open(path)
     {
         Ok(mut f) => {
-            let ids = Reader::read(&mut f, graph, hashes)?;
-            Ok(Writer::from_opened(ids, f))
+            let (ids, version, truncate) = match Reader::read(&mut f, graph, hashes) {
+                Ok(r) => r,
+                Err(err) if err.is::<Incompatible>() => {
+                    warn!("{}: discarding db: {}", path.display(), err);
+                    drop(f);
+                    return Ok(Writer::create(path)?);
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(len) = truncate {
+                // Drop the bad tail so later records aren't appended after it.
+                warn!(
+                    "{}: discarding partial or corrupt records after byte {}",
+                    path.display(),
+                    len
+                );
+                f.set_len(len)?;
+            }
+            Ok(Writer::from_opened(ids, f, path, version))
         }
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
             let w = Writer::create(path)?;
@@ -320,3 +484,204 @@ pub fn open(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Resu
         Err(err) => Err(anyhow!(err)),
     }
 }
+
+/// Loads an on-disk database into the provided Graph without changing the
+/// file, as for a dry run.  A missing or incompatible database loads nothing,
+/// and a bad tail is ignored rather than truncated.
+pub fn load(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<()> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(anyhow!(err)),
+    };
+    match Reader::read(&mut f, graph, hashes) {
+        Ok((_, _, None)) => {}
+        Ok((_, _, Some(len))) => warn!(
+            "{}: ignoring partial or corrupt records after byte {}",
+            path.display(),
+            len
+        ),
+        Err(err) if err.is::<Incompatible>() => {
+            warn!("{}: ignoring db: {}", path.display(), err)
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recompact() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        let manifest = "
+rule cc
+  command = cc $in
+build a.o: cc a.c
+build b.o: cc b.c
+build gone.o: cc gone.c
+";
+        let mut graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+        let [a, b, gone] = ["a.o", "b.o", "gone.o"].map(|name| {
+            let id = graph.files.lookup(name).unwrap();
+            graph.file(id).input.unwrap()
+        });
+        let header = graph.files.id_from_canonical("a.h".to_owned());
+
+        let mut w = open(&path, &mut graph, &mut Hashes::default())?;
+        for i in 0..10 {
+            w.write_build(&graph, a, BuildHash(i), None, None)?;
+        }
+        graph.builds[b].set_discovered_ins(vec![header]);
+        w.write_build(
+            &graph,
+            b,
+            BuildHash(1),
+            Some(BuildHash(2)),
+            Some(Duration::from_millis(1500)),
+        )?;
+        w.write_build(&graph, gone, BuildHash(3), None, None)?;
+        drop(w);
+        let before = std::fs::metadata(&path)?.len();
+
+        // Reload with gone.o removed from the manifest, then recompact.
+        let manifest = manifest.replace("build gone.o: cc gone.c\n", "");
+        let mut graph = crate::load::parse("build.ninja", manifest.clone().into_bytes())?;
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph, &mut hashes)?.recompact(&graph, &hashes)?;
+        assert!(std::fs::metadata(&path)?.len() < before);
+
+        // The rewritten db loads the same state.
+        let mut graph2 = crate::load::parse("build.ninja", manifest.into_bytes())?;
+        let mut hashes2 = Hashes::default();
+        open(&path, &mut graph2, &mut hashes2)?;
+        for id in graph.builds.all_ids() {
+            assert_eq!(hashes2.get(id), hashes.get(id));
+            assert_eq!(hashes2.get_content(id), hashes.get_content(id));
+            assert_eq!(hashes2.get_time(id), hashes.get_time(id));
+            let names = |graph: &Graph| -> Vec<String> {
+                graph.builds[id]
+                    .discovered_ins()
+                    .iter()
+                    .map(|&f| graph.file(f).name.clone())
+                    .collect()
+            };
+            assert_eq!(names(&graph2), names(&graph));
+        }
+        assert_eq!(hashes2.get(a), Some(BuildHash(9)));
+        assert_eq!(hashes2.get_time(a), None);
+        assert_eq!(hashes2.get_time(b), Some(Duration::from_millis(1500)));
+        Ok(())
+    }
+
+    /// Write a small db, returning its path and the hash recorded for a.o.
+    fn write_db(dir: &Path, graph: &mut Graph) -> anyhow::Result<(PathBuf, BuildId)> {
+        let path = dir.join(".n2_db");
+        let a = graph
+            .file(graph.files.lookup("a.o").unwrap())
+            .input
+            .unwrap();
+        let mut w = open(&path, graph, &mut Hashes::default())?;
+        w.write_build(graph, a, BuildHash(7), None, None)?;
+        Ok((path, a))
+    }
+
+    #[test]
+    fn recover_from_garbage() -> anyhow::Result<()> {
+        let manifest = "rule cc\n  command = cc $in\nbuild a.o: cc a.c\n";
+        // A simple LCG, for reproducible garbage.
+        let mut seed: u32 = 1;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        for len in [1, 2, 5, 16, 100, 1000] {
+            let dir = tempfile::tempdir()?;
+            let mut graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+            let (path, a) = write_db(dir.path(), &mut graph)?;
+            let good_len = std::fs::metadata(&path)?.len();
+            let garbage: Vec<u8> = (0..len).map(|_| next()).collect();
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)?
+                .write_all(&garbage)?;
+
+            let mut graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+            let mut hashes = Hashes::default();
+            let mut w = open(&path, &mut graph, &mut hashes)?;
+            assert_eq!(hashes.get(a), Some(BuildHash(7)));
+            assert!(std::fs::metadata(&path)?.len() < good_len + len as u64);
+
+            // Records written after recovery load fine.
+            w.write_build(&graph, a, BuildHash(8), None, None)?;
+            drop(w);
+            let mut hashes = Hashes::default();
+            open(&path, &mut graph, &mut hashes)?;
+            assert_eq!(hashes.get(a), Some(BuildHash(8)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn recover_from_truncation() -> anyhow::Result<()> {
+        let manifest = "rule cc\n  command = cc $in\nbuild a.o: cc a.c\n";
+        let dir = tempfile::tempdir()?;
+        let mut graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+        let (path, a) = write_db(dir.path(), &mut graph)?;
+        let good_len = std::fs::metadata(&path)?.len();
+        {
+            let mut w = open(&path, &mut graph, &mut Hashes::default())?;
+            w.write_build(&graph, a, BuildHash(8), None, None)?;
+        }
+        // Cut the second build record short.
+        let f = std::fs::OpenOptions::new().write(true).open(&path)?;
+        f.set_len(std::fs::metadata(&path)?.len() - 3)?;
+
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph, &mut hashes)?;
+        assert_eq!(hashes.get(a), Some(BuildHash(7)));
+        assert_eq!(std::fs::metadata(&path)?.len(), good_len);
+        Ok(())
+    }
+
+    #[test]
+    fn discard_newer_version() -> anyhow::Result<()> {
+        let manifest = "rule cc\n  command = cc $in\nbuild a.o: cc a.c\n";
+        let dir = tempfile::tempdir()?;
+        let mut graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+        let (path, a) = write_db(dir.path(), &mut graph)?;
+
+        // Bump the version, as a future n2 would have written.
+        let mut bytes = std::fs::read(&path)?;
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        std::fs::write(&path, bytes)?;
+
+        let mut hashes = Hashes::default();
+        let mut w = open(&path, &mut graph, &mut hashes)?;
+        assert_eq!(hashes.get(a), None);
+        w.write_build(&graph, a, BuildHash(8), None, None)?;
+        drop(w);
+
+        // The db was started afresh at the current version.
+        let bytes = std::fs::read(&path)?;
+        assert_eq!(&bytes[4..8], &VERSION.to_le_bytes());
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph, &mut hashes)?;
+        assert_eq!(hashes.get(a), Some(BuildHash(8)));
+        Ok(())
+    }
+
+    #[test]
+    fn discard_bad_signature() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_db");
+        std::fs::write(&path, "n2")?;
+        let mut graph = Graph::default();
+        open(&path, &mut graph, &mut Hashes::default())?;
+        assert_eq!(&std::fs::read(&path)?[..4], b"n2db");
+        Ok(())
+    }
+}
@@ -50,6 +50,20 @@ impl<K: Index, V> DenseMap<K, V> {
         self.vec.push(val);
         id
     }
+
+    pub fn all_ids(&self) -> impl Iterator<Item = K> {
+        (0..self.vec.len()).map(K::from)
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.vec.shrink_to_fit();
+    }
+
+    /// Approximate heap bytes used by the map itself, excluding any
+    /// allocations owned by the values.
+    pub fn heap_size(&self) -> usize {
+        self.vec.capacity() * std::mem::size_of::<V>()
+    }
 }
 
 impl<K: Index, V: Clone> DenseMap<K, V> {
@@ -4,6 +4,7 @@ use crate::{
     scanner::{ParseResult, Scanner},
     smallmap::SmallMap,
 };
+use std::borrow::Cow;
 
 /// Skip spaces and backslashed newlines.
 fn skip_spaces(scanner: &mut Scanner) -> ParseResult<()> {
@@ -28,20 +29,29 @@ fn skip_spaces(scanner: &mut Scanner) -> ParseResult<()> {
 /// Note: treats colon as a valid character in a path because of Windows-style
 /// paths, but this means that the inital `output: ...` path will include the
 /// trailing colon.
-fn read_path<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<&'a str>> {
+/// Spaces and '#' escaped with a backslash are part of the path; the path is
+/// only copied in that case.  Other backslashes are kept as-is, again because
+/// of Windows-style paths.
+fn read_path<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<Cow<'a, str>>> {
     skip_spaces(scanner)?;
     let start = scanner.ofs;
+    let mut unescaped: Option<String> = None;
+    let mut run_start = start;
     loop {
         match scanner.read() {
             '\0' | ' ' | '\r' | '\n' => {
                 scanner.back();
                 break;
             }
-            '\\' => {
-                if scanner.peek_newline() {
-                    scanner.back();
-                    break;
-                }
+            '\\' if scanner.peek_newline() => {
+                scanner.back();
+                break;
+            }
+            '\\' if scanner.peek() == ' ' || scanner.peek() == '#' => {
+                let path = unescaped.get_or_insert_with(String::new);
+                path.push_str(scanner.slice(run_start, scanner.ofs - 1));
+                run_start = scanner.ofs;
+                scanner.next();
             }
             _ => {}
         }
@@ -50,11 +60,20 @@ fn read_path<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<&'a str>> {
     if end == start {
         return Ok(None);
     }
-    Ok(Some(scanner.slice(start, end)))
+    Ok(Some(match unescaped {
+        None => Cow::Borrowed(scanner.slice(start, end)),
+        Some(mut path) => {
+            path.push_str(scanner.slice(run_start, end));
+            Cow::Owned(path)
+        }
+    }))
 }
 
+/// A parsed `.d` file: a map of each target to its prerequisites.
+pub type Deps<'a> = SmallMap<Cow<'a, str>, Vec<Cow<'a, str>>>;
+
 /// Parse a `.d` file into `Deps`.
-pub fn parse<'a>(scanner: &mut Scanner<'a>) -> ParseResult<SmallMap<&'a str, Vec<&'a str>>> {
+pub fn parse<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Deps<'a>> {
     let mut result = SmallMap::default();
     loop {
         while scanner.peek() == ' ' || scanner.peek_newline() {
@@ -65,12 +84,22 @@ pub fn parse<'a>(scanner: &mut Scanner<'a>) -> ParseResult<SmallMap<&'a str, Vec
             Some(o) => o,
         };
         scanner.skip_spaces();
-        let target = match target.strip_suffix(':') {
-            None => {
-                scanner.expect(':')?;
-                target
+        let target = match target {
+            Cow::Borrowed(path) => match path.strip_suffix(':') {
+                Some(path) => Cow::Borrowed(path),
+                None => {
+                    scanner.expect(':')?;
+                    target
+                }
+            },
+            Cow::Owned(mut path) => {
+                if path.ends_with(':') {
+                    path.pop();
+                } else {
+                    scanner.expect(':')?;
+                }
+                Cow::Owned(path)
             }
-            Some(target) => target,
         };
         let mut deps = Vec::new();
         while let Some(p) = read_path(scanner)? {
@@ -88,13 +117,35 @@ mod tests {
     use super::*;
     use std::path::Path;
 
-    fn try_parse(buf: &mut Vec<u8>) -> Result<SmallMap<&str, Vec<&str>>, String> {
+    type OwnedDeps = SmallMap<String, Vec<String>>;
+
+    fn try_parse(buf: &mut Vec<u8>) -> Result<OwnedDeps, String> {
         buf.push(0);
         let mut scanner = Scanner::new(buf);
-        parse(&mut scanner).map_err(|err| scanner.format_parse_error(Path::new("test"), err))
+        let parsed = parse(&mut scanner)
+            .map_err(|err| scanner.format_parse_error(Path::new("test"), err))?;
+        let mut deps = SmallMap::default();
+        for (target, ins) in parsed.into_iter() {
+            deps.insert(
+                target.into_owned(),
+                ins.into_iter().map(Cow::into_owned).collect(),
+            );
+        }
+        Ok(deps)
+    }
+
+    fn expect_deps<const N: usize>(pairs: [(&str, Vec<&str>); N]) -> OwnedDeps {
+        let mut deps = SmallMap::default();
+        for (target, ins) in pairs {
+            deps.insert(
+                target.to_owned(),
+                ins.into_iter().map(str::to_owned).collect(),
+            );
+        }
+        deps
     }
 
-    fn must_parse(buf: &mut Vec<u8>) -> SmallMap<&str, Vec<&str>> {
+    fn must_parse(buf: &mut Vec<u8>) -> OwnedDeps {
         match try_parse(buf) {
             Err(err) => {
                 println!("{}", err);
@@ -120,7 +171,7 @@ mod tests {
                 let deps = must_parse(&mut file);
                 assert_eq!(
                     deps,
-                    SmallMap::from([(
+                    expect_deps([(
                         "build/browse.o",
                         vec!["src/browse.cc", "src/browse.h", "build/browse_py.h",]
                     )])
@@ -136,7 +187,7 @@ mod tests {
             let deps = must_parse(&mut file);
             assert_eq!(
                 deps,
-                SmallMap::from([("build/browse.o", vec!["src/browse.cc",])])
+                expect_deps([("build/browse.o", vec!["src/browse.cc",])])
             );
         });
     }
@@ -150,7 +201,7 @@ mod tests {
                 let deps = must_parse(&mut file);
                 assert_eq!(
                     deps,
-                    SmallMap::from([(
+                    expect_deps([(
                         "build/browse.o",
                         vec!["src/browse.cc", "build/browse_py.h",]
                     )])
@@ -165,7 +216,7 @@ mod tests {
         let deps = must_parse(&mut file);
         assert_eq!(
             deps,
-            SmallMap::from([("build/browse.o", vec!["src/browse.cc",])])
+            expect_deps([("build/browse.o", vec!["src/browse.cc",])])
         );
     }
 
@@ -175,7 +226,7 @@ mod tests {
         let deps = must_parse(&mut file);
         assert_eq!(
             deps,
-            SmallMap::from([("build/browse.o", vec!["src/browse.cc",])])
+            expect_deps([("build/browse.o", vec!["src/browse.cc",])])
         );
     }
 
@@ -183,10 +234,7 @@ mod tests {
     fn test_parse_windows_dep_path() {
         let mut file = b"odd/path.o: C:/odd\\path.c".to_vec();
         let deps = must_parse(&mut file);
-        assert_eq!(
-            deps,
-            SmallMap::from([("odd/path.o", vec!["C:/odd\\path.c",])])
-        );
+        assert_eq!(deps, expect_deps([("odd/path.o", vec!["C:/odd\\path.c",])]));
     }
 
     #[test]
@@ -201,7 +249,7 @@ out/b.o :
         let deps = must_parse(&mut file);
         assert_eq!(
             deps,
-            SmallMap::from([
+            expect_deps([
                 ("out/a.o", vec!["src/a.c", "src/b.c",]),
                 ("out/b.o", vec![])
             ])
@@ -213,9 +261,24 @@ out/b.o :
         let mut file = b"foo bar".to_vec();
         let err = try_parse(&mut file).unwrap_err();
         assert!(
-            err.starts_with("parse error: expected ':'"),
+            err.starts_with("test:1:5: parse error: expected ':'"),
             "expected parse error, got {:?}",
             err
         );
     }
+
+    #[test]
+    fn test_parse_escaped_spaces() {
+        test_for_crlf(
+            "out/a\\ b.o: src/a\\ b.c \\\n  inc/my\\ dir/x.h inc/\\#y.h\n",
+            |text| {
+                let mut file = text.into_bytes();
+                let deps = must_parse(&mut file);
+                assert_eq!(
+                    deps,
+                    expect_deps([("out/a b.o", vec!["src/a b.c", "inc/my dir/x.h", "inc/#y.h"])])
+                );
+            },
+        );
+    }
 }
@@ -0,0 +1,132 @@
+//! Parsing of dyndep files, which list extra implicit inputs and outputs of
+//! builds that only become known once some earlier build has run, e.g. the
+//! Fortran modules a source file produces and consumes.
+//!
+//! See "Dynamic Dependencies" in the Ninja manual.  The format is a subset of
+//! the manifest syntax:
+//!
+//! ```text
+//! ninja_dyndep_version = 1
+//! build out | implicit outs: dyndep | implicit ins
+//! ```
+
+use crate::parse::{Parser, Statement};
+use anyhow::bail;
+use std::path::Path;
+
+/// The extra edges a dyndep file gives one build.
+#[derive(Debug, PartialEq)]
+pub struct DyndepBuild {
+    /// An output of the build, as named in the manifest, to find it by.
+    pub out: String,
+    pub implicit_outs: Vec<String>,
+    pub implicit_ins: Vec<String>,
+}
+
+fn check_version(path: &Path, parser: &Parser) -> anyhow::Result<()> {
+    match parser.vars.get("ninja_dyndep_version").map(String::as_str) {
+        Some("1") | Some("1.0") => Ok(()),
+        Some(version) => bail!(
+            "{}: unsupported ninja_dyndep_version {:?}",
+            path.display(),
+            version
+        ),
+        None => bail!("{}: expected ninja_dyndep_version", path.display()),
+    }
+}
+
+/// Parse the contents of a dyndep file, which must be nul-terminated.
+pub fn parse(path: &Path, buf: &[u8]) -> anyhow::Result<Vec<DyndepBuild>> {
+    let mut parser = Parser::new(buf);
+    let mut builds = Vec::new();
+    loop {
+        let stmt = match parser.read() {
+            Ok(None) => break,
+            Ok(Some(stmt)) => stmt,
+            Err(err) => bail!(parser.format_parse_error(path, err)),
+        };
+        check_version(path, &parser)?;
+        let b = match stmt {
+            Statement::Build(b) => b,
+            _ => bail!("{}: expected only build statements", path.display()),
+        };
+        let loc = format!("{}:{}", path.display(), b.line);
+        if b.rule != "dyndep" {
+            bail!("{}: expected rule \"dyndep\", got {:?}", loc, b.rule);
+        }
+        if b.explicit_outs != 1 {
+            bail!("{}: expected exactly one explicit output", loc);
+        }
+        if b.explicit_ins != 0 || b.order_only_ins != 0 || b.validation_ins != 0 {
+            bail!("{}: only implicit inputs may follow \"dyndep\"", loc);
+        }
+        // Ninja also allows `restat = 1` here, which n2 has no use for.
+        if let Some((name, _)) = b.vars.iter().find(|(name, _)| *name != "restat") {
+            bail!("{}: unexpected variable {:?}", loc, name);
+        }
+        let mut outs = b.outs.iter().map(|out| out.evaluate(&[&parser.vars]));
+        builds.push(DyndepBuild {
+            out: outs.next().unwrap(),
+            implicit_outs: outs.collect(),
+            implicit_ins: b
+                .ins
+                .iter()
+                .map(|input| input.evaluate(&[&parser.vars]))
+                .collect(),
+        });
+    }
+    check_version(path, &parser)?;
+    Ok(builds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(text: &str) -> anyhow::Result<Vec<DyndepBuild>> {
+        let mut buf = text.as_bytes().to_vec();
+        buf.push(0);
+        parse(Path::new("out.dd"), &buf)
+    }
+
+    #[test]
+    fn parse_builds() -> anyhow::Result<()> {
+        let builds = parse_str(
+            "ninja_dyndep_version = 1
+build a.o | a.mod: dyndep | b.mod
+  restat = 1
+build b.o: dyndep
+",
+        )?;
+        assert_eq!(
+            builds,
+            vec![
+                DyndepBuild {
+                    out: "a.o".to_owned(),
+                    implicit_outs: vec!["a.mod".to_owned()],
+                    implicit_ins: vec!["b.mod".to_owned()],
+                },
+                DyndepBuild {
+                    out: "b.o".to_owned(),
+                    implicit_outs: vec![],
+                    implicit_ins: vec![],
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |text: &str| parse_str(text).err().unwrap().to_string();
+        assert!(err("").contains("expected ninja_dyndep_version"));
+        assert!(err("build a.o: dyndep\n").contains("expected ninja_dyndep_version"));
+        assert!(err("ninja_dyndep_version = 2\n").contains("unsupported ninja_dyndep_version"));
+        let v1 = "ninja_dyndep_version = 1\n";
+        assert!(err(&format!("{}build a.o: cc\n", v1)).contains("expected rule \"dyndep\""));
+        assert!(err(&format!("{}build a.o: dyndep in\n", v1)).contains("only implicit inputs"));
+        assert!(err(&format!("{}build a b: dyndep\n", v1)).contains("exactly one explicit"));
+        assert!(err(&format!("{}rule dyndep\n  command = x\n", v1)).contains("only build"));
+        assert!(err(&format!("{}build a: dyndep\n  pool = x\n", v1)).contains("\"pool\""));
+    }
+}
@@ -1,5 +1,54 @@
 //! Represents parsed Ninja strings with embedded variable references, e.g.
 //! `c++ $in -o $out`, and mechanisms for expanding those into plain strings.
+//!
+//! An EvalString is expanded against a chain of Envs, innermost scope first.
+//! The loader evaluates a rule's bindings (like `command`) against
+//!
+//! 1. the build's implicit variables: `$in`, `$out` and friends;
+//! 2. the build's own bindings;
+//! 3. the top-level variables of the file the build is in,
+//!
+//! and a build's own bindings against just the file's variables.  If asked
+//! to, the loader also falls back to the process environment after the
+//! file's variables; see ProcessEnv.  A variable is taken from the first Env
+//! that defines it, and its value is in turn expanded against only the Envs
+//! after that one, so a build binding can refer to a top-level variable of
+//! the same name but not to itself.
+//!
+//! Embedders can supply their own Envs, e.g. to expand a rule's command:
+//!
+//! ```
+//! use n2::eval::{Env, EvalPart, EvalString, Vars};
+//! use n2::parse::{Parser, Statement};
+//! use std::borrow::Cow;
+//!
+//! /// Provides $in and $out for a build that isn't in any manifest.
+//! struct Files {
+//!     ins: Vec<&'static str>,
+//!     out: &'static str,
+//! }
+//! impl Env for Files {
+//!     fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+//!         let val = match var {
+//!             "in" => self.ins.join(" "),
+//!             "out" => self.out.to_owned(),
+//!             _ => return None,
+//!         };
+//!         Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(val))]))
+//!     }
+//! }
+//!
+//! let mut parser = Parser::new(b"rule cc\n  command = cc $cflags $in -o $out\n\0");
+//! let rule = match parser.read().unwrap() {
+//!     Some(Statement::Rule(rule)) => rule,
+//!     _ => unreachable!(),
+//! };
+//! let files = Files { ins: vec!["a.c", "b.c"], out: "ab.o" };
+//! let mut globals = Vars::default();
+//! globals.insert("cflags", "-O2".to_owned());
+//! let command = rule.vars.get("command").unwrap();
+//! assert_eq!(command.evaluate(&[&files, &globals]), "cc -O2 a.c b.c -o ab.o");
+//! ```
 
 use rustc_hash::FxHashMap;
 
@@ -11,7 +60,7 @@ use std::borrow::Cow;
 /// This represents one "frame" of evaluation context, a given EvalString may
 /// need multiple environments in order to be fully expanded.
 pub trait Env {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>>;
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>>;
 }
 
 /// One token within an EvalString, either literal text or a variable reference.
@@ -93,7 +142,7 @@ impl EvalString<&str> {
 }
 
 impl EvalString<String> {
-    pub fn as_cow(&self) -> EvalString<Cow<str>> {
+    pub fn as_cow(&self) -> EvalString<Cow<'_, str>> {
         EvalString(
             self.0
                 .iter()
@@ -107,7 +156,7 @@ impl EvalString<String> {
 }
 
 impl EvalString<&str> {
-    pub fn as_cow(&self) -> EvalString<Cow<str>> {
+    pub fn as_cow(&self) -> EvalString<Cow<'_, str>> {
         EvalString(
             self.0
                 .iter()
@@ -120,7 +169,8 @@ impl EvalString<&str> {
     }
 }
 
-/// A single scope's worth of variable definitions.
+/// A single scope's worth of variable definitions, e.g. a file's top-level
+/// variables.
 #[derive(Debug, Default)]
 pub struct Vars<'text>(FxHashMap<&'text str, String>);
 
@@ -131,29 +181,74 @@ impl<'text> Vars<'text> {
     pub fn get(&self, key: &str) -> Option<&String> {
         self.0.get(key)
     }
+
+    /// A copy that doesn't borrow the text the variables were parsed from.
+    pub fn to_owned(&self) -> OwnedVars {
+        OwnedVars(
+            self.0
+                .iter()
+                .map(|(&key, val)| (key.to_owned(), val.clone()))
+                .collect(),
+        )
+    }
+}
+
+/// Vars kept beyond the text they were parsed from.
+#[derive(Debug, Default)]
+pub struct OwnedVars(FxHashMap<String, String>);
+
+impl OwnedVars {
+    /// Whether this holds the same variables as `vars`.
+    pub fn matches(&self, vars: &Vars) -> bool {
+        self.0.len() == vars.0.len()
+            && vars
+                .0
+                .iter()
+                .all(|(&key, val)| self.0.get(key) == Some(val))
+    }
+}
+
+impl Env for OwnedVars {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+        Some(EvalString::new(vec![EvalPart::Literal(
+            std::borrow::Cow::Borrowed(self.0.get(var)?),
+        )]))
+    }
 }
 impl<'a> Env for Vars<'a> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(EvalString::new(vec![EvalPart::Literal(
             std::borrow::Cow::Borrowed(self.get(var)?),
         )]))
     }
 }
 
+/// The process environment, for looking up variables like `$CC` that no
+/// manifest scope defines.  Ninja never does this, so it's only used when
+/// asked for, last in the chain; see load::Options::process_env.
+pub struct ProcessEnv;
+
+impl Env for ProcessEnv {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+        let val = std::env::var(var).ok()?;
+        Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(val))]))
+    }
+}
+
 impl<K: Borrow<str> + PartialEq> Env for SmallMap<K, EvalString<String>> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(self.get(var)?.as_cow())
     }
 }
 
 impl<K: Borrow<str> + PartialEq> Env for SmallMap<K, EvalString<&str>> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(self.get(var)?.as_cow())
     }
 }
 
 impl Env for SmallMap<&str, String> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(EvalString::new(vec![EvalPart::Literal(
             std::borrow::Cow::Borrowed(self.get(var)?),
         )]))
@@ -0,0 +1,174 @@
+//! Newline-delimited JSON events describing a build as it runs, written by
+//! --events-json for IDEs and other tools that want to follow a build
+//! without scraping the console output, as with cargo's
+//! `--message-format=json`.
+//!
+//! Each line is one JSON object whose "event" field is one of:
+//! - "begin": the build is starting, with "builds", the number of
+//!   (non-phony) builds being considered.  If the manifest itself is out of
+//!   date, regenerating it comes first as a build of its own;
+//! - "start": a command started;
+//! - "finish": a command succeeded, with its "exit_code" and its duration in
+//!   "ms";
+//! - "error": a command failed, timed out, or was interrupted, with "status"
+//!   ("failure", "timeout", or "interrupted"), "exit_code", "ms", and the
+//!   command's console "output".
+//!
+//! "exit_code" is null for a command killed by a signal, or one that couldn't
+//! be started.
+//!
+//! The per-command events all carry the build's "id", "rule", and "outs".
+
+use crate::json;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// The build an event is about.
+pub struct Edge<'a> {
+    pub id: usize,
+    pub rule: &'a str,
+    pub outs: Vec<&'a str>,
+}
+
+fn format_edge(event: &str, edge: &Edge) -> String {
+    let outs: Vec<String> = edge.outs.iter().map(|out| json::quote(out)).collect();
+    format!(
+        "{{\"event\":{},\"id\":{},\"rule\":{},\"outs\":[{}]",
+        json::quote(event),
+        edge.id,
+        json::quote(edge.rule),
+        outs.join(",")
+    )
+}
+
+fn format_begin(builds: usize) -> String {
+    format!("{{\"event\":\"begin\",\"builds\":{}}}\n", builds)
+}
+
+fn format_start(edge: &Edge) -> String {
+    format!("{}}}\n", format_edge("start", edge))
+}
+
+fn format_exit_code(exit_code: Option<i32>) -> String {
+    match exit_code {
+        Some(code) => code.to_string(),
+        None => "null".to_owned(),
+    }
+}
+
+fn format_finish(edge: &Edge, exit_code: Option<i32>, time: Duration) -> String {
+    format!(
+        "{},\"exit_code\":{},\"ms\":{}}}\n",
+        format_edge("finish", edge),
+        format_exit_code(exit_code),
+        time.as_millis()
+    )
+}
+
+fn format_error(
+    edge: &Edge,
+    status: &str,
+    exit_code: Option<i32>,
+    time: Duration,
+    output: &str,
+) -> String {
+    format!(
+        "{},\"status\":{},\"exit_code\":{},\"ms\":{},\"output\":{}}}\n",
+        format_edge("error", edge),
+        json::quote(status),
+        format_exit_code(exit_code),
+        time.as_millis(),
+        json::quote(output)
+    )
+}
+
+/// An open events file.  Each event is written with a single write() so
+/// that a reader following the file sees whole lines.
+pub struct Writer {
+    w: File,
+}
+
+impl Writer {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Writer {
+            w: File::create(path)?,
+        })
+    }
+
+    /// Another writer appending to the same file, for a second Work after
+    /// the manifest was regenerated.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Writer {
+            w: self.w.try_clone()?,
+        })
+    }
+
+    pub fn begin(&mut self, builds: usize) -> std::io::Result<()> {
+        self.w.write_all(format_begin(builds).as_bytes())
+    }
+
+    pub fn start(&mut self, edge: &Edge) -> std::io::Result<()> {
+        self.w.write_all(format_start(edge).as_bytes())
+    }
+
+    pub fn finish(
+        &mut self,
+        edge: &Edge,
+        exit_code: Option<i32>,
+        time: Duration,
+    ) -> std::io::Result<()> {
+        self.w
+            .write_all(format_finish(edge, exit_code, time).as_bytes())
+    }
+
+    pub fn error(
+        &mut self,
+        edge: &Edge,
+        status: &str,
+        exit_code: Option<i32>,
+        time: Duration,
+        output: &str,
+    ) -> std::io::Result<()> {
+        self.w
+            .write_all(format_error(edge, status, exit_code, time, output).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        let edge = Edge {
+            id: 3,
+            rule: "cc",
+            outs: vec!["a.o", "a \"b\".d"],
+        };
+        assert_eq!(format_begin(7), "{\"event\":\"begin\",\"builds\":7}\n");
+        assert_eq!(
+            format_start(&edge),
+            r#"{"event":"start","id":3,"rule":"cc","outs":["a.o","a \"b\".d"]}"#.to_owned() + "\n"
+        );
+        assert_eq!(
+            format_finish(&edge, Some(0), Duration::from_millis(1500)),
+            r#"{"event":"finish","id":3,"rule":"cc","outs":["a.o","a \"b\".d"],"exit_code":0,"ms":1500}"#
+                .to_owned()
+                + "\n"
+        );
+        assert_eq!(
+            format_error(&edge, "failure", Some(1), Duration::from_millis(2), "a.c:1: error\n"),
+            r#"{"event":"error","id":3,"rule":"cc","outs":["a.o","a \"b\".d"],"status":"failure","exit_code":1,"ms":2,"output":"a.c:1: error\n"}"#
+                .to_owned()
+                + "\n"
+        );
+        assert_eq!(
+            format_error(&edge, "interrupted", None, Duration::from_millis(2), ""),
+            r#"{"event":"error","id":3,"rule":"cc","outs":["a.o","a \"b\".d"],"status":"interrupted","exit_code":null,"ms":2,"output":""}"#
+                .to_owned()
+                + "\n"
+        );
+    }
+}
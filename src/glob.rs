@@ -0,0 +1,166 @@
+//! Expansion of `*` and `?` wildcards in paths, for `include` with
+//! Options::include_globs.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Whether a path has wildcards to expand.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Match a single path component against a pattern, where `*` matches any
+/// run of characters and `?` any one character.  On a mismatch only the
+/// last `*` seen takes one more character, which keeps this linear-ish
+/// rather than exponential in the number of `*`s.
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*`, and of the name where its match ends.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The existing files matching a pattern, sorted.  A relative pattern is
+/// resolved against `dir`.  As in the shell, wildcards don't match a leading
+/// `.` of a name.  Every directory listed along the way is added to `listed`,
+/// even one that doesn't exist, since a new file there could change the
+/// result.
+pub fn expand(
+    dir: &Path,
+    pattern: &str,
+    listed: &mut Vec<PathBuf>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = vec![dir.to_path_buf()];
+    for component in Path::new(pattern).components() {
+        let part = match component {
+            Component::Normal(part) => part.to_string_lossy(),
+            _ => {
+                for path in &mut paths {
+                    path.push(component);
+                }
+                continue;
+            }
+        };
+        if !is_glob(&part) {
+            for path in &mut paths {
+                path.push(&*part);
+            }
+            continue;
+        }
+        let pattern: Vec<char> = part.chars().collect();
+        let mut next = Vec::new();
+        for path in paths {
+            let dir = if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &path
+            };
+            listed.push(dir.to_path_buf());
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for entry in entries {
+                let name = entry?.file_name();
+                let name: Vec<char> = name.to_string_lossy().chars().collect();
+                if name.first() == Some(&'.') && pattern[0] != '.' {
+                    continue;
+                }
+                if matches(&pattern, &name) {
+                    next.push(path.join(name.iter().collect::<String>()));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|path| path.exists());
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        let m = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            matches(&pattern, &name)
+        };
+        assert!(m("*.ninja", "a.ninja"));
+        assert!(m("*.ninja", ".ninja"));
+        assert!(!m("*.ninja", "a.ninja.bak"));
+        assert!(m("a?c*", "abc"));
+        assert!(!m("a?c", "ac"));
+        assert!(m("*", ""));
+        assert!(m("a*b*c", "axbxbyc"));
+        assert!(!m("a*b*c", "axbxbyd"));
+        assert!(m("**", "ab"));
+        // Many `*`s that can't match must fail fast rather than backtrack.
+        assert!(!m(&"*a".repeat(40), &"a".repeat(39)));
+    }
+
+    #[test]
+    fn expand_sorted() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in [
+            "gen/b.ninja",
+            "gen/a.ninja",
+            "gen/.hidden.ninja",
+            "gen/c.txt",
+        ] {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, "")?;
+        }
+        std::fs::create_dir_all(dir.path().join("sub/x"))?;
+        std::fs::write(dir.path().join("sub/x/d.ninja"), "")?;
+
+        let mut listed = Vec::new();
+        let mut names = |pattern: &str| -> std::io::Result<Vec<PathBuf>> {
+            Ok(expand(dir.path(), pattern, &mut listed)?
+                .into_iter()
+                .map(|path| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+                .collect())
+        };
+        assert_eq!(
+            names("gen/*.ninja")?,
+            vec![PathBuf::from("gen/a.ninja"), PathBuf::from("gen/b.ninja")]
+        );
+        assert_eq!(names("*/?/d.ninja")?, vec![PathBuf::from("sub/x/d.ninja")]);
+        assert!(names("missing/*.ninja")?.is_empty());
+        assert_eq!(
+            listed,
+            vec![
+                dir.path().join("gen"),
+                dir.path().to_path_buf(),
+                dir.path().join("gen"),
+                dir.path().join("sub"),
+                dir.path().join("missing"),
+            ]
+        );
+        Ok(())
+    }
+}
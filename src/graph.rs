@@ -1,14 +1,17 @@
 //! The build graph, a graph between files and commands.
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHasher};
 
 use crate::{
+    canon::canon_path,
     densemap::{self, DenseMap},
     hash::BuildHash,
+    log::{Category, Warning},
 };
 use std::collections::{hash_map::Entry, HashMap};
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// Id for File nodes in the Graph.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -56,7 +59,7 @@ impl File {
 }
 
 /// A textual location within a build.ninja file, used in error messages.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileLoc {
     pub filename: std::rc::Rc<PathBuf>,
     pub line: usize,
@@ -87,6 +90,32 @@ pub struct BuildIns {
     // pub validation: usize,
 }
 
+impl BuildIns {
+    /// Remove the given files from the inputs, keeping the count of each kind
+    /// of input consistent.
+    pub fn remove(&mut self, remove: &[FileId]) {
+        let mut ids = Vec::new();
+        let (mut explicit, mut implicit, mut order_only) = (0, 0, 0);
+        for (i, &id) in self.ids.iter().enumerate() {
+            if remove.contains(&id) {
+                if i < self.explicit {
+                    explicit += 1;
+                } else if i < self.explicit + self.implicit {
+                    implicit += 1;
+                } else if i < self.explicit + self.implicit + self.order_only {
+                    order_only += 1;
+                }
+                continue;
+            }
+            ids.push(id);
+        }
+        self.ids = ids;
+        self.explicit -= explicit;
+        self.implicit -= implicit;
+        self.order_only -= order_only;
+    }
+}
+
 /// Output files from a Build.
 pub struct BuildOuts {
     /// Similar to ins, we keep both explicit and implicit outs in one Vec.
@@ -101,7 +130,7 @@ impl BuildOuts {
     pub fn remove_duplicates(&mut self) {
         let mut ids = Vec::new();
         for (i, &id) in self.ids.iter().enumerate() {
-            if self.ids[0..i].iter().any(|&prev| prev == id) {
+            if self.ids[0..i].contains(&id) {
                 // Skip over duplicate.
                 if i < self.explicit {
                     self.explicit -= 1;
@@ -142,6 +171,330 @@ mod tests {
         assert_eq!(outs.ids, fileids(vec![1, 2]));
         assert_eq!(outs.explicit, 2);
     }
+
+    #[test]
+    fn remove_ins() {
+        let mut ins = BuildIns {
+            ids: fileids(vec![1, 2, 1, 3, 1, 4]),
+            explicit: 2,
+            implicit: 1,
+            order_only: 2,
+        };
+        ins.remove(&fileids(vec![1]));
+        assert_eq!(ins.ids, fileids(vec![2, 3, 4]));
+        assert_eq!((ins.explicit, ins.implicit, ins.order_only), (1, 0, 1));
+    }
+
+    #[test]
+    fn find_build_by_location() -> anyhow::Result<()> {
+        let file = "
+rule touch
+  command = touch $out
+
+build a: touch
+
+build b: touch a
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let b = graph.files.lookup("b").unwrap();
+        let build_file = Path::new("build.ninja");
+        assert_eq!(
+            graph.find_build_by_location(build_file, 7),
+            graph.file(b).input
+        );
+        assert!(graph.find_build_by_location(build_file, 6).is_none());
+        assert!(graph
+            .find_build_by_location(Path::new("other.ninja"), 7)
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn build_for_output() -> anyhow::Result<()> {
+        let file = "
+rule touch
+  command = touch $out
+build sub/a: touch src
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let build = graph.build_for_output("./sub/../sub/a").unwrap();
+        assert_eq!(build.cmdline.as_deref(), Some("touch sub/a"));
+        assert!(graph.build_for_output("src").is_none());
+        assert!(graph.build_for_output("missing").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn root_and_source_files() -> anyhow::Result<()> {
+        let file = "
+rule touch
+  command = touch $out
+build b.o: touch b.c
+build a.o: touch a.c
+build app: touch a.o b.o
+build extra: touch
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let names = |ids: Vec<FileId>| -> Vec<&str> {
+            ids.into_iter()
+                .map(|id| graph.file(id).name.as_str())
+                .collect()
+        };
+        assert_eq!(names(graph.root_files()), vec!["app", "extra"]);
+        assert_eq!(names(graph.source_files()), vec!["a.c", "b.c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_cycles() -> anyhow::Result<()> {
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+build a: phony b
+build b: phony c | d
+build c: phony a
+build d: phony
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let cycle = graph.detect_cycles().unwrap_err();
+        let names: Vec<&str> = cycle
+            .iter()
+            .map(|&id| graph.file(id).name.as_str())
+            .collect();
+        assert_eq!(names, vec!["a", "b", "c", "a"]);
+
+        // Diamonds, and cycles through validations, are fine.
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+build a: phony b c |@ d
+build b: phony e
+build c: phony e
+build d: phony a
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        assert_eq!(graph.detect_cycles(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn topo_order() -> anyhow::Result<()> {
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+build a: phony b c || d
+build b: phony e
+build c d: phony e
+build e: phony src
+build f: phony
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let order = graph.topo_order().unwrap();
+        assert_eq!(order.len(), 5);
+        for (i, &bid) in order.iter().enumerate() {
+            for &input in graph.builds[bid].ordering_ins() {
+                if let Some(producer) = graph.file(input).input {
+                    assert!(order[..i].contains(&producer));
+                }
+            }
+        }
+
+        let graph = crate::load::parse(
+            "build.ninja",
+            b"build a: phony b\nbuild b: phony a\n".to_vec(),
+        )?;
+        let cycle = graph.topo_order().unwrap_err();
+        assert_eq!(cycle.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn all_ids_sorted() -> anyhow::Result<()> {
+        let names = |manifest: &str| -> anyhow::Result<Vec<String>> {
+            let graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+            Ok(graph
+                .files
+                .all_ids_sorted()
+                .into_iter()
+                .map(|id| graph.file(id).name.clone())
+                .collect())
+        };
+        let sorted = names("build c: phony b\nbuild b: phony a/x\nbuild a: phony\n")?;
+        assert_eq!(sorted, vec!["a", "a/x", "b", "c"]);
+        // The same files declared in a different order list the same way.
+        let reordered = names("build a: phony\nbuild c: phony b\nbuild b: phony a/x\n")?;
+        assert_eq!(sorted, reordered);
+        Ok(())
+    }
+
+    #[test]
+    fn command_histogram() -> anyhow::Result<()> {
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+rule stamp
+  command = touch stamp
+rule cc
+  command = cc $in
+build a: stamp
+build b: stamp
+build c: cc c.c
+build all: phony a b c
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let histogram = graph.command_histogram();
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram["touch stamp"], 2);
+        assert_eq!(histogram["cc c.c"], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn estimated_memory() -> anyhow::Result<()> {
+        let manifest = |n: usize| {
+            let mut file = "rule cc\n  command = cc $in -o $out\n".to_string();
+            for i in 0..n {
+                file.push_str(&format!("build out{0}.o: cc src{0}.c\n", i));
+            }
+            file.into_bytes()
+        };
+        let mut small = crate::load::parse("build.ninja", manifest(100))?;
+        let mut large = crate::load::parse("build.ninja", manifest(1000))?;
+        small.files.shrink_to_fit();
+        large.files.shrink_to_fit();
+        let (small, large) = (small.estimated_memory(), large.estimated_memory());
+        assert!(small > 0);
+        assert!(
+            large > small * 5 && large < small * 20,
+            "{} vs {}",
+            small,
+            large
+        );
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_separators() {
+        let mut files = GraphFiles::default();
+        let id = files.id_from_canonical(canon_path("c:\\foo\\bar.o"));
+        assert_eq!(files.lookup(&canon_path("C:/foo/bar.o")), Some(id));
+        assert_eq!(files.lookup(&canon_path("c:/foo\\bar.o")), Some(id));
+        assert_eq!(files.id_from_canonical(canon_path("C:/foo/./bar.o")), id);
+        // The first spelling is the one kept.
+        assert_eq!(files.by_id[id].name, "C:\\foo\\bar.o");
+        // Only drive letters are case-insensitive.
+        assert_eq!(files.lookup(&canon_path("C:/Foo/bar.o")), None);
+    }
+
+    #[test]
+    fn file_name_hash_collision() {
+        let mut files = GraphFiles::default();
+        let a = files.id_from_canonical("a".to_owned());
+        // Pretend "b" hashes the same as "a".
+        files.by_name.insert(hash_name("b"), a);
+        let b = files.id_from_canonical("b".to_owned());
+        assert_ne!(a, b);
+        assert_eq!(files.by_name_collisions, vec![b]);
+        assert_eq!(files.lookup("a"), Some(a));
+        assert_eq!(files.lookup("b"), Some(b));
+        assert_eq!(files.id_from_canonical("b".to_owned()), b);
+        assert_eq!(files.lookup("c"), None);
+    }
+
+    #[test]
+    fn file_state_grows_for_new_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut graph = crate::load::parse("build.ninja", b"build out: phony in\n".to_vec())?;
+        let mut file_state = FileState::with_symlinks(&graph, Symlinks::Follow);
+        // A file first seen after FileState was created, like a discovered
+        // dep, is keyed by its FileId just the same.
+        let path = dir.path().join("dep.h");
+        std::fs::write(&path, "")?;
+        let dep = graph
+            .files
+            .id_from_canonical(path.to_str().unwrap().to_owned());
+        assert_eq!(file_state.get(dep), None);
+        let mtime = file_state.stat(dep, &path)?;
+        assert!(matches!(mtime, MTime::Stamp(_)));
+        assert_eq!(file_state.get(dep), Some(mtime));
+        assert_eq!(file_state.get(graph.files.lookup("in").unwrap()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn stat_all_sources() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let graph = crate::load::parse(
+            "build.ninja",
+            format!(
+                "build {dir}/out: phony {dir}/a {dir}/b\n",
+                dir = dir.path().display()
+            )
+            .into_bytes(),
+        )?;
+        std::fs::write(dir.path().join("a"), "")?;
+        let mut file_state = FileState::with_symlinks(&graph, Symlinks::Follow);
+        file_state.stat_all(&graph.files, false)?;
+        let lookup = |name: &str| {
+            graph
+                .files
+                .lookup(&format!("{}/{}", dir.path().display(), name))
+                .unwrap()
+        };
+        assert!(matches!(file_state.get(lookup("a")), Some(MTime::Stamp(_))));
+        assert_eq!(file_state.get(lookup("b")), Some(MTime::Missing));
+        // Generated files are left to be stat()ed when their build is checked.
+        assert_eq!(file_state.get(lookup("out")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn stat_by_directory() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let names = ["a", "b", "c", "d", "missing", "sub/x"];
+        let mut graph = Graph::default();
+        let ids: Vec<FileId> = names
+            .iter()
+            .map(|name| {
+                let path = format!("{}/{}", dir.path().display(), name);
+                graph.files.id_from_canonical(path)
+            })
+            .collect();
+        for name in &names[..4] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        #[cfg(unix)]
+        {
+            std::fs::remove_file(dir.path().join("d"))?;
+            std::os::unix::fs::symlink("a", dir.path().join("d"))?;
+        }
+        std::fs::create_dir(dir.path().join("sub"))?;
+        std::fs::write(dir.path().join("sub/x"), "")?;
+
+        let mut file_state = FileState::with_symlinks(&graph, Symlinks::Follow);
+        let mut rest = file_state.stat_by_directory(&graph.files, ids.clone());
+        rest.sort_by_key(densemap::Index::index);
+        for &id in &ids[..3] {
+            let mtime = stat(graph.file(id).path(), Symlinks::Follow)?;
+            assert_eq!(file_state.get(id), Some(mtime));
+        }
+        // Symlinks and missing files are left to stat(), as are files in
+        // directories with too few wanted to be worth listing.
+        #[cfg(unix)]
+        assert_eq!(rest, vec![ids[3], ids[4], ids[5]]);
+        #[cfg(not(unix))]
+        assert_eq!(rest, vec![ids[4], ids[5]]);
+        Ok(())
+    }
 }
 
 /// A single build action, generating File outputs from File inputs with a command.
@@ -149,24 +502,46 @@ pub struct Build {
     /// Source location this Build was declared.
     pub location: FileLoc,
 
+    /// Name of the rule this build uses.
+    pub rule: String,
+
     /// User-provided description of the build step.
     pub desc: Option<String>,
 
     /// Command line to run.  Absent for phony builds.
     pub cmdline: Option<String>,
 
-    /// Path to generated `.d` file, if any.
-    pub depfile: Option<String>,
+    /// Path to generated `.d` file, if any, canonicalized; see set_depfile().
+    depfile: Option<PathBuf>,
 
     /// If true, extract "/showIncludes" lines from output.
     pub parse_showincludes: bool,
 
+    /// If true, the build regenerates the manifest itself (`generator = 1`),
+    /// so its outputs must survive any cleaning.
+    pub generator: bool,
+
+    /// Prefix of the "/showIncludes" lines, from `msvc_deps_prefix`, for
+    /// compilers localized to print something other than the default.
+    pub msvc_deps_prefix: Option<String>,
+
     // Struct that contains the path to the rsp file and its contents, if any.
     pub rspfile: Option<RspFile>,
 
     /// Pool to execute this build in, if any.
     pub pool: Option<String>,
 
+    /// Abstract resource cost of running this build, from `weight = N`.
+    weight: u32,
+
+    /// Megabytes of memory the build is expected to use, from `mem = N`, for
+    /// scheduling against work::Options::max_memory.  0 if unknown.
+    mem: u64,
+
+    /// How long the command may run before it's killed, from `timeout = N`
+    /// (seconds), overriding work::Options::timeout.  Zero means no limit.
+    timeout: Option<Duration>,
+
     pub ins: BuildIns,
 
     /// Additional inputs discovered from a previous build.
@@ -174,23 +549,88 @@ pub struct Build {
 
     /// Output files.
     pub outs: BuildOuts,
+
+    /// File listing more inputs and outputs of this build, from `dyndep =`.
+    /// It's one of the build's inputs, and is loaded once that's up to date;
+    /// see dyndep.rs.
+    pub dyndep: Option<FileId>,
+
+    /// How many of the outs, at the end of the list, came from the dyndep
+    /// file rather than the manifest.
+    dyndep_outs: usize,
 }
 impl Build {
     pub fn new(loc: FileLoc, ins: BuildIns, outs: BuildOuts) -> Self {
         Build {
             location: loc,
+            rule: String::new(),
             desc: None,
             cmdline: None,
             depfile: None,
             parse_showincludes: false,
+            generator: false,
+            msvc_deps_prefix: None,
             rspfile: None,
             pool: None,
+            weight: 1,
+            mem: 0,
+            timeout: None,
             ins,
             discovered_ins: Vec::new(),
             outs,
+            dyndep: None,
+            dyndep_outs: 0,
         }
     }
 
+    /// Whether this build is in the builtin `console` pool, whose commands
+    /// run one at a time with direct access to the terminal.
+    pub fn is_console(&self) -> bool {
+        self.pool.as_deref() == Some("console")
+    }
+
+    /// Resource cost of this build, 1 unless the manifest says otherwise.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
+    /// Expected memory use of this build in megabytes, 0 if not given.
+    pub fn mem(&self) -> u64 {
+        self.mem
+    }
+
+    pub fn set_mem(&mut self, mem: u64) {
+        self.mem = mem;
+    }
+
+    /// The build's own timeout, if the manifest gave one.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Set the path of the `.d` file written by this build.  An empty path
+    /// means no depfile.
+    pub fn set_depfile(&mut self, path: String) {
+        self.depfile = if path.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(canon_path(path)))
+        };
+    }
+
+    /// Canonicalized path to the generated `.d` file, if any.
+    pub fn depfile_path(&self) -> Option<PathBuf> {
+        self.depfile.clone()
+    }
+
     /// Input paths that appear in `$in`.
     pub fn explicit_ins(&self) -> &[FileId] {
         &self.ins.ids[0..self.ins.explicit]
@@ -237,6 +677,13 @@ impl Build {
         &self.discovered_ins
     }
 
+    /// Whether a clean should remove this build's outputs: only builds that
+    /// run a command, excluding generator builds, whose outputs (like
+    /// build.ninja) are needed to rebuild anything at all.
+    pub fn is_cleanable(&self) -> bool {
+        self.cmdline.is_some() && !self.generator
+    }
+
     /// Output paths that appear in `$out`.
     pub fn explicit_outs(&self) -> &[FileId] {
         &self.outs.ids[0..self.outs.explicit]
@@ -246,6 +693,12 @@ impl Build {
     pub fn outs(&self) -> &[FileId] {
         &self.outs.ids
     }
+
+    /// Outputs declared in the manifest, omitting any from a dyndep file,
+    /// which aren't known until that file is loaded.
+    pub fn manifest_outs(&self) -> &[FileId] {
+        &self.outs.ids[..self.outs.ids.len() - self.dyndep_outs]
+    }
 }
 
 /// The build graph: owns Files/Builds and maps FileIds/BuildIds to them.
@@ -253,6 +706,12 @@ impl Build {
 pub struct Graph {
     pub builds: DenseMap<BuildId, Build>,
     pub files: GraphFiles,
+    /// Builds grouped by the file they were declared in, each list sorted by
+    /// line, for mapping source locations back to builds.
+    by_location: FxHashMap<PathBuf, Vec<(usize, BuildId)>>,
+    /// Builds in the order they were declared in the manifest, if requested
+    /// via record_declaration_order().
+    declaration_order: Option<Vec<BuildId>>,
 }
 
 /// Files identified by FileId, as well as mapping string filenames to them.
@@ -260,7 +719,54 @@ pub struct Graph {
 #[derive(Default)]
 pub struct GraphFiles {
     pub by_id: DenseMap<FileId, File>,
-    by_name: FxHashMap<String, FileId>,
+    /// Index from the hash of a file name to the file, so that each name is
+    /// stored only once, in by_id.
+    by_name: FxHashMap<u64, FileId>,
+    /// Files whose name hash collided with an earlier file's.  Almost always
+    /// empty, so a linear scan is fine.
+    by_name_collisions: Vec<FileId>,
+}
+
+/// On Windows `\\` and `/` name the same file, so names are hashed and
+/// compared with separators normalized.  (Drive letter case is already
+/// normalized by canon_path.)  The name kept is the first spelling seen, so
+/// that commands see paths as the manifest wrote them.
+fn name_bytes(name: &str) -> impl Iterator<Item = u8> + '_ {
+    name.bytes().map(|b| match b {
+        b'\\' if cfg!(windows) => b'/',
+        b => b,
+    })
+}
+
+fn hash_name(name: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    if cfg!(windows) {
+        for b in name_bytes(name) {
+            hasher.write_u8(b);
+        }
+    } else {
+        name.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    if cfg!(windows) {
+        a.len() == b.len() && name_bytes(a).eq(name_bytes(b))
+    } else {
+        a == b
+    }
+}
+
+/// Graph::add_build()'s error, for a build listing an output that another
+/// build already produces.
+#[derive(Debug)]
+pub struct DuplicateOutput {
+    pub file: String,
+    /// Where the build being added was declared.
+    pub loc: FileLoc,
+    /// Where the build already producing the file was declared.
+    pub prev: FileLoc,
 }
 
 impl Graph {
@@ -269,8 +775,18 @@ impl Graph {
         &self.files.by_id[id]
     }
 
-    /// Add a new Build, generating a BuildId for it.
-    pub fn add_build(&mut self, mut build: Build) -> anyhow::Result<()> {
+    /// Look up the build that produces a file, given its path as it might be
+    /// written on the command line.  None if the file is unknown or is a
+    /// source file.
+    pub fn build_for_output(&self, path: &str) -> Option<&Build> {
+        let id = self.files.lookup(&canon_path(path))?;
+        Some(&self.builds[self.file(id).input?])
+    }
+
+    /// Add a new Build, generating a BuildId for it.  Returns any warnings
+    /// about the build.
+    pub fn add_build(&mut self, mut build: Build) -> Result<Vec<Warning>, DuplicateOutput> {
+        let mut warnings = Vec::new();
         let new_id = self.builds.next_id();
         for &id in &build.ins.ids {
             self.files.by_id[id].dependents.push(new_id);
@@ -281,18 +797,18 @@ impl Graph {
             match f.input {
                 Some(prev) if prev == new_id => {
                     fixup_dups = true;
-                    println!(
-                        "n2: warn: {}: {:?} is repeated in output list",
-                        build.location, f.name,
-                    );
+                    warnings.push(Warning {
+                        loc: build.location.clone(),
+                        category: Category::DupBuild,
+                        message: format!("{:?} is repeated in output list", f.name),
+                    });
                 }
                 Some(prev) => {
-                    anyhow::bail!(
-                        "{}: {:?} is already an output at {}",
-                        build.location,
-                        f.name,
-                        self.builds[prev].location
-                    );
+                    return Err(DuplicateOutput {
+                        file: f.name.clone(),
+                        loc: build.location,
+                        prev: self.builds[prev].location.clone(),
+                    });
                 }
                 None => f.input = Some(new_id),
             }
@@ -300,15 +816,308 @@ impl Graph {
         if fixup_dups {
             build.outs.remove_duplicates();
         }
+        self.index_location(&build.location, new_id);
+        if let Some(order) = &mut self.declaration_order {
+            order.push(new_id);
+        }
         self.builds.push(build);
+        Ok(warnings)
+    }
+
+    /// Detach a build from the graph, for reloading the part of the manifest
+    /// that declared it.  BuildIds index into `builds`, so the build's slot
+    /// stays, holding a phony build with no inputs or outputs.
+    pub fn remove_build(&mut self, id: BuildId) {
+        let ins = BuildIns {
+            ids: Vec::new(),
+            explicit: 0,
+            implicit: 0,
+            order_only: 0,
+        };
+        let outs = BuildOuts {
+            ids: Vec::new(),
+            explicit: 0,
+        };
+        let location = self.builds[id].location.clone();
+        let mut removed = Build::new(location, ins, outs);
+        removed.rule = "phony".to_owned();
+        let old = std::mem::replace(&mut self.builds[id], removed);
+        for &input in &old.ins.ids {
+            self.files.by_id[input].dependents.retain(|&b| b != id);
+        }
+        for &output in &old.outs.ids {
+            let file = &mut self.files.by_id[output];
+            if file.input == Some(id) {
+                file.input = None;
+            }
+        }
+        if let Some(builds) = self.by_location.get_mut(old.location.filename.as_path()) {
+            builds.retain(|&(_, b)| b != id);
+        }
+        if let Some(order) = &mut self.declaration_order {
+            order.retain(|&b| b != id);
+        }
+    }
+
+    /// Add the implicit inputs and outputs a dyndep file lists for a build.
+    /// Returns the inputs that are new to the build.
+    pub fn add_dyndep_edges(
+        &mut self,
+        id: BuildId,
+        ins: &[FileId],
+        outs: &[FileId],
+    ) -> anyhow::Result<Vec<FileId>> {
+        let build = &mut self.builds[id];
+        let mut new_ins = Vec::new();
+        for &input in ins {
+            if build.outs.ids.contains(&input) || outs.contains(&input) {
+                anyhow::bail!(
+                    "{}: dyndep input {} is also an output",
+                    build.location,
+                    self.files.by_id[input].name
+                );
+            }
+            if build.ins.ids.contains(&input) || new_ins.contains(&input) {
+                continue;
+            }
+            new_ins.push(input);
+        }
+        for &out in outs {
+            match self.files.by_id[out].input {
+                Some(prev) if prev == id => continue,
+                Some(prev) => anyhow::bail!(
+                    "{}: dyndep output {} is already an output at {}",
+                    self.builds[id].location,
+                    self.files.by_id[out].name,
+                    self.builds[prev].location
+                ),
+                None => {}
+            }
+            self.files.by_id[out].input = Some(id);
+            let build = &mut self.builds[id];
+            build.outs.ids.push(out);
+            build.dyndep_outs += 1;
+        }
+        // Implicit inputs go after the explicit and implicit ones already
+        // present, before the order-only and validation inputs.
+        let build = &mut self.builds[id];
+        let pos = build.ins.explicit + build.ins.implicit;
+        build.ins.ids.splice(pos..pos, new_ins.iter().copied());
+        build.ins.implicit += new_ins.len();
+        for &input in &new_ins {
+            self.files.by_id[input].dependents.push(id);
+        }
+        Ok(new_ins)
+    }
+
+    /// Start recording the order in which builds are added, for use by
+    /// builds_in_declaration_order().
+    pub fn record_declaration_order(&mut self) {
+        self.declaration_order.get_or_insert_with(Vec::new);
+    }
+
+    /// Iterate builds in the order they were declared in the manifest.
+    /// If the order wasn't recorded, this falls back to BuildId order.
+    pub fn builds_in_declaration_order(&self) -> impl Iterator<Item = &Build> {
+        let ids: Vec<BuildId> = match &self.declaration_order {
+            Some(order) => order.clone(),
+            None => self.builds.all_ids().collect(),
+        };
+        ids.into_iter().map(move |id| &self.builds[id])
+    }
+
+    /// Check the graph for dependency cycles, following each file to the
+    /// inputs of the build that produces it.  On finding one, returns the
+    /// files in the cycle, with the first file repeated at the end.
+    /// Validation inputs don't count, as they don't order the build.
+    pub fn detect_cycles(&self) -> Result<(), Vec<FileId>> {
+        self.find_cycle(|_| {})
+    }
+
+    /// Order all builds so that each comes after the builds producing its
+    /// ordering_ins(), for driving a build outside of n2's own scheduler.
+    /// Fails with the files in a cycle as detect_cycles() does.
+    pub fn topo_order(&self) -> Result<Vec<BuildId>, Vec<FileId>> {
+        let mut order = Vec::new();
+        let mut added = DenseMap::new_sized(self.builds.next_id(), false);
+        self.find_cycle(|id| {
+            if let Some(bid) = self.file(id).input {
+                if !added[bid] {
+                    added[bid] = true;
+                    order.push(bid);
+                }
+            }
+        })?;
+        Ok(order)
+    }
+
+    /// Depth-first search of the graph for a cycle.  `done` is called on
+    /// each file once everything it depends on has been visited.
+    fn find_cycle(&self, mut done: impl FnMut(FileId)) -> Result<(), Vec<FileId>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            Unvisited,
+            Visiting,
+            Done,
+        }
+        let mut marks = DenseMap::new_sized(self.files.by_id.next_id(), Mark::Unvisited);
+        // The current DFS path: each file along with the index of the next
+        // input of its build to visit.
+        let mut stack: Vec<(FileId, usize)> = Vec::new();
+        // Start from build outputs in build order, so that a cycle is
+        // reported starting from the first build involved in it.
+        let roots = self
+            .builds
+            .all_ids()
+            .flat_map(|bid| self.builds[bid].outs().iter().copied());
+        for root in roots {
+            if marks[root] != Mark::Unvisited {
+                continue;
+            }
+            marks[root] = Mark::Visiting;
+            stack.push((root, 0));
+            while let Some((id, next)) = stack.last_mut() {
+                let ins = match self.file(*id).input {
+                    Some(bid) => self.builds[bid].ordering_ins(),
+                    None => &[],
+                };
+                let input = match ins.get(*next) {
+                    Some(&input) => input,
+                    None => {
+                        marks[*id] = Mark::Done;
+                        done(*id);
+                        stack.pop();
+                        continue;
+                    }
+                };
+                *next += 1;
+                match marks[input] {
+                    Mark::Unvisited => {
+                        marks[input] = Mark::Visiting;
+                        stack.push((input, 0));
+                    }
+                    Mark::Visiting => {
+                        let start = stack.iter().position(|&(sid, _)| sid == input).unwrap();
+                        let mut cycle: Vec<FileId> =
+                            stack[start..].iter().map(|&(sid, _)| sid).collect();
+                        cycle.push(input);
+                        return Err(cycle);
+                    }
+                    Mark::Done => {}
+                }
+            }
+        }
         Ok(())
     }
+
+    /// Count how many builds share each command line, to help spot redundant
+    /// builds.  Phony builds are skipped.
+    pub fn command_histogram(&self) -> HashMap<&str, usize> {
+        let mut histogram = HashMap::new();
+        for id in self.builds.all_ids() {
+            if let Some(cmdline) = &self.builds[id].cmdline {
+                *histogram.entry(cmdline.as_str()).or_insert(0) += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Files that are built but that no build depends on, sorted by name.
+    pub fn root_files(&self) -> Vec<FileId> {
+        self.files
+            .all_ids_sorted()
+            .into_iter()
+            .filter(|&id| {
+                let file = self.file(id);
+                file.input.is_some() && file.dependents.is_empty()
+            })
+            .collect()
+    }
+
+    /// Files that no build produces, sorted by name.  Besides the sources
+    /// builds read, this includes the .ninja files themselves, which are
+    /// tracked as files even when nothing regenerates them.
+    pub fn source_files(&self) -> Vec<FileId> {
+        self.files
+            .all_ids_sorted()
+            .into_iter()
+            .filter(|&id| self.file(id).input.is_none())
+            .collect()
+    }
+
+    /// Outputs a clean would remove; see Build::is_cleanable().
+    pub fn cleanable_outputs(&self) -> Vec<FileId> {
+        let mut outs = Vec::new();
+        for id in self.builds.all_ids() {
+            let build = &self.builds[id];
+            if build.is_cleanable() {
+                outs.extend_from_slice(build.explicit_outs());
+            }
+        }
+        outs
+    }
+
+    /// Approximate heap bytes used by the graph, for reporting purposes.
+    /// Only the larger allocations are counted.
+    pub fn estimated_memory(&self) -> usize {
+        let mut size = self.builds.heap_size() + self.files.estimated_memory();
+        for id in self.builds.all_ids() {
+            let build = &self.builds[id];
+            let strings = [&build.desc, &build.cmdline, &build.pool];
+            size += strings
+                .iter()
+                .map(|s| s.as_ref().map_or(0, |s| s.capacity()))
+                .sum::<usize>();
+            size += build.depfile.as_ref().map_or(0, |p| p.as_os_str().len());
+            size += (build.ins.ids.capacity()
+                + build.outs.ids.capacity()
+                + build.discovered_ins.capacity())
+                * std::mem::size_of::<FileId>();
+        }
+        for builds in self.by_location.values() {
+            size += builds.capacity() * std::mem::size_of::<(usize, BuildId)>();
+        }
+        size
+    }
+
+    fn index_location(&mut self, loc: &FileLoc, id: BuildId) {
+        let builds = match self.by_location.get_mut(loc.filename.as_path()) {
+            Some(builds) => builds,
+            None => self
+                .by_location
+                .entry(loc.filename.to_path_buf())
+                .or_default(),
+        };
+        // Builds within a file are almost always added in line order, but
+        // keep the list sorted regardless.
+        let pos = builds.partition_point(|&(line, _)| line <= loc.line);
+        builds.insert(pos, (loc.line, id));
+    }
+
+    /// Look up the build declared at a given line of a given .ninja file,
+    /// e.g. for mapping an editor cursor position to a build.
+    pub fn find_build_by_location(&self, file: &Path, line: usize) -> Option<BuildId> {
+        let builds = self.by_location.get(file)?;
+        let pos = builds.binary_search_by_key(&line, |&(line, _)| line).ok()?;
+        Some(builds[pos].1)
+    }
 }
 
 impl GraphFiles {
     /// Look up a file by its name.  Name must have been canonicalized already.
     pub fn lookup(&self, file: &str) -> Option<FileId> {
-        self.by_name.get(file).copied()
+        self.lookup_hashed(file, hash_name(file))
+    }
+
+    fn lookup_hashed(&self, file: &str, hash: u64) -> Option<FileId> {
+        let &id = self.by_name.get(&hash)?;
+        if same_name(&self.by_id[id].name, file) {
+            return Some(id);
+        }
+        self.by_name_collisions
+            .iter()
+            .copied()
+            .find(|&id| same_name(&self.by_id[id].name, file))
     }
 
     /// Look up a file by its name, adding it if not already present.
@@ -319,39 +1128,103 @@ impl GraphFiles {
     /// for the case where the entry already exists. But so far, all of our
     /// usages of this function have an owned string easily accessible anyways.
     pub fn id_from_canonical(&mut self, file: String) -> FileId {
-        // TODO: so many string copies :<
-        match self.by_name.entry(file) {
-            Entry::Occupied(o) => *o.get(),
+        let hash = hash_name(&file);
+        if let Some(id) = self.lookup_hashed(&file, hash) {
+            return id;
+        }
+        let id = self.by_id.push(File {
+            name: file,
+            input: None,
+            dependents: Vec::new(),
+        });
+        match self.by_name.entry(hash) {
+            Entry::Occupied(_) => self.by_name_collisions.push(id),
             Entry::Vacant(v) => {
-                let id = self.by_id.push(File {
-                    name: v.key().clone(),
-                    input: None,
-                    dependents: Vec::new(),
-                });
                 v.insert(id);
-                id
             }
         }
+        id
     }
 
+    /// All file ids, in the order the files were first referenced.  Prefer
+    /// this where order doesn't matter; see all_ids_sorted().
     pub fn all_ids(&self) -> impl Iterator<Item = FileId> {
-        (0..self.by_id.next_id().0).map(|id| FileId(id))
+        (0..self.by_id.next_id().0).map(FileId)
+    }
+
+    /// All file ids, sorted by file name, for listing files in a stable order
+    /// that doesn't depend on manifest layout.
+    pub fn all_ids_sorted(&self) -> Vec<FileId> {
+        let mut ids: Vec<FileId> = self.all_ids().collect();
+        ids.sort_unstable_by(|&a, &b| self.by_id[a].name.cmp(&self.by_id[b].name));
+        ids
+    }
+
+    /// Release excess capacity once loading is complete.
+    pub fn shrink_to_fit(&mut self) {
+        self.by_id.shrink_to_fit();
+        self.by_name.shrink_to_fit();
+        self.by_name_collisions.shrink_to_fit();
+    }
+
+    /// Approximate heap bytes used by the files and the name index.
+    fn estimated_memory(&self) -> usize {
+        let mut size = self.by_id.heap_size();
+        size += self.by_name.capacity() * std::mem::size_of::<(u64, FileId)>();
+        size += self.by_name_collisions.capacity() * std::mem::size_of::<FileId>();
+        for id in self.all_ids() {
+            let file = &self.by_id[id];
+            size += file.name.capacity();
+            size += file.dependents.capacity() * std::mem::size_of::<BuildId>();
+        }
+        size
     }
 }
 
 /// MTime info gathered for a file.  This also models "file is absent".
 /// It's not using an Option<> just because it makes the code using it easier
 /// to follow.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Hash)]
 pub enum MTime {
     Missing,
     Stamp(SystemTime),
+    /// A symlink, seen only when tracking symlinks: the link's own mtime,
+    /// which changes when it's repointed, and its target's, if that exists.
+    Link {
+        link: SystemTime,
+        target: Option<SystemTime>,
+    },
+}
+
+/// How stat() treats symlinks.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Symlinks {
+    /// Report the target's state, as if the link were the file itself.
+    #[default]
+    Follow,
+    /// Report links as MTime::Link, so repointing a link is noticed even if
+    /// the new target is no newer than the old.
+    Track,
 }
 
 /// stat() an on-disk path, producing its MTime.
-pub fn stat(path: &Path) -> std::io::Result<MTime> {
-    // TODO: On Windows, use FindFirstFileEx()/FindNextFile() to get timestamps per
-    //       directory, for better stat perf.
+pub fn stat(path: &Path, symlinks: Symlinks) -> std::io::Result<MTime> {
+    if symlinks == Symlinks::Track {
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_symlink() {
+                let target = match stat(path, Symlinks::Follow)? {
+                    MTime::Stamp(mtime) => Some(mtime),
+                    _ => None,
+                };
+                return Ok(MTime::Link {
+                    link: meta.modified()?,
+                    target,
+                });
+            }
+        }
+    }
+    // FileState::stat_all() can get timestamps from directory listings
+    // instead, which may be faster on Windows.
     Ok(match std::fs::metadata(path) {
         Ok(meta) => MTime::Stamp(meta.modified().unwrap()),
         Err(err) => {
@@ -364,49 +1237,207 @@ pub fn stat(path: &Path) -> std::io::Result<MTime> {
     })
 }
 
+/// On Windows, listing a directory returns each entry's metadata along with
+/// its name, so stat()ing files by listing their directory could save opening
+/// each one.  That's only a possible win for directories with several files
+/// wanted.
+const MIN_FILES_TO_LIST: usize = 4;
+
+/// The mtimes of the entries of a directory, by name, from listing it.
+/// Symlinks are left out, as the metadata listed for them is the link's own.
+fn list_mtimes(dir: &Path) -> std::io::Result<HashMap<std::ffi::OsString, SystemTime>> {
+    let mut mtimes = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if !meta.file_type().is_symlink() {
+            mtimes.insert(entry.file_name(), meta.modified()?);
+        }
+    }
+    Ok(mtimes)
+}
+
 /// Gathered state of on-disk files.
 /// Due to discovered deps this map may grow after graph initialization.
-pub struct FileState(DenseMap<FileId, Option<MTime>>);
+pub struct FileState {
+    mtimes: DenseMap<FileId, Option<MTime>>,
+    symlinks: Symlinks,
+}
 
 impl FileState {
-    pub fn new(graph: &Graph) -> Self {
-        FileState(DenseMap::new_sized(graph.files.by_id.next_id(), None))
+    pub fn with_symlinks(graph: &Graph, symlinks: Symlinks) -> Self {
+        FileState {
+            mtimes: DenseMap::new_sized(graph.files.by_id.next_id(), None),
+            symlinks,
+        }
     }
 
     pub fn get(&self, id: FileId) -> Option<MTime> {
-        self.0.lookup(id).copied().unwrap_or(None)
+        self.mtimes.lookup(id).copied().unwrap_or(None)
     }
 
     pub fn stat(&mut self, id: FileId, path: &Path) -> anyhow::Result<MTime> {
-        let mtime = stat(path).map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
-        self.0.set_grow(id, Some(mtime), None);
+        let mtime =
+            stat(path, self.symlinks).map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
+        self.mtimes.set_grow(id, Some(mtime), None);
         Ok(mtime)
     }
+
+    /// Record a file's state without stat()ing it, for files that stand for
+    /// something other than themselves, like phony outputs.
+    pub fn set(&mut self, id: FileId, mtime: MTime) {
+        self.mtimes.set_grow(id, Some(mtime), None);
+    }
+
+    /// Drop what's known of a file, so it's stat()ed again when next needed.
+    pub fn forget(&mut self, id: FileId) {
+        if self.mtimes.lookup(id).is_some() {
+            self.mtimes[id] = None;
+        }
+    }
+
+    /// stat() every source file not yet known, spread across threads, to
+    /// avoid stat()ing them one at a time when checking a large tree with a
+    /// cold cache.  Generated files are left to be stat()ed as their builds
+    /// are checked, and anything this misses is still stat()ed on demand.
+    ///
+    /// With `by_directory`, files are first looked for in listings of their
+    /// directories; see stat_by_directory().
+    pub fn stat_all(&mut self, files: &GraphFiles, by_directory: bool) -> anyhow::Result<()> {
+        let mut ids: Vec<FileId> = files
+            .all_ids()
+            .filter(|&id| files.by_id[id].input.is_none() && self.get(id).is_none())
+            .collect();
+        if by_directory {
+            ids = self.stat_by_directory(files, ids);
+        }
+        // On a cold cache stat() mostly waits on IO, so use some threads even
+        // on a machine with few cores.
+        let threads = std::thread::available_parallelism()
+            .map_or(1, usize::from)
+            .max(4);
+        let symlinks = self.symlinks;
+        let chunk_size = ((ids.len() + threads - 1) / threads).max(1);
+        let mtimes: Vec<std::io::Result<MTime>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = ids
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|&id| stat(files.by_id[id].path(), symlinks))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        for (id, mtime) in ids.into_iter().zip(mtimes) {
+            let path = files.by_id[id].path();
+            let mtime = mtime.map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
+            self.mtimes.set_grow(id, Some(mtime), None);
+        }
+        Ok(())
+    }
+
+    /// Fill in the state of files found by listing their directories, for
+    /// directories with at least MIN_FILES_TO_LIST of them.  Returns the
+    /// files left to stat() one by one: those in other directories, and
+    /// those not listed, which may be missing or spelled with another case.
+    fn stat_by_directory(&mut self, files: &GraphFiles, ids: Vec<FileId>) -> Vec<FileId> {
+        let mut by_dir: HashMap<&Path, Vec<FileId>> = HashMap::new();
+        for id in ids {
+            let dir = match files.by_id[id].path().parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            by_dir.entry(dir).or_default().push(id);
+        }
+        let mut rest = Vec::new();
+        for (dir, ids) in by_dir {
+            let listing = if ids.len() >= MIN_FILES_TO_LIST {
+                list_mtimes(dir).ok()
+            } else {
+                None
+            };
+            for id in ids {
+                let name = files.by_id[id].path().file_name();
+                let mtime = listing.as_ref().zip(name).and_then(|(l, name)| l.get(name));
+                match mtime {
+                    Some(&mtime) => self.mtimes.set_grow(id, Some(MTime::Stamp(mtime)), None),
+                    None => rest.push(id),
+                }
+            }
+        }
+        rest
+    }
 }
 
 #[derive(Default)]
-pub struct Hashes(HashMap<BuildId, BuildHash>);
+pub struct Hashes {
+    hashes: HashMap<BuildId, BuildHash>,
+    /// Hashes over file contents rather than mtimes, recorded only when
+    /// building with content hashing; see hash::hash_build_contents().
+    content: HashMap<BuildId, BuildHash>,
+    /// How long each build took when it last ran, where known.
+    times: HashMap<BuildId, Duration>,
+}
 
 impl Hashes {
     pub fn set(&mut self, id: BuildId, hash: BuildHash) {
-        self.0.insert(id, hash);
+        self.hashes.insert(id, hash);
     }
 
     pub fn get(&self, id: BuildId) -> Option<BuildHash> {
-        self.0.get(&id).copied()
+        self.hashes.get(&id).copied()
+    }
+
+    /// Set or clear the content hash, as a later build record may lack one.
+    pub fn set_content(&mut self, id: BuildId, hash: Option<BuildHash>) {
+        match hash {
+            Some(hash) => self.content.insert(id, hash),
+            None => self.content.remove(&id),
+        };
+    }
+
+    pub fn get_content(&self, id: BuildId) -> Option<BuildHash> {
+        self.content.get(&id).copied()
+    }
+
+    pub fn set_time(&mut self, id: BuildId, time: Option<Duration>) {
+        match time {
+            Some(time) => self.times.insert(id, time),
+            None => self.times.remove(&id),
+        };
+    }
+
+    pub fn get_time(&self, id: BuildId) -> Option<Duration> {
+        self.times.get(&id).copied()
+    }
+
+    /// The median of the known build times, as a guess at how long a build
+    /// that's never run will take.
+    pub fn median_time(&self) -> Option<Duration> {
+        let mut times: Vec<Duration> = self.times.values().copied().collect();
+        if times.is_empty() {
+            return None;
+        }
+        let mid = times.len() / 2;
+        Some(*times.select_nth_unstable(mid).1)
     }
 }
 
 #[test]
 fn stat_mtime_resolution() {
-    use std::time::Duration;
-
     let temp_dir = tempfile::tempdir().unwrap();
     let filename = temp_dir.path().join("dummy");
 
     // Write once and stat.
     std::fs::write(&filename, "foo").unwrap();
-    let mtime1 = match stat(&filename).unwrap() {
+    let mtime1 = match stat(&filename, Symlinks::Follow).unwrap() {
         MTime::Stamp(mtime) => mtime,
         _ => panic!("File not found: {}", filename.display()),
     };
@@ -416,7 +1447,7 @@ fn stat_mtime_resolution() {
 
     // Write twice and stat.
     std::fs::write(&filename, "foo").unwrap();
-    let mtime2 = match stat(&filename).unwrap() {
+    let mtime2 = match stat(&filename, Symlinks::Follow).unwrap() {
         MTime::Stamp(mtime) => mtime,
         _ => panic!("File not found: {}", filename.display()),
     };
@@ -425,3 +1456,41 @@ fn stat_mtime_resolution() {
     assert!(diff > Duration::ZERO);
     assert!(diff < Duration::from_millis(100));
 }
+
+#[cfg(unix)]
+#[test]
+fn stat_track_symlinks() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let old = temp_dir.path().join("old");
+    let new = temp_dir.path().join("new");
+    let link = temp_dir.path().join("link");
+    std::fs::write(&new, "new").unwrap();
+    std::fs::write(&old, "old").unwrap();
+    std::os::unix::fs::symlink(&new, &link).unwrap();
+
+    assert!(matches!(
+        stat(&link, Symlinks::Follow).unwrap(),
+        MTime::Stamp(_)
+    ));
+    let first = stat(&link, Symlinks::Track).unwrap();
+    assert!(matches!(
+        first,
+        MTime::Link {
+            target: Some(_),
+            ..
+        }
+    ));
+
+    // Repoint the link at a file that's no newer than the old target.
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::remove_file(&link).unwrap();
+    std::os::unix::fs::symlink(&old, &link).unwrap();
+    assert_ne!(stat(&link, Symlinks::Track).unwrap(), first);
+
+    std::fs::remove_file(&old).unwrap();
+    assert!(matches!(
+        stat(&link, Symlinks::Track).unwrap(),
+        MTime::Link { target: None, .. }
+    ));
+    assert_eq!(stat(&link, Symlinks::Follow).unwrap(), MTime::Missing);
+}
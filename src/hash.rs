@@ -32,19 +32,19 @@ trait Manifest {
     fn write_cmdline(&mut self, cmdline: &str);
 }
 
+/// Returns the name and state of a file, which must be present.
 fn get_fileid_status<'a>(
     files: &'a GraphFiles,
     file_state: &FileState,
     id: FileId,
-) -> (&'a str, SystemTime) {
+) -> (&'a str, MTime) {
     let name = &files.by_id[id].name;
     let mtime = file_state
         .get(id)
         .unwrap_or_else(|| panic!("no state for {:?}", name));
-    let mtime = match mtime {
-        MTime::Stamp(mtime) => mtime,
-        MTime::Missing => panic!("missing file: {:?}", name),
-    };
+    if mtime == MTime::Missing {
+        panic!("missing file: {:?}", name);
+    }
     (name.as_str(), mtime)
 }
 
@@ -79,7 +79,14 @@ impl Manifest for TerseHash {
         for &id in ids {
             let (name, mtime) = get_fileid_status(files, file_state, id);
             self.write_string(name);
-            mtime.hash(&mut self.0);
+            match mtime {
+                MTime::Stamp(mtime) => mtime.hash(&mut self.0),
+                MTime::Link { link, target } => {
+                    link.hash(&mut self.0);
+                    target.hash(&mut self.0);
+                }
+                MTime::Missing => unreachable!(),
+            }
         }
         self.write_separator();
     }
@@ -119,6 +126,87 @@ pub fn hash_build(files: &GraphFiles, file_state: &FileState, build: &Build) ->
     hasher.finish()
 }
 
+/// A BuildHasher that hashes the contents of files rather than their mtimes,
+/// for builds where touching a file without changing it shouldn't count.
+#[derive(Default)]
+struct ContentHash {
+    hash: TerseHash,
+    /// The first error hit reading a file, if any.
+    error: Option<std::io::Error>,
+}
+
+/// Adapts a Hasher to io::Write, to stream file contents into it.
+struct HashWriter<'a>(&'a mut DefaultHasher);
+
+impl std::io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Manifest for ContentHash {
+    fn write_files(
+        &mut self,
+        _desc: &str,
+        files: &GraphFiles,
+        file_state: &FileState,
+        ids: &[FileId],
+    ) {
+        for &id in ids {
+            let file = &files.by_id[id];
+            self.hash.write_string(&file.name);
+            if let Some(MTime::Link { target, .. }) = file_state.get(id) {
+                // Where a tracked symlink points is part of its contents.
+                match std::fs::read_link(file.path()) {
+                    Ok(dest) => dest.hash(&mut self.hash.0),
+                    Err(err) => {
+                        self.error.get_or_insert(err);
+                    }
+                }
+                if target.is_none() {
+                    // Dangling, so there's nothing more to read.
+                    continue;
+                }
+            }
+            let res = std::fs::File::open(file.path())
+                .and_then(|mut f| std::io::copy(&mut f, &mut HashWriter(&mut self.hash.0)));
+            if let Err(err) = res {
+                self.error.get_or_insert(err);
+            }
+        }
+        self.hash.write_separator();
+    }
+
+    fn write_cmdline(&mut self, cmdline: &str) {
+        self.hash.write_cmdline(cmdline);
+    }
+
+    fn write_rsp(&mut self, rspfile: &RspFile) {
+        self.hash.write_rsp(rspfile);
+    }
+}
+
+/// Like hash_build(), but over the contents of the build's files rather
+/// than their mtimes.  This reads every input and output in full, so it's
+/// only worth doing once hash_build() has found that something changed.
+pub fn hash_build_contents(
+    files: &GraphFiles,
+    file_state: &FileState,
+    build: &Build,
+) -> std::io::Result<BuildHash> {
+    let mut hasher = ContentHash::default();
+    build_manifest(&mut hasher, files, file_state, build);
+    match hasher.error {
+        Some(err) => Err(err),
+        None => Ok(hasher.hash.finish()),
+    }
+}
+
 /// A BuildHasher that records human-readable text for "-d explain" debugging.
 #[derive(Default)]
 struct ExplainHash {
@@ -134,13 +222,29 @@ impl Manifest for ExplainHash {
         ids: &[FileId],
     ) {
         writeln!(&mut self.text, "{desc}:").unwrap();
-        for &id in ids {
-            let (name, mtime) = get_fileid_status(files, file_state, id);
-            let millis = mtime
+        let millis = |mtime: SystemTime| {
+            mtime
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
-                .as_millis();
-            writeln!(&mut self.text, "  {millis} {name}").unwrap();
+                .as_millis()
+        };
+        for &id in ids {
+            let (name, mtime) = get_fileid_status(files, file_state, id);
+            match mtime {
+                MTime::Stamp(mtime) => {
+                    writeln!(&mut self.text, "  {} {name}", millis(mtime)).unwrap()
+                }
+                MTime::Link { link, target } => {
+                    let target = target.map_or("missing".to_owned(), |t| millis(t).to_string());
+                    writeln!(
+                        &mut self.text,
+                        "  {} {name} (symlink, target {target})",
+                        millis(link)
+                    )
+                    .unwrap()
+                }
+                MTime::Missing => unreachable!(),
+            }
         }
     }
 
@@ -0,0 +1,38 @@
+//! Helpers for writing JSON output, which is simple enough that we don't
+//! pull in a serialization library for it.
+
+use std::fmt::Write;
+
+/// Quote a string as a JSON string literal, escaping as needed.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_escapes() {
+        assert_eq!(quote("plain"), r#""plain""#);
+        assert_eq!(
+            quote(r#"cl /Fo"C:\out\a.obj""#),
+            r#""cl /Fo\"C:\\out\\a.obj\"""#
+        );
+        assert_eq!(quote("a\nb\t\u{1}"), r#""a\nb\t\u0001""#);
+    }
+}
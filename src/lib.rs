@@ -1,11 +1,20 @@
+// Declared first so its macros are visible in the modules after it.
+#[macro_use]
+pub mod log;
+
 pub mod canon;
 mod db;
 mod densemap;
 mod depfile;
-mod eval;
-mod graph;
+mod dyndep;
+pub mod eval;
+mod events;
+mod glob;
+pub mod graph;
 mod hash;
+mod json;
 pub mod load;
+mod ninja_log;
 pub mod parse;
 mod process;
 #[cfg(unix)]
@@ -17,9 +26,12 @@ pub mod run;
 pub mod scanner;
 mod signal;
 mod smallmap;
+mod stats;
 mod task;
 mod terminal;
+mod tools;
 mod trace;
+mod watch;
 mod work;
 
 #[cfg(not(any(windows, target_arch = "wasm32")))]
@@ -1,73 +1,424 @@
 //! Graph loading: runs .ninja parsing and constructs the build graph from it.
 
 use crate::{
-    canon::{canon_path, canon_path_fast},
+    canon::{canon_path, canon_path_fast, canon_path_physical, relative_to},
+    densemap::Index,
     eval::{EvalPart, EvalString},
-    graph::{FileId, RspFile},
+    graph::{BuildId, FileId, RspFile},
+    log::{Category, Warning},
     parse::Statement,
     scanner,
     smallmap::SmallMap,
-    {db, eval, graph, parse, trace},
+    {db, eval, glob, graph, json, log, ninja_log, parse, trace},
 };
 use anyhow::{anyhow, bail};
+use std::borrow::{Borrow, Cow};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::{borrow::Cow, path::Path};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 
 /// A variable lookup environment for magic $in/$out variables.
 struct BuildImplicitVars<'a> {
     graph: &'a graph::Graph,
     build: &'a graph::Build,
+    /// If set, directory that paths are expressed relative to; see
+    /// Options::in_cwd.
+    cwd: Option<String>,
+    /// What to join `$in` with, from the `in_sep` binding; a space if unset.
+    in_sep: Option<String>,
 }
 impl<'a> BuildImplicitVars<'a> {
-    fn file_list(&self, ids: &[FileId], sep: char) -> String {
+    /// The implicit variables of a build, which depend on its `in_cwd` (if
+    /// honored) and `in_sep` bindings.
+    fn new<K, T>(
+        graph: &'a graph::Graph,
+        build: &'a graph::Build,
+        rule: &Rule,
+        build_vars: &SmallMap<K, EvalString<T>>,
+        env: &dyn eval::Env,
+        in_cwd: bool,
+    ) -> Self
+    where
+        K: Borrow<str> + PartialEq,
+        T: AsRef<str>,
+        SmallMap<K, EvalString<T>>: eval::Env,
+    {
+        let mut implicit_vars = BuildImplicitVars {
+            graph,
+            build,
+            cwd: None,
+            in_sep: None,
+        };
+        if in_cwd {
+            implicit_vars.cwd = lookup_build_var("in_cwd", rule, &implicit_vars, build_vars, env)
+                .filter(|dir| !dir.is_empty())
+                .map(canon_path);
+        }
+        implicit_vars.in_sep = lookup_build_var("in_sep", rule, &implicit_vars, build_vars, env);
+        implicit_vars
+    }
+
+    fn file_list(&self, ids: &[FileId], sep: &str) -> String {
         let mut out = String::new();
         for &id in ids {
             if !out.is_empty() {
-                out.push(sep);
+                out.push_str(sep);
+            }
+            let name = &self.graph.file(id).name;
+            match &self.cwd {
+                Some(cwd) => out.push_str(&relative_to(name, cwd)),
+                None => out.push_str(name),
             }
-            out.push_str(&self.graph.file(id).name);
         }
         out
     }
+
+    /// `$outstem`: the first explicit output with the extension of its file
+    /// name removed, so `obj/a.pb.o` becomes `obj/a.pb`.  A file name whose
+    /// only dot is the leading one, like `.config`, is kept whole.  Empty if
+    /// the build has no explicit outputs.
+    fn out_stem(&self) -> String {
+        let outs = self.build.explicit_outs();
+        let mut stem = self.file_list(&outs[..outs.len().min(1)], "");
+        let name_start = stem.rfind(['/', '\\']).map_or(0, |i| i + 1);
+        if let Some(dot) = stem[name_start..].rfind('.').filter(|&dot| dot > 0) {
+            stem.truncate(name_start + dot);
+        }
+        stem
+    }
 }
 impl<'a> eval::Env for BuildImplicitVars<'a> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         let string_to_evalstring =
             |s: String| Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(s))]));
         match var {
-            "in" => string_to_evalstring(self.file_list(self.build.explicit_ins(), ' ')),
-            "in_newline" => string_to_evalstring(self.file_list(self.build.explicit_ins(), '\n')),
-            "out" => string_to_evalstring(self.file_list(self.build.explicit_outs(), ' ')),
-            "out_newline" => string_to_evalstring(self.file_list(self.build.explicit_outs(), '\n')),
+            "in" => string_to_evalstring(self.file_list(
+                self.build.explicit_ins(),
+                self.in_sep.as_deref().unwrap_or(" "),
+            )),
+            "in_newline" => string_to_evalstring(self.file_list(self.build.explicit_ins(), "\n")),
+            "out" => string_to_evalstring(self.file_list(self.build.explicit_outs(), " ")),
+            "out_newline" => string_to_evalstring(self.file_list(self.build.explicit_outs(), "\n")),
+            "outstem" => string_to_evalstring(self.out_stem()),
             _ => None,
         }
     }
 }
 
+/// The ways loading can fail that an embedder may want to tell apart, say to
+/// render them its own way.  read_typed() fails with one of these; errors
+/// from read() and State::reload() can also be downcast to this with
+/// anyhow::Error::downcast_ref(), where other failures are plain messages.
+#[derive(Debug)]
+pub enum LoadError {
+    /// A syntax error in a manifest.  `message` is the error as n2 prints
+    /// it, quoting the offending line.
+    Parse {
+        error: scanner::ParseError,
+        message: String,
+    },
+    /// A build using a rule that isn't defined.
+    UnknownRule { name: String, loc: Location },
+    /// A file that is an output of two builds.
+    DuplicateOutput {
+        file: String,
+        loc: Location,
+        prev: Location,
+    },
+    /// A dependency cycle: its files, with the first repeated at the end.
+    Cycle { files: Vec<String> },
+    /// A manifest file that couldn't be read.
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// Any other failure, as its message.
+    Other(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Parse { message, .. } => write!(f, "{}", message),
+            LoadError::UnknownRule { name, loc } => write!(f, "{}: unknown rule {:?}", loc, name),
+            LoadError::DuplicateOutput { file, loc, prev } => {
+                write!(f, "{}: {:?} is already an output at {}", loc, file, prev)
+            }
+            LoadError::Cycle { files } => write!(f, "dependency cycle: {}", files.join(" -> ")),
+            LoadError::Io { path, error } => write!(f, "read {}: {}", path.display(), error),
+            LoadError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<anyhow::Error> for LoadError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<LoadError>() {
+            Ok(err) => err,
+            Err(err) => LoadError::Other(format!("{:#}", err)),
+        }
+    }
+}
+
+/// Where in a manifest a LoadError was found.  Unlike graph::FileLoc this
+/// owns its file name, as errors may be sent across threads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+impl From<&graph::FileLoc> for Location {
+    fn from(loc: &graph::FileLoc) -> Self {
+        Location {
+            file: loc.filename.to_path_buf(),
+            line: loc.line,
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+impl LoadError {
+    fn cycle(graph: &graph::Graph, cycle: &[FileId]) -> Self {
+        LoadError::Cycle {
+            files: cycle
+                .iter()
+                .map(|&id| graph.file(id).name.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Options affecting how the build graph is loaded.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Record the manifest declaration order of builds, available afterwards
+    /// via Graph::builds_in_declaration_order().
+    pub record_build_order: bool,
+    /// On a parse error, keep parsing the rest of the file and report all
+    /// the errors found rather than stopping at the first.
+    pub collect_parse_errors: bool,
+    /// Allow a build to list one of its own outputs as an input, as some
+    /// in-place rewriting rules do.  The self-dependency is then dropped from
+    /// the graph (though it still appears in `$in`) instead of being an error.
+    pub allow_output_as_input: bool,
+    /// Directory to keep the .n2_db (and .ninja_log) in.  When set this takes
+    /// precedence over any `builddir` set in the manifest, which in turn
+    /// takes precedence over the current directory.
+    pub builddir_override: Option<PathBuf>,
+    /// Path of the .n2_db file itself, for keeping the state of several build
+    /// configurations of one tree apart.  Takes precedence over the directory
+    /// chosen as for builddir_override; any missing parent directories are
+    /// created.  A .ninja_log is written to the same directory.
+    pub db_path: Option<PathBuf>,
+    /// Honor the `in_cwd = dir` rule/build attribute, an n2 extension for
+    /// commands that `cd` elsewhere: `$in` and `$out` are then expressed
+    /// relative to that directory.
+    pub in_cwd: bool,
+    /// Don't read or create the .n2_db at all, leaving State::db empty.  For
+    /// one-shot inspection of the graph that shouldn't leave state behind.
+    pub no_db: bool,
+    /// Read the .n2_db but never write it, leaving State::db empty, so that a
+    /// dry run changes nothing: a damaged db is left as it is, and no
+    /// .ninja_log is opened.
+    pub read_only_db: bool,
+    /// Top-level variables set from outside the manifest, like `-D key=value`.
+    /// As with `make VAR=val`, these win over assignments in the manifest,
+    /// though rule- and build-level bindings still shadow them as they would
+    /// any top-level variable.
+    pub var_overrides: HashMap<String, String>,
+    /// Also append each finished build to a Ninja-format .ninja_log, next to
+    /// the .n2_db, for tools that read Ninja's log.  Ignored with no_db.
+    pub ninja_log: bool,
+    /// Resolve ".." in paths against any symlink it follows; see
+    /// canon::canon_path_physical().
+    pub resolve_symlinks: bool,
+    /// Expand variables that no manifest scope defines, like `$CC`, from the
+    /// process environment.  Off by default, as in Ninja, so that builds
+    /// don't depend on the environment they happen to be run from.
+    pub process_env: bool,
+    /// Keep each build's bindings, and the top-level variables of its file,
+    /// so State::resolve() can evaluate its variables after loading.  This
+    /// costs memory, particularly in manifests with many top-level variables
+    /// that change between builds.
+    pub record_bindings: bool,
+    /// Reject manifests with explicit outputs outside the tree: every one
+    /// must be a relative path that doesn't start with "..".  Off by default,
+    /// as absolute outputs are sometimes legitimate.
+    pub confine_outputs: bool,
+    /// Expand `*` and `?` in `include` paths, an n2 extension: every file
+    /// matching the pattern, relative to the including file's directory, is
+    /// included in sorted order.  It's an error for nothing to match.
+    pub include_globs: bool,
+    /// Allow comments after statements and bindings; see
+    /// parse::Parser::allow_inline_comments().
+    pub inline_comments: bool,
+    /// Which categories of warning to fail loading on or to ignore, like
+    /// Ninja's `-w dupbuild=err`.  Other warnings are collected in
+    /// State::warnings.
+    pub warnings: log::WarningConfig,
+}
+
+/// Look up a `key = ...` binding of a build: one in its rule is evaluated
+/// against the build's implicit variables, its own bindings and its file's
+/// variables, and one in the build against just its file's.  See the eval
+/// module docs for how the env chain resolves variables.
+fn lookup_build_var<K, T>(
+    key: &str,
+    rule: &Rule,
+    implicit_vars: &BuildImplicitVars,
+    build_vars: &SmallMap<K, EvalString<T>>,
+    env: &dyn eval::Env,
+) -> Option<String>
+where
+    K: Borrow<str> + PartialEq,
+    T: AsRef<str>,
+    SmallMap<K, EvalString<T>>: eval::Env,
+{
+    Some(match rule.vars.get(key) {
+        Some(val) => val.evaluate(&[implicit_vars, build_vars, env]),
+        None => build_vars.get(key)?.evaluate(&[env]),
+    })
+}
+
+/// A file's top-level variables, the last scope a build's variables are
+/// looked up in, optionally followed by the process environment.
+struct FileEnv<'a> {
+    vars: &'a dyn eval::Env,
+    process_env: bool,
+}
+
+impl eval::Env for FileEnv<'_> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+        match self.vars.get_var(var) {
+            None if self.process_env => eval::ProcessEnv.get_var(var),
+            found => found,
+        }
+    }
+}
+
+/// A build's variable bindings, kept for State::resolve().
+struct Bindings {
+    /// The scope the build's rule was found in.
+    rule_scope: usize,
+    /// The build's own bindings, unevaluated.
+    vars: SmallMap<String, EvalString<String>>,
+    /// The top-level variables of the build's file as of the build, shared
+    /// with neighboring builds that saw the same ones.
+    globals: Rc<eval::OwnedVars>,
+}
+
+/// A rule as declared in the manifest.
+#[derive(Default)]
+struct Rule {
+    vars: SmallMap<String, eval::EvalString<String>>,
+    /// Where the rule was declared; None for the builtin phony rule.
+    location: Option<graph::FileLoc>,
+    /// Whether we've already warned about this rule lacking a command.
+    warned_missing_command: bool,
+}
+
+/// The top-level manifest or a subninja, along with the files it includes:
+/// the unit of the manifest that State::reload() reparses.
+#[derive(Default)]
+struct Scope {
+    /// The enclosing scope, whose rules are visible here unless shadowed.
+    /// None for the top level.
+    parent: Option<usize>,
+    /// The top-level manifest or subninja file itself; None for a manifest
+    /// read from stdin.
+    file: Option<FileId>,
+    /// Every manifest file read in this scope, `file` and its includes, and
+    /// the directories listed to expand include globs.
+    files: Vec<FileId>,
+    rules: HashMap<String, Rule>,
+    builds: Vec<BuildId>,
+    /// Targets of `default` statements, with where each was named.
+    default: Vec<(FileId, graph::FileLoc)>,
+    /// False once a reload has discarded the scope.
+    live: bool,
+}
+
 /// Internal state used while loading.
 #[derive(Default)]
 pub struct Loader {
     graph: graph::Graph,
-    default: Vec<FileId>,
-    /// rule name -> list of (key, val)
-    rules: HashMap<String, SmallMap<String, eval::EvalString<String>>>,
+    /// Every scope read so far, the top level first.
+    scopes: Vec<Scope>,
+    /// Index of the scope being read.
+    scope: usize,
     pools: SmallMap<String, usize>,
     builddir: Option<String>,
+    options: Options,
+    /// The build declaring each rspfile path, to catch two builds sharing one.
+    rspfiles: HashMap<PathBuf, graph::FileLoc>,
+    /// With Options::record_bindings, the bindings of each build.
+    bindings: HashMap<BuildId, Bindings>,
+    /// The top-level variables recorded for the last build, to share with
+    /// the next if they haven't changed.
+    globals: Option<Rc<eval::OwnedVars>>,
+    warnings: Vec<Warning>,
 }
 
 impl Loader {
     pub fn new() -> Self {
         let mut loader = Loader::default();
 
-        loader.rules.insert("phony".to_owned(), SmallMap::default());
+        let mut rules = HashMap::new();
+        rules.insert("phony".to_owned(), Rule::default());
+        loader.scopes.push(Scope {
+            rules,
+            live: true,
+            ..Scope::default()
+        });
 
         loader
     }
 
+    pub fn with_options(options: &Options) -> Self {
+        let mut loader = Loader::new();
+        if options.record_build_order {
+            loader.graph.record_declaration_order();
+        }
+        loader.options = options.clone();
+        loader
+    }
+
+    /// Record a warning, or fail if its category is to be an error.
+    fn warn(&mut self, warning: Warning) -> anyhow::Result<()> {
+        self.options.warnings.apply(warning, &mut self.warnings)
+    }
+
+    /// The innermost scope, starting from the current one, that defines a
+    /// rule.
+    fn rule_scope(&self, name: &str) -> Option<usize> {
+        let mut scope = Some(self.scope);
+        while let Some(index) = scope {
+            if self.scopes[index].rules.contains_key(name) {
+                return Some(index);
+            }
+            scope = self.scopes[index].parent;
+        }
+        None
+    }
+
     /// Convert a path string to a FileId.  For performance reasons
     /// this requires an owned 'path' param.
     fn path(&mut self, mut path: String) -> FileId {
+        if self.options.resolve_symlinks {
+            return self
+                .graph
+                .files
+                .id_from_canonical(canon_path_physical(path));
+        }
         // Perf: this is called while parsing build.ninja files.  We go to
         // some effort to avoid allocating in the common case of a path that
         // refers to a file that is already known.
@@ -97,6 +448,11 @@ impl Loader {
         env: &eval::Vars,
         b: parse::Build,
     ) -> anyhow::Result<()> {
+        let file_vars = env;
+        let env = &FileEnv {
+            vars: env,
+            process_env: self.options.process_env,
+        };
         let ins = graph::BuildIns {
             ids: self.evaluate_paths(b.ins, &[&b.vars, env]),
             explicit: b.explicit_ins,
@@ -117,36 +473,64 @@ impl Loader {
             outs,
         );
 
-        let rule = match self.rules.get(b.rule) {
-            Some(r) => r,
-            None => bail!("unknown rule {:?}", b.rule),
-        };
-
-        let implicit_vars = BuildImplicitVars {
-            graph: &self.graph,
-            build: &build,
+        let rule_scope = match self.rule_scope(b.rule) {
+            Some(scope) => scope,
+            None => {
+                return Err(LoadError::UnknownRule {
+                    name: b.rule.to_owned(),
+                    loc: (&build.location).into(),
+                }
+                .into())
+            }
         };
+        let rule = self.scopes[rule_scope].rules.get(b.rule).unwrap();
 
         // temp variable in order to not move all of b into the closure
         let build_vars = &b.vars;
-        let lookup = |key: &str| -> Option<String> {
-            // Look up `key = ...` binding in build and rule block.
-            Some(match rule.get(key) {
-                Some(val) => val.evaluate(&[&implicit_vars, build_vars, env]),
-                None => build_vars.get(key)?.evaluate(&[env]),
-            })
-        };
+        let implicit_vars = BuildImplicitVars::new(
+            &self.graph,
+            &build,
+            rule,
+            build_vars,
+            env,
+            self.options.in_cwd,
+        );
+        let lookup = |key: &str| lookup_build_var(key, rule, &implicit_vars, build_vars, env);
 
         let cmdline = lookup("command");
         let desc = lookup("description");
         let depfile = lookup("depfile");
+        let dyndep = lookup("dyndep").filter(|d| !d.is_empty());
         let parse_showincludes = match lookup("deps").as_deref() {
             None => false,
             Some("gcc") => false,
             Some("msvc") => true,
             Some(other) => bail!("invalid deps attribute {:?}", other),
         };
+        let msvc_deps_prefix = lookup("msvc_deps_prefix");
+        let generator = lookup("generator").is_some_and(|g| !g.is_empty());
         let pool = lookup("pool");
+        let weight = match lookup("weight") {
+            None => None,
+            Some(w) => match w.parse::<u32>() {
+                Ok(w) if w > 0 => Some(w),
+                _ => bail!("invalid weight {:?}, expected a positive integer", w),
+            },
+        };
+        let mem = match lookup("mem") {
+            None => None,
+            Some(m) => match m.parse::<u64>() {
+                Ok(m) => Some(m),
+                _ => bail!("invalid mem {:?}, expected a size in megabytes", m),
+            },
+        };
+        let timeout = match lookup("timeout") {
+            None => None,
+            Some(t) => match t.parse::<u64>() {
+                Ok(t) => Some(std::time::Duration::from_secs(t)),
+                _ => bail!("invalid timeout {:?}, expected a number of seconds", t),
+            },
+        };
 
         let rspfile_path = lookup("rspfile");
         let rspfile_content = lookup("rspfile_content");
@@ -159,58 +543,248 @@ impl Loader {
             _ => bail!("rspfile and rspfile_content need to be both specified"),
         };
 
+        if cmdline.is_none() {
+            self.warn_missing_command(b.rule)?;
+        }
+
+        let self_deps: Vec<FileId> = build
+            .outs()
+            .iter()
+            .copied()
+            .filter(|id| build.ins.ids.contains(id))
+            .collect();
+        if let Some(&id) = self_deps.first() {
+            if b.rule == "phony" {
+                self.warn(Warning {
+                    loc: build.location.clone(),
+                    category: Category::PhonyCycle,
+                    message: format!(
+                        "phony target {:?} names itself as an input; ignoring",
+                        self.graph.file(id).name
+                    ),
+                })?;
+            } else if !self.options.allow_output_as_input {
+                bail!(
+                    "{}: {:?} is both an input and an output of the same build",
+                    build.location,
+                    self.graph.file(id).name
+                );
+            }
+            build.ins.remove(&self_deps);
+        }
+
+        build.rule = b.rule.to_owned();
         build.cmdline = cmdline;
         build.desc = desc;
-        build.depfile = depfile;
+        if let Some(depfile) = depfile {
+            build.set_depfile(depfile);
+        }
         build.parse_showincludes = parse_showincludes;
+        build.msvc_deps_prefix = msvc_deps_prefix;
+        build.generator = generator;
+        if let Some(rspfile) = &rspfile {
+            if let Some(other) = self
+                .rspfiles
+                .insert(rspfile.path.clone(), build.location.clone())
+            {
+                self.warn(Warning {
+                    loc: build.location.clone(),
+                    category: Category::Rspfile,
+                    message: format!(
+                        "rspfile {} is also used by the build at {}; \
+                         if both run at once they'll overwrite each other's",
+                        rspfile.path.display(),
+                        other
+                    ),
+                })?;
+            }
+        }
         build.rspfile = rspfile;
         build.pool = pool;
+        if let Some(weight) = weight {
+            build.set_weight(weight);
+        }
+        if let Some(mem) = mem {
+            build.set_mem(mem);
+        }
+        if let Some(timeout) = timeout {
+            build.set_timeout(timeout);
+        }
+        if let Some(dyndep) = dyndep {
+            let id = self.path(dyndep);
+            if !build.ins.ids.contains(&id) {
+                bail!(
+                    "{}: dyndep file {} must be an input of the build",
+                    build.location,
+                    self.graph.file(id).name
+                );
+            }
+            build.dyndep = Some(id);
+        }
+
+        let id = self.graph.builds.next_id();
+        let warnings = self
+            .graph
+            .add_build(build)
+            .map_err(|dup| LoadError::DuplicateOutput {
+                file: dup.file,
+                loc: (&dup.loc).into(),
+                prev: (&dup.prev).into(),
+            })?;
+        for warning in warnings {
+            self.warn(warning)?;
+        }
+        self.scopes[self.scope].builds.push(id);
+        if self.options.record_bindings {
+            let globals = match &self.globals {
+                Some(globals) if globals.matches(file_vars) => globals.clone(),
+                _ => Rc::new(file_vars.to_owned()),
+            };
+            self.globals = Some(globals.clone());
+            let mut vars = SmallMap::default();
+            for (name, val) in b.vars.into_iter() {
+                vars.insert(name.to_owned(), val.into_owned());
+            }
+            self.bindings.insert(
+                id,
+                Bindings {
+                    rule_scope,
+                    vars,
+                    globals,
+                },
+            );
+        }
+        Ok(())
+    }
 
-        self.graph.add_build(build)
+    /// Warn (once per rule) about a build using a rule with no command, which
+    /// silently makes it behave like phony.
+    fn warn_missing_command(&mut self, name: &str) -> anyhow::Result<()> {
+        let rule = match self.rule_scope(name) {
+            Some(scope) => self.scopes[scope].rules.get_mut(name).unwrap(),
+            None => return Ok(()),
+        };
+        let location = match &rule.location {
+            Some(location) => location.clone(),
+            None => return Ok(()),
+        };
+        if rule.warned_missing_command {
+            return Ok(());
+        }
+        rule.warned_missing_command = true;
+        self.warn(Warning {
+            loc: location,
+            category: Category::NoCommand,
+            message: format!(
+                "rule {:?} has no command; builds using it act like phony",
+                name
+            ),
+        })
     }
 
     fn read_file(&mut self, id: FileId) -> anyhow::Result<()> {
+        self.scopes[self.scope].files.push(id);
         let path = self.graph.file(id).path().to_path_buf();
         let bytes = match trace::scope("read file", || scanner::read_file_with_nul(&path)) {
             Ok(b) => b,
-            Err(e) => bail!("read {}: {}", path.display(), e),
+            Err(error) => return Err(LoadError::Io { path, error }.into()),
         };
         self.parse(path, &bytes)
     }
 
     fn evaluate_and_read_file(
         &mut self,
+        including: &Path,
         file: EvalString<&str>,
         envs: &[&dyn eval::Env],
     ) -> anyhow::Result<()> {
-        let evaluated = self.evaluate_path(file, envs);
-        self.read_file(evaluated)
+        let path = file.evaluate(envs);
+        if self.options.include_globs && glob::is_glob(&path) {
+            let dir = including.parent().unwrap_or_else(|| Path::new(""));
+            let mut listed = Vec::new();
+            let matches = glob::expand(dir, &path, &mut listed)
+                .map_err(|err| anyhow!("include {}: {}", path, err))?;
+            // The directories count as manifest files, so that a new file
+            // matching the pattern brings a reload.
+            for dir in listed {
+                let id = self.path(dir.to_string_lossy().into_owned());
+                self.scopes[self.scope].files.push(id);
+            }
+            if matches.is_empty() {
+                bail!("{}: include {}: no files match", including.display(), path);
+            }
+            for file in matches {
+                let id = self.path(file.to_string_lossy().into_owned());
+                self.read_file(id)?;
+            }
+            return Ok(());
+        }
+        let id = self.path(path);
+        self.read_file(id)
+    }
+
+    /// Read a subninja into a new scope nested in the current one.
+    fn read_subninja(&mut self, id: FileId) -> anyhow::Result<()> {
+        self.scopes.push(Scope {
+            parent: Some(self.scope),
+            file: Some(id),
+            live: true,
+            ..Scope::default()
+        });
+        let parent = std::mem::replace(&mut self.scope, self.scopes.len() - 1);
+        let result = self.read_file(id);
+        self.scope = parent;
+        result
     }
 
     pub fn parse(&mut self, path: PathBuf, bytes: &[u8]) -> anyhow::Result<()> {
         let filename = std::rc::Rc::new(path);
 
-        let mut parser = parse::Parser::new(&bytes);
+        let overrides = self.options.var_overrides.clone();
+        let mut parser = parse::Parser::new(bytes);
+        if self.options.inline_comments {
+            parser.allow_inline_comments();
+        }
+        for (key, val) in &overrides {
+            parser.override_var(key, val.clone());
+        }
+        let mut errors = Vec::new();
 
         loop {
-            let stmt = match parser
-                .read()
-                .map_err(|err| anyhow!(parser.format_parse_error(&filename, err)))?
-            {
-                None => break,
-                Some(s) => s,
+            let stmt = match parser.read() {
+                Ok(None) => break,
+                Ok(Some(s)) => s,
+                Err(err) => {
+                    let msg = parser.format_parse_error(&filename, err.clone());
+                    if !self.options.collect_parse_errors {
+                        return Err(LoadError::Parse {
+                            error: err.with_filename(&filename),
+                            message: msg,
+                        }
+                        .into());
+                    }
+                    errors.push(msg);
+                    parser.recover();
+                    continue;
+                }
             };
             match stmt {
                 Statement::Include(id) => trace::scope("include", || {
-                    self.evaluate_and_read_file(id, &[&parser.vars])
+                    self.evaluate_and_read_file(&filename, id, &[&parser.vars])
                 })?,
-                // TODO: implement scoping for subninja
-                Statement::Subninja(id) => trace::scope("subninja", || {
-                    self.evaluate_and_read_file(id, &[&parser.vars])
+                Statement::Subninja(file) => trace::scope("subninja", || {
+                    let id = self.evaluate_path(file, &[&parser.vars]);
+                    self.read_subninja(id)
                 })?,
-                Statement::Default(defaults) => {
-                    let evaluated = self.evaluate_paths(defaults, &[&parser.vars]);
-                    self.default.extend(evaluated);
+                Statement::Default { line, targets } => {
+                    let loc = graph::FileLoc {
+                        filename: filename.clone(),
+                        line,
+                    };
+                    let evaluated = self.evaluate_paths(targets, &[&parser.vars]);
+                    self.scopes[self.scope]
+                        .default
+                        .extend(evaluated.into_iter().map(|id| (id, loc.clone())));
                 }
                 Statement::Rule(rule) => {
                     let mut vars: SmallMap<String, eval::EvalString<String>> = SmallMap::default();
@@ -220,14 +794,43 @@ impl Loader {
                         // memory.
                         vars.insert(name.to_owned(), val.into_owned());
                     }
-                    self.rules.insert(rule.name.to_owned(), vars);
+                    self.scopes[self.scope].rules.insert(
+                        rule.name.to_owned(),
+                        Rule {
+                            vars,
+                            location: Some(graph::FileLoc {
+                                filename: filename.clone(),
+                                line: rule.line,
+                            }),
+                            warned_missing_command: false,
+                        },
+                    );
                 }
                 Statement::Build(build) => self.add_build(filename.clone(), &parser.vars, build)?,
                 Statement::Pool(pool) => {
-                    self.pools.insert(pool.name.to_string(), pool.depth);
+                    if pool.depth == Some(0) {
+                        bail!(
+                            "{}:{}: pool {:?} has depth 0, so nothing could run in it",
+                            filename.display(),
+                            pool.line,
+                            pool.name
+                        );
+                    }
+                    // A pool without a depth is unbounded.
+                    self.pools
+                        .insert(pool.name.to_string(), pool.depth.unwrap_or(0));
                 }
             };
         }
+        if !errors.is_empty() {
+            bail!(
+                "{}{} parse error{} in {}",
+                errors.concat(),
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" },
+                filename.display()
+            );
+        }
         self.builddir = parser.vars.get("builddir").cloned();
         Ok(())
     }
@@ -236,50 +839,1655 @@ impl Loader {
 /// State loaded by read().
 pub struct State {
     pub graph: graph::Graph,
-    pub db: db::Writer,
+    pub db: Option<db::Writer>,
+    /// Present only when Options::ninja_log is set.
+    pub ninja_log: Option<ninja_log::Writer>,
     pub hashes: graph::Hashes,
     pub default: Vec<FileId>,
     pub pools: SmallMap<String, usize>,
+    /// Warnings found by read(), or by the last reload() for just the part
+    /// of the manifest it reparsed.
+    pub warnings: Vec<Warning>,
+    /// What reload() needs to reparse part of the manifest.
+    manifest: Manifest,
 }
 
+/// The parts of the Loader kept for reloading.
+struct Manifest {
+    scopes: Vec<Scope>,
+    options: Options,
+    rspfiles: HashMap<PathBuf, graph::FileLoc>,
+    bindings: HashMap<BuildId, Bindings>,
+}
+
+/// What State::reload() did.
+#[derive(Debug, PartialEq)]
+pub enum Reload {
+    /// None of the changed files are part of the manifest.
+    Unchanged,
+    /// The subninjas the changed files are part of were reparsed, leaving
+    /// the rest of the graph as it was.
+    Partial,
+    /// A file of the top-level scope changed, which may affect any build, so
+    /// the caller must read() the manifest again.
+    Full,
+}
+
+/// Whether a scope is the given one or nested within it.
+fn within(scopes: &[Scope], mut index: usize, ancestor: usize) -> bool {
+    loop {
+        if index == ancestor {
+            return true;
+        }
+        match scopes[index].parent {
+            Some(parent) => index = parent,
+            None => return false,
+        }
+    }
+}
+
+/// The targets of all `default` statements, scope by scope.
+fn default_targets(scopes: &[Scope]) -> Vec<FileId> {
+    scopes
+        .iter()
+        .filter(|scope| scope.live)
+        .flat_map(|scope| scope.default.iter().map(|&(id, _)| id))
+        .collect()
+}
+
+/// Fail if a `default` names a file that no build produces or reads, as
+/// that's most likely a typo that would otherwise quietly build nothing.
+fn check_defaults(graph: &graph::Graph, scopes: &[Scope]) -> anyhow::Result<()> {
+    let defaults = scopes
+        .iter()
+        .filter(|scope| scope.live)
+        .flat_map(|scope| scope.default.iter());
+    for (id, loc) in defaults {
+        let file = graph.file(*id);
+        if file.input.is_none() && file.dependents.is_empty() {
+            bail!("{}: default: unknown target {:?}", loc, file.name);
+        }
+    }
+    Ok(())
+}
+
+impl State {
+    /// The manifest files read to produce this State, the top-level manifest
+    /// and every include and subninja, to watch for changes to pass to
+    /// reload().  Directories listed to expand include globs are among them,
+    /// as their contents decide which files are included.
+    pub fn manifest_files(&self) -> Vec<FileId> {
+        self.manifest
+            .scopes
+            .iter()
+            .filter(|scope| scope.live)
+            .flat_map(|scope| scope.files.iter().copied())
+            .collect()
+    }
+
+    /// Bring the graph up to date with edits to the given manifest files,
+    /// for long-running embedders that would rather not reparse everything.
+    ///
+    /// The unit of reparsing is a scope: a subninja together with the files
+    /// it includes.  An edit to any of those files discards the builds,
+    /// rules and defaults of the scope and of the subninjas within it, and
+    /// rereads the subninja in place.  Edits to the top-level manifest or
+    /// its includes can affect every scope, so those return Reload::Full
+    /// without changing anything.
+    ///
+    /// Reparsed builds keep the hashes recorded for the builds they replace,
+    /// by output, so they only run if their command or inputs changed.  A
+    /// reparsed subninja sees all of its parent's rules, even those declared
+    /// after the `subninja` statement.
+    ///
+    /// On error the State is left partially reloaded and should be replaced
+    /// with a fresh read().
+    pub fn reload(&mut self, changed: &[FileId]) -> anyhow::Result<Reload> {
+        let scopes = &self.manifest.scopes;
+        let mut dirty = Vec::new();
+        for (index, scope) in scopes.iter().enumerate() {
+            if scope.live && scope.files.iter().any(|file| changed.contains(file)) {
+                if scope.parent.is_none() {
+                    return Ok(Reload::Full);
+                }
+                dirty.push(index);
+            }
+        }
+        if dirty.is_empty() {
+            return Ok(Reload::Unchanged);
+        }
+        self.warnings.clear();
+        // Rereading a subninja rereads the subninjas within it too.
+        let outermost: Vec<usize> = dirty
+            .iter()
+            .copied()
+            .filter(|&index| {
+                !dirty
+                    .iter()
+                    .any(|&other| other != index && within(scopes, index, other))
+            })
+            .collect();
+        for index in outermost {
+            self.reparse(index)?;
+        }
+        if let Err(cycle) = self.graph.detect_cycles() {
+            return Err(LoadError::cycle(&self.graph, &cycle).into());
+        }
+        self.default = default_targets(&self.manifest.scopes);
+        check_defaults(&self.graph, &self.manifest.scopes)?;
+        Ok(Reload::Partial)
+    }
+
+    /// Discard a subninja scope and those within it, and read it again.
+    fn reparse(&mut self, root: usize) -> anyhow::Result<()> {
+        let mut old_hashes = HashMap::new();
+        let manifest = &mut self.manifest;
+        for index in 0..manifest.scopes.len() {
+            if !manifest.scopes[index].live || !within(&manifest.scopes, index, root) {
+                continue;
+            }
+            let scope = &mut manifest.scopes[index];
+            for id in scope.builds.drain(..) {
+                manifest.bindings.remove(&id);
+                if let Some(&out) = self.graph.builds[id].outs().first() {
+                    let hashes = (
+                        self.hashes.get(id),
+                        self.hashes.get_content(id),
+                        self.hashes.get_time(id),
+                    );
+                    old_hashes.insert(out, hashes);
+                }
+                self.graph.remove_build(id);
+            }
+            for &file in &scope.files {
+                let path = self.graph.file(file).path();
+                manifest
+                    .rspfiles
+                    .retain(|_, loc| loc.filename.as_path() != path);
+            }
+            scope.files.clear();
+            scope.rules.clear();
+            scope.default.clear();
+            scope.live = index == root;
+        }
+
+        let first_new = manifest.scopes.len();
+        let mut loader = Loader {
+            graph: std::mem::take(&mut self.graph),
+            scopes: std::mem::take(&mut manifest.scopes),
+            scope: root,
+            pools: std::mem::take(&mut self.pools),
+            builddir: None,
+            options: manifest.options.clone(),
+            rspfiles: std::mem::take(&mut manifest.rspfiles),
+            bindings: std::mem::take(&mut manifest.bindings),
+            globals: None,
+            warnings: Vec::new(),
+        };
+        let file = loader.scopes[root].file.unwrap();
+        let result = trace::scope("loader.read_file", || loader.read_file(file));
+        self.graph = loader.graph;
+        self.pools = loader.pools;
+        manifest.scopes = loader.scopes;
+        manifest.rspfiles = loader.rspfiles;
+        manifest.bindings = loader.bindings;
+        self.warnings.extend(loader.warnings);
+        result?;
+        if manifest.options.confine_outputs {
+            check_outputs_confined(&self.graph)?;
+        }
+
+        let reparsed = std::iter::once(root).chain(first_new..manifest.scopes.len());
+        for index in reparsed {
+            for &id in &manifest.scopes[index].builds {
+                let out = match self.graph.builds[id].outs().first() {
+                    Some(out) => out,
+                    None => continue,
+                };
+                if let Some(&(hash, content, time)) = old_hashes.get(out) {
+                    if let Some(hash) = hash {
+                        self.hashes.set(id, hash);
+                    }
+                    self.hashes.set_content(id, content);
+                    self.hashes.set_time(id, time);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate a variable as the given build sees it, e.g. "what is
+    /// `$cflags` for this build?", with the same env chain the loader uses:
+    /// a binding in the build's rule is evaluated against the build's
+    /// implicit variables, its bindings and its file's top-level variables.
+    /// Otherwise the variable is looked up in the build's bindings, then its
+    /// implicit variables like `$in`, then its file's variables.
+    ///
+    /// Returns None if nothing defines the variable, or if the manifest
+    /// wasn't loaded with Options::record_bindings.
+    ///
+    /// ```
+    /// use n2::load::{read_from_str, Options};
+    ///
+    /// let options = Options {
+    ///     no_db: true,
+    ///     record_bindings: true,
+    ///     ..Options::default()
+    /// };
+    /// let manifest = "
+    /// cflags = -O2
+    /// rule cc
+    ///   command = cc $cflags -c $in -o $out
+    /// build a.o: cc a.c
+    ///   cflags = $cflags -g
+    /// build b.o: cc b.c
+    /// ";
+    /// let state = read_from_str("build.ninja", manifest, &options).unwrap();
+    /// let build = |out| {
+    ///     let id = state.graph.files.lookup(out).unwrap();
+    ///     state.graph.file(id).input.unwrap()
+    /// };
+    /// assert_eq!(state.resolve(build("a.o"), "cflags").unwrap(), "-O2 -g");
+    /// assert_eq!(state.resolve(build("b.o"), "cflags").unwrap(), "-O2");
+    /// assert_eq!(
+    ///     state.resolve(build("a.o"), "command").unwrap(),
+    ///     "cc -O2 -g -c a.c -o a.o"
+    /// );
+    /// assert_eq!(state.resolve(build("a.o"), "ldflags"), None);
+    /// ```
+    pub fn resolve(&self, id: BuildId, key: &str) -> Option<String> {
+        let bindings = self.manifest.bindings.get(&id)?;
+        let build = &self.graph.builds[id];
+        let rule = self.manifest.scopes[bindings.rule_scope]
+            .rules
+            .get(&build.rule)?;
+        let env = FileEnv {
+            vars: &*bindings.globals,
+            process_env: self.manifest.options.process_env,
+        };
+        let implicit_vars = BuildImplicitVars::new(
+            &self.graph,
+            build,
+            rule,
+            &bindings.vars,
+            &env,
+            self.manifest.options.in_cwd,
+        );
+        if let Some(val) = lookup_build_var(key, rule, &implicit_vars, &bindings.vars, &env) {
+            return Some(val);
+        }
+        let envs: [&dyn eval::Env; 2] = [&implicit_vars, &env];
+        let (i, val) = envs
+            .iter()
+            .enumerate()
+            .find_map(|(i, env)| Some((i, env.get_var(key)?)))?;
+        Some(val.evaluate(&envs[i + 1..]))
+    }
+
+    /// Serialize the loaded graph as JSON, for tools that want to analyze it
+    /// without parsing manifests themselves:
+    ///
+    /// ```text
+    /// {"files":[{"id":0,"name":"a.c"},...],
+    ///  "builds":[{"id":0,"rule":"cc","command":"cc a.c","location":"build.ninja:3",
+    ///             "ins":{"explicit":[0],"implicit":[],"order_only":[],"validation":[]},
+    ///             "outs":{"explicit":[1],"implicit":[]}},...],
+    ///  "pools":[{"name":"link","depth":2}]}
+    /// ```
+    ///
+    /// Files and builds refer to each other by id and are listed in id order,
+    /// and pools by name, so the output of an unchanged manifest is stable.
+    /// Phony builds have a null command.
+    ///
+    /// This is written with the json module's helpers, as n2's other JSON
+    /// output is, rather than with serde behind a feature.  The output is
+    /// flat enough not to need serde, and this way it's always available
+    /// without adding a dependency or deriving on the graph's types.
+    pub fn to_json(&self) -> String {
+        let graph = &self.graph;
+        let ids = |ids: &[FileId]| {
+            let ids: Vec<String> = ids.iter().map(|id| id.index().to_string()).collect();
+            format!("[{}]", ids.join(","))
+        };
+        let files: Vec<String> = graph
+            .files
+            .all_ids()
+            .map(|id| {
+                format!(
+                    "{{\"id\":{},\"name\":{}}}",
+                    id.index(),
+                    json::quote(&graph.file(id).name)
+                )
+            })
+            .collect();
+        let builds: Vec<String> = graph
+            .builds
+            .all_ids()
+            .map(|id| {
+                let build = &graph.builds[id];
+                let ins = &build.ins;
+                let implicit_end = ins.explicit + ins.implicit;
+                let order_only_end = implicit_end + ins.order_only;
+                format!(
+                    "{{\"id\":{},\"rule\":{},\"command\":{},\"location\":{},\
+                     \"ins\":{{\"explicit\":{},\"implicit\":{},\"order_only\":{},\"validation\":{}}},\
+                     \"outs\":{{\"explicit\":{},\"implicit\":{}}}}}",
+                    id.index(),
+                    json::quote(&build.rule),
+                    build.cmdline.as_deref().map_or("null".to_owned(), json::quote),
+                    json::quote(&build.location.to_string()),
+                    ids(build.explicit_ins()),
+                    ids(&ins.ids[ins.explicit..implicit_end]),
+                    ids(&ins.ids[implicit_end..order_only_end]),
+                    ids(build.validation_ins()),
+                    ids(build.explicit_outs()),
+                    ids(&build.outs()[build.explicit_outs().len()..]),
+                )
+            })
+            .collect();
+        let mut pools: Vec<(&String, &usize)> = self
+            .pools
+            .iter()
+            .map(|(name, depth)| (name, depth))
+            .collect();
+        pools.sort();
+        let pools: Vec<String> = pools
+            .into_iter()
+            .map(|(name, depth)| format!("{{\"name\":{},\"depth\":{}}}", json::quote(name), depth))
+            .collect();
+        format!(
+            "{{\"files\":[{}],\"builds\":[{}],\"pools\":[{}]}}",
+            files.join(","),
+            builds.join(","),
+            pools.join(",")
+        )
+    }
+}
+
+/// The build filename that means to read the manifest from stdin.
+pub const STDIN: &str = "-";
+
 /// Load build.ninja/.n2_db and return the loaded build graph and state.
-pub fn read(build_filename: &str) -> anyhow::Result<State> {
-    let mut loader = Loader::new();
+/// A build_filename of STDIN reads the manifest from stdin, in which case
+/// it's named "<stdin>" in errors and is never regenerated.
+pub fn read(build_filename: &str, options: &Options) -> anyhow::Result<State> {
+    let mut loader = Loader::with_options(options);
+    if build_filename == STDIN {
+        let mut bytes = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut bytes)
+            .map_err(|err| anyhow!("read stdin: {}", err))?;
+        bytes.push(0);
+        trace::scope("loader.parse", || {
+            loader.parse(PathBuf::from("<stdin>"), &bytes)
+        })?;
+        return finish(loader, options);
+    }
     trace::scope("loader.read_file", || {
         let id = loader
             .graph
             .files
             .id_from_canonical(canon_path(build_filename));
+        loader.scopes[0].file = Some(id);
         loader.read_file(id)
     })?;
-    let mut hashes = graph::Hashes::default();
-    let db = trace::scope("db::open", || {
-        let mut db_path = PathBuf::from(".n2_db");
-        if let Some(builddir) = &loader.builddir {
-            db_path = Path::new(&builddir).join(db_path);
-            if let Some(parent) = db_path.parent() {
-                std::fs::create_dir_all(parent)?;
+    finish(loader, options)
+}
+
+/// Like read(), but failing with a LoadError, for embedders that want to
+/// match on the kind of failure.
+pub fn read_typed(build_filename: &str, options: &Options) -> Result<State, LoadError> {
+    read(build_filename, options).map_err(LoadError::from)
+}
+
+/// Like read(), but with the contents of the top-level manifest provided as
+/// a string rather than read from disk.  Any included files and the .n2_db
+/// are still read from disk.
+pub fn read_from_str(name: &str, contents: &str, options: &Options) -> anyhow::Result<State> {
+    let mut loader = Loader::with_options(options);
+    let mut bytes = Vec::with_capacity(contents.len() + 1);
+    bytes.extend_from_slice(contents.as_bytes());
+    bytes.push(0);
+    trace::scope("loader.parse", || {
+        let name = canon_path(name);
+        let id = loader.graph.files.id_from_canonical(name.clone());
+        loader.scopes[0].file = Some(id);
+        loader.scopes[0].files.push(id);
+        loader.parse(PathBuf::from(name), &bytes)
+    })?;
+    finish(loader, options)
+}
+
+/// Whether a canonicalized path reaches outside the directory it's relative
+/// to, by being absolute or by starting with "..".
+fn outside_tree(path: &str) -> bool {
+    matches!(
+        Path::new(path).components().next(),
+        Some(Component::ParentDir | Component::RootDir | Component::Prefix(_))
+    )
+}
+
+/// For Options::confine_outputs, fail listing every explicit output that's
+/// outside the tree.
+fn check_outputs_confined(graph: &graph::Graph) -> anyhow::Result<()> {
+    let mut outside = Vec::new();
+    for id in graph.builds.all_ids() {
+        let build = &graph.builds[id];
+        for &out in build.explicit_outs() {
+            let name = &graph.file(out).name;
+            if outside_tree(name) {
+                outside.push(format!("{}: {:?}", build.location, name));
             }
-        };
-        db::open(&db_path, &mut loader.graph, &mut hashes)
-    })
-    .map_err(|err| anyhow!("load .n2_db: {}", err))?;
+        }
+    }
+    if !outside.is_empty() {
+        bail!(
+            "outputs outside the build tree:\n  {}",
+            outside.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Open the .n2_db and produce the final State from a loader that has read
+/// the manifest.
+fn finish(mut loader: Loader, options: &Options) -> anyhow::Result<State> {
+    if options.confine_outputs {
+        check_outputs_confined(&loader.graph)?;
+    }
+    trace::scope("detect_cycles", || -> anyhow::Result<()> {
+        if let Err(cycle) = loader.graph.detect_cycles() {
+            return Err(LoadError::cycle(&loader.graph, &cycle).into());
+        }
+        Ok(())
+    })?;
+    let default = default_targets(&loader.scopes);
+    check_defaults(&loader.graph, &loader.scopes)?;
+    let mut hashes = graph::Hashes::default();
+    let db = if options.no_db {
+        None
+    } else if options.read_only_db {
+        load_db(&mut loader, options, &mut hashes)?;
+        None
+    } else {
+        Some(open_db(&mut loader, options, &mut hashes)?)
+    };
+    let ninja_log = if options.ninja_log && !options.no_db && !options.read_only_db {
+        // open_db() has created the directory.
+        let path = db_path(&loader, options).with_file_name(".ninja_log");
+        let log = ninja_log::Writer::open(&path)
+            .map_err(|err| anyhow!("open {}: {}", path.display(), err))?;
+        Some(log)
+    } else {
+        None
+    };
+    // Only discovered deps may add files from here on.
+    loader.graph.files.shrink_to_fit();
+    loader.graph.builds.shrink_to_fit();
     Ok(State {
         graph: loader.graph,
         db,
+        ninja_log,
         hashes,
-        default: loader.default,
+        default,
         pools: loader.pools,
+        warnings: loader.warnings,
+        manifest: Manifest {
+            scopes: loader.scopes,
+            options: options.clone(),
+            rspfiles: loader.rspfiles,
+            bindings: loader.bindings,
+        },
     })
 }
 
+/// The directory n2 keeps its state in: the builddir, if any, else the
+/// current directory (as an empty path).
+fn state_dir(loader: &Loader, options: &Options) -> PathBuf {
+    match &options.builddir_override {
+        Some(dir) => dir.clone(),
+        None => loader
+            .builddir
+            .as_deref()
+            .map(PathBuf::from)
+            .unwrap_or_default(),
+    }
+}
+
+/// The path of the .n2_db, which the .ninja_log is kept alongside.
+fn db_path(loader: &Loader, options: &Options) -> PathBuf {
+    match &options.db_path {
+        Some(path) => path.clone(),
+        None => state_dir(loader, options).join(".n2_db"),
+    }
+}
+
+fn open_db(
+    loader: &mut Loader,
+    options: &Options,
+    hashes: &mut graph::Hashes,
+) -> anyhow::Result<db::Writer> {
+    trace::scope("db::open", || {
+        let path = db_path(loader, options);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        db::open(&path, &mut loader.graph, hashes)
+    })
+    .map_err(|err| anyhow!("load .n2_db: {}", err))
+}
+
+fn load_db(
+    loader: &mut Loader,
+    options: &Options,
+    hashes: &mut graph::Hashes,
+) -> anyhow::Result<()> {
+    trace::scope("db::load", || {
+        let path = db_path(loader, options);
+        db::load(&path, &mut loader.graph, hashes)
+    })
+    .map_err(|err| anyhow!("load .n2_db: {}", err))
+}
+
 /// Parse a single file's content.
 #[cfg(test)]
-pub fn parse(name: &str, mut content: Vec<u8>) -> anyhow::Result<graph::Graph> {
+pub fn parse(name: &str, content: Vec<u8>) -> anyhow::Result<graph::Graph> {
+    parse_with_options(name, content, &Options::default())
+}
+
+/// Parse a single file's content with the given load options.
+#[cfg(test)]
+pub fn parse_with_options(
+    name: &str,
+    mut content: Vec<u8>,
+    options: &Options,
+) -> anyhow::Result<graph::Graph> {
     content.push(0);
-    let mut loader = Loader::new();
+    let mut loader = Loader::with_options(options);
     trace::scope("loader.read_file", || {
         loader.parse(PathBuf::from(name), &content)
     })?;
+    if options.confine_outputs {
+        check_outputs_confined(&loader.graph)?;
+    }
     Ok(loader.graph)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_build_order() -> anyhow::Result<()> {
+        let file = "
+rule touch
+  command = touch $out
+build c: touch
+build a: touch
+build b: touch
+";
+        let options = Options {
+            record_build_order: true,
+            ..Options::default()
+        };
+        let graph = parse_with_options("build.ninja", file.as_bytes().to_vec(), &options)?;
+        let order: Vec<&str> = graph
+            .builds_in_declaration_order()
+            .map(|build| graph.file(build.outs()[0]).name.as_str())
+            .collect();
+        assert_eq!(order, vec!["c", "a", "b"]);
+        Ok(())
+    }
+
+    fn build_weights(file: &str) -> anyhow::Result<Vec<u32>> {
+        let graph = parse("build.ninja", file.as_bytes().to_vec())?;
+        Ok(graph
+            .builds
+            .all_ids()
+            .map(|id| graph.builds[id].weight())
+            .collect())
+    }
+
+    #[test]
+    fn weight() -> anyhow::Result<()> {
+        let weights = build_weights(
+            "
+rule link
+  command = link $out
+  weight = 4
+rule cc
+  command = cc $out
+build a: cc
+build b: link
+build c: cc
+  weight = 2
+",
+        )?;
+        assert_eq!(weights, vec![1, 4, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_weight() {
+        for weight in ["heavy", "0", "-1"] {
+            let file = format!(
+                "rule cc\n  command = cc\nbuild a: cc\n  weight = {}\n",
+                weight
+            );
+            let err = build_weights(&file).unwrap_err();
+            assert!(err.to_string().contains("invalid weight"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn mem() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            "
+rule link
+  command = link $out
+  mem = 2048
+rule cc
+  command = cc $out
+build a: link
+build b: cc
+  mem = 512
+build c: phony
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let mems: Vec<u64> = graph
+            .builds
+            .all_ids()
+            .map(|id| graph.builds[id].mem())
+            .collect();
+        assert_eq!(mems, vec![2048, 512, 0]);
+        let err = parse(
+            "build.ninja",
+            b"rule cc\n  command = cc\nbuild a: cc\n  mem = 2G\n".to_vec(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("invalid mem"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn timeout() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            "
+rule test
+  command = test $out
+  timeout = 60
+rule cc
+  command = cc $out
+build a: test
+build b: cc
+  timeout = 0
+build c: phony
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let timeouts: Vec<Option<u64>> = graph
+            .builds
+            .all_ids()
+            .map(|id| graph.builds[id].timeout().map(|t| t.as_secs()))
+            .collect();
+        assert_eq!(timeouts, vec![Some(60), Some(0), None]);
+        let err = parse(
+            "build.ninja",
+            b"rule cc\n  command = cc\nbuild a: cc\n  timeout = 1m\n".to_vec(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("invalid timeout"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn pool_depth() {
+        let err = parse("build.ninja", b"\npool slow\n  depth = 0\n".to_vec())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "build.ninja:2: pool \"slow\" has depth 0, so nothing could run in it"
+        );
+        let err = parse("build.ninja", b"pool slow\n  depth = -1\n".to_vec())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("must not be negative"), "{}", err);
+        assert!(parse("build.ninja", b"pool slow\n  depth = 2\n".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn collect_parse_errors() {
+        let file = "
+rule cc
+  command = cc $in
+build b c
+build a: cc
+pool p
+  bogus = 1
+build d: cc e
+x = $
+";
+        let options = Options {
+            collect_parse_errors: true,
+            ..Options::default()
+        };
+        let err = parse_with_options("build.ninja", file.as_bytes().to_vec(), &options)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("build.ninja:4:10: parse error: expected ':'"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("build.ninja:7:8: parse error: unexpected variable \"bogus\""),
+            "{}",
+            err
+        );
+        assert!(err.contains("unexpected EOF"), "{}", err);
+        assert!(err.ends_with("3 parse errors in build.ninja"), "{}", err);
+
+        // Without the option, only the first error is reported.
+        let err = parse("build.ninja", file.as_bytes().to_vec())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("expected ':'"), "{}", err);
+        assert!(!err.contains("bogus"), "{}", err);
+    }
+
+    /// Load a manifest from a string, keeping the .n2_db in a temp dir.
+    fn read_str(dir: &Path, contents: &str) -> anyhow::Result<State> {
+        let contents = format!("builddir = {}\n{}", dir.display(), contents);
+        read_from_str("build.ninja", &contents, &Options::default())
+    }
+
+    #[test]
+    fn read_from_str_builds() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let state = read_str(
+            dir.path(),
+            "
+rule cc
+  command = cc $in -o $out
+build a.o: cc a.c
+build b.o: cc b.c
+default a.o
+",
+        )?;
+        assert!(dir.path().join(".n2_db").exists());
+        let a = state.graph.files.lookup("a.o").unwrap();
+        assert_eq!(state.default, vec![a]);
+        let build = &state.graph.builds[state.graph.file(a).input.unwrap()];
+        assert_eq!(build.cmdline.as_deref(), Some("cc a.c -o a.o"));
+        assert_eq!(build.location.to_string(), "build.ninja:5");
+        assert!(state.graph.files.lookup("build.ninja").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn warnings() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = "
+rule touch
+  command = touch $out
+rule nothing
+build dup dup: touch
+build a: nothing
+";
+        let state = read_str(dir.path(), file)?;
+        let warnings: Vec<(Category, String)> = state
+            .warnings
+            .iter()
+            .map(|w| (w.category, w.to_string()))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                (
+                    Category::DupBuild,
+                    "build.ninja:6: \"dup\" is repeated in output list".to_owned()
+                ),
+                (
+                    Category::NoCommand,
+                    "build.ninja:5: rule \"nothing\" has no command; builds using it act like phony"
+                        .to_owned()
+                ),
+            ]
+        );
+
+        let contents = format!("builddir = {}\n{}", dir.path().display(), file);
+        let mut options = Options::default();
+        options.warnings.set(Category::DupBuild, log::Action::Err);
+        let err = read_from_str("build.ninja", &contents, &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "build.ninja:6: \"dup\" is repeated in output list [-w dupbuild=err]"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn phony_cycle() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = "build all: phony all a\n";
+        let state = read_str(dir.path(), file)?;
+        let all = state.graph.files.lookup("all").unwrap();
+        let build = &state.graph.builds[state.graph.file(all).input.unwrap()];
+        assert_eq!(build.ins.ids, vec![state.graph.files.lookup("a").unwrap()]);
+        assert_eq!(
+            state.warnings[0].to_string(),
+            "build.ninja:2: phony target \"all\" names itself as an input; ignoring"
+        );
+
+        let contents = format!("builddir = {}\n{}", dir.path().display(), file);
+        let mut options = Options::default();
+        options.warnings.parse_flag("phonycycle=off")?;
+        let state = read_from_str("build.ninja", &contents, &options)?;
+        assert!(state.warnings.is_empty());
+        options.warnings.parse_flag("phonycycle=err")?;
+        assert!(read_from_str("build.ninja", &contents, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn default_unknown_target() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let err = read_str(
+            dir.path(),
+            "
+rule touch
+  command = touch $out
+build out: touch in
+default out in does_not_exist
+",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "build.ninja:6: default: unknown target \"does_not_exist\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_evaluated_paths() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let state = read_str(
+            dir.path(),
+            "
+rule touch
+  command = touch $out
+build foo bar out/baz: touch
+baz = out/./baz
+default foo ./bar $baz
+",
+        )?;
+        let lookup = |name| state.graph.files.lookup(name).unwrap();
+        assert_eq!(
+            state.default,
+            vec![lookup("foo"), lookup("bar"), lookup("out/baz")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_from_str_include() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rules = dir.path().join("rules.ninja");
+        std::fs::write(&rules, "rule cc\n  command = cc $in\n")?;
+        let state = read_str(
+            dir.path(),
+            &format!("include {}\nbuild a.o: cc a.c\n", rules.display()),
+        )?;
+        assert!(state.graph.files.lookup("a.o").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn subninja_rule_scope() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sub = dir.path().join("sub.ninja");
+        let nested = dir.path().join("nested.ninja");
+        std::fs::write(
+            &sub,
+            format!(
+                "rule cc\n  command = sub-cc $in\nrule only_sub\n  command = x\n\
+                 build sub.o: cc sub.c\nbuild sub: link sub.o\nsubninja {}\n",
+                nested.display()
+            ),
+        )?;
+        std::fs::write(&nested, "build nested.o: cc nested.c\n")?;
+        let state = read_str(
+            dir.path(),
+            &format!(
+                "rule cc\n  command = top-cc $in\nrule link\n  command = link $in\n\
+                 subninja {}\nbuild top.o: cc top.c\n",
+                sub.display()
+            ),
+        )?;
+        let cmdline = |out: &str| state.graph.build_for_output(out).unwrap().cmdline.clone();
+        // A subninja's rule shadows its parent's, for it and its own subninjas.
+        assert_eq!(cmdline("sub.o").as_deref(), Some("sub-cc sub.c"));
+        assert_eq!(cmdline("nested.o").as_deref(), Some("sub-cc nested.c"));
+        // Parent rules are visible to the subninja...
+        assert_eq!(cmdline("sub").as_deref(), Some("link sub.o"));
+        // ...but its rules don't leak back out.
+        assert_eq!(cmdline("top.o").as_deref(), Some("top-cc top.c"));
+        let err = read_str(
+            dir.path(),
+            &format!(
+                "rule link\n  command = link $in\nsubninja {}\nbuild x: only_sub\n",
+                sub.display()
+            ),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("unknown rule"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn include_globs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        std::fs::create_dir(path("gen"))?;
+        std::fs::write(path("gen/b.ninja"), "build b: cc\n")?;
+        std::fs::write(path("gen/a.ninja"), "build a: cc\n")?;
+        std::fs::write(path("sub.ninja"), "include gen/*.ninja\n")?;
+        let manifest = format!(
+            "rule cc\n  command = cc\nsubninja {}\n",
+            path("sub.ninja").display()
+        );
+        let contents = format!("builddir = {}\n{}", dir.path().display(), manifest);
+        let options = Options {
+            include_globs: true,
+            ..Options::default()
+        };
+        let mut state = read_from_str("build.ninja", &contents, &options)?;
+        let outs: Vec<&str> = state
+            .graph
+            .builds
+            .all_ids()
+            .map(|id| {
+                state
+                    .graph
+                    .file(state.graph.builds[id].outs()[0])
+                    .name
+                    .as_str()
+            })
+            .collect();
+        assert_eq!(outs, vec!["a", "b"]);
+
+        // A new file matching the pattern changes the directory, which is
+        // watched like the manifests.
+        let gen = state
+            .graph
+            .files
+            .lookup(&path("gen").display().to_string())
+            .unwrap();
+        assert!(state.manifest_files().contains(&gen));
+        std::fs::write(path("gen/c.ninja"), "build c: cc\n")?;
+        assert!(matches!(state.reload(&[gen])?, Reload::Partial));
+        let c = state.graph.files.lookup("c").unwrap();
+        assert!(state.graph.file(c).input.is_some());
+
+        // Without the option, the pattern is taken as a file name.
+        let err = read_str(dir.path(), &manifest).err().unwrap();
+        assert!(err.downcast_ref::<LoadError>().is_some(), "{}", err);
+
+        std::fs::write(path("sub.ninja"), "include gen/*.txt\n")?;
+        let err = read_from_str("build.ninja", &contents, &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}: include gen/*.txt: no files match",
+                path("sub.ninja").display()
+            )
+        );
+        Ok(())
+    }
+
+    /// BuildIds follow the order builds are read in, so the same manifest
+    /// always gets the same ids.
+    #[test]
+    fn build_ids_in_source_order() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).display().to_string();
+        std::fs::write(
+            path("sub.ninja"),
+            format!(
+                "build sub1: cc\ninclude {}\nbuild sub2: cc\n",
+                path("inc.ninja")
+            ),
+        )?;
+        std::fs::write(path("inc.ninja"), "build inc: cc\n")?;
+        let manifest = format!(
+            "rule cc\n  command = cc\nbuild top1: cc\nsubninja {}\nbuild top2: cc\n",
+            path("sub.ninja")
+        );
+        let ids = || -> anyhow::Result<Vec<(usize, String)>> {
+            let state = read_str(dir.path(), &manifest)?;
+            let graph = &state.graph;
+            Ok(graph
+                .builds
+                .all_ids()
+                .map(|id| {
+                    (
+                        id.index(),
+                        graph.file(graph.builds[id].outs()[0]).name.clone(),
+                    )
+                })
+                .collect())
+        };
+        let first = ids()?;
+        let names: Vec<&str> = first.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, vec!["top1", "sub1", "inc", "sub2", "top2"]);
+        assert_eq!(ids()?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn reload_subninja() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).display().to_string();
+        std::fs::write(
+            path("a.ninja"),
+            format!("include {}\nbuild a.o: cc a.c\n", path("inc.ninja")),
+        )?;
+        std::fs::write(path("inc.ninja"), "build inc.o: cc inc.c\n")?;
+        std::fs::write(path("b.ninja"), "build b.o: cc b.c\n")?;
+        let mut state = read_str(
+            dir.path(),
+            &format!(
+                "rule cc\n  command = cc $in\nsubninja {}\nsubninja {}\n",
+                path("a.ninja"),
+                path("b.ninja")
+            ),
+        )?;
+        let file = |state: &State, name: &str| state.graph.files.lookup(&path(name)).unwrap();
+        let build =
+            |state: &State, out: &str| state.graph.file(state.graph.files.lookup(out)?).input;
+        let top = state.graph.files.lookup("build.ninja").unwrap();
+        let mut expected = vec![top];
+        expected.extend(["a.ninja", "inc.ninja", "b.ninja"].map(|m| file(&state, m)));
+        assert_eq!(state.manifest_files(), expected);
+
+        let a = build(&state, "a.o").unwrap();
+        let b = build(&state, "b.o").unwrap();
+        state.hashes.set(a, crate::hash::BuildHash(7));
+
+        // Editing a subninja rereads just it.
+        std::fs::write(
+            path("a.ninja"),
+            format!(
+                "include {}\nbuild a.o: cc a.c\nbuild a2.o: cc a2.c\n",
+                path("inc.ninja")
+            ),
+        )?;
+        let changed = file(&state, "a.ninja");
+        assert_eq!(state.reload(&[changed])?, Reload::Partial);
+        let new_a = build(&state, "a.o").unwrap();
+        assert_ne!(new_a, a);
+        assert_eq!(state.hashes.get(new_a), Some(crate::hash::BuildHash(7)));
+        assert!(build(&state, "a2.o").is_some());
+        assert!(build(&state, "inc.o").is_some());
+        assert_eq!(build(&state, "b.o"), Some(b));
+
+        // Editing an included file rereads the subninja including it.
+        std::fs::write(path("inc.ninja"), "build inc2.o: cc inc.c\n")?;
+        let changed = file(&state, "inc.ninja");
+        assert_eq!(state.reload(&[changed])?, Reload::Partial);
+        assert_eq!(build(&state, "inc.o"), None);
+        assert!(build(&state, "inc2.o").is_some());
+        assert_eq!(build(&state, "b.o"), Some(b));
+
+        assert_eq!(state.reload(&[top])?, Reload::Full);
+        let source = state.graph.files.lookup("a.c").unwrap();
+        assert_eq!(state.reload(&[source])?, Reload::Unchanged);
+        Ok(())
+    }
+
+    #[test]
+    fn typed_errors() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let error = |manifest: &str| -> LoadError {
+            let err = read_str(dir.path(), manifest).err().unwrap();
+            match err.downcast::<LoadError>() {
+                Ok(err) => err,
+                Err(err) => panic!("not a LoadError: {}", err),
+            }
+        };
+        let loc = |line| Location {
+            file: PathBuf::from("build.ninja"),
+            line,
+        };
+
+        match error("x\n") {
+            LoadError::Parse { error, message } => {
+                assert_eq!((error.line, error.col), (2, 2));
+                assert_eq!(error.filename, Some(PathBuf::from("build.ninja")));
+                assert!(message.starts_with("build.ninja:2:2: parse error: "));
+            }
+            err => panic!("{}", err),
+        }
+        match error("build a: cc\n") {
+            LoadError::UnknownRule { name, loc: l } => {
+                assert_eq!((name.as_str(), l), ("cc", loc(2)))
+            }
+            err => panic!("{}", err),
+        }
+        match error("build a: phony\nbuild a: phony\n") {
+            LoadError::DuplicateOutput { file, loc: l, prev } => {
+                assert_eq!((file.as_str(), l, prev), ("a", loc(3), loc(2)))
+            }
+            err => panic!("{}", err),
+        }
+        match error("rule r\n  command = r\nbuild a: r b\nbuild b: r a\n") {
+            LoadError::Cycle { files } => assert_eq!(files, vec!["a", "b", "a"]),
+            err => panic!("{}", err),
+        }
+        let missing = dir.path().join("missing.ninja");
+        match error(&format!("include {}\n", missing.display())) {
+            LoadError::Io { path, error } => {
+                assert_eq!(path, missing);
+                assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+            }
+            err => panic!("{}", err),
+        }
+
+        // read_typed() gives the same without downcasting, along with any
+        // other failure as a message.
+        let typed = |manifest: &str| -> LoadError {
+            let path = dir.path().join("build.ninja");
+            let manifest = format!("builddir = {}\n{}", dir.path().display(), manifest);
+            std::fs::write(&path, manifest).unwrap();
+            read_typed(&path.display().to_string(), &Options::default())
+                .err()
+                .unwrap()
+        };
+        match typed("build a: phony b\nbuild b: phony a\n") {
+            LoadError::Cycle { files } => assert_eq!(files, vec!["a", "b", "a"]),
+            err => panic!("{}", err),
+        }
+        match typed("default nope\n") {
+            LoadError::Other(message) => assert_eq!(
+                message,
+                format!(
+                    "{}:2: default: unknown target \"nope\"",
+                    dir.path().join("build.ninja").display()
+                )
+            ),
+            err => panic!("{}", err),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_from_str_errors() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let err = read_str(dir.path(), "build a.o: cc a.c\n").err().unwrap();
+        assert!(err.to_string().contains("unknown rule"), "{}", err);
+        let err = read_str(dir.path(), "build a: phony b\nbuild b: phony a\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "dependency cycle: a -> b -> a");
+        let err = read_str(dir.path(), "build a.o cc\n").err().unwrap();
+        assert!(
+            err.to_string().starts_with("build.ninja:2:13: parse error"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn builddir_override() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let manifest_dir = dir.path().join("manifest");
+        let override_dir = dir.path().join("override");
+        let options = Options {
+            builddir_override: Some(override_dir.clone()),
+            ..Options::default()
+        };
+        let contents = format!("builddir = {}\n", manifest_dir.display());
+        read_from_str("build.ninja", &contents, &options)?;
+        assert!(override_dir.join(".n2_db").exists());
+        assert!(!manifest_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn to_json() -> anyhow::Result<()> {
+        let options = Options {
+            no_db: true,
+            ..Options::default()
+        };
+        let state = read_from_str(
+            "build.ninja",
+            "pool link
+  depth = 2
+rule cc
+  command = cc $in
+build a.o | a.d: cc a.c | a.h || gen |@ lint
+build all: phony a.o
+",
+            &options,
+        )?;
+        assert_eq!(
+            state.to_json(),
+            r#"{"files":[{"id":0,"name":"build.ninja"},{"id":1,"name":"a.c"},{"id":2,"name":"a.h"},{"id":3,"name":"gen"},{"id":4,"name":"lint"},{"id":5,"name":"a.o"},{"id":6,"name":"a.d"},{"id":7,"name":"all"}],"#.to_owned()
+                + r#""builds":[{"id":0,"rule":"cc","command":"cc a.c","location":"build.ninja:5","ins":{"explicit":[1],"implicit":[2],"order_only":[3],"validation":[4]},"outs":{"explicit":[5],"implicit":[6]}},"#
+                + r#"{"id":1,"rule":"phony","command":null,"location":"build.ninja:6","ins":{"explicit":[5],"implicit":[],"order_only":[],"validation":[]},"outs":{"explicit":[7],"implicit":[]}}],"#
+                + r#""pools":[{"name":"link","depth":2}]}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn db_path() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let manifest_dir = dir.path().join("manifest");
+        let override_dir = dir.path().join("override");
+        let db_path = dir.path().join("configs/debug.n2_db");
+        let options = Options {
+            builddir_override: Some(override_dir.clone()),
+            db_path: Some(db_path.clone()),
+            ninja_log: true,
+            ..Options::default()
+        };
+        let contents = format!("builddir = {}\n", manifest_dir.display());
+        read_from_str("build.ninja", &contents, &options)?;
+        assert!(db_path.exists());
+        assert!(dir.path().join("configs/.ninja_log").exists());
+        assert!(!override_dir.join(".n2_db").exists());
+        assert!(!manifest_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn depfile_path() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            "
+rule cc
+  command = cc $in -o $out
+  depfile = ./obj/../$out.d
+rule gen
+  command = gen $out
+build a.o: cc a.c
+build b.o: gen
+  depfile =
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let build = |name: &str| {
+            &graph.builds[graph.file(graph.files.lookup(name).unwrap()).input.unwrap()]
+        };
+        assert_eq!(build("a.o").depfile_path(), Some(PathBuf::from("a.o.d")));
+        // An empty depfile binding means no depfile.
+        assert_eq!(build("b.o").depfile_path(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_output_locations() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let other = dir.path().join("other.ninja");
+        std::fs::write(&other, "\n\nbuild foo.o: cc foo.c\n")?;
+        let err = read_str(
+            dir.path(),
+            &format!(
+                "rule cc\n  command = cc\ninclude {}\nbuild foo.o: cc bar.c\n",
+                other.display()
+            ),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "build.ninja:5: \"foo.o\" is already an output at {}:3",
+                other.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn output_as_input() -> anyhow::Result<()> {
+        let file = "
+rule sed
+  command = sed -i s/a/b/ $in
+build foo: sed foo | bar
+";
+        let err = parse("build.ninja", file.as_bytes().to_vec())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "build.ninja:4: \"foo\" is both an input and an output of the same build"
+        );
+
+        let options = Options {
+            allow_output_as_input: true,
+            ..Options::default()
+        };
+        let graph = parse_with_options("build.ninja", file.as_bytes().to_vec(), &options)?;
+        let foo = graph.files.lookup("foo").unwrap();
+        let build = &graph.builds[graph.file(foo).input.unwrap()];
+        assert_eq!(build.cmdline.as_deref(), Some("sed -i s/a/b/ foo"));
+        assert_eq!(build.dirtying_ins(), &[graph.files.lookup("bar").unwrap()]);
+        assert_eq!(graph.detect_cycles(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn validation_ins() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            b"rule cc\n  command = cc\nbuild out: cc in | dep |@ check\n".to_vec(),
+        )?;
+        let id = |name: &str| graph.files.lookup(name).unwrap();
+        let build = graph.build_for_output("out").unwrap();
+        assert_eq!(build.dirtying_ins(), &[id("in"), id("dep")]);
+        assert_eq!(build.validation_ins(), &[id("check")]);
+        Ok(())
+    }
+
+    #[test]
+    fn no_db() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = Options {
+            no_db: true,
+            ..Options::default()
+        };
+        let contents = format!("builddir = {}\nbuild a: phony\n", dir.path().display());
+        let state = read_from_str("build.ninja", &contents, &options)?;
+        assert!(state.db.is_none());
+        assert!(!dir.path().join(".n2_db").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_db() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let contents = format!("builddir = {}\nbuild a: phony\n", dir.path().display());
+        let options = Options {
+            read_only_db: true,
+            ..Options::default()
+        };
+        let state = read_from_str("build.ninja", &contents, &options)?;
+        assert!(state.db.is_none());
+        assert!(!dir.path().join(".n2_db").exists());
+
+        // A corrupt tail is left in place.
+        read_from_str("build.ninja", &contents, &Options::default())?;
+        let path = dir.path().join(".n2_db");
+        let mut bytes = std::fs::read(&path)?;
+        bytes.extend_from_slice(b"\xff\xff");
+        std::fs::write(&path, &bytes)?;
+        read_from_str("build.ninja", &contents, &options)?;
+        assert_eq!(std::fs::read(&path)?, bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn generator_not_cleanable() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            "
+rule regen
+  command = cmake .
+  generator = 1
+rule cc
+  command = cc $in -o $out
+build build.ninja: regen CMakeLists.txt
+build a.o: cc a.c
+build all: phony a.o
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let manifest = graph.files.lookup("build.ninja").unwrap();
+        assert!(graph.builds[graph.file(manifest).input.unwrap()].generator);
+        assert_eq!(
+            graph.cleanable_outputs(),
+            vec![graph.files.lookup("a.o").unwrap()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn var_overrides() -> anyhow::Result<()> {
+        let file = "
+cflags = -O0
+rule cc
+  command = cc $cflags $extra $in
+build a.o: cc a.c
+build b.o: cc b.c
+  cflags = -g
+";
+        let options = Options {
+            var_overrides: HashMap::from([
+                ("cflags".to_owned(), "-O2".to_owned()),
+                ("extra".to_owned(), "-Wall".to_owned()),
+            ]),
+            ..Options::default()
+        };
+        let graph = parse_with_options("build.ninja", file.as_bytes().to_vec(), &options)?;
+        let cmdline = |name: &str| {
+            let id = graph.files.lookup(name).unwrap();
+            graph.builds[graph.file(id).input.unwrap()].cmdline.clone()
+        };
+        // The override wins over the top-level assignment...
+        assert_eq!(cmdline("a.o").as_deref(), Some("cc -O2 -Wall a.c"));
+        // ...but not over a build-level binding.
+        assert_eq!(cmdline("b.o").as_deref(), Some("cc -g -Wall b.c"));
+        Ok(())
+    }
+
+    #[test]
+    fn in_sep() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            "
+rule join
+  command = tool --srcs=$in -o $out
+  in_sep = ,
+rule cc
+  command = cc $in
+build out: join a b | c
+build single: join a
+build a.o: cc a b
+build b.o: cc a b
+  in_sep = ;
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let cmdline = |out: &str| graph.build_for_output(out).unwrap().cmdline.clone();
+        assert_eq!(cmdline("out").as_deref(), Some("tool --srcs=a,b -o out"));
+        assert_eq!(
+            cmdline("single").as_deref(),
+            Some("tool --srcs=a -o single")
+        );
+        assert_eq!(cmdline("a.o").as_deref(), Some("cc a b"));
+        assert_eq!(cmdline("b.o").as_deref(), Some("cc a;b"));
+        Ok(())
+    }
+
+    #[test]
+    fn outstem() -> anyhow::Result<()> {
+        let graph = parse(
+            "build.ninja",
+            "
+rule gen
+  command = gen --out $out --dep $outstem.d
+build obj/a.pb.o: gen a.proto
+build obj/b obj/b.h: gen b.proto
+build .config | config.stamp: gen
+build stamp: gen || .config
+  outstem = mine
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let cmdline = |out: &str| graph.build_for_output(out).unwrap().cmdline.clone();
+        assert_eq!(
+            cmdline("obj/a.pb.o").as_deref(),
+            Some("gen --out obj/a.pb.o --dep obj/a.pb.d")
+        );
+        // Only the first output counts, and only its file name loses a dot.
+        assert_eq!(
+            cmdline("obj/b.h").as_deref(),
+            Some("gen --out obj/b obj/b.h --dep obj/b.d")
+        );
+        assert_eq!(
+            cmdline("config.stamp").as_deref(),
+            Some("gen --out .config --dep .config.d")
+        );
+        // Like $out, it isn't a binding the manifest can override.
+        assert_eq!(
+            cmdline("stamp").as_deref(),
+            Some("gen --out stamp --dep stamp.d")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn in_cwd() -> anyhow::Result<()> {
+        let file = "
+rule sub
+  command = cd sub && tool $in -o $out
+  in_cwd = sub
+build sub/out.txt: sub sub/in.txt sub/dir/x.txt top.txt
+";
+        let options = Options {
+            in_cwd: true,
+            ..Options::default()
+        };
+        let cmdline = |options: &Options| -> anyhow::Result<String> {
+            let graph = parse_with_options("build.ninja", file.as_bytes().to_vec(), options)?;
+            let out = graph.files.lookup("sub/out.txt").unwrap();
+            Ok(graph.builds[graph.file(out).input.unwrap()]
+                .cmdline
+                .clone()
+                .unwrap())
+        };
+        assert_eq!(
+            cmdline(&options)?,
+            "cd sub && tool in.txt dir/x.txt ../top.txt -o out.txt"
+        );
+        // Without the compat flag, the attribute has no effect.
+        assert_eq!(
+            cmdline(&Options::default())?,
+            "cd sub && tool sub/in.txt sub/dir/x.txt top.txt -o sub/out.txt"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn confine_outputs() -> anyhow::Result<()> {
+        let file = "
+rule touch
+  command = touch $out
+build ../outside/foo.o: touch
+build sub/../inside.o | sub/../../implicit.o: touch
+build /abs/bar.o: touch
+";
+        // Allowed by default.
+        parse("build.ninja", file.as_bytes().to_vec())?;
+
+        let options = Options {
+            confine_outputs: true,
+            ..Options::default()
+        };
+        let err = parse_with_options("build.ninja", file.as_bytes().to_vec(), &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "outputs outside the build tree:
+  build.ninja:4: \"../outside/foo.o\"
+  build.ninja:6: \"/abs/bar.o\""
+        );
+
+        let file = "
+rule touch
+  command = touch $out
+build out/foo.o sub/../bar.o: touch
+";
+        parse_with_options("build.ninja", file.as_bytes().to_vec(), &options)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_positional() -> anyhow::Result<()> {
+        let options = Options {
+            no_db: true,
+            record_bindings: true,
+            ..Options::default()
+        };
+        let state = read_from_str(
+            "build.ninja",
+            "
+flags = -a
+rule cc
+  command = cc $flags $in
+build a.o: cc a.c
+build b.o: cc b.c
+flags = -b
+build c.o: cc c.c
+",
+            &options,
+        )?;
+        let resolve = |out: &str| {
+            let id = state.graph.files.lookup(out).unwrap();
+            state.resolve(state.graph.file(id).input.unwrap(), "flags")
+        };
+        // Each build sees the top-level variables as they stood at it.
+        assert_eq!(resolve("a.o").unwrap(), "-a");
+        assert_eq!(resolve("c.o").unwrap(), "-b");
+        let globals = |out: &str| {
+            let id = state.graph.files.lookup(out).unwrap();
+            state.manifest.bindings[&state.graph.file(id).input.unwrap()]
+                .globals
+                .clone()
+        };
+        assert!(Rc::ptr_eq(&globals("a.o"), &globals("b.o")));
+
+        // Nothing is kept without the option.
+        let state = read_from_str(
+            "build.ninja",
+            "rule cc\n  command = cc\nbuild a.o: cc\n",
+            &Options {
+                no_db: true,
+                ..Options::default()
+            },
+        )?;
+        let id = state.graph.files.lookup("a.o").unwrap();
+        assert_eq!(
+            state.resolve(state.graph.file(id).input.unwrap(), "command"),
+            None
+        );
+        Ok(())
+    }
+}
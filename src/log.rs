@@ -0,0 +1,192 @@
+//! n2's own messages, as opposed to the output of the commands it runs.
+//!
+//! With --quiet, only failures are printed: warnings and status chatter like
+//! the progress display and the end-of-build summary are suppressed.  Errors
+//! that stop n2 are still reported by the caller of run().
+
+use crate::graph::FileLoc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether to hold back everything but failures.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Print a "n2: warn:" message, unless --quiet.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if !$crate::log::quiet() {
+            println!("n2: warn: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+/// The kinds of warning found while loading a manifest or building.  Each can
+/// be made an error or silenced instead, as with Ninja's `-w dupbuild=err`; see
+/// WarningConfig.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// An output listed twice by the same build.
+    DupBuild,
+    /// Two builds writing the same rspfile.
+    Rspfile,
+    /// A rule without a command, so that its builds act like phony.
+    NoCommand,
+    /// A phony build listing its own output as an input, as old CMake
+    /// generated.  The self-reference is dropped.
+    PhonyCycle,
+    /// A depfile naming a target that isn't an output of its build.
+    DepfileTarget,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::DupBuild,
+        Category::Rspfile,
+        Category::NoCommand,
+        Category::PhonyCycle,
+        Category::DepfileTarget,
+    ];
+
+    /// The name used for the category by -w.
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::DupBuild => "dupbuild",
+            Category::Rspfile => "rspfile",
+            Category::NoCommand => "nocommand",
+            Category::PhonyCycle => "phonycycle",
+            Category::DepfileTarget => "depfiletarget",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Category> {
+        Category::ALL.iter().copied().find(|c| c.name() == name)
+    }
+}
+
+/// What to do about a category of warning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Warn,
+    Err,
+    Off,
+}
+
+/// The action for each category of warning, as set by -w flags.
+///
+/// ```
+/// use n2::load::{read_from_str, Options};
+/// use n2::log::{Action, Category};
+///
+/// let mut options = Options {
+///     no_db: true,
+///     ..Options::default()
+/// };
+/// let manifest = "build a: phony a\n";
+/// let state = read_from_str("build.ninja", manifest, &options).unwrap();
+/// assert_eq!(state.warnings[0].category, Category::PhonyCycle);
+///
+/// options.warnings.set(Category::PhonyCycle, Action::Err);
+/// assert!(read_from_str("build.ninja", manifest, &options).is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WarningConfig {
+    /// Categories set to other than Action::Warn.
+    actions: Vec<(Category, Action)>,
+}
+
+impl WarningConfig {
+    pub fn action(&self, category: Category) -> Action {
+        self.actions
+            .iter()
+            .find(|(c, _)| *c == category)
+            .map_or(Action::Warn, |&(_, action)| action)
+    }
+
+    pub fn set(&mut self, category: Category, action: Action) {
+        self.actions.retain(|(c, _)| *c != category);
+        if action != Action::Warn {
+            self.actions.push((category, action));
+        }
+    }
+
+    /// Act on a warning: add it to `warnings`, fail, or drop it.
+    pub fn apply(&self, warning: Warning, warnings: &mut Vec<Warning>) -> anyhow::Result<()> {
+        match self.action(warning.category) {
+            Action::Warn => warnings.push(warning),
+            Action::Err => anyhow::bail!("{} [-w {}=err]", warning, warning.category.name()),
+            Action::Off => {}
+        }
+        Ok(())
+    }
+
+    /// Apply a -w flag: comma-separated settings like "dupbuild=err",
+    /// where the action is one of err, warn or off.
+    pub fn parse_flag(&mut self, flag: &str) -> anyhow::Result<()> {
+        for setting in flag.split(',') {
+            let (name, action) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("-w expects name=action, got {:?}", setting))?;
+            let category = Category::from_name(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown -w {:?}, use -w list to list", name))?;
+            let action = match action {
+                "err" => Action::Err,
+                "warn" => Action::Warn,
+                "off" => Action::Off,
+                _ => anyhow::bail!("-w {}: expected err, warn or off, got {:?}", name, action),
+            };
+            self.set(category, action);
+        }
+        Ok(())
+    }
+}
+
+/// A problem with a manifest that doesn't stop it from loading.  Warnings are
+/// collected in load::State rather than printed, so that embedders can decide
+/// what to do with them.
+#[derive(Debug)]
+pub struct Warning {
+    pub loc: FileLoc,
+    pub category: Category,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.loc, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_flags() -> anyhow::Result<()> {
+        let mut config = WarningConfig::default();
+        config.parse_flag("dupbuild=err,rspfile=off")?;
+        config.parse_flag("phonycycle=err")?;
+        config.parse_flag("phonycycle=warn")?;
+        assert_eq!(config.action(Category::DupBuild), Action::Err);
+        assert_eq!(config.action(Category::Rspfile), Action::Off);
+        assert_eq!(config.action(Category::NoCommand), Action::Warn);
+        assert_eq!(config.action(Category::PhonyCycle), Action::Warn);
+
+        let err = config.parse_flag("bogus=err").err().unwrap();
+        assert_eq!(err.to_string(), "unknown -w \"bogus\", use -w list to list");
+        let err = config.parse_flag("dupbuild").err().unwrap();
+        assert_eq!(err.to_string(), "-w expects name=action, got \"dupbuild\"");
+        let err = config.parse_flag("dupbuild=yes").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "-w dupbuild: expected err, warn or off, got \"yes\""
+        );
+        Ok(())
+    }
+}
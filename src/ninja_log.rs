@@ -0,0 +1,158 @@
+//! Writes a Ninja-format .ninja_log alongside the .n2_db, so that tools that
+//! read Ninja's build log (`ninja -t recompact`, ninjatracing, etc.) work on
+//! n2 builds too.  n2 never reads this file back; the .n2_db remains the
+//! source of truth for what is up to date.
+//!
+//! See "The build log" in ninja's src/build_log.cc for the format.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const SIGNATURE: &str = "# ninja log v5\n";
+
+/// The hash Ninja records per command: MurmurHash64A with Ninja's seed.
+pub fn hash_command(command: &str) -> u64 {
+    const SEED: u64 = 0xDECA_FBAD_DECA_FBAD;
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let data = command.as_bytes();
+    let mut h = SEED ^ (data.len() as u64).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// One line of the log, describing one output of a finished build.
+pub struct Entry<'a> {
+    pub output: &'a str,
+    pub command: &'a str,
+    /// Start and end of the build, relative to the start of the n2 run.
+    pub start: Duration,
+    pub end: Duration,
+    /// The output's mtime after the build, if it exists.
+    pub mtime: Option<SystemTime>,
+}
+
+fn format_entry(entry: &Entry) -> String {
+    let mtime = entry
+        .mtime
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!(
+        "{}\t{}\t{}\t{}\t{:x}\n",
+        entry.start.as_millis(),
+        entry.end.as_millis(),
+        mtime,
+        entry.output,
+        hash_command(entry.command)
+    )
+}
+
+/// An opened .ninja_log, ready for appends.
+pub struct Writer {
+    w: File,
+}
+
+impl Writer {
+    /// Opens the log for appending.  As Ninja does, a log in some other
+    /// format is discarded and started afresh.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let valid = match File::open(path) {
+            Ok(f) => {
+                let mut line = String::new();
+                BufReader::new(f).read_line(&mut line)?;
+                line == SIGNATURE
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+        let mut w = if valid {
+            std::fs::OpenOptions::new().append(true).open(path)?
+        } else {
+            File::create(path)?
+        };
+        if !valid {
+            w.write_all(SIGNATURE.as_bytes())?;
+        }
+        Ok(Writer { w })
+    }
+
+    pub fn write_entry(&mut self, entry: &Entry) -> std::io::Result<()> {
+        self.w.write_all(format_entry(entry).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        // Whole 8-byte words and the tail are mixed in separately, so cover
+        // lengths on either side of a word boundary.
+        assert_eq!(hash_command(""), 0x87c2bc0beaf1d91d);
+        assert_eq!(hash_command("a"), 0x90fcb1aca689663e);
+        assert_eq!(hash_command("touch out"), 0x8ea3cc54bdccad2c);
+        assert_eq!(hash_command("cc -c foo.c -o foo.o"), 0xc1cfc0967c85181b);
+    }
+
+    #[test]
+    fn entry_format() {
+        let line = format_entry(&Entry {
+            output: "out",
+            command: "touch out",
+            start: Duration::from_millis(5),
+            end: Duration::from_millis(1234),
+            mtime: Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(42)),
+        });
+        assert_eq!(
+            line,
+            format!("5\t1234\t42\tout\t{:x}\n", hash_command("touch out"))
+        );
+    }
+
+    #[test]
+    fn replaces_other_versions() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".ninja_log");
+        std::fs::write(&path, "# ninja log v4\nstale\n")?;
+        let entry = Entry {
+            output: "out",
+            command: "",
+            start: Duration::ZERO,
+            end: Duration::ZERO,
+            mtime: None,
+        };
+        Writer::open(&path)?.write_entry(&entry)?;
+        Writer::open(&path)?.write_entry(&entry)?;
+        let expected = format!(
+            "{}{}{}",
+            SIGNATURE,
+            format_entry(&entry),
+            format_entry(&entry)
+        );
+        assert_eq!(std::fs::read_to_string(&path)?, expected);
+        Ok(())
+    }
+}
@@ -18,6 +18,7 @@ pub type VarList<'text> = SmallMap<&'text str, EvalString<&'text str>>;
 
 pub struct Rule<'text> {
     pub name: &'text str,
+    pub line: usize,
     pub vars: VarList<'text>,
 }
 
@@ -37,13 +38,18 @@ pub struct Build<'text> {
 #[derive(Debug)]
 pub struct Pool<'text> {
     pub name: &'text str,
-    pub depth: usize,
+    pub line: usize,
+    /// The `depth = N` binding, if present.
+    pub depth: Option<usize>,
 }
 
 pub enum Statement<'text> {
     Rule(Rule<'text>),
     Build(Build<'text>),
-    Default(Vec<EvalString<&'text str>>),
+    Default {
+        line: usize,
+        targets: Vec<EvalString<&'text str>>,
+    },
     Include(EvalString<&'text str>),
     Subninja(EvalString<&'text str>),
     Pool(Pool<'text>),
@@ -52,9 +58,13 @@ pub enum Statement<'text> {
 pub struct Parser<'text> {
     scanner: Scanner<'text>,
     pub vars: Vars<'text>,
+    /// Top-level variables fixed by override_var().
+    overridden: Vec<&'text str>,
     /// Reading EvalStrings is very hot when parsing, so we always read into
     /// this buffer and then clone it afterwards.
     eval_buf: Vec<EvalPart<&'text str>>,
+    /// See allow_inline_comments().
+    inline_comments: bool,
 }
 
 impl<'text> Parser<'text> {
@@ -62,14 +72,48 @@ impl<'text> Parser<'text> {
         Parser {
             scanner: Scanner::new(buf),
             vars: Vars::default(),
+            overridden: Vec::new(),
             eval_buf: Vec::with_capacity(16),
+            inline_comments: false,
         }
     }
 
+    /// Treat a '#' after unescaped whitespace as starting a comment that runs
+    /// to the end of the line, an n2 extension.  Ninja keeps such a '#' as
+    /// part of the statement, so this changes the meaning of some valid
+    /// manifests, like a `command` that passes a '#' to the shell.
+    pub fn allow_inline_comments(&mut self) {
+        self.inline_comments = true;
+    }
+
+    /// Fix a top-level variable to the given value.  Later assignments to it
+    /// in the file are still parsed but ignored.
+    pub fn override_var(&mut self, key: &'text str, val: String) {
+        self.vars.insert(key, val);
+        self.overridden.push(key);
+    }
+
     pub fn format_parse_error(&self, filename: &Path, err: ParseError) -> String {
         self.scanner.format_parse_error(filename, err)
     }
 
+    /// After read() returns an error, skip past the rest of the failed
+    /// statement -- the current line and any indented lines that follow it --
+    /// so that reading can resume and report further errors.
+    pub fn recover(&mut self) {
+        loop {
+            self.skip_to_eol();
+            if self.scanner.peek() == '\0' {
+                return;
+            }
+            self.scanner.skip('\r');
+            self.scanner.next();
+            if self.scanner.peek() != ' ' {
+                return;
+            }
+        }
+    }
+
     pub fn read(&mut self) -> ParseResult<Option<Statement<'text>>> {
         loop {
             match self.scanner.peek() {
@@ -83,7 +127,7 @@ impl<'text> Parser<'text> {
                     match ident {
                         "rule" => return Ok(Some(Statement::Rule(self.read_rule()?))),
                         "build" => return Ok(Some(Statement::Build(self.read_build()?))),
-                        "default" => return Ok(Some(Statement::Default(self.read_default()?))),
+                        "default" => return Ok(Some(self.read_default()?)),
                         "include" => {
                             return Ok(Some(Statement::Include(self.read_eval(false)?)));
                         }
@@ -97,7 +141,9 @@ impl<'text> Parser<'text> {
                             // multiple parsers in parallel and then evaluate
                             // all the variables in series at the end.
                             let val = self.read_vardef()?.evaluate(&[&self.vars]);
-                            self.vars.insert(ident, val);
+                            if !self.overridden.contains(&ident) {
+                                self.vars.insert(ident, val);
+                            }
                         }
                     }
                 }
@@ -111,25 +157,38 @@ impl<'text> Parser<'text> {
         self.scanner.expect('=')?;
         self.skip_spaces();
         // read_eval will error out if there's nothing to read
-        if self.scanner.peek_newline() {
-            self.scanner.skip('\r');
-            self.scanner.expect('\n')?;
+        if self.scanner.peek() == '\0' || self.scanner.peek_newline() {
+            self.expect_line_end()?;
             return Ok(EvalString::new(Vec::new()));
         }
-        let result = self.read_eval(false);
+        let result = self.read_eval(false)?;
+        self.expect_line_end()?;
+        Ok(result)
+    }
+
+    /// Consume the newline ending a statement.  The end of the file also
+    /// ends the last statement, as a file needn't end in a newline.
+    fn expect_line_end(&mut self) -> ParseResult<()> {
         self.scanner.skip('\r');
-        self.scanner.expect('\n')?;
-        result
+        if self.scanner.peek() == '\0' {
+            return Ok(());
+        }
+        self.scanner.expect('\n')
     }
 
-    /// Read a collection of `  foo = bar` variables, with leading indent.
+    /// Read a collection of `  foo = bar` variables, with leading indent of
+    /// spaces or tabs.
     fn read_scoped_vars(
         &mut self,
         variable_name_validator: fn(var: &str) -> bool,
     ) -> ParseResult<VarList<'text>> {
         let mut vars = VarList::default();
-        while self.scanner.peek() == ' ' {
+        while matches!(self.scanner.peek(), ' ' | '\t') {
             self.scanner.skip_spaces();
+            if self.scanner.peek() == '#' {
+                self.skip_comment()?;
+                continue;
+            }
             let name = self.read_ident()?;
             if !variable_name_validator(name) {
                 self.scanner
@@ -143,9 +202,10 @@ impl<'text> Parser<'text> {
     }
 
     fn read_rule(&mut self) -> ParseResult<Rule<'text>> {
+        let line = self.scanner.line;
         let name = self.read_ident()?;
-        self.scanner.skip('\r');
-        self.scanner.expect('\n')?;
+        self.skip_spaces();
+        self.expect_line_end()?;
         let vars = self.read_scoped_vars(|var| {
             matches!(
                 var,
@@ -155,30 +215,41 @@ impl<'text> Parser<'text> {
                     | "description"
                     | "deps"
                     | "generator"
+                    | "in_cwd"
+                    | "in_sep"
+                    | "mem"
                     | "pool"
                     | "restat"
+                    | "timeout"
+                    | "weight"
                     | "rspfile"
                     | "rspfile_content"
                     | "msvc_deps_prefix"
             )
         })?;
-        Ok(Rule { name, vars })
+        Ok(Rule { name, line, vars })
     }
 
     fn read_pool(&mut self) -> ParseResult<Pool<'text>> {
+        let line = self.scanner.line;
         let name = self.read_ident()?;
-        self.scanner.skip('\r');
-        self.scanner.expect('\n')?;
+        self.skip_spaces();
+        self.expect_line_end()?;
         let vars = self.read_scoped_vars(|var| matches!(var, "depth"))?;
-        let mut depth = 0;
+        let mut depth = None;
         if let Some((_, val)) = vars.into_iter().next() {
             let val = val.evaluate(&[]);
+            if val.starts_with('-') {
+                return self
+                    .scanner
+                    .parse_error(format!("pool depth must not be negative, got {}", val));
+            }
             depth = match val.parse::<usize>() {
-                Ok(d) => d,
+                Ok(d) => Some(d),
                 Err(err) => return self.scanner.parse_error(format!("pool depth: {}", err)),
             }
         }
-        Ok(Pool { name, depth })
+        Ok(Pool { name, line, depth })
     }
 
     fn read_unevaluated_paths_to(
@@ -188,6 +259,7 @@ impl<'text> Parser<'text> {
         self.skip_spaces();
         while self.scanner.peek() != ':'
             && self.scanner.peek() != '|'
+            && self.scanner.peek() != '\0'
             && !self.scanner.peek_newline()
         {
             v.push(self.read_eval(true)?);
@@ -239,13 +311,18 @@ impl<'text> Parser<'text> {
 
         if self.scanner.peek() == '|' {
             self.scanner.next();
-            self.scanner.expect('@')?;
+            if self.scanner.peek() != '@' {
+                return self.misordered_ins();
+            }
+            self.scanner.next();
             self.read_unevaluated_paths_to(&mut ins)?;
         }
         let validation_ins = ins.len() - order_only_ins - implicit_ins - explicit_ins;
+        if self.scanner.peek() == '|' {
+            return self.misordered_ins();
+        }
 
-        self.scanner.skip('\r');
-        self.scanner.expect('\n')?;
+        self.expect_line_end()?;
         let vars = self.read_scoped_vars(|_| true)?;
         Ok(Build {
             rule,
@@ -261,15 +338,21 @@ impl<'text> Parser<'text> {
         })
     }
 
-    fn read_default(&mut self) -> ParseResult<Vec<EvalString<&'text str>>> {
-        let mut defaults = Vec::new();
-        self.read_unevaluated_paths_to(&mut defaults)?;
-        if defaults.is_empty() {
+    fn misordered_ins<T>(&self) -> ParseResult<T> {
+        self.scanner.parse_error(
+            "build inputs must be in the order: explicit, | implicit, || order-only, |@ validations",
+        )
+    }
+
+    fn read_default(&mut self) -> ParseResult<Statement<'text>> {
+        let line = self.scanner.line;
+        let mut targets = Vec::new();
+        self.read_unevaluated_paths_to(&mut targets)?;
+        if targets.is_empty() {
             return self.scanner.parse_error("expected path");
         }
-        self.scanner.skip('\r');
-        self.scanner.expect('\n')?;
-        Ok(defaults)
+        self.expect_line_end()?;
+        Ok(Statement::Default { line, targets })
     }
 
     fn skip_comment(&mut self) -> ParseResult<()> {
@@ -315,8 +398,7 @@ impl<'text> Parser<'text> {
         let end = if stop_at_path_separators {
             loop {
                 match self.scanner.read() {
-                    '\0' => return self.scanner.parse_error("unexpected EOF"),
-                    ' ' | ':' | '|' | '\n' => {
+                    ' ' | ':' | '|' | '\n' | '\0' => {
                         self.scanner.back();
                         break self.scanner.ofs;
                     }
@@ -340,11 +422,23 @@ impl<'text> Parser<'text> {
         } else {
             loop {
                 match self.scanner.read() {
-                    '\0' => return self.scanner.parse_error("unexpected EOF"),
-                    '\n' => {
+                    '\n' | '\0' => {
                         self.scanner.back();
                         break self.scanner.ofs;
                     }
+                    '#' if self.inline_comments => {
+                        // A '#' preceded by unescaped whitespace starts a
+                        // trailing comment, which isn't part of the value.
+                        let hash = self.scanner.ofs - 1;
+                        let mut end = hash;
+                        while end > ofs && matches!(self.scanner.slice(end - 1, end), " " | "\t") {
+                            end -= 1;
+                        }
+                        if end < hash {
+                            self.skip_to_eol();
+                            break end;
+                        }
+                    }
                     '\r' if self.scanner.peek() == '\n' => {
                         self.scanner.back();
                         break self.scanner.ofs;
@@ -368,7 +462,7 @@ impl<'text> Parser<'text> {
                 .push(EvalPart::Literal(self.scanner.slice(ofs, end)));
         }
         if self.eval_buf.is_empty() {
-            return self.scanner.parse_error(format!("Expected a string"));
+            return self.scanner.parse_error("Expected a string");
         }
         Ok(EvalString::new(self.eval_buf.clone()))
     }
@@ -390,20 +484,47 @@ impl<'text> Parser<'text> {
     }
 
     /// Read and interpret the text following a '$' escape character.
+    /// A `$` line continuation promises another line, so unlike a plain
+    /// newline it can't be followed by the end of the file.
+    fn expect_continued(&self) -> ParseResult<()> {
+        if self.scanner.peek() == '\0' {
+            return self.scanner.parse_error("unexpected EOF");
+        }
+        Ok(())
+    }
+
     fn read_escape(&mut self) -> ParseResult<EvalPart<&'text str>> {
         Ok(match self.scanner.read() {
-            '\n' | '\r' => {
+            '\n' => {
+                self.scanner.skip_spaces();
+                self.expect_continued()?;
+                EvalPart::Literal(self.scanner.slice(0, 0))
+            }
+            '\r' => {
+                if self.scanner.peek() == '\0' {
+                    // A CRLF manifest cut off between the two.
+                    return self.scanner.parse_error("unexpected EOF after '$'");
+                }
+                self.scanner.expect('\n')?;
                 self.scanner.skip_spaces();
+                self.expect_continued()?;
                 EvalPart::Literal(self.scanner.slice(0, 0))
             }
             ' ' | '$' | ':' => {
                 EvalPart::Literal(self.scanner.slice(self.scanner.ofs - 1, self.scanner.ofs))
             }
+            '\0' => {
+                self.scanner.back();
+                return self.scanner.parse_error("unexpected EOF after '$'");
+            }
             '{' => {
                 let start = self.scanner.ofs;
                 loop {
                     match self.scanner.read() {
-                        '\0' => return self.scanner.parse_error("unexpected EOF"),
+                        '\0' => {
+                            self.scanner.back();
+                            return self.scanner.parse_error("unexpected EOF");
+                        }
                         '}' => break,
                         _ => {}
                     }
@@ -420,16 +541,34 @@ impl<'text> Parser<'text> {
         })
     }
 
+    /// Skip spaces and line continuations, as well as a trailing comment if
+    /// one follows the spaces.
     fn skip_spaces(&mut self) {
+        let mut skipped = false;
         loop {
             match self.scanner.read() {
-                ' ' => {}
+                ' ' | '\t' => skipped = true,
                 '$' => {
-                    if self.scanner.peek() != '\n' {
+                    if !self.scanner.peek_newline() {
                         self.scanner.back();
                         return;
                     }
+                    let (ofs, line) = (self.scanner.ofs - 1, self.scanner.line);
+                    self.scanner.skip('\r');
                     self.scanner.next();
+                    self.scanner.skip_spaces();
+                    if self.scanner.peek() == '\0' {
+                        // Leave a continuation into the end of the file for
+                        // read_escape() to report.
+                        self.scanner.ofs = ofs;
+                        self.scanner.line = line;
+                        return;
+                    }
+                    skipped = true;
+                }
+                '#' if skipped && self.inline_comments => {
+                    self.skip_to_eol();
+                    return;
                 }
                 _ => {
                     self.scanner.back();
@@ -438,6 +577,324 @@ impl<'text> Parser<'text> {
             }
         }
     }
+
+    /// Skip to the end of the line, without consuming the newline.
+    fn skip_to_eol(&mut self) {
+        while self.scanner.peek() != '\0' && !self.scanner.peek_newline() {
+            self.scanner.next();
+        }
+    }
+}
+
+/// The kind of a lexical token, as reported by tokenize().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A `# ...` comment, not including the trailing newline.
+    Comment,
+    /// A statement keyword: `build`, `rule`, `pool`, `default`, `include`,
+    /// or `subninja`.
+    Keyword,
+    /// A rule, pool, or variable name.
+    Ident,
+    /// Literal text within a path or variable value.
+    Text,
+    /// A `$` escape of a literal character, or a `$` line continuation.
+    Escape,
+    /// A `$var` or `${var}` variable reference.
+    VarRef,
+    /// One of `:`, `|`, `||`, `|@`, or `=`.
+    Operator,
+    /// Input that could not be tokenized, e.g. a truncated escape.
+    Error,
+}
+
+/// A single token within a .ninja file, as a byte range of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Split .ninja file text into tokens, e.g. for syntax highlighting.
+/// Unlike Parser this never fails; input it can't make sense of is reported
+/// as TokenKind::Error tokens.  Whitespace and newlines are not reported.
+/// `inline_comments` matches Parser::allow_inline_comments().
+pub fn tokenize(buf: &str, inline_comments: bool) -> impl Iterator<Item = Token> {
+    let mut bytes = Vec::with_capacity(buf.len() + 1);
+    bytes.extend_from_slice(buf.as_bytes());
+    bytes.push(0);
+    let mut tokenizer = Tokenizer {
+        scanner: Scanner::new(&bytes),
+        len: buf.len(),
+        tokens: Vec::new(),
+        inline_comments,
+    };
+    tokenizer.run();
+    tokenizer.tokens.into_iter()
+}
+
+/// Lexer state for tokenize().  This follows the same structure as Parser
+/// but records spans instead of building statements.
+struct Tokenizer<'text> {
+    scanner: Scanner<'text>,
+    /// Length of the input, excluding the trailing nul.
+    len: usize,
+    tokens: Vec<Token>,
+    inline_comments: bool,
+}
+
+impl<'text> Tokenizer<'text> {
+    fn push(&mut self, kind: TokenKind, start: usize) {
+        if self.scanner.ofs > start {
+            self.tokens.push(Token {
+                kind,
+                start,
+                end: self.scanner.ofs,
+            });
+        }
+    }
+
+    fn at_eof(&self) -> bool {
+        self.scanner.ofs >= self.len
+    }
+
+    /// True at the end of a line or of the input.
+    fn at_eol(&self) -> bool {
+        self.scanner.peek() == '\0' || self.scanner.peek_newline()
+    }
+
+    fn run(&mut self) {
+        let mut indented = false;
+        loop {
+            match self.scanner.peek() {
+                '\0' if self.at_eof() => return,
+                '\0' => {
+                    let start = self.scanner.ofs;
+                    self.scanner.next();
+                    self.push(TokenKind::Error, start);
+                }
+                '\n' => {
+                    indented = false;
+                    self.scanner.next();
+                }
+                ' ' | '\t' | '\r' => {
+                    indented = true;
+                    self.scanner.next();
+                }
+                '#' => self.comment(),
+                _ => {
+                    self.statement(indented);
+                    self.rest_of_line();
+                }
+            }
+        }
+    }
+
+    fn comment(&mut self) {
+        let start = self.scanner.ofs;
+        while !self.at_eol() {
+            self.scanner.next();
+        }
+        self.push(TokenKind::Comment, start);
+    }
+
+    /// Report anything left on the current line as an error.
+    fn rest_of_line(&mut self) {
+        self.skip_spaces();
+        let start = self.scanner.ofs;
+        while !self.at_eol() {
+            self.scanner.next();
+        }
+        self.push(TokenKind::Error, start);
+    }
+
+    fn statement(&mut self, indented: bool) {
+        let start = self.scanner.ofs;
+        let ident = self.ident();
+        if ident.is_empty() {
+            return;
+        }
+        match ident {
+            "build" | "rule" | "pool" | "default" | "include" | "subninja" if !indented => {
+                self.push(TokenKind::Keyword, start);
+                self.skip_spaces();
+                match ident {
+                    "build" => self.paths(true),
+                    "rule" | "pool" => {
+                        let start = self.scanner.ofs;
+                        self.ident();
+                        self.push(TokenKind::Ident, start);
+                    }
+                    "default" => self.paths(false),
+                    _ => self.eval(false),
+                }
+            }
+            _ => {
+                self.push(TokenKind::Ident, start);
+                self.skip_spaces();
+                if self.scanner.peek() == '=' {
+                    let start = self.scanner.ofs;
+                    self.scanner.next();
+                    self.push(TokenKind::Operator, start);
+                    self.skip_spaces();
+                    self.eval(false);
+                }
+            }
+        }
+    }
+
+    fn ident(&mut self) -> &'text str {
+        let start = self.scanner.ofs;
+        while matches!(
+            self.scanner.peek(),
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.'
+        ) {
+            self.scanner.next();
+        }
+        self.scanner.slice(start, self.scanner.ofs)
+    }
+
+    /// Skip spaces, reporting any `$` line continuations among them as well
+    /// as a trailing comment.
+    fn skip_spaces(&mut self) {
+        let mut skipped = false;
+        loop {
+            match self.scanner.peek() {
+                ' ' | '\t' => {
+                    self.scanner.next();
+                    skipped = true;
+                }
+                '$' => {
+                    let start = self.scanner.ofs;
+                    self.scanner.next();
+                    if !self.scanner.peek_newline() {
+                        self.scanner.back();
+                        return;
+                    }
+                    self.scanner.skip('\r');
+                    self.scanner.next();
+                    self.push(TokenKind::Escape, start);
+                    skipped = true;
+                }
+                '#' if skipped && self.inline_comments => {
+                    self.comment();
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Read the paths and separators of a `build` or `default` line.
+    fn paths(&mut self, build: bool) {
+        loop {
+            self.skip_spaces();
+            if self.at_eol() {
+                return;
+            }
+            let start = self.scanner.ofs;
+            match self.scanner.peek() {
+                ':' if build => {
+                    self.scanner.next();
+                    self.push(TokenKind::Operator, start);
+                    self.skip_spaces();
+                    let start = self.scanner.ofs;
+                    self.ident();
+                    self.push(TokenKind::Ident, start);
+                }
+                '|' if build => {
+                    self.scanner.next();
+                    if matches!(self.scanner.peek(), '|' | '@') {
+                        self.scanner.next();
+                    }
+                    self.push(TokenKind::Operator, start);
+                }
+                // Separators only mean something on build lines, but eval()
+                // still stops at them.
+                ':' | '|' => {
+                    self.scanner.next();
+                    self.push(TokenKind::Error, start);
+                }
+                _ => self.eval(true),
+            }
+        }
+    }
+
+    /// Read text with embedded escapes, up to the end of the line or, if
+    /// stop_at_path_separators is set, the end of the path.
+    fn eval(&mut self, stop_at_path_separators: bool) {
+        let mut start = self.scanner.ofs;
+        loop {
+            match self.scanner.peek() {
+                ' ' | ':' | '|' if stop_at_path_separators => break,
+                '$' => {
+                    self.push(TokenKind::Text, start);
+                    self.escape();
+                    start = self.scanner.ofs;
+                }
+                '#' if self.inline_comments
+                    && self.scanner.ofs > start
+                    && matches!(
+                        self.scanner.slice(self.scanner.ofs - 1, self.scanner.ofs),
+                        " " | "\t"
+                    ) =>
+                {
+                    // Trailing comment; see Parser::read_eval.
+                    let mut end = self.scanner.ofs;
+                    while end > start && matches!(self.scanner.slice(end - 1, end), " " | "\t") {
+                        end -= 1;
+                    }
+                    if end > start {
+                        self.tokens.push(Token {
+                            kind: TokenKind::Text,
+                            start,
+                            end,
+                        });
+                    }
+                    self.comment();
+                    return;
+                }
+                _ if self.at_eol() => break,
+                _ => self.scanner.next(),
+            }
+        }
+        self.push(TokenKind::Text, start);
+    }
+
+    fn escape(&mut self) {
+        let start = self.scanner.ofs;
+        self.scanner.next();
+        let kind = match self.scanner.peek() {
+            '\n' | ' ' | '$' | ':' => {
+                self.scanner.next();
+                TokenKind::Escape
+            }
+            '\r' if self.scanner.peek_newline() => {
+                self.scanner.next();
+                self.scanner.next();
+                TokenKind::Escape
+            }
+            '{' => {
+                while !self.at_eol() && self.scanner.peek() != '}' {
+                    self.scanner.next();
+                }
+                if self.scanner.skip('}') {
+                    TokenKind::VarRef
+                } else {
+                    TokenKind::Error
+                }
+            }
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => {
+                while matches!(self.scanner.peek(), 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-') {
+                    self.scanner.next();
+                }
+                TokenKind::VarRef
+            }
+            _ => TokenKind::Error,
+        };
+        self.push(kind, start);
+    }
 }
 
 #[cfg(test)]
@@ -461,10 +918,10 @@ mod tests {
     #[test]
     fn parse_defaults() {
         test_for_line_endings(&["var = 3", "default a b$var c", ""], |test_case| {
-            let mut buf = test_case_buffer(test_case);
-            let mut parser = Parser::new(&mut buf);
+            let buf = test_case_buffer(test_case);
+            let mut parser = Parser::new(&buf);
             let default = match parser.read().unwrap().unwrap() {
-                Statement::Default(d) => d,
+                Statement::Default { targets, .. } => targets,
                 _ => panic!("expected default"),
             };
             assert_eq!(
@@ -480,8 +937,8 @@ mod tests {
 
     #[test]
     fn parse_dot_in_eval() {
-        let mut buf = test_case_buffer("x = $y.z\n");
-        let mut parser = Parser::new(&mut buf);
+        let buf = test_case_buffer("x = $y.z\n");
+        let mut parser = Parser::new(&buf);
         parser.read().unwrap();
         let x = parser.vars.get("x").unwrap();
         assert_eq!(x, ".z");
@@ -489,22 +946,481 @@ mod tests {
 
     #[test]
     fn parse_dot_in_rule() {
-        let mut buf = test_case_buffer("rule x.y\n  command = x\n");
-        let mut parser = Parser::new(&mut buf);
+        let buf = test_case_buffer("rule x.y\n  command = x\n");
+        let mut parser = Parser::new(&buf);
         let stmt = parser.read().unwrap().unwrap();
-        assert!(matches!(
-            stmt,
-            Statement::Rule(Rule {
-                name: "x.y",
-                vars: _
-            })
-        ));
+        assert!(matches!(stmt, Statement::Rule(Rule { name: "x.y", .. })));
+    }
+
+    #[test]
+    fn parse_cmake_style_idents() {
+        let buf = test_case_buffer(
+            "CMAKE_BUILD = out2
+rule CXX_STATIC_LINKER__foo
+  command = ar $CMAKE_BUILD $in
+build link2.a: CXX_STATIC_LINKER__foo $CMAKE_BUILD/a.o
+  cc.host-1 = x
+",
+        );
+        let mut parser = Parser::new(&buf);
+        let rule = match parser.read().unwrap().unwrap() {
+            Statement::Rule(r) => r,
+            _ => panic!("expected rule"),
+        };
+        assert_eq!(parser.vars.get("CMAKE_BUILD").unwrap(), "out2");
+        assert_eq!(rule.name, "CXX_STATIC_LINKER__foo");
+        assert_eq!(
+            rule.vars.get("command"),
+            Some(&EvalString::new(vec![
+                EvalPart::Literal("ar "),
+                EvalPart::VarRef("CMAKE_BUILD"),
+                EvalPart::Literal(" "),
+                EvalPart::VarRef("in"),
+            ]))
+        );
+        let build = match parser.read().unwrap().unwrap() {
+            Statement::Build(b) => b,
+            _ => panic!("expected build"),
+        };
+        assert_eq!(build.rule, "CXX_STATIC_LINKER__foo");
+        assert_eq!(
+            build.ins,
+            vec![EvalString::new(vec![
+                EvalPart::VarRef("CMAKE_BUILD"),
+                EvalPart::Literal("/a.o"),
+            ])]
+        );
+        assert!(build.vars.get("cc.host-1").is_some());
+    }
+
+    #[test]
+    fn parse_mixed_line_endings() {
+        let buf = test_case_buffer(
+            "rule cc\r\n  command = cc $in\n\r\nbuild out: cc in\r\n  flags = -O2\n",
+        );
+        let mut parser = Parser::new(&buf);
+        let rule = match parser.read().unwrap().unwrap() {
+            Statement::Rule(r) => r,
+            _ => panic!("expected rule"),
+        };
+        assert_eq!(rule.name, "cc");
+        assert_eq!(
+            rule.vars.get("command"),
+            Some(&EvalString::new(vec![
+                EvalPart::Literal("cc "),
+                EvalPart::VarRef("in")
+            ]))
+        );
+        let build = match parser.read().unwrap().unwrap() {
+            Statement::Build(b) => b,
+            _ => panic!("expected build"),
+        };
+        assert_eq!(build.rule, "cc");
+        assert_eq!(
+            build.outs,
+            vec![EvalString::new(vec![EvalPart::Literal("out")])]
+        );
+        assert_eq!(
+            build.ins,
+            vec![EvalString::new(vec![EvalPart::Literal("in")])]
+        );
+        assert_eq!(
+            build.vars.get("flags"),
+            Some(&EvalString::new(vec![EvalPart::Literal("-O2")]))
+        );
+        assert!(parser.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_continuation_line_endings() {
+        test_for_line_endings(
+            &[
+                "x = a$",
+                "    b",
+                "build$",
+                "    out: $",
+                "    phony in",
+                "",
+            ],
+            |test_case| {
+                let buf = test_case_buffer(test_case);
+                let mut parser = Parser::new(&buf);
+                let build = match parser.read().unwrap().unwrap() {
+                    Statement::Build(b) => b,
+                    _ => panic!("expected build"),
+                };
+                assert_eq!(parser.vars.get("x").unwrap(), "ab");
+                assert_eq!(build.rule, "phony");
+                assert_eq!(
+                    build.outs,
+                    vec![EvalString::new(vec![EvalPart::Literal("out")])]
+                );
+            },
+        );
+    }
+
+    fn parse_error_text(test_case: &str) -> String {
+        let buf = test_case_buffer(test_case);
+        let mut parser = Parser::new(&buf);
+        loop {
+            match parser.read() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("expected parse error"),
+                Err(err) => return parser.format_parse_error(Path::new("build.ninja"), err),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_build_input_kinds() {
+        let buf = test_case_buffer("build out: rule a b | c d || e |@ f\n");
+        let mut parser = Parser::new(&buf);
+        let build = match parser.read().unwrap().unwrap() {
+            Statement::Build(b) => b,
+            _ => panic!("expected build"),
+        };
+        assert_eq!(build.ins.len(), 6);
+        assert_eq!(
+            (
+                build.explicit_ins,
+                build.implicit_ins,
+                build.order_only_ins,
+                build.validation_ins
+            ),
+            (2, 2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn parse_misordered_build_inputs() {
+        for line in [
+            "build out: rule a || b | c\n",
+            "build out: rule a | b || c | d\n",
+            "build out: rule a |@ b || c\n",
+            "build out: rule a |@ b |@ c\n",
+        ] {
+            let err = parse_error_text(line);
+            assert!(
+                err.contains("build inputs must be in the order"),
+                "{}: {}",
+                line,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn parse_dollar_at_eof() {
+        let err = parse_error_text("rule cc\n  command = foo$");
+        assert!(
+            err.starts_with("build.ninja:2:17: parse error: unexpected EOF after '$'"),
+            "{}",
+            err
+        );
+        let err = parse_error_text("build out$");
+        assert!(
+            err.contains("parse error: unexpected EOF after '$'"),
+            "{}",
+            err
+        );
+        let err = parse_error_text("rule cc\r\n  command = foo$\r");
+        assert!(
+            err.starts_with("build.ninja:2:18: parse error: unexpected EOF after '$'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_tab_indent() {
+        test_for_line_endings(
+            &["rule cc", "\tcommand =\tcc $in", "\t\tdescription = CC", ""],
+            |test_case| {
+                let buf = test_case_buffer(test_case);
+                let mut parser = Parser::new(&buf);
+                let rule = match parser.read().unwrap().unwrap() {
+                    Statement::Rule(r) => r,
+                    _ => panic!("expected rule"),
+                };
+                assert_eq!(
+                    rule.vars.get("command"),
+                    Some(&EvalString::new(vec![
+                        EvalPart::Literal("cc "),
+                        EvalPart::VarRef("in"),
+                    ]))
+                );
+                assert_eq!(
+                    rule.vars.get("description"),
+                    Some(&EvalString::new(vec![EvalPart::Literal("CC")]))
+                );
+            },
+        );
+
+        // Statements themselves still can't be indented.
+        let err = parse_error_text("\trule cc\n");
+        assert!(err.contains("unexpected whitespace"), "{}", err);
+    }
+
+    #[test]
+    fn parse_no_final_newline() {
+        let buf = test_case_buffer("x = 1\nrule r\n  command = c\nbuild out: r in\n  d = $x");
+        let mut parser = Parser::new(&buf);
+        assert!(matches!(parser.read().unwrap(), Some(Statement::Rule(_))));
+        let build = match parser.read().unwrap().unwrap() {
+            Statement::Build(b) => b,
+            _ => panic!("expected build"),
+        };
+        assert_eq!(
+            build.ins,
+            vec![EvalString::new(vec![EvalPart::Literal("in")])]
+        );
+        assert_eq!(
+            build.vars.get("d"),
+            Some(&EvalString::new(vec![EvalPart::VarRef("x")]))
+        );
+        assert!(parser.read().unwrap().is_none());
+
+        for text in ["x =", "default a b", "build out: r", "pool p\n  depth = 1"] {
+            let buf = test_case_buffer(text);
+            let mut parser = Parser::new(&buf);
+            while parser.read().unwrap().is_some() {}
+        }
+
+        // But a `$` line continuation can't run into the end of the file.
+        for text in ["x = $\n", "x = a $\n  ", "x = a$\r\n", "build out: r $\n"] {
+            let buf = test_case_buffer(text);
+            let mut parser = Parser::new(&buf);
+            let err = loop {
+                match parser.read() {
+                    Ok(Some(_)) => {}
+                    Ok(None) => panic!("{:?} parsed", text),
+                    Err(err) => break err,
+                }
+            };
+            assert_eq!(err.msg, "unexpected EOF", "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn parse_bom() {
+        let buf = test_case_buffer("\u{FEFF}x = 1\nrule r\n  command = $x\n");
+        let mut parser = Parser::new(&buf);
+        assert!(matches!(parser.read().unwrap(), Some(Statement::Rule(_))));
+        assert_eq!(parser.vars.get("x").unwrap(), "1");
+
+        let err = parse_error_text("\u{FEFF}x 1\n");
+        assert!(
+            err.starts_with("build.ninja:1:3: parse error: expected '='"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_dollar_before_nul() {
+        let err = parse_error_text("x = a$\0b\n");
+        assert!(
+            err.contains("parse error: unexpected EOF after '$'"),
+            "{}",
+            err
+        );
+    }
+
+    fn tokens(buf: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(buf, false)
+            .map(|t| (t.kind, &buf[t.start..t.end]))
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_manifest() {
+        use TokenKind::*;
+        let buf = "# comment
+cflags = -O2 $x
+rule cc
+  command = cc ${cflags} $in -o $out
+build out.o | out.d: cc in.c$ 1 || gen.h |@ check
+default out.o
+";
+        assert_eq!(
+            tokens(buf),
+            vec![
+                (Comment, "# comment"),
+                (Ident, "cflags"),
+                (Operator, "="),
+                (Text, "-O2 "),
+                (VarRef, "$x"),
+                (Keyword, "rule"),
+                (Ident, "cc"),
+                (Ident, "command"),
+                (Operator, "="),
+                (Text, "cc "),
+                (VarRef, "${cflags}"),
+                (Text, " "),
+                (VarRef, "$in"),
+                (Text, " -o "),
+                (VarRef, "$out"),
+                (Keyword, "build"),
+                (Text, "out.o"),
+                (Operator, "|"),
+                (Text, "out.d"),
+                (Operator, ":"),
+                (Ident, "cc"),
+                (Text, "in.c"),
+                (Escape, "$ "),
+                (Text, "1"),
+                (Operator, "||"),
+                (Text, "gen.h"),
+                (Operator, "|@"),
+                (Text, "check"),
+                (Keyword, "default"),
+                (Text, "out.o"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_inline_comments() {
+        use TokenKind::*;
+        let buf = "cflags = -O2 # release\nbuild a: cc b # c\n";
+        assert_eq!(
+            tokenize(buf, true)
+                .map(|t| (t.kind, &buf[t.start..t.end]))
+                .collect::<Vec<_>>(),
+            vec![
+                (Ident, "cflags"),
+                (Operator, "="),
+                (Text, "-O2"),
+                (Comment, "# release"),
+                (Keyword, "build"),
+                (Text, "a"),
+                (Operator, ":"),
+                (Ident, "cc"),
+                (Text, "b"),
+                (Comment, "# c"),
+            ]
+        );
+        // Without them, as in Ninja, the '#' is just text.
+        assert_eq!(
+            tokens("cflags = -O2 # release\n"),
+            vec![(Ident, "cflags"), (Operator, "="), (Text, "-O2 # release")]
+        );
+    }
+
+    #[test]
+    fn tokenize_continuation() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("build$\r\n  out: phony\n"),
+            vec![
+                (Keyword, "build"),
+                (Escape, "$\r\n"),
+                (Text, "out"),
+                (Operator, ":"),
+                (Ident, "phony"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_incomplete() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("x = ${unterminated\ny = a$"),
+            vec![
+                (Ident, "x"),
+                (Operator, "="),
+                (Error, "${unterminated"),
+                (Ident, "y"),
+                (Operator, "="),
+                (Text, "a"),
+                (Error, "$"),
+            ]
+        );
+        assert_eq!(tokens("build"), vec![(Keyword, "build")]);
+        assert_eq!(tokens(": x"), vec![(Error, ": x")]);
+        assert_eq!(
+            tokens("default a:b\n"),
+            vec![(Keyword, "default"), (Text, "a"), (Error, ":"), (Text, "b")]
+        );
+        assert_eq!(
+            tokens("default |\n"),
+            vec![(Keyword, "default"), (Error, "|")]
+        );
+    }
+
+    #[test]
+    fn parse_inline_comments() {
+        let buf = test_case_buffer(
+            "cflags = -O2 # release
+empty = # nothing
+hash = a#b
+rule cc # the compiler
+  # indented comment
+  command = cc $cflags $in	# tab-separated
+build out: cc in # trailing
+  flags = x$ # y
+",
+        );
+        let mut parser = Parser::new(&buf);
+        parser.allow_inline_comments();
+        let rule = match parser.read().unwrap().unwrap() {
+            Statement::Rule(r) => r,
+            _ => panic!("expected rule"),
+        };
+        assert_eq!(parser.vars.get("cflags").unwrap(), "-O2");
+        assert_eq!(parser.vars.get("empty").unwrap(), "");
+        assert_eq!(parser.vars.get("hash").unwrap(), "a#b");
+        assert_eq!(rule.name, "cc");
+        assert_eq!(
+            rule.vars.get("command"),
+            Some(&EvalString::new(vec![
+                EvalPart::Literal("cc "),
+                EvalPart::VarRef("cflags"),
+                EvalPart::Literal(" "),
+                EvalPart::VarRef("in"),
+            ]))
+        );
+        let build = match parser.read().unwrap().unwrap() {
+            Statement::Build(b) => b,
+            _ => panic!("expected build"),
+        };
+        assert_eq!(
+            build.ins,
+            vec![EvalString::new(vec![EvalPart::Literal("in")])]
+        );
+        // An escaped space doesn't start a comment.
+        assert_eq!(
+            build.vars.get("flags"),
+            Some(&EvalString::new(vec![
+                EvalPart::Literal("x"),
+                EvalPart::Literal(" "),
+                EvalPart::Literal("# y"),
+            ]))
+        );
+        assert!(parser.read().unwrap().is_none());
+
+        // They're off by default, as Ninja has no such thing.
+        let buf = test_case_buffer("rule cc\n  command = cc $in # x\n");
+        let mut parser = Parser::new(&buf);
+        let rule = match parser.read().unwrap().unwrap() {
+            Statement::Rule(r) => r,
+            _ => panic!("expected rule"),
+        };
+        assert_eq!(
+            rule.vars.get("command"),
+            Some(&EvalString::new(vec![
+                EvalPart::Literal("cc "),
+                EvalPart::VarRef("in"),
+                EvalPart::Literal(" # x"),
+            ]))
+        );
     }
 
     #[test]
     fn parse_trailing_newline() {
-        let mut buf = test_case_buffer("build$\n foo$\n : $\n  touch $\n\n");
-        let mut parser = Parser::new(&mut buf);
+        let buf = test_case_buffer("build$\n foo$\n : $\n  touch $\n\n");
+        let mut parser = Parser::new(&buf);
         let stmt = parser.read().unwrap().unwrap();
         assert!(matches!(
             stmt,
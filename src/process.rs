@@ -1,15 +1,29 @@
 //! Exposes process::run_command, a wrapper around platform-native process execution.
 
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
 #[cfg(unix)]
 pub use crate::process_posix::run_command;
 #[cfg(windows)]
 pub use crate::process_win::run_command;
 
+#[cfg(unix)]
+pub use crate::process_posix::load_average;
+
+/// Windows has no load average, so -l has no effect there.
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
 #[cfg(target_arch = "wasm32")]
 fn run_command(
     cmdline: &str,
+    console: bool,
+    timeout: Option<Duration>,
     mut output_cb: impl FnMut(&[u8]),
-) -> anyhow::Result<(Termination, Vec<u8>)> {
+) -> anyhow::Result<(Termination, Option<i32>)> {
     anyhow::bail!("wasm cannot run commands");
 }
 
@@ -18,4 +32,60 @@ pub enum Termination {
     Success,
     Interrupted,
     Failure,
+    /// Killed for running past its timeout.
+    TimedOut,
+}
+
+/// Why a Watchdog is stopping a command.
+pub enum Stop {
+    /// n2 was interrupted; only delivered once.
+    Interrupt,
+    /// The command ran past its timeout.
+    Timeout,
+}
+
+/// How often a Watchdog checks whether n2 was interrupted.
+const WATCHDOG_TICK: Duration = Duration::from_millis(100);
+
+/// Watches a running command from another thread, calling `stop` if it
+/// outlives its timeout.  run_command blocks reading the command's output, so
+/// it can't keep time itself.
+pub struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: std::thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    pub fn start(timeout: Duration, mut stop: impl FnMut(Stop) + Send + 'static) -> Self {
+        let (done, rx) = mpsc::channel();
+        let deadline = Instant::now() + timeout;
+        let thread = std::thread::spawn(move || {
+            let mut interrupted = false;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    stop(Stop::Timeout);
+                    return true;
+                }
+                match rx.recv_timeout((deadline - now).min(WATCHDOG_TICK)) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => return false,
+                }
+                if !interrupted && crate::signal::was_interrupted() {
+                    interrupted = true;
+                    stop(Stop::Interrupt);
+                }
+            }
+        });
+        Watchdog { done, thread }
+    }
+
+    /// Stop watching, returning whether the timeout fired.  The command may
+    /// have exited just before that, so callers check its status too.  Call
+    /// this before reaping the command, so that `stop` can't act on a
+    /// process id that has since been reused.
+    pub fn finish(self) -> bool {
+        let _ = self.done.send(());
+        self.thread.join().unwrap()
+    }
 }
@@ -1,10 +1,11 @@
 //! Implements run_command on posix using posix_spawn.
 //! See run_command comments for why.
 
-use crate::process::Termination;
+use crate::process::{Stop, Termination, Watchdog};
 use std::io::{Error, Read};
 use std::os::fd::FromRawFd;
 use std::os::unix::process::ExitStatusExt;
+use std::time::Duration;
 
 // https://github.com/rust-lang/libc/issues/2520
 // libc crate doesn't expose the 'environ' pointer.
@@ -56,6 +57,15 @@ impl PosixSpawnAttr {
             )
         }
     }
+
+    fn setpgroup(&mut self, pgroup: libc::pid_t) -> anyhow::Result<()> {
+        unsafe {
+            check_posix_spawn(
+                "posix_spawnattr_setpgroup",
+                libc::posix_spawnattr_setpgroup(self.as_ptr(), pgroup),
+            )
+        }
+    }
 }
 
 impl Drop for PosixSpawnAttr {
@@ -150,31 +160,58 @@ fn pipe2() -> anyhow::Result<[libc::c_int; 2]> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Run a command, returning how it ended along with its exit code, which is
+/// None if it was killed by a signal.
+pub fn run_command(
+    cmdline: &str,
+    console: bool,
+    timeout: Option<Duration>,
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, Option<i32>)> {
+    // A command with a timeout gets a process group of its own, so that
+    // whatever it spawned can be killed along with it.  Console commands
+    // must stay in n2's group to keep access to the terminal, so only the
+    // command itself can be killed.
+    let own_group = timeout.is_some() && !console;
+
     // Spawn the subprocess using posix_spawn with output redirected to the pipe.
     // We don't use Rust's process spawning because of issue #14 and because
     // we want to feed both stdout and stderr into the same pipe, which cannot
     // be done with the existing std::process API.
+    // Console commands instead inherit n2's stdio, so the pipe just sees EOF.
     let (pid, mut pipe) = unsafe {
         let pipe = pipe2()?;
 
         let mut attr = PosixSpawnAttr::new()?;
+        let mut flags = 0;
 
         // Apple-specific extension: close any open fds.
         #[cfg(target_os = "macos")]
-        attr.setflags(libc::POSIX_SPAWN_CLOEXEC_DEFAULT as _)?;
+        {
+            flags |= libc::POSIX_SPAWN_CLOEXEC_DEFAULT;
+        }
+
+        if own_group {
+            flags |= libc::POSIX_SPAWN_SETPGROUP;
+            attr.setpgroup(0)?;
+        }
+        if flags != 0 {
+            attr.setflags(flags as _)?;
+        }
 
         let mut actions = PosixSpawnFileActions::new()?;
-        // open /dev/null over stdin
-        actions.addopen(
-            0,
-            std::ffi::CStr::from_bytes_with_nul_unchecked(b"/dev/null\0"),
-            libc::O_RDONLY,
-            0,
-        )?;
-        // stdout/stderr => pipe
-        actions.adddup2(pipe[1], 1)?;
-        actions.adddup2(pipe[1], 2)?;
+        if !console {
+            // open /dev/null over stdin
+            actions.addopen(
+                0,
+                std::ffi::CStr::from_bytes_with_nul_unchecked(b"/dev/null\0"),
+                libc::O_RDONLY,
+                0,
+            )?;
+            // stdout/stderr => pipe
+            actions.adddup2(pipe[1], 1)?;
+            actions.adddup2(pipe[1], 2)?;
+        }
         // close pipe in child
         actions.addclose(pipe[0])?;
         actions.addclose(pipe[1])?;
@@ -208,6 +245,20 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         (pid, std::fs::File::from_raw_fd(pipe[0]))
     };
 
+    // Outside its own group, the command sees a ^C directly; inside it
+    // needs passing along.
+    let watchdog = timeout.map(|timeout| {
+        let target = if own_group { -pid } else { pid };
+        Watchdog::start(timeout, move |stop| {
+            let sig = match stop {
+                Stop::Interrupt if own_group => libc::SIGINT,
+                Stop::Interrupt => return,
+                Stop::Timeout => libc::SIGKILL,
+            };
+            unsafe { libc::kill(target, sig) };
+        })
+    });
+
     let mut buf: [u8; 4 << 10] = [0; 4 << 10];
     loop {
         let n = pipe.read(&mut buf)?;
@@ -218,13 +269,37 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
     }
     drop(pipe);
 
+    let timed_out = match watchdog {
+        Some(watchdog) => {
+            // Wait for the command to exit without reaping it, so the
+            // watchdog can still kill it meanwhile.
+            unsafe {
+                let mut info: libc::siginfo_t = std::mem::zeroed();
+                check_ret_errno(
+                    "waitid",
+                    libc::waitid(
+                        libc::P_PID,
+                        pid as libc::id_t,
+                        &mut info,
+                        libc::WEXITED | libc::WNOWAIT,
+                    ),
+                )?;
+            }
+            watchdog.finish()
+        }
+        None => false,
+    };
+
     let status = unsafe {
         let mut status: i32 = 0;
         check_ret_errno("waitpid", libc::waitpid(pid, &mut status, 0))?;
         std::process::ExitStatus::from_raw(status)
     };
 
-    let termination = if status.success() {
+    // The timeout may have fired just after the command exited by itself.
+    let termination = if timed_out && status.signal() == Some(libc::SIGKILL) {
+        Termination::TimedOut
+    } else if status.success() {
         Termination::Success
     } else if let Some(sig) = status.signal() {
         match sig {
@@ -241,5 +316,16 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         Termination::Failure
     };
 
-    Ok(termination)
+    Ok((termination, status.code()))
+}
+
+/// The system's one-minute load average, if it can be read.
+pub fn load_average() -> Option<f64> {
+    let mut avg = [0f64; 1];
+    let n = unsafe { libc::getloadavg(avg.as_mut_ptr(), 1) };
+    if n == 1 {
+        Some(avg[0])
+    } else {
+        None
+    }
 }
@@ -1,16 +1,17 @@
 //! Implements run_command on Windows using native Windows calls.
 //! See run_command comments for why.
 
-use crate::process::Termination;
+use crate::process::{Stop, Termination, Watchdog};
 use std::ffi::c_void;
 use std::io::Read;
 use std::os::windows::io::{FromRawHandle, OwnedHandle};
 use std::os::windows::prelude::AsRawHandle;
-use std::pin::{pin, Pin};
+use std::pin::Pin;
+use std::time::Duration;
 use windows_sys::Win32::{
     Foundation::*,
     Security::SECURITY_ATTRIBUTES,
-    System::{Console::*, Diagnostics::Debug::*, Pipes::CreatePipe, Threading::*},
+    System::{Console::*, Diagnostics::Debug::*, JobObjects::*, Pipes::CreatePipe, Threading::*},
 };
 
 fn get_error_string(err: u32) -> String {
@@ -47,6 +48,10 @@ macro_rules! win_bail {
     };
 }
 
+/// Exit code given to a command killed for running past its timeout.
+/// STATUS_TIMEOUT, which a command is unlikely to exit with itself.
+const TIMEOUT_EXIT_CODE: u32 = 0x0000_0102;
+
 /// Wrapper for PROCESS_INFORMATION that cleans up on Drop.
 struct ProcessInformation(PROCESS_INFORMATION);
 
@@ -84,6 +89,24 @@ impl Drop for ProcessInformation {
     }
 }
 
+/// Narrow handles down to those PROC_THREAD_ATTRIBUTE_HANDLE_LIST accepts,
+/// made inheritable.  The list rejects duplicates, such as stdout and stderr
+/// under `n2 > log 2>&1`, and null or otherwise unusable handles, which a
+/// process without a console may have as its std handles.
+fn inheritable_handles(handles: &[HANDLE]) -> Vec<HANDLE> {
+    let mut list = Vec::new();
+    for &handle in handles {
+        if handle == 0 || handle == INVALID_HANDLE_VALUE || list.contains(&handle) {
+            continue;
+        }
+        if unsafe { SetHandleInformation(handle, HANDLE_FLAG_INHERIT, HANDLE_FLAG_INHERIT) } == 0 {
+            continue;
+        }
+        list.push(handle);
+    }
+    list
+}
+
 /// Wrapper for PROC_THREAD_ATTRIBUTE_LIST.
 /// Per MSDN: attribute values "must persist until the attribute list is
 /// destroyed using the DeleteProcThreadAttributeList function", which is
@@ -153,7 +176,13 @@ impl<'a> Drop for ProcThreadAttributeList<'a> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Run a command, returning how it ended along with its exit code.
+pub fn run_command(
+    cmdline: &str,
+    console: bool,
+    timeout: Option<Duration>,
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, Option<i32>)> {
     // Don't want to run `cmd /c` since that limits cmd line length to 8192 bytes.
     // std::process::Command can't take a string and pass it through to CreateProcess unchanged,
     // so call that ourselves.
@@ -180,23 +209,58 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         )
     };
 
+    // A command with a timeout runs in a job object, so that whatever it
+    // spawned can be killed along with it.
+    let job = match timeout {
+        Some(_) => unsafe {
+            let job = CreateJobObjectA(std::ptr::null(), std::ptr::null());
+            if job == 0 {
+                win_bail!(CreateJobObjectA);
+            }
+            Some(OwnedHandle::from_raw_handle(job as *mut c_void))
+        },
+        None => None,
+    };
+
     let process_info = unsafe {
-        // TODO: Set this to just 0 for console pool jobs.
-        let process_flags = CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT;
+        // Console commands stay in our process group, to get ctl-c directly.
+        let mut process_flags = if console {
+            EXTENDED_STARTUPINFO_PRESENT
+        } else {
+            CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT
+        };
+        // Start suspended so the command can't spawn anything before it's
+        // in the job.
+        if job.is_some() {
+            process_flags |= CREATE_SUSPENDED;
+        }
 
         let mut startup_info = std::mem::zeroed::<STARTUPINFOEXA>();
         startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXA>() as u32;
         startup_info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
-        startup_info.StartupInfo.hStdInput = GetStdHandle(STD_INPUT_HANDLE);
+        let stdin = GetStdHandle(STD_INPUT_HANDLE);
         let raw_pipe_write = pipe_write.as_raw_handle() as isize;
-        startup_info.StartupInfo.hStdOutput = raw_pipe_write;
-        startup_info.StartupInfo.hStdError = raw_pipe_write;
+        // Console commands write to our own stdout/stderr, leaving the pipe
+        // to be closed unused.
+        let (stdout, stderr) = if console {
+            (
+                GetStdHandle(STD_OUTPUT_HANDLE),
+                GetStdHandle(STD_ERROR_HANDLE),
+            )
+        } else {
+            (raw_pipe_write, raw_pipe_write)
+        };
+        startup_info.StartupInfo.hStdInput = stdin;
+        startup_info.StartupInfo.hStdOutput = stdout;
+        startup_info.StartupInfo.hStdError = stderr;
 
         // Safely inherit in/out handles.
         // https://devblogs.microsoft.com/oldnewthing/20111216-00/?p=8873
-        let handles = pin!([startup_info.StartupInfo.hStdInput, raw_pipe_write]);
+        let handles = inheritable_handles(&[stdin, stdout, stderr]);
         let mut attrs = ProcThreadAttributeList::new(1)?;
-        attrs.inherit_handles(handles)?;
+        if !handles.is_empty() {
+            attrs.inherit_handles(Pin::new(&handles[..]))?;
+        }
         startup_info.lpAttributeList = attrs.as_mut_ptr();
 
         let mut process_info = ProcessInformation::new();
@@ -209,7 +273,7 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
             cmdline_nul.as_mut_ptr(),
             std::ptr::null_mut(),
             std::ptr::null_mut(),
-            /*inherit handles = */ TRUE,
+            /*inherit handles = */ if handles.is_empty() { FALSE } else { TRUE },
             process_flags,
             std::ptr::null_mut(),
             std::ptr::null_mut(),
@@ -232,9 +296,31 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         }
         drop(pipe_write);
 
+        if let Some(job) = &job {
+            if AssignProcessToJobObject(job.as_raw_handle() as HANDLE, process_info.hProcess) == 0 {
+                let err = windows_error("AssignProcessToJobObject");
+                TerminateProcess(process_info.hProcess, 1);
+                return Err(err);
+            }
+            if ResumeThread(process_info.hThread) == u32::MAX {
+                win_bail!(ResumeThread);
+            }
+        }
+
         process_info
     };
 
+    // Non-console commands are in their own process group and don't get
+    // ctl-c anyway, so only the timeout matters here.
+    let watchdog = job.as_ref().zip(timeout).map(|(job, timeout)| {
+        let job = job.as_raw_handle() as HANDLE;
+        Watchdog::start(timeout, move |stop| {
+            if let Stop::Timeout = stop {
+                unsafe { TerminateJobObject(job, TIMEOUT_EXIT_CODE) };
+            }
+        })
+    });
+
     let mut pipe = std::fs::File::from(pipe_read);
     let mut buf: [u8; 4 << 10] = [0; 4 << 10];
     loop {
@@ -257,14 +343,18 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         exit_code
     };
+    // The job handle outlives the watchdog, which is all that uses it.
+    let timed_out = watchdog.is_some_and(Watchdog::finish);
 
+    // The timeout may have fired just after the command exited by itself.
     let termination = match exit_code {
+        TIMEOUT_EXIT_CODE if timed_out => Termination::TimedOut,
         0 => Termination::Success,
         0xC000013A => Termination::Interrupted,
         _ => Termination::Failure,
     };
 
-    Ok(termination)
+    Ok((termination, Some(exit_code as i32)))
 }
 
 #[cfg(test)]
@@ -275,7 +365,9 @@ mod tests {
     #[test]
     fn run_echo() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        run_command("cmd /c echo hello", |buf| output.extend_from_slice(buf))?;
+        run_command("cmd /c echo hello", false, None, |buf| {
+            output.extend_from_slice(buf)
+        })?;
         assert_eq!(output, b"hello\r\n");
         Ok(())
     }
@@ -284,8 +376,8 @@ mod tests {
     #[test]
     fn empty_command() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err =
-            run_command("", |buf| output.extend_from_slice(buf)).expect_err("expected failure");
+        let err = run_command("", false, None, |buf| output.extend_from_slice(buf))
+            .expect_err("expected failure");
         assert!(err.to_string().contains("command is empty"));
         Ok(())
     }
@@ -294,9 +386,49 @@ mod tests {
     #[test]
     fn initial_space() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err = run_command(" cmd /c echo hello", |buf| output.extend_from_slice(buf))
-            .expect_err("expected failure");
+        let err = run_command(" cmd /c echo hello", false, None, |buf| {
+            output.extend_from_slice(buf)
+        })
+        .expect_err("expected failure");
         assert!(err.to_string().contains("command has leading whitespace"));
         Ok(())
     }
+
+    /// Handles the handle list would reject are dropped.
+    #[test]
+    fn inheritable_handles_filtered() {
+        assert!(inheritable_handles(&[0, INVALID_HANDLE_VALUE]).is_empty());
+        let stdout = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+        let handles = inheritable_handles(&[stdout, stdout]);
+        assert!(handles.len() <= 1);
+    }
+
+    /// A command past its timeout is killed, and one within it isn't.
+    #[test]
+    fn timeout() -> anyhow::Result<()> {
+        let (slow, _) = run_command(
+            "cmd /c ping -n 10 127.0.0.1",
+            false,
+            Some(Duration::from_millis(100)),
+            |_| {},
+        )?;
+        assert!(matches!(slow, Termination::TimedOut));
+        let (fast, code) = run_command(
+            "cmd /c exit 0",
+            false,
+            Some(Duration::from_secs(10)),
+            |_| {},
+        )?;
+        assert!(matches!(fast, Termination::Success));
+        assert_eq!(code, Some(0));
+        Ok(())
+    }
+
+    /// Console commands inherit whichever std handles are usable.
+    #[test]
+    fn run_console() -> anyhow::Result<()> {
+        let (termination, _) = run_command("cmd /c exit 0", true, None, |_| {})?;
+        assert!(matches!(termination, Termination::Success));
+        Ok(())
+    }
 }
@@ -2,7 +2,7 @@
 //! user.
 
 use crate::{
-    graph::Build, graph::BuildId, process::Termination, task::TaskResult, terminal,
+    graph::Build, graph::BuildId, log, process::Termination, task::TaskResult, terminal,
     work::BuildState, work::StateCounts,
 };
 use std::collections::VecDeque;
@@ -22,10 +22,28 @@ pub fn build_message(build: &Build) -> &str {
         .unwrap_or_else(|| build.cmdline.as_ref().unwrap())
 }
 
-/// Trait for build progress notifications.
+/// The full command a build runs, as printed by -v and -n.  A build with an
+/// rspfile is preceded by a heredoc writing the rspfile, so that the output
+/// can be run as a shell script.
+pub fn command_text(build: &Build) -> String {
+    let cmdline = build.cmdline.as_deref().unwrap();
+    match &build.rspfile {
+        Some(rspfile) => format!(
+            "cat > {} <<'N2_RSPFILE'\n{}\nN2_RSPFILE\n{}",
+            rspfile.path.display(),
+            rspfile.content,
+            cmdline
+        ),
+        None => cmdline.to_owned(),
+    }
+}
+
+/// Trait for build progress notifications, implemented by each way of
+/// displaying a build's progress.
 pub trait Progress {
-    /// Called as individual build tasks progress through build states.
-    fn update(&mut self, counts: &StateCounts);
+    /// Called as individual build tasks progress through build states, with
+    /// an estimate of the time left if there's history to base one on.
+    fn update(&mut self, counts: &StateCounts, eta: Option<Duration>);
 
     /// Called when a task starts.
     fn task_started(&mut self, id: BuildId, build: &Build);
@@ -63,29 +81,48 @@ pub struct DumbConsoleProgress {
     /// The id of the last command printed, used to avoid printing it twice
     /// when we have two updates from the same command in a row.
     last_started: Option<BuildId>,
+
+    /// A running console pool task, which owns the terminal.
+    console_task: Option<BuildId>,
+    /// Output held back while the console task runs, as Ninja does.
+    held: Vec<u8>,
 }
 
 impl DumbConsoleProgress {
     pub fn new(verbose: bool) -> Self {
         Self {
             verbose,
-            last_started: None,
+            ..Default::default()
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.console_task.is_some() {
+            self.held.extend_from_slice(bytes);
+        } else {
+            std::io::stdout().write_all(bytes).unwrap();
         }
     }
 }
 
 impl Progress for DumbConsoleProgress {
-    fn update(&mut self, _counts: &StateCounts) {
+    fn update(&mut self, _counts: &StateCounts, _eta: Option<Duration>) {
         // ignore
     }
 
     fn task_started(&mut self, id: BuildId, build: &Build) {
-        self.log(if self.verbose {
-            build.cmdline.as_ref().unwrap()
+        if log::quiet() {
+            return;
+        }
+        if self.verbose {
+            self.log(&command_text(build));
         } else {
-            build_message(build)
-        });
+            self.log(build_message(build));
+        }
         self.last_started = Some(id);
+        if build.is_console() {
+            self.console_task = Some(id);
+        }
     }
 
     fn task_output(&mut self, _id: BuildId, _line: Vec<u8>) {
@@ -93,7 +130,13 @@ impl Progress for DumbConsoleProgress {
     }
 
     fn task_finished(&mut self, id: BuildId, build: &Build, result: &TaskResult) {
+        if self.console_task == Some(id) {
+            self.console_task = None;
+            let held = std::mem::take(&mut self.held);
+            self.write(&held);
+        }
         match result.termination {
+            Termination::Success if log::quiet() => return,
             Termination::Success => {
                 if result.output.is_empty() || self.last_started == Some(id) {
                     // Output is empty, or we just printed the command, don't print it again.
@@ -103,14 +146,15 @@ impl Progress for DumbConsoleProgress {
             }
             Termination::Interrupted => self.log(&format!("interrupted: {}", build_message(build))),
             Termination::Failure => self.log(&format!("failed: {}", build_message(build))),
+            Termination::TimedOut => self.log(&format!("timed out: {}", build_message(build))),
         };
         if !result.output.is_empty() {
-            std::io::stdout().write_all(&result.output).unwrap();
+            self.write(&result.output);
         }
     }
 
     fn log(&mut self, msg: &str) {
-        println!("{}", msg);
+        self.write(format!("{}\n", msg).as_bytes());
     }
 }
 
@@ -135,7 +179,10 @@ impl FancyConsoleProgress {
             dirty: false,
             dirty_cond: dirty_cond.clone(),
             counts: StateCounts::default(),
+            eta: None,
             tasks: VecDeque::new(),
+            console_task: None,
+            held: Vec::new(),
             verbose,
         }));
 
@@ -174,8 +221,8 @@ impl FancyConsoleProgress {
 }
 
 impl Progress for FancyConsoleProgress {
-    fn update(&mut self, counts: &StateCounts) {
-        self.state.lock().unwrap().update(counts);
+    fn update(&mut self, counts: &StateCounts, eta: Option<Duration>) {
+        self.state.lock().unwrap().update(counts, eta);
     }
 
     fn task_started(&mut self, id: BuildId, build: &Build) {
@@ -208,9 +255,16 @@ struct FancyState {
 
     /// Counts of tasks in each state.  TODO: pass this as function args?
     counts: StateCounts,
+    /// Estimated time until the build finishes, and when it was estimated.
+    eta: Option<(Duration, Instant)>,
     /// Build tasks that are currently executing.
     /// Pushed to as tasks are started, so it's always in order of age.
     tasks: VecDeque<Task>,
+    /// A running console pool task, which owns the terminal: no progress is
+    /// printed until it finishes.
+    console_task: Option<BuildId>,
+    /// Output held back while the console task runs, as Ninja does.
+    held: Vec<u8>,
     /// Whether to print command lines of started programs.
     verbose: bool,
 }
@@ -221,16 +275,24 @@ impl FancyState {
         self.dirty_cond.notify_one();
     }
 
-    fn update(&mut self, counts: &StateCounts) {
+    fn update(&mut self, counts: &StateCounts, eta: Option<Duration>) {
         self.counts = counts.clone();
+        self.eta = eta.map(|eta| (eta, Instant::now()));
         self.dirty();
     }
 
     fn task_started(&mut self, id: BuildId, build: &Build) {
         if self.verbose {
-            self.log(build.cmdline.as_ref().unwrap());
+            self.log(&command_text(build));
         }
         let message = build_message(build);
+        if build.is_console() {
+            // Clear the progress, and say what's about to take it over.
+            if !self.verbose {
+                self.log(message);
+            }
+            self.console_task = Some(id);
+        }
         self.tasks.push_back(Task {
             id,
             start: Instant::now(),
@@ -249,6 +311,11 @@ impl FancyState {
     fn task_finished(&mut self, id: BuildId, build: &Build, result: &TaskResult) {
         self.tasks
             .remove(self.tasks.iter().position(|t| t.id == id).unwrap());
+        if self.console_task == Some(id) {
+            self.console_task = None;
+            let held = std::mem::take(&mut self.held);
+            self.write(&held);
+        }
         match result.termination {
             Termination::Success => {
                 if result.output.is_empty() {
@@ -259,16 +326,25 @@ impl FancyState {
             }
             Termination::Interrupted => self.log(&format!("interrupted: {}", build_message(build))),
             Termination::Failure => self.log(&format!("failed: {}", build_message(build))),
+            Termination::TimedOut => self.log(&format!("timed out: {}", build_message(build))),
         };
         if !result.output.is_empty() {
-            std::io::stdout().write_all(&result.output).unwrap();
+            self.write(&result.output);
         }
         self.dirty();
     }
 
     fn log(&mut self, msg: &str) {
+        self.write(format!("{}\n", msg).as_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.console_task.is_some() {
+            self.held.extend_from_slice(bytes);
+            return;
+        }
         self.clear_progress();
-        println!("{}", msg);
+        std::io::stdout().write_all(bytes).unwrap();
         self.dirty();
     }
 
@@ -285,30 +361,21 @@ impl FancyState {
     }
 
     fn print_progress(&mut self) {
-        self.clear_progress();
-        let failed = self.counts.get(BuildState::Failed);
-        let mut progress_line = format!(
-            "[{}] {}/{} done, ",
-            progress_bar(&self.counts, 40),
-            self.counts.get(BuildState::Done) + failed,
-            self.counts.total()
-        );
-        if failed > 0 {
-            progress_line.push_str(&format!("{} failed, ", failed));
+        if self.console_task.is_some() {
+            return;
         }
-        progress_line.push_str(&format!(
-            "{}/{} running",
-            self.tasks.len(),
-            self.counts.get(BuildState::Queued)
-                + self.counts.get(BuildState::Running)
-                + self.counts.get(BuildState::Ready),
-        ));
-        println!("{}", progress_line);
+        self.clear_progress();
+        let now = Instant::now();
+        // Count down between updates, which may be far apart while long
+        // commands run.
+        let eta = self
+            .eta
+            .map(|(eta, at)| eta.saturating_sub(now.duration_since(at)));
+        println!("{}", progress_line(&self.counts, self.tasks.len(), eta));
         let mut lines = 1;
 
         let max_cols = terminal::get_cols().unwrap_or(80);
         let max_tasks = 8;
-        let now = Instant::now();
         for task in self.tasks.iter().take(max_tasks) {
             let delta = now.duration_since(task.start).as_secs() as usize;
             println!("{}", task_message(&task.message, delta, max_cols));
@@ -332,6 +399,44 @@ impl FancyState {
     }
 }
 
+/// The first line of the fancy progress display: a progress bar, the counts
+/// of builds done and running, and the estimated time left, if known.
+fn progress_line(counts: &StateCounts, running: usize, eta: Option<Duration>) -> String {
+    let failed = counts.get(BuildState::Failed);
+    let mut line = format!(
+        "[{}] {}/{} done, ",
+        progress_bar(counts, 40),
+        counts.get(BuildState::Done) + failed,
+        counts.total()
+    );
+    if failed > 0 {
+        line.push_str(&format!("{} failed, ", failed));
+    }
+    line.push_str(&format!(
+        "{}/{} running",
+        running,
+        counts.get(BuildState::Queued)
+            + counts.get(BuildState::Running)
+            + counts.get(BuildState::Ready),
+    ));
+    if let Some(eta) = eta {
+        line.push_str(&format!(", ETA {}", format_eta(eta)));
+    }
+    line
+}
+
+/// Format a duration briefly, to the second, as in "1h02m", "3m05s" or "9s".
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
 /// Format a task's status message to optionally include how long it has been running
 /// and also to fit within a maximum number of terminal columns.
 fn task_message(message: &str, seconds: usize, max_cols: usize) -> String {
@@ -429,6 +534,27 @@ mod tests {
         assert_eq!(progress_bar(&counts, 10), "=---------");
     }
 
+    #[test]
+    fn progress_line_rendering() {
+        let mut counts = StateCounts::default();
+        counts.add(BuildState::Done, 3);
+        counts.add(BuildState::Running, 2);
+        counts.add(BuildState::Want, 5);
+        assert_eq!(
+            progress_line(&counts, 2, None),
+            "[============--------                    ] 3/10 done, 2/2 running"
+        );
+        assert!(progress_line(&counts, 2, Some(Duration::from_secs(65)))
+            .ends_with("2/2 running, ETA 1m05s"));
+    }
+
+    #[test]
+    fn eta_rendering() {
+        assert_eq!(format_eta(Duration::from_millis(9500)), "9s");
+        assert_eq!(format_eta(Duration::from_secs(185)), "3m05s");
+        assert_eq!(format_eta(Duration::from_secs(3720)), "1h02m");
+    }
+
     #[test]
     fn task_rendering() {
         assert_eq!(task_message("building foo.o", 0, 80), "building foo.o");
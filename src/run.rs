@@ -1,68 +1,76 @@
 use crate::{
-    load,
+    events,
+    graph::{FileId, Symlinks},
+    load, log,
     progress::{DumbConsoleProgress, FancyConsoleProgress, Progress},
-    terminal, trace, work,
+    signal, stats, terminal, tools, trace, watch, work,
 };
 use anyhow::anyhow;
 use std::path::Path;
 
-fn build(
-    options: work::Options,
-    build_filename: String,
-    targets: Vec<String>,
+/// Flags for build() that the build itself doesn't need to know about.
+struct BuildFlags {
     verbose: bool,
-) -> anyhow::Result<Option<usize>> {
-    let (mut dumb_console, mut fancy_console);
-    let progress: &mut dyn Progress = if terminal::use_fancy() {
-        fancy_console = FancyConsoleProgress::new(verbose);
-        &mut fancy_console
-    } else {
-        dumb_console = DumbConsoleProgress::new(verbose);
-        &mut dumb_console
-    };
+    /// Print each command as it starts, rather than an updating progress
+    /// display, even on a terminal.
+    no_progress: bool,
+    stats: Option<stats::Format>,
+    events_path: Option<String>,
+    /// After building, wait for source files to change and build again.
+    watch: bool,
+}
+
+/// The line to print after a build, given its result as from Work::run().
+fn summary(tasks: Option<usize>, dry_run: bool) -> Option<String> {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    match tasks {
+        // Don't print any summary, the failing task is enough info.
+        None => None,
+        // Special case: don't print numbers when no work done.
+        Some(0) => Some("n2: no work to do".to_owned()),
+        Some(n) if dry_run => Some(format!("n2: would run {} task{}", n, plural(n))),
+        Some(n) => Some(format!("n2: ran {} task{}, now up to date", n, plural(n))),
+    }
+}
 
-    let mut state = trace::scope("load::read", || load::read(&build_filename))?;
+fn print_warnings(warnings: &[log::Warning]) {
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+}
+
+/// Start a Work on the graph of a freshly loaded State.  The rest of the
+/// State stays behind, for the --watch loop to reload() it later.
+fn new_work<'a>(
+    state: &mut load::State,
+    options: &work::Options,
+    progress: &'a mut dyn Progress,
+    events: Option<&events::Writer>,
+) -> anyhow::Result<work::Work<'a>> {
     let mut work = work::Work::new(
-        state.graph,
-        state.hashes,
-        state.db,
-        &options,
+        std::mem::take(&mut state.graph),
+        std::mem::take(&mut state.hashes),
+        state.db.take(),
+        state.ninja_log.take(),
+        options,
         progress,
-        state.pools,
+        std::mem::take(&mut state.pools),
     );
-
-    let mut tasks_finished = 0;
-
-    // Attempt to rebuild build.ninja.
-    let build_file_target = work.lookup(&build_filename);
-    if let Some(target) = build_file_target {
-        work.want_file(target)?;
-        match trace::scope("work.run", || work.run())? {
-            None => return Ok(None),
-            Some(0) => {
-                // build.ninja already up to date.
-                // TODO: this logic is not right in the case where a build has
-                // a step that doesn't touch build.ninja.  We should instead
-                // verify the specific FileId was updated.
-            }
-            Some(n) => {
-                // Regenerated build.ninja; start over.
-                tasks_finished = n;
-                state = trace::scope("load::read", || load::read(&build_filename))?;
-                work = work::Work::new(
-                    state.graph,
-                    state.hashes,
-                    state.db,
-                    &options,
-                    progress,
-                    state.pools,
-                );
-            }
-        }
+    if let Some(events) = events {
+        work.set_events(events.try_clone()?);
     }
+    Ok(work)
+}
 
+/// Mark the requested targets, or the manifest's defaults, as wanted.
+fn want_targets(
+    work: &mut work::Work,
+    targets: &[String],
+    default: &[FileId],
+    build_file_target: Option<FileId>,
+) -> anyhow::Result<()> {
     if !targets.is_empty() {
-        for name in &targets {
+        for name in targets {
             let target = work
                 .lookup(name)
                 .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
@@ -72,17 +80,162 @@ fn build(
             }
             work.want_file(target)?;
         }
-    } else if !state.default.is_empty() {
-        for target in state.default {
+    } else if !default.is_empty() {
+        for &target in default {
             work.want_file(target)?;
         }
     } else {
         work.want_every_file(build_file_target)?;
     }
+    Ok(())
+}
 
-    let tasks = trace::scope("work.run", || work.run())?;
-    // Include any tasks from initial build in final count of steps.
-    Ok(tasks.map(|n| n + tasks_finished))
+fn build(
+    load_options: load::Options,
+    options: work::Options,
+    build_filename: String,
+    targets: Vec<String>,
+    flags: BuildFlags,
+) -> anyhow::Result<Option<usize>> {
+    let (mut dumb_console, mut fancy_console);
+    let fancy = !flags.no_progress && !log::quiet() && terminal::use_fancy();
+    let progress: &mut dyn Progress = if fancy {
+        fancy_console = FancyConsoleProgress::new(flags.verbose);
+        &mut fancy_console
+    } else {
+        dumb_console = DumbConsoleProgress::new(flags.verbose);
+        &mut dumb_console
+    };
+
+    let events = match &flags.events_path {
+        Some(path) => Some(
+            events::Writer::create(Path::new(path))
+                .map_err(|err| anyhow!("create {}: {}", path, err))?,
+        ),
+        None => None,
+    };
+
+    // With --watch, a change to the manifest brings us back here to load it
+    // again from scratch.
+    'load: loop {
+        let mut state = trace::scope("load::read", || load::read(&build_filename, &load_options))?;
+        print_warnings(&state.warnings);
+        let mut manifests = state.manifest_files();
+        let mut work = new_work(&mut state, &options, progress, events.as_ref())?;
+
+        let mut tasks_finished = 0;
+
+        // Attempt to rebuild build.ninja.
+        let mut build_file_target = work.lookup(&build_filename);
+        if let Some(target) = build_file_target {
+            work.want_file(target)?;
+            match trace::scope("work.run", || work.run())? {
+                None => return Ok(None),
+                Some(0) => {
+                    // build.ninja already up to date.
+                    // TODO: this logic is not right in the case where a build has
+                    // a step that doesn't touch build.ninja.  We should instead
+                    // verify the specific FileId was updated.
+                }
+                Some(n) => {
+                    // Regenerated build.ninja; start over.
+                    tasks_finished = n;
+                    state =
+                        trace::scope("load::read", || load::read(&build_filename, &load_options))?;
+                    print_warnings(&state.warnings);
+                    manifests = state.manifest_files();
+                    work = new_work(&mut state, &options, progress, events.as_ref())?;
+                    build_file_target = work.lookup(&build_filename);
+                }
+            }
+        }
+
+        want_targets(&mut work, &targets, &state.default, build_file_target)?;
+        let tasks = trace::scope("work.run", || work.run())?;
+        if let Some(format) = flags.stats {
+            let stats = work.stats().format(format);
+            work.progress.log(&stats);
+        }
+        // Include any tasks from initial build in final count of steps.
+        let mut tasks = tasks.map(|n| n + tasks_finished);
+        if !flags.watch {
+            return Ok(tasks);
+        }
+
+        // The graph and what's known of its files carry over from one build
+        // to the next; only the files that changed are stat()ed again.
+        let symlinks = if options.track_symlinks {
+            Symlinks::Track
+        } else {
+            Symlinks::Follow
+        };
+        let mut watcher = watch::Watcher::new(work.graph(), &manifests, symlinks);
+        loop {
+            if signal::was_interrupted() {
+                return Ok(tasks);
+            }
+            // Builds may have discovered new dependencies, and a reload may
+            // have brought in new files.
+            watcher.add(work.graph(), &work.graph().source_files());
+            watcher.add(work.graph(), &manifests);
+            if !log::quiet() {
+                if let Some(summary) = summary(tasks, options.dry_run) {
+                    work.progress.log(&summary);
+                }
+                work.progress.log("n2: watching for changes (^C to stop)");
+            }
+            let changed = match watcher.wait() {
+                Some(changed) => changed,
+                None => return Ok(tasks),
+            };
+            if changed.iter().any(|id| manifests.contains(id)) {
+                // Reparse just the subninjas that changed, if that's all.
+                let reload = work.update_graph(|graph, hashes| {
+                    std::mem::swap(graph, &mut state.graph);
+                    std::mem::swap(hashes, &mut state.hashes);
+                    let reload = state.reload(&changed);
+                    std::mem::swap(graph, &mut state.graph);
+                    std::mem::swap(hashes, &mut state.hashes);
+                    reload
+                });
+                match reload {
+                    Ok(load::Reload::Unchanged) => {}
+                    Ok(load::Reload::Partial) => {
+                        print_warnings(&state.warnings);
+                        manifests = state.manifest_files();
+                    }
+                    // A reload that failed partway leaves a mix of old and
+                    // new, so read everything again, which reports the
+                    // error if it's still there.
+                    Ok(load::Reload::Full) | Err(_) => {
+                        if !log::quiet() {
+                            work.progress.log("n2: manifest changed, reloading");
+                        }
+                        continue 'load;
+                    }
+                }
+            }
+            work.reset(&changed);
+            if let Some(target) = build_file_target {
+                // The manifest's generator may depend on the changed files.
+                work.want_file(target)?;
+                match trace::scope("work.run", || work.run())? {
+                    Some(0) => {}
+                    Some(_) => continue 'load,
+                    None => {
+                        tasks = None;
+                        continue;
+                    }
+                }
+            }
+            want_targets(&mut work, &targets, &state.default, build_file_target)?;
+            tasks = trace::scope("work.run", || work.run())?;
+            if let Some(format) = flags.stats {
+                let stats = work.stats().format(format);
+                work.progress.log(&stats);
+            }
+        }
+    }
 }
 
 fn default_parallelism() -> anyhow::Result<usize> {
@@ -99,8 +252,8 @@ struct Args {
     #[argh(option, short = 'C')]
     chdir: Option<String>,
 
-    /// input build file [default=build.ninja]
-    #[argh(option, short = 'f', default = "(\"build.ninja\".into())")]
+    /// input build file, or - for stdin [default=build.ninja]
+    #[argh(option, short = 'f', default = "\"build.ninja\".into()")]
     build_file: String,
 
     /// debugging tools
@@ -115,6 +268,10 @@ struct Args {
     #[argh(option, short = 'j')] // tododefault_parallelism()")]
     parallelism: Option<usize>,
 
+    /// don't start new jobs while the load average is above N (Unix only)
+    #[argh(option, short = 'l')]
+    load_average: Option<f64>,
+
     /// keep going until at least N failures (0 means infinity) [default=1]
     #[argh(option, short = 'k', default = "1")]
     keep_going: usize,
@@ -132,18 +289,181 @@ struct Args {
     #[argh(switch, short = 'v')]
     verbose: bool,
 
+    /// print each command as it starts instead of a progress display
+    #[argh(switch)]
+    no_progress: bool,
+
+    /// print only failures, without progress, warnings or a summary
+    #[argh(switch)]
+    quiet: bool,
+
+    /// dry run: print the commands that would run, without running them
+    #[argh(switch, short = 'n')]
+    dry_run: bool,
+
+    /// don't read or write the .n2_db; builds always run and aren't recorded
+    #[argh(switch)]
+    no_db: bool,
+
+    /// print -t targets output as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// don't rebuild when changed inputs have the same contents as last time
+    #[argh(switch)]
+    content_hash: bool,
+
+    /// also write a Ninja-format .ninja_log for tools that read Ninja's log
+    #[argh(switch)]
+    ninja_log: bool,
+
+    /// treat repointing a symlink input as a change, even to an older target
+    #[argh(switch)]
+    track_symlinks: bool,
+
+    /// resolve ".." in paths against symlinked directories, as the OS does
+    #[argh(switch)]
+    resolve_symlinks: bool,
+
+    /// expand variables the manifest doesn't define from the environment
+    #[argh(switch)]
+    process_env: bool,
+
+    /// fail to load a manifest with outputs outside the tree
+    #[argh(switch)]
+    confine_outputs: bool,
+
+    /// expand * and ? in include paths
+    #[argh(switch)]
+    include_globs: bool,
+
+    /// allow `# comments` after statements and bindings
+    #[argh(switch)]
+    inline_comments: bool,
+
+    /// get source file mtimes by listing their directories (experimental)
+    #[argh(switch)]
+    stat_by_directory: bool,
+
+    /// print a summary of the build, with the slowest commands, at the end
+    #[argh(switch)]
+    stats: bool,
+
+    /// like --stats, but print the summary as JSON
+    #[argh(switch)]
+    stats_json: bool,
+
+    /// hold back builds while their `mem = N` hints would exceed this many MB
+    #[argh(option)]
+    max_memory: Option<u64>,
+
+    /// kill commands running longer than this many seconds, failing their
+    /// builds; a build's `timeout = N` binding overrides it
+    #[argh(option)]
+    timeout: Option<u64>,
+
+    /// keep the outputs of failed builds rather than deleting them
+    #[argh(switch)]
+    no_delete_on_failure: bool,
+
+    /// after building, rebuild whenever a source file changes, until ^C
+    #[argh(switch)]
+    watch: bool,
+
+    /// write newline-delimited JSON build events to this path, for IDEs
+    #[argh(option)]
+    events_json: Option<String>,
+
+    /// make warnings errors or ignore them, as dupbuild=err,rspfile=off
+    #[argh(option, short = 'w')]
+    warning: Vec<String>,
+
+    /// override a top-level manifest variable, as key=value
+    #[argh(option, short = 'D')]
+    define: Vec<String>,
+
     /// targets to build
     #[argh(positional)]
     targets: Vec<String>,
 }
 
+/// Split the contents of a response file into arguments: whitespace separates
+/// them, and single or double quotes group text including whitespace.  There
+/// is no escaping, so that Windows paths can be written as is.
+fn split_response_file(text: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => arg.get_or_insert_with(String::new).push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            None if c.is_whitespace() => args.extend(arg.take()),
+            None => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        anyhow::bail!("unterminated {} quote", q);
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Parse the command line, for which a first argument of `@file` stands for
+/// the arguments read from the file, to get around limits on command line
+/// length.  Like argh::from_env(), this exits on bad arguments or --help.
+fn parse_args() -> anyhow::Result<Args> {
+    let mut args: Vec<String> = std::env::args_os()
+        .map(|arg| {
+            arg.into_string()
+                .map_err(|arg| anyhow!("invalid utf8: {}", arg.to_string_lossy()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    if args.is_empty() {
+        anyhow::bail!("no program name, argv is empty");
+    }
+    if let Some(path) = args.get(1).and_then(|arg| arg.strip_prefix('@')) {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| anyhow!("read {}: {}", path, err))?;
+        let from_file = split_response_file(&text).map_err(|err| anyhow!("{}: {}", path, err))?;
+        args.splice(1..2, from_file);
+    }
+    let cmd = Path::new(&args[0])
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&args[0]);
+    let strs: Vec<&str> = args[1..].iter().map(|arg| arg.as_str()).collect();
+    let early_exit = match <Args as argh::FromArgs>::from_args(&[cmd], &strs) {
+        Ok(args) => return Ok(args),
+        Err(early_exit) => early_exit,
+    };
+    std::process::exit(match early_exit.status {
+        Ok(()) => {
+            println!("{}", early_exit.output);
+            0
+        }
+        Err(()) => {
+            eprintln!(
+                "{}\nRun {} --help for more information.",
+                early_exit.output, cmd
+            );
+            1
+        }
+    })
+}
+
 fn run_impl() -> anyhow::Result<i32> {
     let mut fake_ninja_compat = Path::new(&std::env::args().next().unwrap())
         .file_name()
         .unwrap()
         == std::ffi::OsStr::new(&format!("ninja{}", std::env::consts::EXE_SUFFIX));
 
-    let args: Args = argh::from_env();
+    let args = parse_args()?;
+    log::set_quiet(args.quiet);
 
     let mut options = work::Options {
         parallelism: match args.parallelism {
@@ -151,9 +471,50 @@ fn run_impl() -> anyhow::Result<i32> {
             None => default_parallelism()?,
         },
         failures_left: Some(args.keep_going).filter(|&n| n > 0),
-        explain: false,
-        adopt: false,
+        dry_run: args.dry_run,
+        content_hash: args.content_hash,
+        track_symlinks: args.track_symlinks,
+        resolve_symlinks: args.resolve_symlinks,
+        max_memory: args.max_memory,
+        max_load_average: args.load_average,
+        timeout: args.timeout.map(std::time::Duration::from_secs),
+        delete_failed_outputs: !args.no_delete_on_failure,
+        stat_by_directory: args.stat_by_directory,
+        ..Default::default()
+    };
+
+    let mut load_options = load::Options {
+        no_db: args.no_db,
+        read_only_db: args.dry_run,
+        ninja_log: args.ninja_log,
+        resolve_symlinks: args.resolve_symlinks,
+        process_env: args.process_env,
+        confine_outputs: args.confine_outputs,
+        include_globs: args.include_globs,
+        inline_comments: args.inline_comments,
+        ..load::Options::default()
     };
+    for flag in &args.warning {
+        if flag == "list" {
+            println!("warning flags, each =err, =warn or =off:");
+            println!("  dupbuild       an output repeated within one build");
+            println!("  rspfile        builds sharing an rspfile");
+            println!("  nocommand      a rule with no command");
+            println!("  phonycycle     a phony build naming itself as an input");
+            println!("  depfiletarget  a depfile naming a file the build doesn't output");
+            return Ok(1);
+        }
+        load_options.warnings.parse_flag(flag)?;
+    }
+    options.warnings = load_options.warnings.clone();
+    for define in &args.define {
+        let (key, val) = define
+            .split_once('=')
+            .ok_or_else(|| anyhow!("-D expects key=value, got {:?}", define))?;
+        load_options
+            .var_overrides
+            .insert(key.to_owned(), val.to_owned());
+    }
 
     if let Some(dir) = args.chdir {
         let dir = Path::new(&dir);
@@ -190,17 +551,70 @@ fn run_impl() -> anyhow::Result<i32> {
         match tool.as_str() {
             "list" => {
                 println!("subcommands:");
-                println!("  (none yet, but see README if you're looking here trying to get CMake to work)");
+                println!("  clean     remove built files, except those of generator rules");
+                println!("  compdb    dump a compile_commands.json for the given rules (or all)");
+                println!("  deps      show dependencies discovered by previous builds");
+                println!("  graph     output graphviz dot file for targets");
+                println!("  query     show inputs/outputs for a path");
+                println!("  recompact rewrite .n2_db, dropping records no longer needed");
+                println!("  targets   list targets by 'depth [N]', 'all', or 'rule [NAME]'");
+                println!("  stats     print build graph size and approximate memory use");
+                println!("  why       show the chain of out of date builds behind a target");
                 return Ok(1);
             }
-            "compdb" if fake_ninja_compat => {
-                // meson wants to invoke this tool.
-                return Ok(0); // do nothing; TODO
+            "clean" => {
+                return tools::clean::tool_clean(&args.build_file, &load_options, &args.targets);
+            }
+            "deps" => {
+                return tools::deps::tool_deps(&args.build_file, &load_options, &args.targets);
+            }
+            "graph" => {
+                return tools::graph::tool_graph(&args.build_file, &load_options, &args.targets);
+            }
+            "query" => {
+                return tools::query::tool_query(&args.build_file, &load_options, &args.targets);
+            }
+            "targets" => {
+                return tools::targets::tool_targets(
+                    &args.build_file,
+                    &load_options,
+                    args.json,
+                    &args.targets,
+                );
+            }
+            "stats" => {
+                let state = load::read(&args.build_file, &load_options)?;
+                println!("builds: {}", state.graph.builds.all_ids().count());
+                println!("files: {}", state.graph.files.all_ids().count());
+                let histogram = state.graph.command_histogram();
+                let shared: Vec<usize> = histogram.values().copied().filter(|&n| n > 1).collect();
+                println!(
+                    "commands: {} distinct, {} shared by {} builds",
+                    histogram.len(),
+                    shared.len(),
+                    shared.iter().sum::<usize>()
+                );
+                println!("memory: ~{} KiB", state.graph.estimated_memory() / 1024);
+                return Ok(0);
+            }
+            "why" => {
+                return tools::why::tool_why(
+                    &args.build_file,
+                    &load_options,
+                    &options,
+                    &args.targets,
+                );
+            }
+            "compdb" => {
+                return tools::compdb::tool_compdb(&args.build_file, &load_options, &args.targets);
             }
             "recompact" if fake_ninja_compat => {
                 // CMake unconditionally invokes this tool, yuck.
                 return Ok(0); // do nothing
             }
+            "recompact" => {
+                return tools::recompact::tool_recompact(&args.build_file, &load_options);
+            }
             "restat" if fake_ninja_compat => {
                 // CMake invokes this after generating build files; mark build
                 // targets as up to date by running the build with "adopt" flag
@@ -213,25 +627,33 @@ fn run_impl() -> anyhow::Result<i32> {
         }
     }
 
-    match build(options, args.build_file, args.targets, args.verbose)? {
-        None => {
-            // Don't print any summary, the failing task is enough info.
-            return Ok(1);
-        }
-        Some(0) => {
-            // Special case: don't print numbers when no work done.
-            println!("n2: no work to do");
-        }
-        Some(n) => {
-            println!(
-                "n2: ran {} task{}, now up to date",
-                n,
-                if n == 1 { "" } else { "s" }
-            );
+    let dry_run = options.dry_run;
+    let watch = args.watch;
+    let tasks = build(
+        load_options,
+        options,
+        args.build_file,
+        args.targets,
+        BuildFlags {
+            verbose: args.verbose,
+            no_progress: args.no_progress,
+            stats: if args.stats_json {
+                Some(stats::Format::Json)
+            } else if args.stats {
+                Some(stats::Format::Text)
+            } else {
+                None
+            },
+            events_path: args.events_json,
+            watch,
+        },
+    )?;
+    if !watch && !log::quiet() {
+        if let Some(summary) = summary(tasks, dry_run) {
+            println!("{}", summary);
         }
     }
-
-    Ok(0)
+    Ok(if tasks.is_some() { 0 } else { 1 })
 }
 
 pub fn run() -> anyhow::Result<i32> {
@@ -239,3 +661,22 @@ pub fn run() -> anyhow::Result<i32> {
     trace::close();
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_file() -> anyhow::Result<()> {
+        assert_eq!(
+            split_response_file("-j 4\n  out\t'a b' \"c'd\"e C:\\dir\\f ''\n")?,
+            vec!["-j", "4", "out", "a b", "c'de", "C:\\dir\\f", ""]
+        );
+        assert!(split_response_file("")?.is_empty());
+        assert_eq!(
+            split_response_file("a \"b").err().unwrap().to_string(),
+            "unterminated \" quote"
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,40 @@
 //! Scans an input string (source file) character by character.
 
-use std::{io::Read, path::Path};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ParseError {
-    msg: String,
-    ofs: usize,
+    pub msg: String,
+    /// Byte offset of the error within the input.
+    pub ofs: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in UTF-8 characters rather than bytes.
+    pub col: usize,
+    /// The file being parsed, if known; see with_filename().
+    pub filename: Option<PathBuf>,
+}
+
+impl ParseError {
+    pub fn with_filename(mut self, filename: &Path) -> Self {
+        self.filename = Some(filename.to_owned());
+        self
+    }
 }
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(filename) = &self.filename {
+            write!(f, "{}:", filename.display())?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.col, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
 pub type ParseResult<T> = Result<T, ParseError>;
 
 pub struct Scanner<'a> {
@@ -15,14 +43,18 @@ pub struct Scanner<'a> {
     pub line: usize,
 }
 
+/// The UTF-8 byte order mark, which some editors write at the start of files.
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
 impl<'a> Scanner<'a> {
+    /// Scan `buf`, skipping any leading byte order mark.
     pub fn new(buf: &'a [u8]) -> Self {
         if !buf.ends_with(b"\0") {
             panic!("Scanner requires nul-terminated buf");
         }
         Scanner {
             buf,
-            ofs: 0,
+            ofs: if buf.starts_with(BOM) { BOM.len() } else { 0 },
             line: 1,
         }
     }
@@ -30,18 +62,20 @@ impl<'a> Scanner<'a> {
     pub fn slice(&self, start: usize, end: usize) -> &'a str {
         unsafe { std::str::from_utf8_unchecked(self.buf.get_unchecked(start..end)) }
     }
+    /// The next character, or '\0' at the end of the buffer, including once
+    /// the trailing nul itself has been read.
     pub fn peek(&self) -> char {
-        unsafe { *self.buf.get_unchecked(self.ofs) as char }
+        match self.buf.get(self.ofs) {
+            Some(&c) => c as char,
+            None => '\0',
+        }
     }
     pub fn peek_newline(&self) -> bool {
-        if self.peek() == '\n' {
-            return true;
-        }
-        if self.ofs >= self.buf.len() - 1 {
-            return false;
+        match self.peek() {
+            '\n' => true,
+            '\r' => self.buf.get(self.ofs + 1) == Some(&b'\n'),
+            _ => false,
         }
-        let peek2 = unsafe { *self.buf.get_unchecked(self.ofs + 1) as char };
-        self.peek() == '\r' && peek2 == '\n'
     }
     pub fn next(&mut self) {
         if self.peek() == '\n' {
@@ -74,8 +108,9 @@ impl<'a> Scanner<'a> {
         false
     }
 
+    /// Skip spaces and tabs.
     pub fn skip_spaces(&mut self) {
-        while self.skip(' ') {}
+        while self.skip(' ') || self.skip('\t') {}
     }
 
     pub fn expect(&mut self, ch: char) -> ParseResult<()> {
@@ -88,48 +123,69 @@ impl<'a> Scanner<'a> {
     }
 
     pub fn parse_error<T, S: Into<String>>(&self, msg: S) -> ParseResult<T> {
+        let line_start = self.line_start(self.ofs);
         Err(ParseError {
             msg: msg.into(),
             ofs: self.ofs,
+            line: self.line,
+            col: self.slice(line_start, self.ofs).chars().count() + 1,
+            filename: None,
         })
     }
 
+    /// Find the offset of the start of the line containing ofs, which for
+    /// the first line is after any byte order mark.
+    fn line_start(&self, ofs: usize) -> usize {
+        let first = if self.buf.starts_with(BOM) {
+            BOM.len()
+        } else {
+            0
+        };
+        self.buf[..ofs]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(first, |i| i + 1)
+    }
+
     pub fn format_parse_error(&self, filename: &Path, err: ParseError) -> String {
-        let mut ofs = 0;
-        let lines = self.buf.split(|&c| c == b'\n');
-        for (line_number, line) in lines.enumerate() {
-            if ofs + line.len() >= err.ofs {
-                let mut msg = "parse error: ".to_string();
-                msg.push_str(&err.msg);
-                msg.push('\n');
-
-                let prefix = format!("{}:{}: ", filename.display(), line_number + 1);
-                msg.push_str(&prefix);
-
-                let mut context = unsafe { std::str::from_utf8_unchecked(line) };
-                let mut col = err.ofs - ofs;
-                if col > 40 {
-                    // Trim beginning of line to fit it on screen.
-                    msg.push_str("...");
-                    context = &context[col - 20..];
-                    col = 3 + 20;
-                }
-                if context.len() > 40 {
-                    context = &context[0..40];
-                    msg.push_str(context);
-                    msg.push_str("...");
-                } else {
-                    msg.push_str(context);
-                }
-                msg.push('\n');
-
-                msg.push_str(&" ".repeat(prefix.len() + col));
-                msg.push_str("^\n");
-                return msg;
-            }
-            ofs += line.len() + 1;
+        let err = err.with_filename(filename);
+        let line_start = self.line_start(err.ofs);
+        let line_end = self.buf[line_start..]
+            .iter()
+            .position(|&c| c == b'\n' || c == b'\0')
+            .map_or(self.buf.len(), |i| line_start + i);
+        let line = self.slice(line_start, line_end).trim_end_matches('\r');
+
+        let mut msg = format!(
+            "{}:{}:{}: parse error: {}\n",
+            filename.display(),
+            err.line,
+            err.col,
+            err.msg
+        );
+
+        let prefix = format!("{}:{}: ", filename.display(), err.line);
+        msg.push_str(&prefix);
+
+        let mut context: Vec<char> = line.chars().collect();
+        let mut col = err.col - 1;
+        if col > 40 {
+            // Trim beginning of line to fit it on screen.
+            msg.push_str("...");
+            context.drain(..col - 20);
+            col = 3 + 20;
         }
-        panic!("invalid offset when formatting error")
+        if context.len() > 40 {
+            msg.extend(&context[0..40]);
+            msg.push_str("...");
+        } else {
+            msg.extend(&context);
+        }
+        msg.push('\n');
+
+        msg.push_str(&" ".repeat(prefix.len() + col));
+        msg.push_str("^\n");
+        msg
     }
 }
 
@@ -142,11 +198,78 @@ pub fn read_file_with_nul(path: &Path) -> std::io::Result<Vec<u8>> {
     // right size up front.
     let mut file = std::fs::File::open(path)?;
     let size = file.metadata()?.len() as usize;
-    let mut bytes = Vec::with_capacity(size + 1);
-    unsafe {
-        bytes.set_len(size);
-    }
+    let mut bytes = vec![0; size + 1];
     file.read_exact(&mut bytes[..size])?;
-    bytes.push(0);
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_at(buf: &str, ofs: usize) -> ParseError {
+        let buf = format!("{}\0", buf);
+        let mut scanner = Scanner::new(buf.as_bytes());
+        while scanner.ofs < ofs {
+            scanner.next();
+        }
+        scanner.parse_error::<(), _>("oops").unwrap_err()
+    }
+
+    #[test]
+    fn line_and_col() {
+        let err = error_at("ab\ncd", 4);
+        assert_eq!((err.line, err.col), (2, 2));
+        assert_eq!(err.to_string(), "2:2: oops");
+        let err = err.with_filename(Path::new("build.ninja"));
+        assert_eq!(err.to_string(), "build.ninja:2:2: oops");
+    }
+
+    #[test]
+    fn peek_at_end() {
+        let mut scanner = Scanner::new(b"a\r\0");
+        assert_eq!(scanner.read(), 'a');
+        assert!(!scanner.peek_newline());
+        assert_eq!(scanner.read(), '\r');
+        assert_eq!(scanner.read(), '\0');
+        // Past the nul, peeking keeps seeing the end of the buffer.
+        assert_eq!(scanner.peek(), '\0');
+        assert!(!scanner.peek_newline());
+    }
+
+    #[test]
+    fn skip_bom() {
+        let mut scanner = Scanner::new(b"\xEF\xBB\xBFab\0");
+        assert_eq!(scanner.read(), 'a');
+        let err = scanner.parse_error::<(), _>("oops").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 2));
+        let msg = scanner.format_parse_error(Path::new("build.ninja"), err);
+        assert_eq!(
+            msg,
+            "build.ninja:1:2: parse error: oops\nbuild.ninja:1: ab\n                ^\n"
+        );
+    }
+
+    #[test]
+    fn col_counts_chars() {
+        // "é" is two bytes in UTF-8.
+        let err = error_at("build é: x", 8);
+        assert_eq!((err.line, err.col), (1, 8));
+    }
+
+    #[test]
+    fn format_multibyte_context() {
+        let buf = format!("{}: x\0", "é".repeat(50));
+        let mut scanner = Scanner::new(buf.as_bytes());
+        while scanner.peek() != ':' {
+            scanner.next();
+        }
+        let err = scanner.parse_error::<(), _>("oops").unwrap_err();
+        let text = scanner.format_parse_error(Path::new("build.ninja"), err);
+        assert!(
+            text.starts_with("build.ninja:1:51: parse error: oops\n"),
+            "{}",
+            text
+        );
+    }
+}
@@ -6,13 +6,11 @@
 
 use std::sync::atomic::AtomicBool;
 
-static mut INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 #[cfg(unix)]
 extern "C" fn sigint_handler(_sig: libc::c_int) {
-    unsafe {
-        INTERRUPTED.store(true, std::sync::atomic::Ordering::Relaxed);
-    }
+    INTERRUPTED.store(true, std::sync::atomic::Ordering::Relaxed);
     // SA_RESETHAND should clear the handler.
 }
 
@@ -21,12 +19,12 @@ pub fn register_sigint() {
     // Safety: registering a signal handler is libc unsafe code.
     unsafe {
         let mut sa: libc::sigaction = std::mem::zeroed();
-        sa.sa_sigaction = sigint_handler as libc::sighandler_t;
+        sa.sa_sigaction = sigint_handler as *const () as libc::sighandler_t;
         sa.sa_flags = libc::SA_RESETHAND;
         libc::sigaction(libc::SIGINT, &sa, std::ptr::null_mut());
     }
 }
 
 pub fn was_interrupted() -> bool {
-    unsafe { INTERRUPTED.load(std::sync::atomic::Ordering::Relaxed) }
+    INTERRUPTED.load(std::sync::atomic::Ordering::Relaxed)
 }
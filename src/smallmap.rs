@@ -38,11 +38,11 @@ impl<K: PartialEq, V> SmallMap<K, V> {
         None
     }
 
-    pub fn iter(&self) -> std::slice::Iter<(K, V)> {
+    pub fn iter(&self) -> std::slice::Iter<'_, (K, V)> {
         self.0.iter()
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<(K, V)> {
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, (K, V)> {
         self.0.iter_mut()
     }
 
@@ -75,6 +75,6 @@ impl<K: Debug, V: Debug> Debug for SmallMap<K, V> {
 #[cfg(test)]
 impl<K: PartialEq, V: PartialEq> PartialEq for SmallMap<K, V> {
     fn eq(&self, other: &Self) -> bool {
-        return self.0 == other.0;
+        self.0 == other.0
     }
 }
@@ -0,0 +1,120 @@
+//! The end-of-build summary printed by --stats and --stats-json, for
+//! tracking build times across runs.
+
+use crate::json;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How many of the slowest commands to list.
+pub const SLOWEST: usize = 5;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+pub struct Stats {
+    /// Non-phony builds needed for the requested targets, whether or not
+    /// they ran.
+    pub considered: usize,
+    /// Commands run, successfully or not.
+    pub ran: usize,
+    pub failed: usize,
+    pub wall: Duration,
+    /// The longest running commands, by description, slowest first.
+    pub slowest: Vec<(String, Duration)>,
+}
+
+impl Stats {
+    /// Builds that didn't need to run because they were up to date, or were
+    /// never reached because an input failed.
+    pub fn skipped(&self) -> usize {
+        self.considered - self.ran
+    }
+
+    pub fn format(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.format_text(),
+            Format::Json => self.format_json(),
+        }
+    }
+
+    fn format_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "n2: stats: {} builds considered, {} ran ({} failed), {} skipped, {:.3}s",
+            self.considered,
+            self.ran,
+            self.failed,
+            self.skipped(),
+            self.wall.as_secs_f64()
+        )
+        .unwrap();
+        for (desc, time) in &self.slowest {
+            writeln!(out, "  {:>8.3}s {}", time.as_secs_f64(), desc).unwrap();
+        }
+        out.pop();
+        out
+    }
+
+    fn format_json(&self) -> String {
+        let slowest: Vec<String> = self
+            .slowest
+            .iter()
+            .map(|(desc, time)| {
+                format!(
+                    "{{\"desc\":{},\"ms\":{}}}",
+                    json::quote(desc),
+                    time.as_millis()
+                )
+            })
+            .collect();
+        format!(
+            "{{\"considered\":{},\"ran\":{},\"failed\":{},\"skipped\":{},\"wall_ms\":{},\"slowest\":[{}]}}",
+            self.considered,
+            self.ran,
+            self.failed,
+            self.skipped(),
+            self.wall.as_millis(),
+            slowest.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Stats {
+        Stats {
+            considered: 10,
+            ran: 3,
+            failed: 1,
+            wall: Duration::from_millis(2500),
+            slowest: vec![
+                ("link \"app\"".to_owned(), Duration::from_millis(1500)),
+                ("cc a.c".to_owned(), Duration::from_millis(20)),
+            ],
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            stats().format(Format::Text),
+            "n2: stats: 10 builds considered, 3 ran (1 failed), 7 skipped, 2.500s
+     1.500s link \"app\"
+     0.020s cc a.c"
+        );
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            stats().format(Format::Json),
+            r#"{"considered":10,"ran":3,"failed":1,"skipped":7,"wall_ms":2500,"slowest":[{"desc":"link \"app\"","ms":1500},{"desc":"cc a.c","ms":20}]}"#
+        );
+    }
+}
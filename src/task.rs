@@ -15,9 +15,9 @@ use crate::{
     scanner::{self, Scanner},
 };
 use anyhow::{anyhow, bail};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::mpsc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub struct FinishedTask {
     /// A (faked) "thread id", used to put different finished builds in different
@@ -26,36 +26,48 @@ pub struct FinishedTask {
     pub buildid: BuildId,
     pub span: (Instant, Instant),
     pub result: TaskResult,
+    /// Whether the task ran in the console pool; see Runner::start().
+    pub console: bool,
 }
 
 /// The result of running a build step.
 pub struct TaskResult {
     pub termination: process::Termination,
+    /// The command's exit code; None if it was killed by a signal, or never
+    /// ran at all.
+    pub exit_code: Option<i32>,
     /// Console output.
     pub output: Vec<u8>,
     pub discovered_deps: Option<Vec<String>>,
+    /// The targets named by the build's depfile, if it had one, to be checked
+    /// against the build's outputs.
+    pub depfile_targets: Vec<String>,
 }
 
-/// Reads dependencies from a .d file path.
-fn read_depfile(path: &Path) -> anyhow::Result<Vec<String>> {
+/// Reads dependencies from a .d file path, returning the targets it names
+/// and their dependencies.
+fn read_depfile(path: &Path) -> anyhow::Result<(Vec<String>, Vec<String>)> {
     let bytes = match scanner::read_file_with_nul(path) {
         Ok(b) => b,
         // See discussion of missing depfiles in #80.
         // TODO(#99): warn or error in this circumstance?
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), Vec::new())),
         Err(e) => bail!("read {}: {}", path.display(), e),
     };
 
     let mut scanner = Scanner::new(&bytes);
     let parsed_deps = depfile::parse(&mut scanner)
         .map_err(|err| anyhow!(scanner.format_parse_error(path, err)))?;
-    // TODO verify deps refers to correct output
+    let targets: Vec<String> = parsed_deps
+        .iter()
+        .map(|(target, _)| target.to_string())
+        .collect();
     let deps: Vec<String> = parsed_deps
         .values()
         .flat_map(|x| x.iter())
-        .map(|&dep| dep.to_owned())
+        .map(|dep| dep.to_string())
         .collect();
-    Ok(deps)
+    Ok((targets, deps))
 }
 
 fn write_rspfile(rspfile: &RspFile) -> anyhow::Result<()> {
@@ -66,21 +78,28 @@ fn write_rspfile(rspfile: &RspFile) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The line prefix MSVC/clang-cl print for /showIncludes when not localized.
+const DEFAULT_SHOWINCLUDES_PREFIX: &str = "Note: including file:";
+
 /// Parse some subcommand output to extract "Note: including file:" lines as
-/// emitted by MSVC/clang-cl.
-fn extract_showincludes(output: Vec<u8>) -> (Vec<String>, Vec<u8>) {
+/// emitted by MSVC/clang-cl.  Nested includes are indented further; that
+/// whitespace is dropped.
+fn extract_showincludes(output: Vec<u8>, prefix: &str) -> (Vec<String>, Vec<u8>) {
     let mut filtered_output = Vec::new();
     let mut includes = Vec::new();
     for line in output.split(|&c| c == b'\n') {
-        if let Some(include) = line.strip_prefix(b"Note: including file: ") {
-            let start = include.iter().position(|&c| c != b' ').unwrap_or(0);
-            let end = if include.ends_with(&[b'\r']) {
+        if let Some(include) = line.strip_prefix(prefix.as_bytes()) {
+            let start = include
+                .iter()
+                .position(|&c| c != b' ' && c != b'\t')
+                .unwrap_or(include.len());
+            let end = if include.ends_with(b"\r") {
                 include.len() - 1
             } else {
                 include.len()
             };
-            let include = &include[start..end];
-            includes.push(unsafe { String::from_utf8_unchecked(include.to_vec()) });
+            let include = &include[start.min(end)..end];
+            includes.push(String::from_utf8_lossy(include).into_owned());
         } else {
             if !filtered_output.is_empty() {
                 filtered_output.push(b'\n');
@@ -114,10 +133,14 @@ fn find_last_line(buf: &[u8]) -> &[u8] {
 /// This is run as a separate thread from the main n2 process and will block
 /// on the subprocess, so any additional per-subprocess work we can do belongs
 /// here.
+/// A console task's output goes straight to the terminal, so none is
+/// captured.
 fn run_task(
     cmdline: &str,
+    console: bool,
+    timeout: Option<Duration>,
     depfile: Option<&Path>,
-    parse_showincludes: bool,
+    showincludes_prefix: Option<&str>,
     rspfile: Option<&RspFile>,
     mut last_line_cb: impl FnMut(&[u8]),
 ) -> anyhow::Result<TaskResult> {
@@ -126,27 +149,42 @@ fn run_task(
     }
 
     let mut output = Vec::new();
-    let termination = process::run_command(cmdline, |buf| {
+    let (termination, exit_code) = process::run_command(cmdline, console, timeout, |buf| {
         output.extend_from_slice(buf);
         last_line_cb(find_last_line(&output));
     })?;
+    if let (process::Termination::TimedOut, Some(timeout)) = (&termination, timeout) {
+        if !output.is_empty() && !output.ends_with(b"\n") {
+            output.push(b'\n');
+        }
+        output.extend_from_slice(format!("timed out after {}s\n", timeout.as_secs()).as_bytes());
+    }
 
     let mut discovered_deps = None;
-    if parse_showincludes {
+    let mut depfile_targets = Vec::new();
+    if let Some(prefix) = showincludes_prefix {
         // Remove /showIncludes lines from output, regardless of success/fail.
-        let (includes, filtered) = extract_showincludes(output);
+        let (includes, filtered) = extract_showincludes(output, prefix);
         output = filtered;
         discovered_deps = Some(includes);
     }
     if termination == process::Termination::Success {
         if let Some(depfile) = depfile {
-            discovered_deps = Some(read_depfile(depfile)?);
+            let (targets, deps) = read_depfile(depfile)?;
+            depfile_targets = targets;
+            discovered_deps = Some(deps);
+        }
+        // As in Ninja, an rspfile is only kept around to debug a failure.
+        if let Some(rspfile) = rspfile {
+            let _ = std::fs::remove_file(&rspfile.path);
         }
     }
     Ok(TaskResult {
         termination,
+        exit_code,
         output,
         discovered_deps,
+        depfile_targets,
     })
 }
 
@@ -186,35 +224,59 @@ pub struct Runner {
     tx: mpsc::Sender<Message>,
     rx: mpsc::Receiver<Message>,
     pub running: usize,
+    /// How many of the running tasks are console tasks, which don't count
+    /// against parallelism.
+    console_running: usize,
     tids: ThreadIds,
     parallelism: usize,
+    /// Timeout for builds without a `timeout` binding of their own.
+    timeout: Option<Duration>,
 }
 
 impl Runner {
-    pub fn new(parallelism: usize) -> Self {
+    pub fn new(parallelism: usize, timeout: Option<Duration>) -> Self {
         let (tx, rx) = mpsc::channel();
         Runner {
             tx,
             rx,
             running: 0,
+            console_running: 0,
             tids: ThreadIds::default(),
             parallelism,
+            timeout,
         }
     }
 
     pub fn can_start_more(&self) -> bool {
-        self.running < self.parallelism
+        self.running - self.console_running < self.parallelism
     }
 
     pub fn is_running(&self) -> bool {
         self.running > 0
     }
 
+    /// Start running a build.  Console builds inherit n2's stdio rather
+    /// than having their output captured, and may be started even when
+    /// can_start_more() is false.
     pub fn start(&mut self, id: BuildId, build: &Build) {
         let cmdline = build.cmdline.clone().unwrap();
-        let depfile = build.depfile.clone().map(PathBuf::from);
+        let console = build.is_console();
+        let timeout = build
+            .timeout()
+            .or(self.timeout)
+            .filter(|timeout| !timeout.is_zero());
+        let depfile = build.depfile_path();
         let rspfile = build.rspfile.clone();
-        let parse_showincludes = build.parse_showincludes;
+        let showincludes_prefix = if build.parse_showincludes {
+            Some(
+                build
+                    .msvc_deps_prefix
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SHOWINCLUDES_PREFIX.to_owned()),
+            )
+        } else {
+            None
+        };
 
         let tid = self.tids.claim();
         let tx = self.tx.clone();
@@ -222,8 +284,10 @@ impl Runner {
             let start = Instant::now();
             let result = run_task(
                 &cmdline,
+                console,
+                timeout,
                 depfile.as_deref(),
-                parse_showincludes,
+                showincludes_prefix.as_deref(),
                 rspfile.as_ref(),
                 |line| {
                     let _ = tx.send(Message::Output((id, line.to_owned())));
@@ -231,8 +295,10 @@ impl Runner {
             )
             .unwrap_or_else(|err| TaskResult {
                 termination: process::Termination::Failure,
+                exit_code: None,
                 output: format!("{}\n", err).into_bytes(),
                 discovered_deps: None,
+                depfile_targets: Vec::new(),
             });
             let finish = Instant::now();
 
@@ -241,11 +307,15 @@ impl Runner {
                 buildid: id,
                 span: (start, finish),
                 result,
+                console,
             };
             // The send will only fail if the receiver disappeared, e.g. due to shutting down.
             let _ = tx.send(Message::Done(task));
         });
         self.running += 1;
+        if console {
+            self.console_running += 1;
+        }
     }
 
     /// Wait for a build to complete.  May block for a long time.
@@ -256,6 +326,9 @@ impl Runner {
                 Message::Done(task) => {
                     self.tids.release(task.tid);
                     self.running -= 1;
+                    if task.console {
+                        self.console_running -= 1;
+                    }
                     return task;
                 }
             }
@@ -277,6 +350,7 @@ Note: including file: b\r
 more text
 "
             .to_vec(),
+            DEFAULT_SHOWINCLUDES_PREFIX,
         );
         assert_eq!(includes, &["a", "b"]);
         assert_eq!(
@@ -288,6 +362,35 @@ more text
         );
     }
 
+    #[test]
+    fn show_includes_nested() {
+        let (includes, output) = extract_showincludes(
+            b"Note: including file: a.h\r
+Note: including file:  b.h\r
+Note: including file:   \tc.h\r
+x.cc\r
+"
+            .to_vec(),
+            DEFAULT_SHOWINCLUDES_PREFIX,
+        );
+        assert_eq!(includes, &["a.h", "b.h", "c.h"]);
+        assert_eq!(output, b"x.cc\r\n");
+    }
+
+    #[test]
+    fn show_includes_localized_prefix() {
+        let (includes, output) = extract_showincludes(
+            "Remarque : inclusion du fichier :  a.h
+Note: including file: b.h
+"
+            .as_bytes()
+            .to_vec(),
+            "Remarque : inclusion du fichier :",
+        );
+        assert_eq!(includes, &["a.h"]);
+        assert_eq!(output, b"Note: including file: b.h\n");
+    }
+
     #[test]
     fn find_last() {
         assert_eq!(find_last_line(b""), b"");
@@ -305,7 +408,8 @@ more text
 
     #[test]
     fn missing_depfile_allowed() {
-        let deps = read_depfile(Path::new("/missing/dep/file")).unwrap();
+        let (targets, deps) = read_depfile(Path::new("/missing/dep/file")).unwrap();
+        assert_eq!(targets.len(), 0);
         assert_eq!(deps.len(), 0);
     }
 }
@@ -0,0 +1,138 @@
+//! `-t clean`: removes the outputs of builds from disk.
+
+use crate::{
+    canon::canon_path,
+    graph::{BuildId, FileId, Graph},
+    load,
+};
+use rustc_hash::FxHashSet;
+use std::io::Write;
+
+/// Remove the outputs of every build, or of the builds needed for the given
+/// targets.  Returns 1 if any output couldn't be removed.
+pub fn tool_clean(
+    build_file: &str,
+    options: &load::Options,
+    targets: &[String],
+) -> anyhow::Result<i32> {
+    let state = load::read(build_file, options)?;
+    let graph = &state.graph;
+    let outs = if targets.is_empty() {
+        graph.cleanable_outputs()
+    } else {
+        let mut ids = Vec::new();
+        for name in targets {
+            let id = graph
+                .files
+                .lookup(&canon_path(name))
+                .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
+            ids.push(id);
+        }
+        reachable_outputs(graph, &ids)
+    };
+    let mut out = std::io::stdout().lock();
+    let mut err = std::io::stderr().lock();
+    clean(&mut out, &mut err, graph, &outs)
+}
+
+/// The cleanable outputs of all builds the given targets depend on,
+/// including their own.
+fn reachable_outputs(graph: &Graph, targets: &[FileId]) -> Vec<FileId> {
+    let mut outs = Vec::new();
+    let mut seen: FxHashSet<BuildId> = FxHashSet::default();
+    let mut stack: Vec<FileId> = targets.to_vec();
+    while let Some(id) = stack.pop() {
+        let bid = match graph.file(id).input {
+            Some(bid) => bid,
+            None => continue,
+        };
+        if !seen.insert(bid) {
+            continue;
+        }
+        let build = &graph.builds[bid];
+        if build.is_cleanable() {
+            outs.extend_from_slice(build.explicit_outs());
+        }
+        stack.extend_from_slice(build.ordering_ins());
+    }
+    outs
+}
+
+/// Remove the given files, reporting how many were removed.  Files that are
+/// already gone are skipped.
+fn clean(
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+    graph: &Graph,
+    outs: &[FileId],
+) -> anyhow::Result<i32> {
+    writeln!(out, "Cleaning...")?;
+    let mut removed = 0;
+    let mut failed = false;
+    for &id in outs {
+        let file = graph.file(id);
+        match std::fs::remove_file(file.path()) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                writeln!(err, "n2: remove {}: {}", file.name, e)?;
+                failed = true;
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{} file{}.",
+        removed,
+        if removed == 1 { "" } else { "s" }
+    )?;
+    Ok(if failed { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_targets() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let graph = crate::load::parse(
+            "build.ninja",
+            format!(
+                "
+rule cc
+  command = cc $in -o $out
+rule regen
+  command = regen
+  generator = 1
+build {dir}/build.ninja: regen
+build {dir}/a.o: cc {dir}/a.c
+build {dir}/b.o: cc {dir}/b.c
+build {dir}/a: cc {dir}/a.o || {dir}/build.ninja
+",
+                dir = dir.path().display()
+            )
+            .into_bytes(),
+        )?;
+        for name in ["build.ninja", "a.c", "a.o", "b.o"] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        let target = graph
+            .files
+            .lookup(&format!("{}/a", dir.path().display()))
+            .unwrap();
+        let outs = reachable_outputs(&graph, &[target]);
+
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        assert_eq!(clean(&mut out, &mut err, &graph, &outs)?, 0);
+        // "a" was never built, and "b.o" isn't needed for "a".
+        assert_eq!(String::from_utf8(out)?, "Cleaning...\n1 file.\n");
+        assert!(err.is_empty());
+        assert!(!dir.path().join("a.o").exists());
+        assert!(dir.path().join("a.c").exists());
+        assert!(dir.path().join("b.o").exists());
+        assert!(dir.path().join("build.ninja").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! `-t compdb`: writes a compile_commands.json compilation database, as used
+//! by clang tooling, to stdout.
+
+use crate::{graph::Graph, json, load};
+use std::io::Write;
+
+/// Print a compilation database covering the builds using any of the given
+/// rules, or all builds with a command if no rules are given.
+pub fn tool_compdb(
+    build_file: &str,
+    options: &load::Options,
+    rules: &[String],
+) -> anyhow::Result<i32> {
+    let state = load::read(build_file, options)?;
+    let directory = std::env::current_dir()?;
+    let mut out = std::io::stdout().lock();
+    write_compdb(&mut out, &state.graph, &directory.to_string_lossy(), rules)?;
+    Ok(0)
+}
+
+fn write_compdb(
+    out: &mut dyn Write,
+    graph: &Graph,
+    directory: &str,
+    rules: &[String],
+) -> std::io::Result<()> {
+    write!(out, "[")?;
+    let mut first = true;
+    for id in graph.builds.all_ids() {
+        let build = &graph.builds[id];
+        let cmdline = match &build.cmdline {
+            Some(cmdline) => cmdline,
+            None => continue,
+        };
+        if !rules.is_empty() && !rules.contains(&build.rule) {
+            continue;
+        }
+        let (file, output) = match (build.explicit_ins().first(), build.explicit_outs().first()) {
+            (Some(&file), Some(&output)) => (file, output),
+            _ => continue,
+        };
+        if !first {
+            write!(out, ",")?;
+        }
+        first = false;
+        write!(
+            out,
+            "\n  {{\n    \"directory\": {},\n    \"command\": {},\n    \"file\": {},\n    \"output\": {}\n  }}",
+            json::quote(directory),
+            json::quote(cmdline),
+            json::quote(&graph.file(file).name),
+            json::quote(&graph.file(output).name),
+        )?;
+    }
+    writeln!(out, "\n]")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compdb(file: &str, rules: &[&str]) -> anyhow::Result<String> {
+        let graph = load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let rules: Vec<String> = rules.iter().map(|&r| r.to_owned()).collect();
+        let mut out = Vec::new();
+        write_compdb(&mut out, &graph, "/src", &rules)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = link $in -o $out
+build a.o: cc a.c
+build b.o: cc b.c | a.h
+build app: link a.o b.o
+build all: phony app
+";
+
+    #[test]
+    fn filter_by_rule() -> anyhow::Result<()> {
+        assert_eq!(
+            compdb(MANIFEST, &["cc"])?,
+            r#"[
+  {
+    "directory": "/src",
+    "command": "cc -c a.c -o a.o",
+    "file": "a.c",
+    "output": "a.o"
+  },
+  {
+    "directory": "/src",
+    "command": "cc -c b.c -o b.o",
+    "file": "b.c",
+    "output": "b.o"
+  }
+]
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn all_rules() -> anyhow::Result<()> {
+        let out = compdb(MANIFEST, &[])?;
+        assert!(out.contains("link a.o b.o -o app"));
+        // Phony builds have no command.
+        assert!(!out.contains("\"all\""));
+        assert_eq!(compdb(MANIFEST, &["nothing"])?, "[\n]\n");
+        Ok(())
+    }
+
+    #[test]
+    fn escaping() -> anyhow::Result<()> {
+        let out = compdb(
+            r#"
+rule cl
+  command = cl /Fo"$out" $in
+build out\a.obj: cl src\a.c
+"#,
+            &["cl"],
+        )?;
+        assert!(
+            out.contains(r#""command": "cl /Fo\"out\\a.obj\" src\\a.c","#),
+            "{}",
+            out
+        );
+        Ok(())
+    }
+}
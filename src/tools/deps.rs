@@ -0,0 +1,111 @@
+//! `-t deps`: prints the dependencies discovered by previous builds, as
+//! stored in the .n2_db, in the format of Ninja's `-t deps`.
+
+use crate::{
+    canon::canon_path,
+    graph::{BuildId, Graph, Hashes},
+    load,
+};
+use std::io::Write;
+
+/// Print the stored deps for the builds of the given targets, or for every
+/// build that records deps if no targets are given.
+pub fn tool_deps(
+    build_file: &str,
+    options: &load::Options,
+    targets: &[String],
+) -> anyhow::Result<i32> {
+    let state = load::read(build_file, options)?;
+    let graph = &state.graph;
+    let mut out = std::io::stdout().lock();
+    if targets.is_empty() {
+        for id in graph.builds.all_ids() {
+            let build = &graph.builds[id];
+            let has_deps = build.depfile_path().is_some() || build.parse_showincludes;
+            if has_deps && state.hashes.get(id).is_some() {
+                write_deps(&mut out, graph, &state.hashes, id)?;
+            }
+        }
+    } else {
+        for name in targets {
+            let fid = graph
+                .files
+                .lookup(&canon_path(name))
+                .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
+            match graph.file(fid).input {
+                Some(id) if state.hashes.get(id).is_some() => {
+                    write_deps(&mut out, graph, &state.hashes, id)?
+                }
+                _ => writeln!(out, "{}: deps not found", graph.file(fid).name)?,
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// n2 doesn't record an mtime for deps as Ninja does, so in its place this
+/// prints the hash of the build's inputs recorded when the deps were
+/// gathered.
+fn write_deps(
+    out: &mut dyn Write,
+    graph: &Graph,
+    hashes: &Hashes,
+    id: BuildId,
+) -> std::io::Result<()> {
+    let build = &graph.builds[id];
+    let name = match build.outs().first() {
+        Some(&output) => &graph.file(output).name,
+        None => return Ok(()),
+    };
+    let deps = build.discovered_ins();
+    write!(out, "{}: #deps {}", name, deps.len())?;
+    if let Some(hash) = hashes.get(id) {
+        write!(out, ", deps hash {:016x}", hash.0)?;
+    }
+    writeln!(out)?;
+    for &dep in deps {
+        writeln!(out, "    {}", graph.file(dep).name)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::BuildHash;
+
+    #[test]
+    fn format() -> anyhow::Result<()> {
+        let mut graph = crate::load::parse(
+            "build.ninja",
+            "
+rule cc
+  command = cc $in -o $out
+  depfile = $out.d
+build a.o: cc a.c
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let id = graph
+            .file(graph.files.lookup("a.o").unwrap())
+            .input
+            .unwrap();
+        let deps = vec![
+            graph.files.id_from_canonical("a.c".to_owned()),
+            graph.files.id_from_canonical("a.h".to_owned()),
+        ];
+        graph.builds[id].set_discovered_ins(deps);
+        let mut hashes = Hashes::default();
+        hashes.set(id, BuildHash(0xabc));
+
+        let mut out = Vec::new();
+        write_deps(&mut out, &graph, &hashes, id)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "a.o: #deps 2, deps hash 0000000000000abc\n    a.c\n    a.h\n\n"
+        );
+        Ok(())
+    }
+}
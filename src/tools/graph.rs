@@ -0,0 +1,179 @@
+//! `-t graph`: prints the build graph in Graphviz dot format.
+
+use crate::{
+    canon::canon_path,
+    densemap::Index,
+    graph::{BuildId, FileId, Graph},
+    json, load,
+};
+use rustc_hash::FxHashSet;
+use std::io::Write;
+
+/// Print the part of the graph needed to build the given targets, or the
+/// whole graph (starting from its roots) if no targets are given.
+pub fn tool_graph(
+    build_file: &str,
+    options: &load::Options,
+    targets: &[String],
+) -> anyhow::Result<i32> {
+    let state = load::read(build_file, options)?;
+    let graph = &state.graph;
+    let roots = if targets.is_empty() {
+        graph.root_files()
+    } else {
+        let mut ids = Vec::new();
+        for name in targets {
+            let id = graph
+                .files
+                .lookup(&canon_path(name))
+                .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
+            ids.push(id);
+        }
+        ids
+    };
+    let mut out = std::io::stdout().lock();
+    write_graph(&mut out, graph, &roots)?;
+    Ok(0)
+}
+
+fn file_node(id: FileId) -> String {
+    format!("\"f{}\"", id.index())
+}
+
+fn build_node(id: BuildId) -> String {
+    format!("\"b{}\"", id.index())
+}
+
+fn write_graph(out: &mut dyn Write, graph: &Graph, roots: &[FileId]) -> std::io::Result<()> {
+    writeln!(out, "digraph ninja {{")?;
+    writeln!(out, "rankdir=\"LR\"")?;
+    writeln!(out, "node [fontsize=10, shape=box, height=0.25]")?;
+    writeln!(out, "edge [fontsize=10]")?;
+
+    let mut files_seen: FxHashSet<FileId> = FxHashSet::default();
+    let mut builds_seen: FxHashSet<BuildId> = FxHashSet::default();
+    let mut stack: Vec<FileId> = roots.iter().rev().copied().collect();
+    while let Some(id) = stack.pop() {
+        if !files_seen.insert(id) {
+            continue;
+        }
+        let file = graph.file(id);
+        writeln!(
+            out,
+            "{} [label={}, shape=ellipse]",
+            file_node(id),
+            json::quote(&file.name)
+        )?;
+        let bid = match file.input {
+            Some(bid) => bid,
+            None => continue,
+        };
+        if !builds_seen.insert(bid) {
+            continue;
+        }
+        let build = &graph.builds[bid];
+        let label = build.desc.as_deref().unwrap_or(&build.rule);
+        writeln!(
+            out,
+            "{} [label={}, shape=rectangle]",
+            build_node(bid),
+            json::quote(label)
+        )?;
+        let dirtying = build.dirtying_ins().len();
+        for (i, &input) in build.ordering_ins().iter().enumerate() {
+            let style = if i < dirtying { "" } else { ", style=dashed" };
+            writeln!(
+                out,
+                "{} -> {} [arrowhead=none{}]",
+                file_node(input),
+                build_node(bid),
+                style
+            )?;
+        }
+        for &output in build.outs() {
+            writeln!(out, "{} -> {}", build_node(bid), file_node(output))?;
+        }
+        // Visit outputs first so that each build's outputs are declared
+        // together, then its inputs in order.
+        stack.extend(build.ordering_ins().iter().rev());
+        stack.extend(build.outs().iter().rev());
+    }
+
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dot(file: &str, targets: &[&str]) -> anyhow::Result<String> {
+        let graph = load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let roots = if targets.is_empty() {
+            graph.root_files()
+        } else {
+            targets
+                .iter()
+                .map(|&t| graph.files.lookup(t).unwrap())
+                .collect()
+        };
+        let mut out = Vec::new();
+        write_graph(&mut out, &graph, &roots)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc $in -o $out
+  description = CC $out
+rule link
+  command = link $in -o $out
+build gen.h: cc gen.in
+build a.o: cc a.c || gen.h
+build app: link a.o
+build other: link b.o
+";
+
+    #[test]
+    fn target() -> anyhow::Result<()> {
+        let out = dot(MANIFEST, &["a.o"])?;
+        assert_eq!(
+            out,
+            r#"digraph ninja {
+rankdir="LR"
+node [fontsize=10, shape=box, height=0.25]
+edge [fontsize=10]
+"f3" [label="a.o", shape=ellipse]
+"b1" [label="CC a.o", shape=rectangle]
+"f2" -> "b1" [arrowhead=none]
+"f1" -> "b1" [arrowhead=none, style=dashed]
+"b1" -> "f3"
+"f2" [label="a.c", shape=ellipse]
+"f1" [label="gen.h", shape=ellipse]
+"b0" [label="CC gen.h", shape=rectangle]
+"f0" -> "b0" [arrowhead=none]
+"b0" -> "f1"
+"f0" [label="gen.in", shape=ellipse]
+}
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn all_roots() -> anyhow::Result<()> {
+        let out = dot(MANIFEST, &[])?;
+        // Both top-level outputs are reached, with the rule name used as
+        // the label when there's no description.
+        assert!(out.contains(r#"[label="app", shape=ellipse]"#), "{}", out);
+        assert!(out.contains(r#"[label="other", shape=ellipse]"#), "{}", out);
+        assert!(
+            out.contains(r#"[label="link", shape=rectangle]"#),
+            "{}",
+            out
+        );
+        // Each node appears once.
+        assert_eq!(out.matches(r#"[label="a.o""#).count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+//! Implementations of the `-t` subcommands.
+
+pub mod clean;
+pub mod compdb;
+pub mod deps;
+pub mod graph;
+pub mod query;
+pub mod recompact;
+pub mod targets;
+pub mod why;
@@ -0,0 +1,155 @@
+//! `-t query`: shows the inputs and outputs of a path, in the format of
+//! Ninja's `-t query`.
+
+use crate::{
+    canon::canon_path,
+    graph::{FileId, Graph},
+    load,
+};
+use std::io::Write;
+
+pub fn tool_query(
+    build_file: &str,
+    options: &load::Options,
+    targets: &[String],
+) -> anyhow::Result<i32> {
+    if targets.is_empty() {
+        anyhow::bail!("expected a target to query");
+    }
+    let state = load::read(build_file, options)?;
+    let graph = &state.graph;
+    let mut ids = Vec::new();
+    for name in targets {
+        let id = graph
+            .files
+            .lookup(&canon_path(name))
+            .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
+        ids.push(id);
+    }
+    let mut out = std::io::stdout().lock();
+    for id in ids {
+        write_query(&mut out, graph, id)?;
+    }
+    Ok(0)
+}
+
+fn write_query(out: &mut dyn Write, graph: &Graph, id: FileId) -> std::io::Result<()> {
+    let file = graph.file(id);
+    writeln!(out, "{}:", file.name)?;
+    let build = file.input.map(|bid| &graph.builds[bid]);
+
+    if let Some(build) = build {
+        writeln!(out, "  input: {}", build.rule)?;
+        let explicit = build.explicit_ins().len();
+        let dirtying = build.dirtying_ins().len();
+        for (i, &input) in build.ordering_ins().iter().enumerate() {
+            let prefix = if i < explicit {
+                ""
+            } else if i < dirtying {
+                "| "
+            } else {
+                "|| "
+            };
+            writeln!(out, "    {}{}", prefix, graph.file(input).name)?;
+        }
+        if !build.validation_ins().is_empty() {
+            writeln!(out, "  validations:")?;
+            for &input in build.validation_ins() {
+                writeln!(out, "    {}", graph.file(input).name)?;
+            }
+        }
+    }
+
+    // As in Ninja, "outputs" are the files built from this one.
+    writeln!(out, "  outputs:")?;
+    for &bid in &file.dependents {
+        for &output in graph.builds[bid].outs() {
+            writeln!(out, "    {}", graph.file(output).name)?;
+        }
+    }
+
+    // The remaining sections have no Ninja equivalent.
+    if let Some(build) = build {
+        if let Some(cmdline) = &build.cmdline {
+            writeln!(out, "  command: {}", cmdline)?;
+        }
+        if !build.discovered_ins().is_empty() {
+            writeln!(out, "  discovered:")?;
+            for &input in build.discovered_ins() {
+                writeln!(out, "    {}", graph.file(input).name)?;
+            }
+        }
+        if build.outs().len() > 1 {
+            writeln!(out, "  also built:")?;
+            for &output in build.outs().iter().filter(|&&output| output != id) {
+                writeln!(out, "    {}", graph.file(output).name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(file: &str, target: &str, discovered: &[&str]) -> anyhow::Result<String> {
+        let mut graph = load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let id = graph.files.lookup(target).unwrap();
+        if let Some(bid) = graph.file(id).input {
+            let deps = discovered
+                .iter()
+                .map(|&d| graph.files.id_from_canonical(d.to_owned()))
+                .collect();
+            graph.builds[bid].set_discovered_ins(deps);
+        }
+        let mut out = Vec::new();
+        write_query(&mut out, &graph, id)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc $in -o $out
+build gen.h: cc gen.in
+build a.o | a.d: cc a.c | extra.h || gen.h |@ lint
+build app: cc a.o
+build all: phony app
+";
+
+    #[test]
+    fn built_file() -> anyhow::Result<()> {
+        assert_eq!(
+            query(MANIFEST, "a.o", &["a.h"])?,
+            "a.o:
+  input: cc
+    a.c
+    | extra.h
+    || gen.h
+  validations:
+    lint
+  outputs:
+    app
+  command: cc a.c -o a.o
+  discovered:
+    a.h
+  also built:
+    a.d
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn source_and_phony() -> anyhow::Result<()> {
+        assert_eq!(
+            query(MANIFEST, "a.c", &[])?,
+            "a.c:\n  outputs:\n    a.o\n    a.d\n"
+        );
+        assert_eq!(
+            query(MANIFEST, "all", &[])?,
+            "all:\n  input: phony\n    app\n  outputs:\n"
+        );
+        Ok(())
+    }
+}
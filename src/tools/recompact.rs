@@ -0,0 +1,15 @@
+//! `-t recompact`: rewrites the .n2_db keeping only the records still in use.
+
+use crate::load;
+
+/// Rewrite the .n2_db of the given manifest.
+pub fn tool_recompact(build_file: &str, options: &load::Options) -> anyhow::Result<i32> {
+    let state = load::read(build_file, options)?;
+    let db = match state.db {
+        Some(db) => db,
+        None => anyhow::bail!("-t recompact: no .n2_db to rewrite"),
+    };
+    db.recompact(&state.graph, &state.hashes)
+        .map_err(|err| anyhow::anyhow!("recompact .n2_db: {}", err))?;
+    Ok(0)
+}
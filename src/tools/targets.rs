@@ -0,0 +1,277 @@
+//! `-t targets`: lists targets in the build graph, in the modes of Ninja's
+//! `-t targets`, optionally as JSON.
+
+use crate::{
+    graph::{FileId, Graph},
+    json, load,
+};
+use std::collections::BTreeSet;
+use std::io::Write;
+
+/// Print targets according to the mode given in args:
+///   depth [N]  the tree of targets from the roots, N levels deep
+///              (default 1, 0 for unlimited)
+///   all        every output, with the rule that builds it
+///   rule       every source file
+///   rule NAME  every output of the named rule
+pub fn tool_targets(
+    build_file: &str,
+    options: &load::Options,
+    json: bool,
+    args: &[String],
+) -> anyhow::Result<i32> {
+    let state = load::read(build_file, options)?;
+    let mut out = std::io::stdout().lock();
+    write_targets(&mut out, &state.graph, json, args)?;
+    Ok(0)
+}
+
+fn write_targets(
+    out: &mut dyn Write,
+    graph: &Graph,
+    json: bool,
+    args: &[String],
+) -> anyhow::Result<()> {
+    match args.first().map(String::as_str) {
+        None | Some("depth") => {
+            let depth = match args.get(1) {
+                None => 1,
+                Some(n) => n
+                    .parse::<usize>()
+                    .map_err(|_| anyhow::anyhow!("invalid depth {:?}", n))?,
+            };
+            if json {
+                write_depth_json(out, graph, depth)?;
+            } else {
+                write_depth(out, graph, &graph.root_files(), depth, 0)?;
+            }
+        }
+        Some("all") => {
+            let mut outputs = Vec::new();
+            for id in graph.builds.all_ids() {
+                let build = &graph.builds[id];
+                for &output in build.outs() {
+                    outputs.push((graph.file(output).name.as_str(), build.rule.as_str()));
+                }
+            }
+            if json {
+                let items: Vec<String> = outputs
+                    .iter()
+                    .map(|&(name, rule)| {
+                        format!(
+                            "{{\"output\": {}, \"rule\": {}}}",
+                            json::quote(name),
+                            json::quote(rule)
+                        )
+                    })
+                    .collect();
+                write_json_array(out, &items)?;
+            } else {
+                for (name, rule) in outputs {
+                    writeln!(out, "{}: {}", name, rule)?;
+                }
+            }
+        }
+        Some("rule") => {
+            let mut names = BTreeSet::new();
+            match args.get(1) {
+                None => {
+                    for id in graph.builds.all_ids() {
+                        for &input in graph.builds[id].ins.ids.iter() {
+                            let file = graph.file(input);
+                            if file.input.is_none() {
+                                names.insert(file.name.as_str());
+                            }
+                        }
+                    }
+                }
+                Some(rule) => {
+                    for id in graph.builds.all_ids() {
+                        let build = &graph.builds[id];
+                        if &build.rule == rule {
+                            for &output in build.outs() {
+                                names.insert(graph.file(output).name.as_str());
+                            }
+                        }
+                    }
+                }
+            }
+            if json {
+                let items: Vec<String> = names.iter().map(|name| json::quote(name)).collect();
+                write_json_array(out, &items)?;
+            } else {
+                for name in names {
+                    writeln!(out, "{}", name)?;
+                }
+            }
+        }
+        Some(mode) => anyhow::bail!(
+            "unknown targets mode {:?}, expected 'depth', 'all', or 'rule'",
+            mode
+        ),
+    }
+    Ok(())
+}
+
+/// Print files and, for generated files, their rule, followed by their
+/// inputs indented below them.
+fn write_depth(
+    out: &mut dyn Write,
+    graph: &Graph,
+    ids: &[FileId],
+    depth: usize,
+    indent: usize,
+) -> std::io::Result<()> {
+    for &id in ids {
+        let file = graph.file(id);
+        write!(out, "{:1$}", "", indent * 2)?;
+        match file.input {
+            Some(bid) => {
+                let build = &graph.builds[bid];
+                writeln!(out, "{}: {}", file.name, build.rule)?;
+                if depth != 1 {
+                    write_depth(
+                        out,
+                        graph,
+                        build.ordering_ins(),
+                        depth.saturating_sub(1),
+                        indent + 1,
+                    )?;
+                }
+            }
+            None => writeln!(out, "{}", file.name)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_depth_json(out: &mut dyn Write, graph: &Graph, depth: usize) -> std::io::Result<()> {
+    let items: Vec<String> = graph
+        .root_files()
+        .into_iter()
+        .map(|id| depth_json(graph, id, depth))
+        .collect();
+    write_json_array(out, &items)
+}
+
+/// A file as `{"name", "rule", "deps"}`, where rule is null for source files
+/// and deps are expanded for `depth` levels.
+fn depth_json(graph: &Graph, id: FileId, depth: usize) -> String {
+    let file = graph.file(id);
+    let (rule, deps) = match file.input {
+        Some(bid) => {
+            let build = &graph.builds[bid];
+            let deps: Vec<String> = if depth != 1 {
+                build
+                    .ordering_ins()
+                    .iter()
+                    .map(|&input| depth_json(graph, input, depth.saturating_sub(1)))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            (json::quote(&build.rule), deps)
+        }
+        None => ("null".to_owned(), Vec::new()),
+    };
+    format!(
+        "{{\"name\": {}, \"rule\": {}, \"deps\": [{}]}}",
+        json::quote(&file.name),
+        rule,
+        deps.join(", ")
+    )
+}
+
+/// Write a JSON array with one element per line.
+fn write_json_array(out: &mut dyn Write, items: &[String]) -> std::io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, item) in items.iter().enumerate() {
+        let comma = if i + 1 < items.len() { "," } else { "" };
+        writeln!(out, "  {}{}", item, comma)?;
+    }
+    writeln!(out, "]")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc $in -o $out
+rule link
+  command = link $in -o $out
+build a.o: cc a.c
+build \"b.o: cc b.c
+build app: link a.o \"b.o
+";
+
+    fn targets(json: bool, args: &[&str]) -> anyhow::Result<String> {
+        let graph = load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        let args: Vec<String> = args.iter().map(|&a| a.to_owned()).collect();
+        let mut out = Vec::new();
+        write_targets(&mut out, &graph, json, &args)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn depth() -> anyhow::Result<()> {
+        assert_eq!(targets(false, &[])?, "app: link\n");
+        assert_eq!(
+            targets(false, &["depth", "0"])?,
+            "app: link\n  a.o: cc\n    a.c\n  \"b.o: cc\n    b.c\n"
+        );
+        assert_eq!(
+            targets(true, &["depth", "2"])?,
+            r#"[
+  {"name": "app", "rule": "link", "deps": [{"name": "a.o", "rule": "cc", "deps": []}, {"name": "\"b.o", "rule": "cc", "deps": []}]}
+]
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn all() -> anyhow::Result<()> {
+        assert_eq!(targets(false, &["all"])?, "a.o: cc\n\"b.o: cc\napp: link\n");
+        assert_eq!(
+            targets(true, &["all"])?,
+            r#"[
+  {"output": "a.o", "rule": "cc"},
+  {"output": "\"b.o", "rule": "cc"},
+  {"output": "app", "rule": "link"}
+]
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rule() -> anyhow::Result<()> {
+        assert_eq!(targets(false, &["rule"])?, "a.c\nb.c\n");
+        assert_eq!(targets(false, &["rule", "cc"])?, "\"b.o\na.o\n");
+        assert_eq!(targets(true, &["rule", "link"])?, "[\n  \"app\"\n]\n");
+        assert!(targets(false, &["bogus"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rule_shared_source() -> anyhow::Result<()> {
+        let graph = load::parse(
+            "build.ninja",
+            "
+rule cc
+  command = cc $in -o $out
+build a.o: cc a.c common.h
+build b.o: cc b.c common.h
+"
+            .as_bytes()
+            .to_vec(),
+        )?;
+        let mut out = Vec::new();
+        write_targets(&mut out, &graph, false, &["rule".to_owned()])?;
+        assert_eq!(String::from_utf8(out)?, "a.c\nb.c\ncommon.h\n");
+        Ok(())
+    }
+}
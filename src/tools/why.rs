@@ -0,0 +1,165 @@
+//! `-t why`: explains why a target would rebuild by walking up from it to
+//! the first out of date build and printing each step of the chain.
+
+use crate::{
+    canon::canon_path,
+    graph::{BuildId, FileId},
+    load,
+    progress::DumbConsoleProgress,
+    work::{self, Work},
+};
+use std::collections::HashSet;
+use std::io::Write;
+
+/// Print the rebuild chain for each of the given targets.
+pub fn tool_why(
+    build_file: &str,
+    options: &load::Options,
+    work_options: &work::Options,
+    targets: &[String],
+) -> anyhow::Result<i32> {
+    if targets.is_empty() {
+        anyhow::bail!("-t why requires a target");
+    }
+    let state = load::read(build_file, options)?;
+    // A Work makes the same checks as the build would, without a db to
+    // record anything in.
+    let mut progress = DumbConsoleProgress::new(false);
+    let mut work = Work::new(
+        state.graph,
+        state.hashes,
+        None,
+        None,
+        work_options,
+        &mut progress,
+        state.pools,
+    );
+    let mut out = std::io::stdout().lock();
+    for name in targets {
+        let fid = work
+            .graph()
+            .files
+            .lookup(&canon_path(name))
+            .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
+        write_why(&mut out, &mut work, fid)?;
+    }
+    Ok(0)
+}
+
+/// Walks the inputs of the build generating `target`, depth first, and
+/// writes the chain from the first out of date build down to the target.
+fn write_why(out: &mut dyn Write, work: &mut Work, target: FileId) -> anyhow::Result<()> {
+    let name = work.graph().file(target).name.clone();
+    let id = match work.graph().file(target).input {
+        Some(id) => id,
+        None => {
+            writeln!(out, "{}: not generated by any build", name)?;
+            return Ok(());
+        }
+    };
+    let mut visited = HashSet::new();
+    match find_dirty(work, id, &mut visited)? {
+        None => writeln!(out, "{}: up to date", name)?,
+        Some(chain) => {
+            writeln!(out, "{}: out of date", name)?;
+            let graph = work.graph();
+            for (id, reason) in chain {
+                let build = &graph.builds[id];
+                let build_name = match build.outs().first() {
+                    Some(&out) => graph.file(out).name.as_str(),
+                    None => "",
+                };
+                writeln!(out, "  {} ({}): {}", build_name, build.location, reason)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the chain of builds from the first out of date ancestor of `id`
+/// to `id` itself, each with the reason it is out of date, or None if the
+/// build is up to date.
+fn find_dirty(
+    work: &mut Work,
+    id: BuildId,
+    visited: &mut HashSet<BuildId>,
+) -> anyhow::Result<Option<Vec<(BuildId, String)>>> {
+    if !visited.insert(id) {
+        return Ok(None);
+    }
+    let build = &work.graph().builds[id];
+    let deps: Vec<FileId> = build
+        .dirtying_ins()
+        .iter()
+        .chain(build.discovered_ins())
+        .copied()
+        .collect();
+    for dep in deps {
+        if let Some(input) = work.graph().file(dep).input {
+            if let Some(mut chain) = find_dirty(work, input, visited)? {
+                let reason = format!("input {} is out of date", work.graph().file(dep).name);
+                chain.push((id, reason));
+                return Ok(Some(chain));
+            }
+        }
+    }
+
+    let reason = work.dirty_reason(id)?;
+    Ok(reason.map(|reason| vec![(id, reason.describe(work.graph(), id))]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_built() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let graph = crate::load::parse(
+            "build.ninja",
+            format!(
+                "
+rule touch
+  command = touch $out
+build {dir}/a: touch {dir}/in
+build {dir}/b: touch {dir}/a
+build {dir}/c: phony {dir}/b
+",
+                dir = dir.path().display()
+            )
+            .into_bytes(),
+        )?;
+        std::fs::write(dir.path().join("in"), "")?;
+        let target = graph
+            .files
+            .lookup(&format!("{}/c", dir.path().display()))
+            .unwrap();
+        let options = work::Options {
+            failures_left: None,
+            ..Default::default()
+        };
+        let mut progress = DumbConsoleProgress::new(false);
+        let mut work = Work::new(
+            graph,
+            Default::default(),
+            None,
+            None,
+            &options,
+            &mut progress,
+            Default::default(),
+        );
+
+        let mut out = Vec::new();
+        write_why(&mut out, &mut work, target)?;
+        let out = String::from_utf8(out)?.replace(&dir.path().display().to_string(), "");
+        assert_eq!(
+            out,
+            "/c: out of date
+  /a (build.ninja:4): output /a missing
+  /b (build.ninja:5): input /a is out of date
+  /c (build.ninja:6): input /b is out of date
+"
+        );
+        Ok(())
+    }
+}
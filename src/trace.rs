@@ -101,7 +101,7 @@ pub fn open(path: &str) -> std::io::Result<()> {
 pub fn if_enabled(f: impl FnOnce(&mut Trace)) {
     // Safety: accessing global mut, not threadsafe.
     unsafe {
-        match &mut TRACE {
+        match &mut *std::ptr::addr_of_mut!(TRACE) {
             None => {}
             Some(t) => f(t),
         }
@@ -112,7 +112,7 @@ pub fn if_enabled(f: impl FnOnce(&mut Trace)) {
 pub fn scope<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
     // Safety: accessing global mut, not threadsafe.
     unsafe {
-        match &mut TRACE {
+        match &mut *std::ptr::addr_of_mut!(TRACE) {
             None => f(),
             Some(t) => t.scope(name, f),
         }
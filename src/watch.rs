@@ -0,0 +1,268 @@
+//! --watch: after a build, wait for input files to change and build again.
+//!
+//! With the `watch` cargo feature, the directories of the watched files are
+//! watched for changes through the notify crate, which wraps each platform's
+//! change notifications.  An event
+//! only says that something in a directory changed, so the watched files are
+//! then stat()ed to find which of them did.  That also copes with editors
+//! that save by writing a new file and renaming it over the old one, which
+//! shows up as events about other names.  A watched directory, such as one
+//! an include glob was expanded in, is watched itself too, so that a new file
+//! in it changes its mtime and brings a reload.  Where notifications can't be
+//! set up, such as for a directory that doesn't exist yet, or without the
+//! feature, the files are polled with stat() instead.
+
+use crate::graph::{self, FileId, Graph, MTime, Symlinks};
+use crate::signal;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// How long to wait between checks for ^C, and between stat()s of the
+/// watched files when polling.  A change is also only acted on once the
+/// files have stopped changing for this long.
+const POLL: Duration = Duration::from_millis(200);
+
+pub struct Watcher {
+    /// Each watched file with its state as last seen; None if it couldn't
+    /// be stat()ed.
+    files: Vec<(FileId, PathBuf, Option<MTime>)>,
+    watched: HashSet<FileId>,
+    symlinks: Symlinks,
+    /// None if the platform's notifications couldn't be set up at all.
+    notifier: Option<notifier::Notifier>,
+    /// Directories already registered with the notifier.
+    dirs: HashSet<PathBuf>,
+    /// Receives a message for each notification.
+    events: mpsc::Receiver<()>,
+    /// Set if any file's directory couldn't be watched, so all are polled.
+    poll_always: bool,
+}
+
+impl Watcher {
+    pub fn new(graph: &Graph, ids: &[FileId], symlinks: Symlinks) -> Self {
+        let (tx, events) = mpsc::channel();
+        let notifier = notifier::Notifier::new(tx);
+        let mut watcher = Watcher {
+            files: Vec::new(),
+            watched: HashSet::new(),
+            symlinks,
+            poll_always: notifier.is_none(),
+            notifier,
+            dirs: HashSet::new(),
+            events,
+        };
+        watcher.add(graph, ids);
+        watcher
+    }
+
+    /// Also watch the given files, skipping any already watched.  This lets
+    /// the watched set grow as builds discover new dependencies.
+    pub fn add(&mut self, graph: &Graph, ids: &[FileId]) {
+        for &id in ids {
+            if !self.watched.insert(id) {
+                continue;
+            }
+            let path = graph.file(id).path().to_path_buf();
+            self.watch_dir(parent_dir(&path));
+            self.watch_if_dir(&path);
+            let mtime = graph::stat(&path, self.symlinks).ok();
+            self.files.push((id, path, mtime));
+        }
+    }
+
+    /// Watch a directory's own contents, for files added to it.
+    fn watch_if_dir(&mut self, path: &Path) {
+        if path.is_dir() {
+            self.watch_dir(path);
+        }
+    }
+
+    fn watch_dir(&mut self, dir: &Path) {
+        let notifier = match &mut self.notifier {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        if self.dirs.contains(dir) {
+            return;
+        }
+        if !notifier.watch(dir) {
+            self.poll_always = true;
+        }
+        self.dirs.insert(dir.to_path_buf());
+    }
+
+    /// Re-stat every file, returning those that changed since last time.
+    fn poll(&mut self) -> Vec<FileId> {
+        let mut changed = Vec::new();
+        let mut appeared = Vec::new();
+        for (id, path, last) in &mut self.files {
+            let mtime = graph::stat(path, self.symlinks).ok();
+            if mtime != *last {
+                if matches!(last, None | Some(MTime::Missing)) {
+                    appeared.push(path.clone());
+                }
+                *last = mtime;
+                changed.push(*id);
+            }
+        }
+        for path in appeared {
+            self.watch_if_dir(&path);
+        }
+        changed
+    }
+
+    /// Block until some files change, then until they've stopped changing
+    /// for a poll interval, so that a burst of edits (like a save of many
+    /// files, or an editor's write-then-rename) causes one rebuild rather
+    /// than several.  Returns None if interrupted by ^C instead.
+    pub fn wait(&mut self) -> Option<Vec<FileId>> {
+        let mut changed: Vec<FileId> = Vec::new();
+        loop {
+            let notified = self.events.recv_timeout(POLL).is_ok();
+            while self.events.try_recv().is_ok() {}
+            if signal::was_interrupted() {
+                return None;
+            }
+            let now = if notified || self.poll_always {
+                self.poll()
+            } else {
+                Vec::new()
+            };
+            if now.is_empty() && !changed.is_empty() {
+                return Some(changed);
+            }
+            for id in now {
+                if !changed.contains(&id) {
+                    changed.push(id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "watch")]
+mod notifier {
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    pub struct Notifier(notify::RecommendedWatcher);
+
+    impl Notifier {
+        /// None if the platform's notifications can't be set up.
+        pub fn new(tx: mpsc::Sender<()>) -> Option<Self> {
+            notify::recommended_watcher(move |_: notify::Result<notify::Event>| {
+                let _ = tx.send(());
+            })
+            .ok()
+            .map(Notifier)
+        }
+
+        /// Send a message on any change to a directory's entries; false if
+        /// the directory can't be watched.
+        pub fn watch(&mut self, dir: &Path) -> bool {
+            use notify::Watcher as _;
+            self.0
+                .watch(dir, notify::RecursiveMode::NonRecursive)
+                .is_ok()
+        }
+    }
+}
+
+/// Without the `watch` feature there are no notifications, so a Notifier
+/// can't be made and everything is polled.
+#[cfg(not(feature = "watch"))]
+mod notifier {
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    pub enum Notifier {}
+
+    impl Notifier {
+        pub fn new(_tx: mpsc::Sender<()>) -> Option<Self> {
+            None
+        }
+
+        pub fn watch(&mut self, _dir: &Path) -> bool {
+            match *self {}
+        }
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_changes() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        std::fs::write(&a, "")?;
+        let mut graph = Graph::default();
+        let a_id = graph.files.id_from_canonical(a.display().to_string());
+        let b_id = graph.files.id_from_canonical(b.display().to_string());
+        let mut watcher = Watcher::new(&graph, &[a_id, b_id], Symlinks::Follow);
+        assert!(watcher.poll().is_empty());
+
+        // An editor's atomic save: write elsewhere and rename over.
+        let tmp = dir.path().join("a.tmp");
+        std::fs::write(&tmp, "new")?;
+        filetime::set_file_mtime(&tmp, filetime::FileTime::from_unix_time(1, 0))?;
+        std::fs::rename(&tmp, &a)?;
+        // A file appearing counts as a change too.
+        std::fs::write(&b, "")?;
+        assert_eq!(watcher.poll(), vec![a_id, b_id]);
+        assert!(watcher.poll().is_empty());
+
+        std::fs::remove_file(&b)?;
+        assert_eq!(watcher.poll(), vec![b_id]);
+
+        // A new file in a watched directory changes the directory.
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub)?;
+        filetime::set_file_mtime(&sub, filetime::FileTime::from_unix_time(1, 0))?;
+        let sub_id = graph.files.id_from_canonical(sub.display().to_string());
+        watcher.add(&graph, &[sub_id]);
+        std::fs::write(sub.join("new.ninja"), "")?;
+        assert_eq!(watcher.poll(), vec![sub_id]);
+        Ok(())
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn notified_of_changes() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a");
+        std::fs::write(&a, "")?;
+        filetime::set_file_mtime(&a, filetime::FileTime::from_unix_time(1, 0))?;
+        let mut graph = Graph::default();
+        let a_id = graph.files.id_from_canonical(a.display().to_string());
+        let mut watcher = Watcher::new(&graph, &[], Symlinks::Follow);
+        // Files can be added after the fact, as deps are discovered.
+        watcher.add(&graph, &[a_id, a_id]);
+        assert_eq!(watcher.files.len(), 1);
+        assert!(!watcher.poll_always, "notifications unavailable");
+
+        std::fs::write(&a, "new")?;
+        assert_eq!(watcher.wait(), Some(vec![a_id]));
+
+        // A new file in a watched directory changes the directory.
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub)?;
+        filetime::set_file_mtime(&sub, filetime::FileTime::from_unix_time(1, 0))?;
+        let sub_id = graph.files.id_from_canonical(sub.display().to_string());
+        watcher.add(&graph, &[sub_id]);
+        std::fs::write(sub.join("new.ninja"), "")?;
+        assert_eq!(watcher.wait(), Some(vec![sub_id]));
+        Ok(())
+    }
+}
@@ -1,11 +1,23 @@
 //! Build runner, choosing and executing tasks as determined by out of date inputs.
 
 use crate::{
-    canon::canon_path, db, densemap::DenseMap, graph::*, hash, process, progress,
-    progress::Progress, signal, smallmap::SmallMap, task, trace,
+    canon::{canon_path, canon_path_physical},
+    db,
+    densemap::{DenseMap, Index},
+    dyndep, events,
+    graph::*,
+    hash, log, ninja_log, process, progress,
+    progress::Progress,
+    scanner, signal,
+    smallmap::SmallMap,
+    stats, task, trace,
 };
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Build steps go through this sequence of states.
 /// See "Build states" in the design notes.
@@ -90,6 +102,49 @@ impl PoolState {
     }
 }
 
+/// Estimates how long the rest of a build will take, from how long each
+/// build took the last time it ran.
+struct Eta {
+    /// How long each build is expected to take.  Builds without a recorded
+    /// time are expected to take the median of those with one.
+    expected: DenseMap<BuildId, Duration>,
+    /// Sum of the expected times of the builds wanted but not yet finished.
+    /// Builds still to be checked are assumed to need to run: the ones whose
+    /// inputs are all up to date are checked right away, so those left
+    /// waiting are mostly behind builds that are running.
+    pending: Duration,
+    /// When each running build started.
+    started: HashMap<BuildId, Instant>,
+}
+
+impl Eta {
+    /// Returns None if there's no history to estimate from.
+    fn new(graph: &Graph, hashes: &Hashes) -> Option<Self> {
+        let median = hashes.median_time()?;
+        let mut expected = DenseMap::new_sized(graph.builds.next_id(), Duration::ZERO);
+        for id in graph.builds.all_ids() {
+            if graph.builds[id].cmdline.is_some() {
+                expected[id] = hashes.get_time(id).unwrap_or(median);
+            }
+        }
+        Some(Eta {
+            expected,
+            pending: Duration::ZERO,
+            started: HashMap::new(),
+        })
+    }
+
+    /// The time left, assuming the pending builds run `parallelism` at once.
+    fn remaining(&self, pending_builds: usize, parallelism: usize) -> Duration {
+        let now = Instant::now();
+        let mut left = self.pending;
+        for (&id, &start) in &self.started {
+            left = left.saturating_sub(now.duration_since(start).min(self.expected[id]));
+        }
+        left / parallelism.min(pending_builds).max(1) as u32
+    }
+}
+
 /// BuildStates tracks progress of each Build step through the build.
 /// See "Tracking build state" in the design notes.
 struct BuildStates {
@@ -108,14 +163,24 @@ struct BuildStates {
     /// Named pools of queued and running builds.
     /// Builds otherwise default to using an unnamed infinite pool.
     pools: SmallMap<String, PoolState>,
+
+    /// Megabytes that running builds may use between them, if limited; see
+    /// Options::max_memory.
+    max_memory: Option<u64>,
+
+    /// Sum of the `mem` hints of the running builds.
+    running_memory: u64,
+
+    /// For estimating the time left, if there's history to go by.
+    eta: Option<Eta>,
 }
 
 impl BuildStates {
-    fn new(size: BuildId, depths: SmallMap<String, usize>) -> Self {
+    fn new(size: BuildId, depths: SmallMap<String, usize>, max_memory: Option<u64>) -> Self {
         let mut pools = SmallMap::default();
         // The implied default pool.
         pools.insert(String::from(""), PoolState::new(0));
-        // TODO: the console pool is just a depth-1 pool for now.
+        // Builds in the console pool run one at a time, with the terminal.
         pools.insert(String::from("console"), PoolState::new(1));
         for (name, depth) in depths.into_iter() {
             pools.insert(name, PoolState::new(depth));
@@ -126,6 +191,27 @@ impl BuildStates {
             total_pending: 0,
             ready: VecDeque::new(),
             pools,
+            max_memory,
+            running_memory: 0,
+            eta: None,
+        }
+    }
+
+    /// Forget every build's state, to start over with another build of the
+    /// same graph.
+    fn reset(&mut self, size: BuildId) {
+        self.states = DenseMap::new_sized(size, BuildState::Unknown);
+        self.counts = StateCounts::default();
+        self.total_pending = 0;
+        self.ready.clear();
+        for (_, pool) in self.pools.iter_mut() {
+            pool.queued.clear();
+            pool.running = 0;
+        }
+        self.running_memory = 0;
+        if let Some(eta) = &mut self.eta {
+            eta.pending = Duration::ZERO;
+            eta.started.clear();
         }
     }
 
@@ -144,9 +230,16 @@ impl BuildStates {
         // println!("{:?} {:?}=>{:?} {:?}", id, prev, state, self.counts);
         if prev == BuildState::Unknown {
             self.total_pending += 1;
+            if let Some(eta) = &mut self.eta {
+                eta.pending += eta.expected[id];
+            }
         } else {
             if prev == BuildState::Running {
                 self.get_pool(build).unwrap().running -= 1;
+                self.running_memory -= build.mem();
+                if let Some(eta) = &mut self.eta {
+                    eta.started.remove(&id);
+                }
             }
             if !skip_ui_count {
                 self.counts.add(prev, -1);
@@ -164,9 +257,16 @@ impl BuildStates {
                 //     trace::if_enabled(|t| t.write_instant("first build"));
                 // }
                 self.get_pool(build).unwrap().running += 1;
+                self.running_memory += build.mem();
+                if let Some(eta) = &mut self.eta {
+                    eta.started.insert(id, Instant::now());
+                }
             }
             BuildState::Done | BuildState::Failed => {
                 self.total_pending -= 1;
+                if let Some(eta) = &mut self.eta {
+                    eta.pending = eta.pending.saturating_sub(eta.expected[id]);
+                }
             }
             _ => {}
         };
@@ -195,6 +295,13 @@ impl BuildStates {
         self.total_pending > 0
     }
 
+    /// The estimated time left in the build, if there's history to go by.
+    fn eta(&self, parallelism: usize) -> Option<Duration> {
+        let eta = self.eta.as_ref()?;
+        let finished = self.counts.get(BuildState::Done) + self.counts.get(BuildState::Failed);
+        Some(eta.remaining(self.counts.total() - finished, parallelism))
+    }
+
     /// Visits a BuildId that is an input to the desired output.
     /// Will recursively visit its own inputs.
     fn want_build(
@@ -290,19 +397,66 @@ impl BuildStates {
         Ok(())
     }
 
-    /// Pop a ready to run queued build.
-    pub fn pop_queued(&mut self) -> Option<BuildId> {
-        for (_, pool) in self.pools.iter_mut() {
-            if pool.depth == 0 || pool.running < pool.depth {
-                if let Some(id) = pool.queued.pop_front() {
-                    return Some(id);
+    /// Pop a ready to run queued build.  Unless `any`, only the console
+    /// pool is considered, as its builds don't count against parallelism.
+    /// A pool's depth caps how many of its builds run at once; the memory
+    /// budget can only hold them back further.
+    pub fn pop_queued(&mut self, graph: &Graph, any: bool) -> Option<BuildId> {
+        // A build is always let run when nothing else is using memory, so
+        // that one larger than the whole budget doesn't stall the build.
+        let (max_memory, running_memory) = (self.max_memory, self.running_memory);
+        let over_memory = |mem: u64| match max_memory {
+            Some(max) => running_memory > 0 && running_memory + mem > max,
+            None => false,
+        };
+        for (name, pool) in self.pools.iter_mut() {
+            if !any && name != "console" {
+                continue;
+            }
+            if pool.depth != 0 && pool.running >= pool.depth {
+                continue;
+            }
+            match pool.queued.front() {
+                Some(&id) if !over_memory(graph.builds[id].mem()) => {
+                    return pool.queued.pop_front();
                 }
+                _ => {}
             }
         }
         None
     }
 }
 
+/// Why a build needs to run, as found by Work::dirty_reason().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dirty {
+    /// One of its inputs or outputs is missing.
+    Missing(FileId),
+    /// It hasn't run before, as far as the .n2_db knows.
+    NoPreviousState,
+    /// Its command or the mtimes of its files differ from its last run, and
+    /// with content hashing, so do the files' contents.
+    Changed,
+}
+
+impl Dirty {
+    /// Describe the reason for the build `id`.
+    pub fn describe(&self, graph: &Graph, id: BuildId) -> String {
+        match *self {
+            Dirty::Missing(file) => {
+                let desc = if graph.builds[id].outs().contains(&file) {
+                    "output"
+                } else {
+                    "input"
+                };
+                format!("{} {} missing", desc, graph.file(file).name)
+            }
+            Dirty::NoPreviousState => "no previous state known".to_owned(),
+            Dirty::Changed => "command or files changed since last run".to_owned(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Options {
     pub failures_left: Option<usize>,
@@ -311,38 +465,170 @@ pub struct Options {
     pub explain: bool,
     /// When true, just mark targets up to date without running anything.
     pub adopt: bool,
+    /// When true, print the commands of dirty builds instead of running them.
+    pub dry_run: bool,
+    /// When true, a build whose inputs' mtimes changed but whose file
+    /// contents all match those of its last run is considered up to date.
+    /// This costs reading every file of such builds in full, and of every
+    /// build that runs, to record their content hashes.
+    pub content_hash: bool,
+    /// When true, symlinks are stat()ed as links, so a build is dirtied when
+    /// one of its inputs is repointed; see graph::Symlinks.
+    pub track_symlinks: bool,
+    /// Resolve ".." in discovered deps against any symlink it follows, to
+    /// match load::Options::resolve_symlinks.
+    pub resolve_symlinks: bool,
+    /// Megabytes of memory that running builds may use between them, going
+    /// by their `mem = N` hints.  Builds without a hint count as using none.
+    pub max_memory: Option<u64>,
+    /// Don't start new commands while the system load average is above
+    /// this, as with `ninja -l`, unless nothing is running.  Only Unix has a
+    /// load average; elsewhere this has no effect.
+    pub max_load_average: Option<f64>,
+    /// Kill commands that run longer than this, failing their builds, unless
+    /// the build has a `timeout = N` of its own.
+    pub timeout: Option<Duration>,
+    /// When true, remove the explicit outputs of a build that fails, times
+    /// out, or is interrupted, so that a half-written output isn't mistaken
+    /// for a good one.  Only outputs the command modified are removed, and
+    /// generator builds' outputs are always kept.
+    pub delete_failed_outputs: bool,
+    /// Get the mtimes of source files from listings of their directories
+    /// where there are several, rather than stat()ing each one.  This is
+    /// meant to be faster on Windows; on Linux `cargo bench --bench stat`
+    /// shows it slower.
+    pub stat_by_directory: bool,
+    /// What to do about each category of warning found while building, as
+    /// with load::Options::warnings.
+    pub warnings: log::WarningConfig,
+}
+
+/// The command line's defaults, except for running one build at a time.
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            failures_left: Some(1),
+            parallelism: 1,
+            explain: false,
+            adopt: false,
+            dry_run: false,
+            content_hash: false,
+            track_symlinks: false,
+            resolve_symlinks: false,
+            max_memory: None,
+            max_load_average: None,
+            timeout: None,
+            delete_failed_outputs: true,
+            stat_by_directory: false,
+            warnings: log::WarningConfig::default(),
+        }
+    }
 }
 
 pub struct Work<'a> {
     graph: Graph,
-    db: db::Writer,
+    /// The database to record builds in, absent if running without one.
+    db: Option<db::Writer>,
+    /// A Ninja-format log to also record builds in, if requested.
+    ninja_log: Option<ninja_log::Writer>,
+    /// When this Work was created, which .ninja_log times are relative to.
+    start: Instant,
+    /// Where to write --events-json events, if requested.
+    events: Option<events::Writer>,
     pub progress: &'a mut dyn Progress,
     options: Options,
+    /// options.failures_left as given, which run() counts down.
+    failures_allowed: Option<usize>,
     file_state: FileState,
     last_hashes: Hashes,
     build_states: BuildStates,
+    /// Dyndep files already loaded into the graph.
+    dyndeps_loaded: HashSet<FileId>,
+    /// Phony outputs found missing on disk, which stand for their inputs.
+    phony_aliases: HashSet<FileId>,
+    /// In a dry run, the outputs of builds that would have run, which dirty
+    /// their dependents even though nothing on disk changed.
+    dry_run_outs: HashSet<FileId>,
+    /// How long each command that ran took, for stats().
+    task_times: Vec<(BuildId, Duration)>,
 }
 
 impl<'a> Work<'a> {
     pub fn new(
         graph: Graph,
         last_hashes: Hashes,
-        db: db::Writer,
+        db: Option<db::Writer>,
+        ninja_log: Option<ninja_log::Writer>,
         options: &Options,
         progress: &'a mut dyn Progress,
         pools: SmallMap<String, usize>,
     ) -> Self {
-        let file_state = FileState::new(&graph);
+        let symlinks = if options.track_symlinks {
+            Symlinks::Track
+        } else {
+            Symlinks::Follow
+        };
+        let file_state = FileState::with_symlinks(&graph, symlinks);
         let build_count = graph.builds.next_id();
+        let mut build_states = BuildStates::new(build_count, pools, options.max_memory);
+        build_states.eta = Eta::new(&graph, &last_hashes);
         Work {
             graph,
             db,
+            ninja_log,
+            start: Instant::now(),
+            events: None,
             progress,
             options: options.clone(),
+            failures_allowed: options.failures_left,
             file_state,
             last_hashes,
-            build_states: BuildStates::new(build_count, pools),
+            build_states,
+            dyndeps_loaded: HashSet::new(),
+            phony_aliases: HashSet::new(),
+            dry_run_outs: HashSet::new(),
+            task_times: Vec::new(),
+        }
+    }
+
+    /// Also report the progress of the build as JSON events.
+    pub fn set_events(&mut self, events: events::Writer) {
+        self.events = Some(events);
+    }
+
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    /// Let `f` edit the graph and the hashes last recorded for its builds, as
+    /// State::reload() does when a subninja changes under --watch.  Builds
+    /// may be replaced or added, but existing ids must stay valid.  What's
+    /// known of files carries over, so call reset() afterwards as usual.
+    pub fn update_graph<R>(&mut self, f: impl FnOnce(&mut Graph, &mut Hashes) -> R) -> R {
+        f(&mut self.graph, &mut self.last_hashes)
+    }
+
+    /// Prepare to build again after the given source files changed, as with
+    /// --watch.  What's known of the other source files is kept, so only the
+    /// changed ones are stat()ed again; generated files are always stat()ed
+    /// again, as a failed or interrupted build may have left them changed.
+    /// Targets must be wanted again before the next run().
+    pub fn reset(&mut self, changed: &[FileId]) {
+        self.build_states.reset(self.graph.builds.next_id());
+        // Take in the times of the builds that just ran.
+        self.build_states.eta = Eta::new(&self.graph, &self.last_hashes);
+        for id in self.graph.files.all_ids() {
+            if self.graph.file(id).input.is_some() {
+                self.file_state.forget(id);
+            }
         }
+        for &id in changed {
+            self.file_state.forget(id);
+        }
+        self.options.failures_left = self.failures_allowed;
+        self.dry_run_outs.clear();
+        self.task_times.clear();
+        self.start = Instant::now();
     }
 
     pub fn lookup(&mut self, name: &str) -> Option<FileId> {
@@ -363,7 +649,9 @@ impl<'a> Work<'a> {
             }
             self.want_file(id)?;
         }
-        Ok(())
+        // Every file is wanted, so gather their state up front.
+        self.file_state
+            .stat_all(&self.graph.files, self.options.stat_by_directory)
     }
 
     /// Check whether a given build is ready, generally after one of its inputs
@@ -438,14 +726,184 @@ impl<'a> Work<'a> {
         Ok(None)
     }
 
-    /// Given a task that just finished, record any discovered deps and hash.
+    /// Verify that a discovered dep of a build doesn't make the build depend
+    /// on itself, either directly via one of its own outputs or indirectly via
+    /// a generated file whose build depends on this build's outputs.
+    /// `visited` is shared across the deps of one build; see build_depends_on().
+    fn check_discovered_dep(
+        &self,
+        id: BuildId,
+        dep: FileId,
+        visited: &mut HashSet<BuildId>,
+    ) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        let file = self.graph.file(dep);
+        if build.outs().contains(&dep) {
+            anyhow::bail!(
+                "{}: depfile lists output {} as an input",
+                build.location,
+                file.name
+            );
+        }
+        if let Some(input) = file.input {
+            if self.build_depends_on(input, id, visited) {
+                anyhow::bail!(
+                    "{}: depfile input {} would create a dependency cycle",
+                    build.location,
+                    file.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether build `from` (transitively) depends on an output of
+    /// build `target`.  Builds in `visited` are known not to, so checking
+    /// several builds against the same target can share it, to walk each part
+    /// of the graph only once.
+    fn build_depends_on(
+        &self,
+        from: BuildId,
+        target: BuildId,
+        visited: &mut HashSet<BuildId>,
+    ) -> bool {
+        let mut stack = vec![from];
+        while let Some(id) = stack.pop() {
+            if id == target {
+                return true;
+            }
+            if !visited.insert(id) {
+                continue;
+            }
+            for &file in self.graph.builds[id].ordering_ins() {
+                if let Some(input) = self.graph.file(file).input {
+                    stack.push(input);
+                }
+            }
+        }
+        false
+    }
+
+    /// Load the dyndep file of a ready build, if it has one not yet loaded,
+    /// adding the edges it lists to the graph.  The file is an input of the
+    /// build, so it's up to date by now.  The file may also list other
+    /// builds naming it; any of those already wanted start wanting their
+    /// new inputs too.
+    fn load_dyndep(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let dd = match self.graph.builds[id].dyndep {
+            Some(dd) if !self.dyndeps_loaded.contains(&dd) => dd,
+            _ => return Ok(()),
+        };
+        self.dyndeps_loaded.insert(dd);
+        let path = self.graph.file(dd).path().to_path_buf();
+        let bytes = trace::scope("read dyndep", || scanner::read_file_with_nul(&path))
+            .map_err(|err| anyhow::anyhow!("read {}: {}", path.display(), err))?;
+        for b in dyndep::parse(&path, &bytes)? {
+            let bid = self
+                .graph
+                .files
+                .lookup(&canon_path(&b.out))
+                .and_then(|out| self.graph.file(out).input)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "{}: no build statement exists for {}",
+                        path.display(),
+                        b.out
+                    )
+                })?;
+            if self.graph.builds[bid].dyndep != Some(dd) {
+                anyhow::bail!(
+                    "{}: build for {} doesn't name this file as its dyndep",
+                    path.display(),
+                    b.out
+                );
+            }
+            let ins: Vec<FileId> = b
+                .implicit_ins
+                .into_iter()
+                .map(|name| self.graph.files.id_from_canonical(canon_path(name)))
+                .collect();
+            let outs: Vec<FileId> = b
+                .implicit_outs
+                .into_iter()
+                .map(|name| self.graph.files.id_from_canonical(canon_path(name)))
+                .collect();
+            let mut visited = HashSet::new();
+            for &input in &ins {
+                if let Some(input_build) = self.graph.file(input).input {
+                    if self.build_depends_on(input_build, bid, &mut visited) {
+                        anyhow::bail!(
+                            "{}: dyndep input {} would create a dependency cycle",
+                            self.graph.builds[bid].location,
+                            self.graph.file(input).name
+                        );
+                    }
+                }
+            }
+            let new_ins = self.graph.add_dyndep_edges(bid, &ins, &outs)?;
+            if self.build_states.get(bid) != BuildState::Unknown {
+                for input in new_ins {
+                    let mut stack = Vec::new();
+                    self.build_states
+                        .want_file(&self.graph, &mut stack, input)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warn about depfile targets that aren't outputs of the build, which
+    /// usually means the rule tells the compiler the wrong output name.
+    fn check_depfile_targets(&mut self, id: BuildId, targets: &[String]) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        let mut warnings = Vec::new();
+        for target in targets {
+            let name = canon_path(target.as_str());
+            if build
+                .outs()
+                .iter()
+                .any(|&out| self.graph.file(out).name == name)
+            {
+                continue;
+            }
+            let warning = log::Warning {
+                loc: build.location.clone(),
+                category: log::Category::DepfileTarget,
+                message: format!(
+                    "depfile names {:?}, which isn't an output of this build",
+                    target
+                ),
+            };
+            self.options.warnings.apply(warning, &mut warnings)?;
+        }
+        for warning in warnings {
+            warn(self.progress, format_args!("{}", warning));
+        }
+        Ok(())
+    }
+
+    /// Given a task that just finished, record any discovered deps and hash,
+    /// and how long it took if it ran.
     /// Postcondition: all outputs have been stat()ed.
-    fn record_finished(&mut self, id: BuildId, result: task::TaskResult) -> anyhow::Result<()> {
+    fn record_finished(
+        &mut self,
+        id: BuildId,
+        result: task::TaskResult,
+        time: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        self.check_depfile_targets(id, &result.depfile_targets)?;
+
         // Clean up the deps discovered from the task.
         let mut deps = Vec::new();
+        let mut visited = HashSet::new();
         if let Some(names) = result.discovered_deps {
             for name in names {
-                let fileid = self.graph.files.id_from_canonical(canon_path(name));
+                let name = if self.options.resolve_symlinks {
+                    canon_path_physical(name)
+                } else {
+                    canon_path(name)
+                };
+                let fileid = self.graph.files.id_from_canonical(name);
                 // Filter duplicates from the file list.
                 if deps.contains(&fileid) {
                     continue;
@@ -456,6 +914,7 @@ impl<'a> Work<'a> {
                 if self.graph.builds[id].dirtying_ins().contains(&fileid) {
                     continue;
                 }
+                self.check_discovered_dep(id, fileid, &mut visited)?;
                 deps.push(fileid);
             }
         }
@@ -486,10 +945,57 @@ impl<'a> Work<'a> {
             return Ok(());
         }
 
+        let db = match &mut self.db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
         let build = &self.graph.builds[id];
         let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
-        self.db.write_build(&self.graph, id, hash)?;
+        // If the files can't be read, their contents are unknown, so no
+        // content hash is recorded and the next check falls back to mtimes.
+        let content_hash = if self.options.content_hash {
+            hash::hash_build_contents(&self.graph.files, &self.file_state, build).ok()
+        } else {
+            None
+        };
+        // A build that didn't run keeps the time it took before.
+        let time = time.or(self.last_hashes.get_time(id));
+        db.write_build(&self.graph, id, hash, content_hash, time)?;
+        // Keep what was recorded for any later build by this Work, as with
+        // --watch.
+        self.last_hashes.set(id, hash);
+        self.last_hashes.set_content(id, content_hash);
+        self.last_hashes.set_time(id, time);
+
+        Ok(())
+    }
 
+    /// Append a finished build's outputs to the .ninja_log, if there is one.
+    /// Must be called after record_finished(), which stats the outputs.
+    fn write_ninja_log(&mut self, id: BuildId, span: (Instant, Instant)) -> anyhow::Result<()> {
+        let log = match &mut self.ninja_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let build = &self.graph.builds[id];
+        let command = match &build.cmdline {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        for &out in build.outs() {
+            let mtime = match self.file_state.get(out) {
+                Some(MTime::Stamp(mtime)) => Some(mtime),
+                Some(MTime::Link { link, .. }) => Some(link),
+                _ => None,
+            };
+            log.write_entry(&ninja_log::Entry {
+                output: &self.graph.file(out).name,
+                command,
+                start: span.0.saturating_duration_since(self.start),
+                end: span.1.saturating_duration_since(self.start),
+                mtime,
+            })?;
+        }
         Ok(())
     }
 
@@ -578,37 +1084,82 @@ impl<'a> Work<'a> {
 
         // Maintain the invariant that we have stat info for all outputs, but
         // we generally don't expect them to have been created.
-        // TODO: what should happen if a rule uses a phony output as its own input?
-        // The Ninja manual suggests you can use phony rules to aggregate outputs
-        // together, so we might need to create some sort of fake mtime here?
-        self.stat_all_outputs(id)?;
+        if self.stat_all_outputs(id)?.is_none() {
+            return Ok(());
+        }
+
+        // A phony output that doesn't exist on disk is an alias for its
+        // inputs, so give it a stamp made from all of their states: builds
+        // depending on the alias then see it change whenever any input does,
+        // whether its mtime moves forward or back, or it goes missing.  With
+        // no inputs present it stays missing, so dependents always run, as
+        // with Ninja's `build FORCE: phony` idiom.
+        let mut hasher = DefaultHasher::new();
+        let mut present = false;
+        for &input in self.graph.builds[id].dirtying_ins() {
+            let mtime = match self.file_state.get(input) {
+                Some(mtime) => mtime,
+                None => self.file_state.stat(input, self.graph.file(input).path())?,
+            };
+            present |= mtime != MTime::Missing;
+            mtime.hash(&mut hasher);
+        }
+        let stamp = SystemTime::UNIX_EPOCH + Duration::from_nanos(hasher.finish());
+        for &out in self.graph.builds[id].outs() {
+            if self.file_state.get(out) != Some(MTime::Missing) {
+                self.phony_aliases.remove(&out);
+                continue;
+            }
+            self.phony_aliases.insert(out);
+            if present {
+                self.file_state.set(out, MTime::Stamp(stamp));
+            }
+        }
         Ok(())
     }
 
     /// Check a ready build for whether it needs to run, returning true if so.
     /// Prereq: any dependent input is already generated.
     fn check_build_dirty(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        let dirty = match self.dirty_reason(id)? {
+            Some(dirty) => dirty,
+            None => return Ok(false),
+        };
+        if self.options.explain {
+            let build = &self.graph.builds[id];
+            self.progress.log(&format!(
+                "explain: {}: {}",
+                build.location,
+                dirty.describe(&self.graph, id)
+            ));
+            if dirty == Dirty::Changed {
+                self.progress.log(&hash::explain_hash_build(
+                    &self.graph.files,
+                    &self.file_state,
+                    build,
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Like check_build_dirty(), but returning why the build needs to run,
+    /// for tools that explain it.  Prereq: any dependent input is already
+    /// generated, or has been passed to dirty_reason() itself.
+    pub fn dirty_reason(&mut self, id: BuildId) -> anyhow::Result<Option<Dirty>> {
         let build = &self.graph.builds[id];
         let phony = build.cmdline.is_none();
         let file_missing = if phony {
             self.check_build_files_missing_phony(id)?;
-            return Ok(false); // Phony builds never need to run anything.
+            return Ok(None); // Phony builds never need to run anything.
         } else {
             self.check_build_files_missing(id)?
         };
 
         // If any files are missing, the build is dirty without needing
         // to consider hashes.
-        let build = &self.graph.builds[id];
         if let Some(missing) = file_missing {
-            if self.options.explain {
-                self.progress.log(&format!(
-                    "explain: {}: input {} missing",
-                    build.location,
-                    self.graph.file(missing).name
-                ));
-            }
-            return Ok(true);
+            return Ok(Some(Dirty::Missing(missing)));
         }
 
         // If we get here, all the relevant files are present and stat()ed,
@@ -618,33 +1169,125 @@ impl<'a> Work<'a> {
         // More complex than just moving this block up, because we currently
         // assume that we've always checked inputs after we've run a build.
         let prev_hash = match self.last_hashes.get(id) {
-            None => {
-                if self.options.explain {
-                    self.progress.log(&format!(
-                        "explain: {}: no previous state known",
-                        build.location
-                    ));
-                }
-                return Ok(true);
-            }
+            None => return Ok(Some(Dirty::NoPreviousState)),
             Some(prev_hash) => prev_hash,
         };
 
+        let build = &self.graph.builds[id];
         let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
-        if prev_hash != hash {
-            if self.options.explain {
-                self.progress
-                    .log(&format!("explain: {}: manifest changed", build.location));
-                self.progress.log(&hash::explain_hash_build(
-                    &self.graph.files,
-                    &self.file_state,
-                    build,
-                ));
-            }
-            return Ok(true);
+        if prev_hash != hash && !self.contents_unchanged(id, hash)? {
+            return Ok(Some(Dirty::Changed));
         }
 
-        Ok(false)
+        Ok(None)
+    }
+
+    /// With content hashing on, check whether a build whose mtime-based hash
+    /// changed has the same file contents as its last run.  If so, record
+    /// the new hash so the next check can skip reading the files.
+    fn contents_unchanged(&mut self, id: BuildId, hash: hash::BuildHash) -> anyhow::Result<bool> {
+        if !self.options.content_hash {
+            return Ok(false);
+        }
+        let prev_content_hash = match self.last_hashes.get_content(id) {
+            Some(prev) => prev,
+            None => return Ok(false),
+        };
+        let build = &self.graph.builds[id];
+        if build
+            .dirtying_ins()
+            .iter()
+            .any(|&input| self.is_phony_alias(input))
+        {
+            // The alias has no contents of its own to compare.
+            return Ok(false);
+        }
+        let content_hash =
+            match hash::hash_build_contents(&self.graph.files, &self.file_state, build) {
+                Ok(content_hash) if content_hash == prev_content_hash => content_hash,
+                // Changed, or unreadable and so not known to be unchanged.
+                _ => return Ok(false),
+            };
+        if self.options.explain {
+            self.progress.log(&format!(
+                "explain: {}: files touched but contents unchanged",
+                build.location
+            ));
+        }
+        if let Some(db) = &mut self.db {
+            let time = self.last_hashes.get_time(id);
+            db.write_build(&self.graph, id, hash, Some(content_hash), time)?;
+            self.last_hashes.set(id, hash);
+        }
+        Ok(true)
+    }
+
+    /// Whether a file is a phony output with nothing on disk behind it.
+    fn is_phony_alias(&self, id: FileId) -> bool {
+        self.phony_aliases.contains(&id)
+    }
+
+    /// In a dry run, whether a build reads an output of a build that would
+    /// have run.
+    fn dry_run_dirty(&self, id: BuildId) -> bool {
+        self.options.dry_run
+            && self.graph.builds[id]
+                .dirtying_ins()
+                .iter()
+                .any(|id| self.dry_run_outs.contains(id))
+    }
+
+    /// Print the command a dirty build would run, and treat it as done.
+    /// Phony builds print nothing but still pass on their dirtiness.
+    fn dry_run_build(&mut self, id: BuildId) -> bool {
+        let build = &self.graph.builds[id];
+        self.dry_run_outs.extend(build.outs());
+        if build.cmdline.is_none() {
+            self.ready_dependents(id);
+            return false;
+        }
+        self.progress.log(&progress::command_text(build));
+        self.ready_dependents(id);
+        true
+    }
+
+    /// Fail before running anything if a wanted build reads a source file
+    /// that doesn't exist, listing every such file, rather than failing only
+    /// once the build gets to it.
+    fn check_missing_sources(&mut self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        for id in self.graph.builds.all_ids() {
+            if self.build_states.get(id) == BuildState::Unknown {
+                continue;
+            }
+            let build = &self.graph.builds[id];
+            if build.cmdline.is_none() {
+                // Phony inputs may be missing; see check_build_files_missing_phony.
+                continue;
+            }
+            for &input in build.dirtying_ins() {
+                let file = self.graph.file(input);
+                if file.input.is_some() {
+                    continue;
+                }
+                let mtime = match self.file_state.get(input) {
+                    Some(mtime) => mtime,
+                    None => self.file_state.stat(input, file.path())?,
+                };
+                if mtime == MTime::Missing {
+                    errors.push(format!(
+                        "{}: {}, needed by {}, missing and no known rule to make it",
+                        build.location,
+                        file.name,
+                        self.graph.file(build.outs()[0]).name
+                    ));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!(errors.join("\n"));
+        }
+        Ok(())
     }
 
     /// Create the parent directories of a given list of fileids.
@@ -654,7 +1297,7 @@ impl<'a> Work<'a> {
         let mut dirs: Vec<&std::path::Path> = Vec::new();
         for &out in ids {
             if let Some(parent) = self.graph.file(out).path().parent() {
-                if dirs.iter().any(|&p| p == parent) {
+                if dirs.contains(&parent) {
                     continue;
                 }
                 std::fs::create_dir_all(parent)?;
@@ -669,11 +1312,20 @@ impl<'a> Work<'a> {
     pub fn run(&mut self) -> anyhow::Result<Option<usize>> {
         #[cfg(unix)]
         signal::register_sigint();
+        self.check_missing_sources()?;
+        let considered = self.build_states.counts.total();
+        match &mut self.events {
+            Some(events) if considered > 0 => events.begin(considered)?,
+            _ => {}
+        }
         let mut tasks_done = 0;
-        let mut tasks_failed = 0;
-        let mut runner = task::Runner::new(self.options.parallelism);
+        let mut failed = Vec::new();
+        let mut runner = task::Runner::new(self.options.parallelism, self.options.timeout);
         while self.build_states.unfinished() {
-            self.progress.update(&self.build_states.counts);
+            self.progress.update(
+                &self.build_states.counts,
+                self.build_states.eta(self.options.parallelism),
+            );
 
             // Approach:
             // - First make sure we're running as many queued tasks as the runner
@@ -686,33 +1338,66 @@ impl<'a> Work<'a> {
             //   loop.
 
             let mut made_progress = false;
-            while runner.can_start_more() {
-                let id = match self.build_states.pop_queued() {
-                    Some(id) => id,
-                    None => break,
-                };
+            while let Some(id) = self.build_states.pop_queued(
+                &self.graph,
+                runner.can_start_more()
+                    && !over_load_average(
+                        self.options.max_load_average,
+                        runner.is_running(),
+                        process::load_average,
+                    ),
+            ) {
                 let build = &self.graph.builds[id];
                 self.build_states.set(id, build, BuildState::Running);
                 self.create_parent_dirs(build.outs())?;
+                // Know the outputs' state before running, so that a failure
+                // only deletes those the command went on to modify.
+                for &out in build.outs() {
+                    if self.file_state.get(out).is_none() {
+                        self.file_state.stat(out, self.graph.file(out).path())?;
+                    }
+                }
                 runner.start(id, build);
                 self.progress.task_started(id, build);
+                if let Some(events) = &mut self.events {
+                    events.start(&event_edge(&self.graph, id))?;
+                }
                 made_progress = true;
             }
 
             while let Some(id) = self.build_states.pop_ready() {
-                if !self.check_build_dirty(id)? {
+                if self.graph.builds[id].dyndep.is_some() {
+                    self.load_dyndep(id)?;
+                    if !self.recheck_ready(id) {
+                        // Its dyndep file added inputs that must be built first.
+                        let build = &self.graph.builds[id];
+                        self.build_states.set(id, build, BuildState::Want);
+                        made_progress = true;
+                        continue;
+                    }
+                }
+                if !self.dry_run_dirty(id) && !self.check_build_dirty(id)? {
                     // Not dirty; go directly to the Done state.
                     self.ready_dependents(id);
+                } else if self.options.dry_run {
+                    if self.dry_run_build(id) {
+                        tasks_done += 1;
+                    }
                 } else if self.options.adopt {
                     // Act as if the target already finished.
                     self.record_finished(
                         id,
                         task::TaskResult {
                             termination: process::Termination::Success,
+                            exit_code: None,
                             output: vec![],
                             discovered_deps: None,
+                            depfile_targets: Vec::new(),
                         },
+                        None,
                     )?;
+                    let now = Instant::now();
+                    self.write_ninja_log(id, (now, now))?;
                     self.ready_dependents(id);
                 } else {
                     self.build_states.enqueue(id, &self.graph.builds[id])?;
@@ -725,7 +1410,7 @@ impl<'a> Work<'a> {
             }
 
             if !runner.is_running() {
-                if tasks_failed > 0 {
+                if !failed.is_empty() {
                     // No more progress can be made, hopefully due to tasks that failed.
                     break;
                 }
@@ -735,6 +1420,9 @@ impl<'a> Work<'a> {
             let task = runner.wait(|id, line| {
                 self.progress.task_output(id, line);
             });
+            if task.result.termination != process::Termination::Success {
+                self.delete_failed_outputs(task.buildid);
+            }
             let build = &self.graph.builds[task.buildid];
             trace::if_enabled(|t| {
                 let desc = progress::build_message(build);
@@ -743,15 +1431,36 @@ impl<'a> Work<'a> {
 
             self.progress
                 .task_finished(task.buildid, build, &task.result);
+            let time = task.span.1 - task.span.0;
+            self.task_times.push((task.buildid, time));
+            if let Some(events) = &mut self.events {
+                let edge = event_edge(&self.graph, task.buildid);
+                let code = task.result.exit_code;
+                match task.result.termination {
+                    process::Termination::Success => events.finish(&edge, code, time)?,
+                    process::Termination::Failure => {
+                        let output = String::from_utf8_lossy(&task.result.output);
+                        events.error(&edge, "failure", code, time, &output)?
+                    }
+                    process::Termination::Interrupted => {
+                        events.error(&edge, "interrupted", code, time, "")?
+                    }
+                    process::Termination::TimedOut => {
+                        let output = String::from_utf8_lossy(&task.result.output);
+                        events.error(&edge, "timeout", code, time, &output)?
+                    }
+                }
+            }
             match task.result.termination {
-                process::Termination::Failure => {
+                process::Termination::Failure | process::Termination::TimedOut => {
+                    failed.push(task.buildid);
                     if let Some(failures_left) = &mut self.options.failures_left {
                         *failures_left -= 1;
                         if *failures_left == 0 {
+                            self.report_failures(&failed);
                             return Ok(None);
                         }
                     }
-                    tasks_failed += 1;
                     self.build_states
                         .set(task.buildid, build, BuildState::Failed);
                 }
@@ -761,7 +1470,8 @@ impl<'a> Work<'a> {
                 }
                 process::Termination::Success => {
                     tasks_done += 1;
-                    self.record_finished(task.buildid, task.result)?;
+                    self.record_finished(task.buildid, task.result, Some(time))?;
+                    self.write_ninja_log(task.buildid, task.span)?;
                     self.ready_dependents(task.buildid);
                 }
             };
@@ -771,9 +1481,118 @@ impl<'a> Work<'a> {
         // But at least for the LLVM test suite it can catch sigint and print
         // "interrupted by user" and exit with success, and in that case we
         // don't want n2 to print a "succeeded" message afterwards.
-        let success = tasks_failed == 0 && !signal::was_interrupted();
+        self.report_failures(&failed);
+        let success = failed.is_empty() && !signal::was_interrupted();
         Ok(success.then_some(tasks_done))
     }
+
+    /// Summarize the builds run so far, for --stats.
+    pub fn stats(&self) -> stats::Stats {
+        let mut slowest = self.task_times.clone();
+        slowest.sort_by_key(|&(_, time)| std::cmp::Reverse(time));
+        slowest.truncate(stats::SLOWEST);
+        let counts = &self.build_states.counts;
+        stats::Stats {
+            considered: counts.total(),
+            ran: self.task_times.len(),
+            failed: counts.get(BuildState::Failed),
+            wall: self.start.elapsed(),
+            slowest: slowest
+                .into_iter()
+                .map(|(id, time)| {
+                    (
+                        progress::build_message(&self.graph.builds[id]).to_owned(),
+                        time,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Remove the outputs of a build that didn't finish successfully; see
+    /// Options::delete_failed_outputs.
+    fn delete_failed_outputs(&mut self, id: BuildId) {
+        let build = &self.graph.builds[id];
+        if !self.options.delete_failed_outputs || build.generator {
+            return;
+        }
+        for &out in build.explicit_outs() {
+            let path = self.graph.file(out).path();
+            // Keep an output the command didn't touch, like one it never got
+            // to writing, or a restat build's unchanged output.
+            let before = self.file_state.get(out);
+            match self.file_state.stat(out, path) {
+                Ok(MTime::Missing) => continue,
+                Ok(now) if before == Some(now) => continue,
+                _ => {}
+            }
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn(
+                    self.progress,
+                    format_args!("remove {}: {}", path.display(), err),
+                ),
+            }
+            self.file_state.forget(out);
+        }
+    }
+
+    /// With -k, failures are interleaved with the output of builds that
+    /// kept going, so list them all again at the end.  A lone failure is
+    /// already the last thing printed.
+    fn report_failures(&mut self, failed: &[BuildId]) {
+        if failed.len() < 2 {
+            return;
+        }
+        self.progress
+            .log(&format!("n2: {} builds failed:", failed.len()));
+        for &id in failed {
+            let build = &self.graph.builds[id];
+            self.progress.log(&format!(
+                "  {}: {}",
+                build.location,
+                progress::build_message(build)
+            ));
+        }
+    }
+}
+
+/// Whether the load average is too high to start another command, given the
+/// limit if any and whether any command is running.  With nothing running,
+/// one is started regardless so the build can't stall.
+fn over_load_average(
+    max: Option<f64>,
+    running: bool,
+    load_average: impl FnOnce() -> Option<f64>,
+) -> bool {
+    match max {
+        Some(max) if running => load_average().is_some_and(|load| load > max),
+        _ => false,
+    }
+}
+
+/// Print a warning during a build.  This goes through the progress display,
+/// which would otherwise draw over it, but like log's warn! is silenced by
+/// --quiet.
+fn warn(progress: &mut dyn Progress, msg: std::fmt::Arguments) {
+    if !log::quiet() {
+        progress.log(&format!("n2: warn: {}", msg));
+    }
+}
+
+/// Describe a build for an --events-json event.
+fn event_edge(graph: &Graph, id: BuildId) -> events::Edge<'_> {
+    let build = &graph.builds[id];
+    events::Edge {
+        id: id.index(),
+        rule: &build.rule,
+        outs: build
+            .outs()
+            .iter()
+            .map(|&out| graph.file(out).name.as_str())
+            .collect(),
+    }
 }
 
 #[cfg(test)]
@@ -789,7 +1608,7 @@ build c: phony a
 ";
         let mut graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
         let a_id = graph.files.id_from_canonical("a".to_owned());
-        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default());
+        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default(), None);
         let mut stack = Vec::new();
         match states.want_file(&graph, &mut stack, a_id) {
             Ok(_) => panic!("expected build cycle error"),
@@ -797,4 +1616,106 @@ build c: phony a
         }
         Ok(())
     }
+
+    #[test]
+    fn max_memory() -> anyhow::Result<()> {
+        let file = "
+rule link
+  command = link $out
+  mem = 600
+rule ld
+  command = ld $out
+build a: link
+build b: link
+build c: ld
+  mem = 2000
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default(), Some(1000));
+        for id in graph.builds.all_ids() {
+            states.enqueue(id, &graph.builds[id])?;
+        }
+        let run = |states: &mut BuildStates| {
+            let id = states.pop_queued(&graph, true)?;
+            states.set(id, &graph.builds[id], BuildState::Running);
+            Some(id)
+        };
+        let finish = |states: &mut BuildStates, id: BuildId| {
+            states.set(id, &graph.builds[id], BuildState::Done);
+        };
+
+        let a = run(&mut states).unwrap();
+        // b would take the running total to 1200.
+        assert_eq!(run(&mut states), None);
+        finish(&mut states, a);
+        let b = run(&mut states).unwrap();
+        assert_eq!(run(&mut states), None);
+        finish(&mut states, b);
+        // c is over the whole budget, so it runs alone.
+        assert!(run(&mut states).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn load_average_limit() -> anyhow::Result<()> {
+        let busy = || Some(8.0);
+        assert!(!over_load_average(None, true, busy));
+        assert!(over_load_average(Some(4.0), true, busy));
+        assert!(!over_load_average(Some(8.0), true, busy));
+        // Nothing running, so the build can't stall.
+        assert!(!over_load_average(Some(4.0), false, busy));
+        // No load average to go by, as on Windows.
+        assert!(!over_load_average(Some(0.0), true, || None));
+
+        // Over the limit, builds are started one at a time.
+        let file = "
+rule cc
+  command = cc $out
+build a: cc
+build b: cc
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default(), None);
+        for id in graph.builds.all_ids() {
+            states.enqueue(id, &graph.builds[id])?;
+        }
+        let can_start = |running: bool| !over_load_average(Some(4.0), running, busy);
+        let a = states.pop_queued(&graph, can_start(false)).unwrap();
+        states.set(a, &graph.builds[a], BuildState::Running);
+        assert_eq!(states.pop_queued(&graph, can_start(true)), None);
+        states.set(a, &graph.builds[a], BuildState::Done);
+        assert!(states.pop_queued(&graph, can_start(false)).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn eta() -> anyhow::Result<()> {
+        let file = "
+rule cc
+  command = cc $out
+build a: cc
+build b: cc
+build c: cc
+build all: phony a b c
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let id = |name: &str| graph.file(graph.files.lookup(name).unwrap()).input.unwrap();
+        let mut hashes = Hashes::default();
+        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default(), None);
+        assert!(Eta::new(&graph, &hashes).is_none());
+
+        hashes.set_time(id("a"), Some(Duration::from_secs(2)));
+        hashes.set_time(id("b"), Some(Duration::from_secs(4)));
+        states.eta = Eta::new(&graph, &hashes);
+        for id in graph.builds.all_ids() {
+            states.enqueue(id, &graph.builds[id])?;
+        }
+        // c has never run, so it's expected to take the median time.
+        assert_eq!(states.eta(1), Some(Duration::from_secs(10)));
+        // Three builds are left to spread over two at a time.
+        assert_eq!(states.eta(2), Some(Duration::from_secs(5)));
+        states.set(id("c"), &graph.builds[id("c")], BuildState::Done);
+        assert_eq!(states.eta(8), Some(Duration::from_secs(3)));
+        Ok(())
+    }
 }
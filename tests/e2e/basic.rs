@@ -23,6 +23,69 @@ fn basic_build() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn build_subset() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "build other: touch in",
+            "build sub/x: touch in",
+            "default other",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    // Only the named targets and what they depend on are built, rather than
+    // the default.
+    let out = space.run_expect(&mut n2_command(vec!["out", "./sub/../sub/x"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    space.read("mid")?;
+    space.read("out")?;
+    space.read("sub/x")?;
+    assert!(space.read("other").is_err());
+
+    // Naming something the manifest doesn't know is an error, even among
+    // known targets, and nothing is built.
+    let out = space.run(&mut n2_command(vec!["other", "nope"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "n2: error: unknown path requested: \"nope\"");
+    assert!(space.read("other").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn response_file_args() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let mut manifest = vec![TOUCH_RULE.to_owned()];
+    let mut targets = Vec::new();
+    for i in 0..50 {
+        manifest.push(format!("build out{}: touch in", i));
+        targets.push(format!("out{}", i));
+    }
+    manifest.push("build skipped: touch in\n".to_owned());
+    space.write("build.ninja", &manifest.join("\n"))?;
+    space.write("in", "")?;
+    space.write("args.txt", &format!("-j 2\n{}\n", targets.join("\n")))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["@args.txt"]))?;
+    assert_output_contains(&out, "ran 50 tasks");
+    space.read("out49")?;
+    assert!(space.read("skipped").is_err());
+
+    let out = space.run(&mut n2_command(vec!["@missing.txt"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "n2: error: read missing.txt: ");
+
+    Ok(())
+}
+
 #[test]
 fn create_subdir() -> anyhow::Result<()> {
     // Run a build rule that needs a subdir to be automatically created.
@@ -76,9 +139,11 @@ build baz: touch in
     assert_eq!(foo_rsp, b"random stuff");
 
     // The 'make/me' directory was created when writing an rsp file.
-    // It should still be there.
+    // It should still be there, though the rsp files are cleaned up.
     let meta = space.metadata("make/me").unwrap();
     assert!(meta.is_dir());
+    assert!(space.read("main.rsp").is_err());
+    assert!(space.read("make/me/foo.rsp").is_err());
 
     // Run again: everything should be up to date.
     let out = space.run_expect(&mut n2_command(vec!["main"]))?;
@@ -87,6 +152,59 @@ build baz: touch in
     Ok(())
 }
 
+#[cfg(unix)]
+#[test]
+fn rsp_file_kept_on_failure() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule link
+  command = cp ${out}.rsp ${out} && test -z \"$fail\"
+  rspfile = ${out}.rsp
+  rspfile_content = $in
+build app: link a.o b.o
+build bad: link a.o
+  fail = 1
+",
+    )?;
+    space.write("a.o", "")?;
+    space.write("b.o", "")?;
+    space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_eq!(space.read("app")?, b"a.o b.o");
+    assert!(space.read("app.rsp").is_err());
+
+    let out = space.run(&mut n2_command(vec!["bad"]))?;
+    assert!(!out.status.success());
+    assert_eq!(space.read("bad.rsp")?, b"a.o");
+    Ok(())
+}
+
+#[test]
+fn shared_rsp_file_warns() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "  rspfile = shared.rsp",
+            "  rspfile_content = a",
+            "build b: touch",
+            "  rspfile = shared.rsp",
+            "  rspfile_content = b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["a", "b"]))?;
+    assert_output_contains(
+        &out,
+        "build.ninja:9: rspfile shared.rsp is also used by the build at build.ninja:6",
+    );
+    Ok(())
+}
+
 /// Run a task that prints something, and verify it shows up.
 #[cfg(unix)]
 #[test]
@@ -153,6 +271,53 @@ fn repeated_out() -> anyhow::Result<()> {
     let out = space.run_expect(&mut n2_command(vec!["out"]))?;
     assert_output_contains(&out, "is repeated in output list");
 
+    let out = space.run(&mut n2_command(vec!["-w", "dupbuild=err", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "n2: error: build.ninja:6: \"dup\" is repeated in output list [-w dupbuild=err]",
+    );
+
+    space.write("in", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "-w",
+        "rspfile=err,dupbuild=off",
+        "out",
+    ]))?;
+    assert_output_not_contains(&out, "is repeated in output list");
+
+    Ok(())
+}
+
+#[test]
+fn quiet() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule fail",
+            "  command = echo oops && exit 1",
+            "  description = failing $out",
+            "build dup dup: touch in",
+            "build out: touch dup",
+            "build bad: fail",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    // Success, with a warning along the way: nothing to say.
+    let out = space.run_expect(&mut n2_command(vec!["--quiet", "out"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "");
+    space.read("out")?;
+
+    let out = space.run(&mut n2_command(vec!["--quiet", "bad"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "failed: failing bad");
+    assert_output_contains(&out, "oops");
+
     Ok(())
 }
 
@@ -197,7 +362,10 @@ fn explain() -> anyhow::Result<()> {
     space.write("in", "")?;
     let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
     // The main "explain" log line:
-    assert_output_contains(&out, "explain: build.ninja:6: manifest changed");
+    assert_output_contains(
+        &out,
+        "explain: build.ninja:6: command or files changed since last run",
+    );
     // The dump of the file manifest after includes mtimes that we don't want
     // to be sensitive to, so just look for some bits we know show up there.
     assert_output_contains(&out, "discovered:");
@@ -266,6 +434,40 @@ build out: echo
     Ok(())
 }
 
+// A localized compiler's /showIncludes lines, as from a Japanese MSVC, are
+// recognized by msvc_deps_prefix and kept out of the output.
+#[cfg(unix)]
+#[test]
+fn showincludes_localized() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cl
+  command = echo '$text' && touch $out
+  description = CL $out
+  deps = msvc
+  msvc_deps_prefix = メモ: インクルード ファイル:
+build out: cl
+  text = メモ: インクルード ファイル: foo
+",
+    )?;
+    space.write("foo", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_not_contains(&out, "インクルード");
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.sub_mtime("foo", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    Ok(())
+}
+
 // Repro for issue #83.
 #[cfg(unix)]
 #[test]
@@ -312,6 +514,70 @@ build out3: phony out2
     Ok(())
 }
 
+// A phony alias never exists on disk, and is only out of date when one of
+// its inputs is.
+#[test]
+fn phony_alias() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+build a: touch in
+build b: touch
+build alias: phony a b src
+build out: touch alias
+",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.write("src", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    assert!(space.read("alias").is_err());
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.write("in", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // Any input changing counts, even one that isn't the newest.
+    space.sub_mtime("src", std::time::Duration::from_secs(10))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+// A phony build with no inputs runs nothing itself, but as in Ninja's
+// `build FORCE: phony` idiom, builds depending on it always run.
+#[test]
+fn empty_phony() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build all: phony",
+            "build out: touch FORCE",
+            "build FORCE: phony",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
 // builddir controls where .n2_db is written.
 #[test]
 fn builddir() -> anyhow::Result<()> {
@@ -350,6 +616,32 @@ build out: my_rule
     Ok(())
 }
 
+#[test]
+fn rule_missing_command() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule typo
+    description = touch $out
+
+build out1: typo
+build out2: typo
+build all: phony out1 out2
+",
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: build.ninja:2: rule \"typo\" has no command; builds using it act like phony",
+    );
+    // Warned once per rule, and never for phony itself.
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    assert_eq!(stdout.matches("n2: warn:").count(), 1, "{}", stdout);
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn deps_evaluate_build_bindings() -> anyhow::Result<()> {
@@ -451,3 +743,657 @@ build foo: write_file
     assert_eq!(space.read("foo")?, b"Hello, world!\n");
     Ok(())
 }
+
+#[test]
+fn define_overrides_variable() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            "msg = manifest",
+            "rule echo",
+            "  command = echo $msg > $out",
+            "build out: echo",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["-D", "msg=cmdline", "out"]))?;
+    assert_eq!(space.read("out")?, b"cmdline\n");
+
+    let out = space.run(&mut n2_command(vec!["-D", "msg"]))?;
+    assert_output_contains(&out, "-D expects key=value");
+    Ok(())
+}
+
+#[test]
+fn content_hash_skips_touched_inputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "hello")?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Touched but unchanged: up to date.
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Without the flag, the mtime change alone is enough to rebuild.
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // That build recorded no content hash, so this one rebuilds too.
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Changed contents rebuild.
+    space.write("in", "goodbye")?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Contents that can't be read are unknown, so rebuild rather than fail,
+    // both when checking and recording.
+    space.mkdir("dir")?;
+    space.rename("in", "in.old")?;
+    space.rename("dir", "in")?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn track_symlinks_notices_repointed_input() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("old", "")?;
+    space.sub_mtime("old", std::time::Duration::from_secs(10))?;
+    space.write("new", "")?;
+    space.symlink("new", "in")?;
+    let out = space.run_expect(&mut n2_command(vec!["--track-symlinks", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Repointing at an older file is still a change.
+    space.symlink("old", "in")?;
+    let out = space.run_expect(&mut n2_command(vec!["--track-symlinks", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(&mut n2_command(vec!["--track-symlinks", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn dry_run() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+rule link
+  command = cat $out.rsp > $out
+  rspfile = $out.rsp
+  rspfile_content = $in
+build a: touch in
+build b: touch in
+build all: phony a
+build c: link all b
+",
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["c"]))?;
+
+    // Only a is stale, but everything downstream of it would rerun too,
+    // including through the phony.
+    space.sub_mtime("a", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["-n", "c"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "touch a\ncat > c.rsp <<'N2_RSPFILE'\nall b\nN2_RSPFILE\ncat c.rsp > c\nn2: would run 2 tasks\n"
+    );
+
+    // The db is left as it is, even a damaged one.
+    let mut db = space.read(".n2_db")?;
+    db.extend_from_slice(b"\xff\xff");
+    space.write_bytes(".n2_db", &db)?;
+    let out = space.run_expect(&mut n2_command(vec!["-n", "c"]))?;
+    assert_output_contains(&out, "ignoring partial or corrupt records");
+    assert_eq!(space.read(".n2_db")?, db);
+
+    // Nothing was actually run, so it's all still dirty.  -v prints the same
+    // commands as they run.
+    let out = space.run_expect(&mut n2_command(vec!["-v", "c"]))?;
+    assert_output_contains(&out, "touch a\n");
+    assert_output_contains(
+        &out,
+        "cat > c.rsp <<'N2_RSPFILE'\nall b\nN2_RSPFILE\ncat c.rsp > c\n",
+    );
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn stats() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+rule slow
+  command = sleep 0.2 && touch $out
+  description = SLOW $out
+build a: touch
+build b: slow a
+build c: touch
+build all: phony b c
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--stats", "all"]))?;
+    assert_output_contains(
+        &out,
+        "n2: stats: 3 builds considered, 3 ran (0 failed), 0 skipped",
+    );
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    let slowest: Vec<&str> = stdout.lines().filter(|l| l.starts_with("  ")).collect();
+    assert_eq!(slowest.len(), 3, "{}", stdout);
+    assert!(slowest[0].ends_with("s SLOW b"), "{}", stdout);
+
+    space.sub_mtime("c", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--stats-json", "all"]))?;
+    assert_output_contains(
+        &out,
+        r#"{"considered":3,"ran":1,"failed":0,"skipped":2,"wall_ms":"#,
+    );
+    assert_output_contains(&out, r#""slowest":[{"desc":"touch c","ms":"#);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn events_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+rule fail
+  command = echo oops && exit 3
+build a: touch
+build b: fail a
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["--events-json", "events.json", "b"]))?;
+    // The human output is unaffected.
+    assert_output_contains(&out, "oops");
+    assert_output_not_contains(&out, "\"event\"");
+
+    let events = String::from_utf8(space.read("events.json")?)?;
+    let lines: Vec<&str> = events.lines().collect();
+    assert_eq!(lines.len(), 5, "{}", events);
+    assert_eq!(lines[0], r#"{"event":"begin","builds":2}"#);
+    assert_eq!(
+        lines[1],
+        r#"{"event":"start","id":0,"rule":"touch","outs":["a"]}"#
+    );
+    assert!(
+        lines[2].starts_with(
+            r#"{"event":"finish","id":0,"rule":"touch","outs":["a"],"exit_code":0,"ms":"#
+        ),
+        "{}",
+        events
+    );
+    assert_eq!(
+        lines[3],
+        r#"{"event":"start","id":1,"rule":"fail","outs":["b"]}"#
+    );
+    assert!(
+        lines[4].starts_with(
+            r#"{"event":"error","id":1,"rule":"fail","outs":["b"],"status":"failure","exit_code":3,"ms":"#
+        ),
+        "{}",
+        events
+    );
+    assert!(lines[4].ends_with(r#""output":"oops\n"}"#), "{}", events);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn process_env() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = "
+rule cc
+  command = echo $CC -c $in > $out
+build a.o: cc a.c
+";
+    space.write("build.ninja", manifest)?;
+    space.write("a.c", "")?;
+
+    // Ninja's default: undefined variables are empty.
+    space.run_expect(n2_command(vec!["a.o"]).env("CC", "mycc"))?;
+    assert_eq!(space.read("a.o")?, b"-c a.c\n");
+
+    space.run_expect(n2_command(vec!["--process-env", "a.o"]).env("CC", "mycc"))?;
+    assert_eq!(space.read("a.o")?, b"mycc -c a.c\n");
+
+    // The manifest wins over the environment.
+    space.write("build.ninja", &format!("CC = clang\n{}", manifest))?;
+    space.run_expect(n2_command(vec!["--process-env", "a.o"]).env("CC", "mycc"))?;
+    assert_eq!(space.read("a.o")?, b"clang -c a.c\n");
+    Ok(())
+}
+
+#[test]
+fn manifest_from_stdin() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("rules.ninja", TOUCH_RULE)?;
+    let manifest = "include rules.ninja\nbuild out: touch\n";
+    let out = space.run_with_stdin(&mut n2_command(vec!["-f", "-", "out"]), manifest)?;
+    assert!(out.status.success());
+    assert_output_contains(&out, "ran 1 task");
+    assert!(space.read("out").is_ok());
+
+    // The .n2_db is used as usual.
+    let out = space.run_with_stdin(&mut n2_command(vec!["-f", "-", "out"]), manifest)?;
+    assert_output_contains(&out, "no work to do");
+
+    let out = space.run_with_stdin(&mut n2_command(vec!["-f", "-"]), "build out: bogus\n")?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "unknown rule");
+    let out = space.run_with_stdin(&mut n2_command(vec!["-f", "-"]), "build out touch\n")?;
+    assert_output_contains(&out, "<stdin>:1:");
+    Ok(())
+}
+
+// With a load average limit no machine is under, builds still all run.  That
+// they're held back to one at a time is tested in work.rs.
+#[test]
+fn load_average_limit() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch",
+            "build c: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j", "3", "-l", "0", "a", "b", "c"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    Ok(())
+}
+
+#[test]
+fn keep_going_summary() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = exit 1
+  description = FAIL $out
+rule touch
+  command = touch $out
+build a: fail
+build b: fail
+build c: touch
+build d: touch a
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["-k", "0", "a", "b", "c", "d"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "n2: 2 builds failed:");
+    assert_output_contains(&out, "  build.ninja:7: FAIL a");
+    assert_output_contains(&out, "  build.ninja:8: FAIL b");
+    // Unrelated builds still ran, but those depending on failures didn't.
+    assert!(space.read("c").is_ok());
+    assert!(space.read("d").is_err());
+
+    // Without -k the first failure stops the build, with no summary.
+    let out = space.run(&mut n2_command(vec!["a", "b"]))?;
+    assert_output_not_contains(&out, "builds failed");
+    Ok(())
+}
+
+#[test]
+fn ninja_log() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert!(space.metadata(".ninja_log").is_err());
+
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    space.run_expect(&mut n2_command(vec!["--ninja-log", "out"]))?;
+    let log = String::from_utf8(space.read(".ninja_log")?)?;
+    let mut lines = log.lines();
+    assert_eq!(lines.next(), Some("# ninja log v5"));
+    let fields: Vec<&str> = lines.next().unwrap().split('\t').collect();
+    assert_eq!(fields.len(), 5);
+    assert_eq!(fields[3], "out");
+    assert_eq!(lines.next(), None);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn console_pool() -> anyhow::Result<()> {
+    use std::io::{Seek, Write};
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cat
+  command = cat
+build out: cat
+  pool = console
+",
+    )?;
+    // Console commands read n2's own stdin, rather than /dev/null.
+    let mut stdin = tempfile::tempfile()?;
+    stdin.write_all(b"from stdin\n")?;
+    stdin.seek(std::io::SeekFrom::Start(0))?;
+    let out = space.run_expect(n2_command(vec!["out"]).stdin(stdin))?;
+    assert_output_contains(&out, "from stdin");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn console_pool_bypasses_parallelism() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule wait
+  command = for i in 1 2 3 4 5 6 7 8 9 10; do test -f b && break; sleep 0.2; done; test -f b && touch $out
+  pool = console
+rule touch
+  command = touch $out
+build a: wait
+build x: touch
+build b: touch x
+build all: phony a b
+",
+    )?;
+    // With -j1, b can only run while a waits for it if a doesn't take up
+    // the one slot.
+    let out = space.run_expect(&mut n2_command(vec!["-j", "1", "all"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    Ok(())
+}
+
+#[test]
+fn no_final_newline() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in",
+            "  description = touch $out",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.read("out")?;
+    Ok(())
+}
+
+#[test]
+fn truncated_manifest() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    // A generated manifest cut off right after an escape.
+    space.write(
+        "build.ninja",
+        "rule touch\n  command = touch $out\nbuild out: touch in $",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_eq!(out.status.code(), Some(1));
+    assert_output_contains(
+        &out,
+        "build.ninja:3:22: parse error: unexpected EOF after '$'",
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn watch() -> anyhow::Result<()> {
+    use std::io::BufRead;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+  description = cp $out
+build out: cp in
+build other: cp other.in
+",
+    )?;
+    space.write("in", "1")?;
+    space.write("other.in", "")?;
+    let mut child = space.spawn(&mut n2_command(vec!["--watch", "out", "other"]))?;
+    let mut lines = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut read_until = |text: &str| -> anyhow::Result<String> {
+        let mut seen = String::new();
+        for line in &mut lines {
+            let line = line?;
+            seen.push_str(&line);
+            seen.push('\n');
+            if line.contains(text) {
+                return Ok(seen);
+            }
+        }
+        anyhow::bail!("expected {:?} but output ended:\n{}", text, seen);
+    };
+
+    let out = read_until("watching for changes")?;
+    assert!(out.contains("ran 2 tasks"), "{}", out);
+
+    // Save the way editors do, writing a new file and renaming it over.
+    space.write("in.tmp", "2")?;
+    space.rename("in.tmp", "in")?;
+    let out = read_until("watching for changes")?;
+    // Only the build of the changed file runs again.
+    assert!(out.contains("ran 1 task,"), "{}", out);
+    assert_eq!(space.read("out")?, b"2");
+
+    // ^C stops watching.
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    assert!(child.wait()?.success());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn timeout() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+rule hang
+  command = sleep 30 & sleep 30
+  description = HANG $out
+build a: hang
+  timeout = 1
+build b: touch
+build c: hang
+",
+        ]
+        .join("\n"),
+    )?;
+    let start = std::time::Instant::now();
+    let out = space.run(&mut n2_command(vec!["-k", "0", "a", "b"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "timed out: HANG a");
+    assert_output_contains(&out, "timed out after 1s");
+    // The backgrounded sleep was killed too, or n2 would still be waiting
+    // for it to close the output pipe.
+    assert!(start.elapsed().as_secs() < 20);
+    // Keep going carried on past the timeout.
+    assert!(space.read("b").is_ok());
+
+    // --timeout applies to builds without a timeout of their own.
+    let out = space.run(&mut n2_command(vec!["--timeout", "1", "c"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "timed out: HANG c");
+    assert!(start.elapsed().as_secs() < 40);
+    Ok(())
+}
+
+#[test]
+fn delete_failed_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule partial
+  command = echo partial > $out && exit 1
+build out: partial
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert!(space.read("out").is_err());
+
+    // With the output kept, the failed build still isn't up to date.
+    let out = space.run(&mut n2_command(vec!["--no-delete-on-failure", "out"]))?;
+    assert!(!out.status.success());
+    assert_eq!(space.read("out")?, b"partial\n");
+    let out = space.run(&mut n2_command(vec!["--no-delete-on-failure", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_not_contains(&out, "no work to do");
+
+    // An output the failing command didn't touch is kept.
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = exit 1
+build out: fail
+",
+    )?;
+    space.write("out", "good")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_eq!(space.read("out")?, b"good");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn watch_reload_subninja() -> anyhow::Result<()> {
+    use std::io::BufRead;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+  description = cp $out
+build top: cp top.in
+subninja sub.ninja
+",
+    )?;
+    space.write(
+        "sub.ninja",
+        "
+rule gendep
+  command = echo \"$out: dep\" > $out.d && cp $in $out
+  depfile = $out.d
+build out: gendep in
+",
+    )?;
+    space.write("top.in", "")?;
+    space.write("in", "1")?;
+    space.write("dep", "")?;
+    let mut child = space.spawn(&mut n2_command(vec!["--watch", "top", "out"]))?;
+    let mut lines = std::io::BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut read_until = |text: &str| -> anyhow::Result<String> {
+        let mut seen = String::new();
+        for line in &mut lines {
+            let line = line?;
+            seen.push_str(&line);
+            seen.push('\n');
+            if line.contains(text) {
+                return Ok(seen);
+            }
+        }
+        anyhow::bail!("expected {:?} but output ended:\n{}", text, seen);
+    };
+
+    let out = read_until("watching for changes")?;
+    assert!(out.contains("ran 2 tasks"), "{}", out);
+
+    // A dependency discovered by the build is watched too.
+    space.write("dep", "changed")?;
+    let out = read_until("watching for changes")?;
+    assert!(out.contains("ran 1 task,"), "{}", out);
+
+    // Editing the subninja reparses just that, rerunning only its build.
+    space.write(
+        "sub.ninja",
+        "
+rule gendep
+  command = echo \"$out: dep\" > $out.d && cp $in $out && echo again
+  depfile = $out.d
+build out: gendep in
+",
+    )?;
+    let out = read_until("watching for changes")?;
+    assert!(!out.contains("manifest changed"), "{}", out);
+    assert!(out.contains("ran 1 task,"), "{}", out);
+
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    assert!(child.wait()?.success());
+    Ok(())
+}
+
+#[test]
+fn stat_by_directory() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch a b c d", ""].join("\n"),
+    )?;
+    for name in ["a", "b", "c", "d"] {
+        space.write(name, "")?;
+    }
+    let out = space.run_expect(&mut n2_command(vec!["--stat-by-directory", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["--stat-by-directory", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    // The listed mtimes match those stat() gives.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    space.sub_mtime("c", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--stat-by-directory", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
@@ -60,6 +60,54 @@ build out: gendep
     Ok(())
 }
 
+/// depfile lists the build's own output as an input.
+#[test]
+fn depfile_self_reference() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out: gendep
+  dep_content = out: out
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "depfile lists output out as an input");
+    Ok(())
+}
+
+/// depfile lists an input whose build depends on this build's output.
+#[test]
+fn depfile_cycle() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            TOUCH_RULE,
+            "build gen: touch out",
+            "
+build out: gendep
+  dep_content = out: gen
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "depfile input gen would create a dependency cycle");
+    Ok(())
+}
+
 /// depfile contains reference to existing order-only dep.
 #[test]
 fn discover_existing_dep() -> anyhow::Result<()> {
@@ -157,3 +205,46 @@ build out: myrule
     assert_output_contains(&out, "no work");
     Ok(())
 }
+
+/// depfile names a target that isn't the build's output.
+#[test]
+fn depfile_wrong_target() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out: gendep
+  dep_content = other:
+build out2: gendep
+  dep_content = ./out2:
+build out3 | out3.imp: gendep
+  dep_content = out3.imp:
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out", "out2", "out3"]))?;
+    assert_output_contains(
+        &out,
+        "build.ninja:8: depfile names \"other\", which isn't an output of this build",
+    );
+    // Targets are compared after canonicalization.
+    assert_output_not_contains(&out, "\"./out2\"");
+    // Implicit outputs count too.
+    assert_output_not_contains(&out, "\"out3.imp\"");
+
+    // Like load warnings, -w can silence it or make it an error.
+    space.sub_mtime("out", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["-w", "depfiletarget=off", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_not_contains(&out, "depfile names");
+    space.sub_mtime("out", std::time::Duration::from_secs(1))?;
+    let out = space.run(&mut n2_command(vec!["-w", "depfiletarget=err", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "[-w depfiletarget=err]");
+    Ok(())
+}
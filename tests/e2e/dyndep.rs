@@ -0,0 +1,88 @@
+//! Tests for dyndep files, which add inputs and outputs to builds at build
+//! time.
+
+use crate::e2e::*;
+
+#[cfg(unix)]
+const DYNDEP_RULES: &str = "
+rule cp
+  command = cp $in $out
+rule touch_mod
+  command = touch $out $mod
+";
+
+#[cfg(unix)]
+#[test]
+fn dyndep_adds_edges() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            DYNDEP_RULES,
+            "build out.dd: cp out.dd.in",
+            "build gen.h: cp gen.h.in",
+            "build out: touch_mod || out.dd",
+            "  dyndep = out.dd",
+            "  mod = out.mod",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("gen.h.in", "")?;
+    space.write(
+        "out.dd.in",
+        "ninja_dyndep_version = 1\nbuild out | out.mod: dyndep | gen.h\n",
+    )?;
+
+    // gen.h is only known to be needed once out.dd is built.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    space.read("out.mod")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // The dyndep input dirties the build like any other.
+    space.write("gen.h.in", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // As does dropping it from the dyndep file.
+    space.write("out.dd.in", "ninja_dyndep_version = 1\nbuild out: dyndep\n")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+#[test]
+fn dyndep_must_be_input() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", "  dyndep = out.dd", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "dyndep file out.dd must be an input of the build");
+    Ok(())
+}
+
+#[test]
+fn dyndep_names_other_build() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build other: touch",
+            "build out: touch || out.dd",
+            "  dyndep = out.dd",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("out.dd", "ninja_dyndep_version = 1\nbuild other: dyndep\n")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "build for other doesn't name this file as its dyndep");
+    Ok(())
+}
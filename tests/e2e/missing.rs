@@ -11,7 +11,35 @@ fn missing_input() -> anyhow::Result<()> {
     )?;
 
     let out = space.run(&mut n2_command(vec!["out"]))?;
-    assert_output_contains(&out, "input in missing");
+    assert_output_contains(
+        &out,
+        "build.ninja:6: in, needed by out, missing and no known rule to make it",
+    );
+
+    Ok(())
+}
+
+#[test]
+fn missing_inputs_reported_before_building() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch a x",
+            "build c: touch b | y",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run(&mut n2_command(vec!["c"]))?;
+    assert!(!out.status.success());
+    // Every missing file is listed, and nothing ran.
+    assert_output_contains(&out, "x, needed by b, missing");
+    assert_output_contains(&out, "y, needed by c, missing");
+    assert!(space.read("a").is_err());
 
     Ok(())
 }
@@ -3,8 +3,10 @@
 mod basic;
 mod directories;
 mod discovered;
+mod dyndep;
 mod missing;
 mod regen;
+mod tools;
 mod validations;
 
 use anyhow::anyhow;
@@ -67,16 +69,41 @@ impl TestSpace {
         std::fs::write(self.dir.path().join(path), content)
     }
 
+    /// Write a file of arbitrary bytes into the working space.
+    pub fn write_bytes(&self, path: &str, content: &[u8]) -> std::io::Result<()> {
+        std::fs::write(self.dir.path().join(path), content)
+    }
+
     /// Read a file from the working space.
     pub fn read(&self, path: &str) -> anyhow::Result<Vec<u8>> {
         let path = self.dir.path().join(path);
         std::fs::read(&path).map_err(|err| anyhow!("read {}: {}", path.display(), err))
     }
 
+    /// Create a directory in the working space.
+    pub fn mkdir(&self, path: &str) -> std::io::Result<()> {
+        std::fs::create_dir(self.dir.path().join(path))
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        std::fs::rename(self.dir.path().join(from), self.dir.path().join(to))
+    }
+
     pub fn metadata(&self, path: &str) -> std::io::Result<std::fs::Metadata> {
         std::fs::metadata(self.dir.path().join(path))
     }
 
+    /// Create a symlink at `path` pointing at `target`, replacing any
+    /// existing link.
+    #[cfg(unix)]
+    pub fn symlink(&self, target: &str, path: &str) -> std::io::Result<()> {
+        let path = self.dir.path().join(path);
+        if std::fs::symlink_metadata(&path).is_ok() {
+            std::fs::remove_file(&path)?;
+        }
+        std::os::unix::fs::symlink(target, path)
+    }
+
     pub fn sub_mtime(&self, path: &str, dur: std::time::Duration) -> anyhow::Result<()> {
         let path = self.dir.path().join(path);
         let t = std::time::SystemTime::now() - dur;
@@ -89,6 +116,30 @@ impl TestSpace {
         cmd.current_dir(self.dir.path()).output()
     }
 
+    /// Like run, but with `input` piped to n2's stdin.
+    pub fn run_with_stdin(
+        &self,
+        cmd: &mut std::process::Command,
+        input: &str,
+    ) -> std::io::Result<std::process::Output> {
+        use std::io::Write;
+        let mut child = cmd
+            .current_dir(self.dir.path())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        child.wait_with_output()
+    }
+
+    /// Start n2 without waiting for it to finish, with its stdout piped.
+    pub fn spawn(&self, cmd: &mut std::process::Command) -> std::io::Result<std::process::Child> {
+        cmd.current_dir(self.dir.path())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+    }
+
     /// Like run, but also print output if the build failed.
     pub fn run_expect(
         &self,
@@ -39,6 +39,49 @@ EOT
     Ok(())
 }
 
+/// Editing an input of the generator regenerates build.ninja before anything
+/// else is built, and the build then follows the new manifest.
+#[cfg(unix)]
+#[test]
+fn regenerate_from_template() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "gen.sh",
+        "
+echo 'regenerating build.ninja'
+cat >build.ninja <<EOT
+rule regen
+  command = sh ./gen.sh
+  generator = 1
+build build.ninja: regen gen.sh build.ninja.in
+EOT
+cat build.ninja.in >>build.ninja
+",
+    )?;
+    space.write(
+        "build.ninja.in",
+        &[TOUCH_RULE, "build out: touch", "default out", ""].join("\n"),
+    )?;
+    space.run_expect(std::process::Command::new("sh").args(vec!["./gen.sh"]))?;
+    space.run_expect(&mut n2_command(vec![]))?;
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "no work");
+
+    space.write(
+        "build.ninja.in",
+        &[TOUCH_RULE, "build out2: touch", "default out2", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "regenerating build.ninja");
+    assert_output_contains(&out, "ran 2 tasks");
+    space.read("out2")?;
+
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "no work");
+
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn generate_specified_build_file() -> anyhow::Result<()> {
@@ -0,0 +1,230 @@
+//! Tests for the -t subcommands.
+
+use crate::e2e::*;
+
+#[test]
+fn stats() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule stamp",
+            "  command = touch stamp",
+            "build a: touch in",
+            "build b: touch a",
+            "build c: stamp",
+            "build d: stamp",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "stats"]))?;
+    assert_output_contains(&out, "builds: 4\n");
+    // build.ninja itself is also a file.
+    assert_output_contains(&out, "files: 6\n");
+    assert_output_contains(&out, "commands: 3 distinct, 1 shared by 2 builds\n");
+    assert_output_contains(&out, "memory: ~");
+    Ok(())
+}
+
+#[test]
+fn compdb() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule cc",
+            "  command = cc $in",
+            "build a.o: cc a.c",
+            "build b: touch a.o",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "compdb", "cc"]))?;
+    assert_output_contains(&out, "\"command\": \"cc a.c\"");
+    assert_output_contains(&out, "\"output\": \"a.o\"");
+    assert_output_not_contains(&out, "touch");
+    Ok(())
+}
+
+#[test]
+fn graph() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch in", "build b: touch a", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "graph", "b"]))?;
+    assert_output_contains(&out, "digraph ninja {");
+    assert_output_contains(&out, "[label=\"in\", shape=ellipse]");
+    assert_output_contains(&out, "[label=\"touch b\", shape=rectangle]");
+    Ok(())
+}
+
+#[test]
+fn query() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch in", "build b: touch a", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "query", "a"]))?;
+    assert_output_contains(&out, "a:\n  input: touch\n    in\n  outputs:\n    b\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn deps() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gendep
+  command = echo \"$out: in dep\" > $out.d && touch $out
+  depfile = $out.d
+build out: gendep in
+",
+    )?;
+    space.write("in", "")?;
+    space.write("dep", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "deps", "out"]))?;
+    assert_output_contains(&out, "out: deps not found");
+
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "deps"]))?;
+    // "in" is already a declared input, so only "dep" is discovered.
+    assert_output_contains(&out, "out: #deps 1, deps hash ");
+    assert_output_contains(&out, "\n    dep\n");
+    Ok(())
+}
+
+#[test]
+fn no_db() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &["rule cc", "  command = cc $in", "build a.o: cc a.c", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--no-db", "-t", "compdb"]))?;
+    assert_output_contains(&out, "\"command\": \"cc a.c\"");
+    assert!(space.read(".n2_db").is_err());
+    Ok(())
+}
+
+#[test]
+fn why() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch in",
+            "build b: touch a",
+            "build c: touch b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["c"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "why", "c"]))?;
+    assert_output_contains(&out, "c: up to date");
+
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "why", "c"]))?;
+    assert_output_contains(
+        &out,
+        "c: out of date
+  a (build.ninja:6): command or files changed since last run
+  b (build.ninja:7): input a is out of date
+  c (build.ninja:8): input b is out of date
+",
+    );
+
+    // With content hashing, touching a file without changing it is fine,
+    // as it is for the build.
+    space.run_expect(&mut n2_command(vec!["--content-hash", "c"]))?;
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "-t", "why", "c"]))?;
+    assert_output_contains(&out, "c: up to date");
+    let out = space.run_expect(&mut n2_command(vec!["-t", "why", "c"]))?;
+    assert_output_contains(&out, "c: out of date");
+    Ok(())
+}
+
+#[test]
+fn clean() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule regen",
+            "  command = touch build.ninja",
+            "  generator = 1",
+            "build build.ninja: regen",
+            "build a: touch in",
+            "build b: touch a",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["b"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean"]))?;
+    assert_output_contains(&out, "2 files.");
+    assert!(space.read("a").is_err());
+    assert!(space.read("b").is_err());
+    assert!(space.read("in").is_ok());
+    assert!(space.read("build.ninja").is_ok());
+
+    // Already clean: nothing left to remove, and that's not an error.
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean"]))?;
+    assert_output_contains(&out, "0 files.");
+    Ok(())
+}
+
+#[test]
+fn targets_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch in", "build b: touch a", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "targets", "all"]))?;
+    assert_output_contains(&out, "a: touch\nb: touch\n");
+    let out = space.run_expect(&mut n2_command(vec!["--json", "-t", "targets", "all"]))?;
+    assert_output_contains(&out, "{\"output\": \"b\", \"rule\": \"touch\"}\n]");
+    Ok(())
+}
+
+#[test]
+fn recompact() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch in", "build b: touch a", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    for _ in 0..3 {
+        space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+        let out = space.run_expect(&mut n2_command(vec!["b"]))?;
+        assert_output_contains(&out, "ran 2 tasks");
+    }
+    let before = space.metadata(".n2_db")?.len();
+
+    space.run_expect(&mut n2_command(vec!["-t", "recompact"]))?;
+    assert!(space.metadata(".n2_db")?.len() < before);
+
+    // The rewritten db still knows everything is up to date.
+    let out = space.run_expect(&mut n2_command(vec!["b"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
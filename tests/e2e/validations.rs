@@ -21,6 +21,32 @@ fn basic_validation() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn validation_does_not_dirty_build() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in |@ check",
+            "build check: touch check_in",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.write("check_in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // Only the validation reruns when its own input changes.
+    space.sub_mtime("check_in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_contains(&out, "touch check");
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn build_starts_before_validation_finishes() -> anyhow::Result<()> {